@@ -0,0 +1,303 @@
+//! Operator commands served on the token-protected `/admin` WebSocket path.
+//!
+//! Every command is a `{"type": ..., "data": ...}` frame like the player
+//! protocol, and every command gets exactly one direct reply.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State as AxumState},
+    middleware,
+    routing::{delete, get},
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use tracing::info;
+
+use crate::{
+    announcements::Severity,
+    create_event_json,
+    faults::{self, Fault},
+    game::{FinishReason, Game, GameId},
+    http::{self, Authorize, Response},
+    lock_state, CloseReason, ServerEvent, ServerState, State,
+};
+
+pub const ADMIN_PATH: &str = "/admin";
+const ADMIN_TOKEN_VAR: &str = "ADMIN_TOKEN";
+
+/// Checks the token given in an `Authorization: Bearer` header. It is never
+/// taken from the query string, which ends up in access logs. The channel is
+/// disabled when no `ADMIN_TOKEN` is configured.
+pub fn is_authorized(authorization: Option<&str>) -> bool {
+    token_matches(ADMIN_TOKEN_VAR, authorization)
+}
+
+/// Checks a request against the token configured in `var`; nothing matches
+/// when it is unset or empty. Compared in constant time, so the time taken
+/// tells nothing about how much of the token was right.
+pub(crate) fn token_matches(var: &str, authorization: Option<&str>) -> bool {
+    let expected = match std::env::var(var) {
+        Ok(token) if !token.is_empty() => token,
+        _ => return false,
+    };
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| bool::from(token.as_bytes().ct_eq(expected.as_bytes())))
+}
+
+pub fn handle_admin_command(command_json: &str, state: &mut ServerState) -> String {
+    let json: serde_json::Value =
+        serde_json::from_str(command_json).unwrap_or(serde_json::Value::Null);
+    let command_type = json["type"].as_str().unwrap_or("unknown").to_owned();
+    let data: serde_json::Value = match &json["data"] {
+        serde_json::Value::String(data) => {
+            serde_json::from_str(data).unwrap_or(serde_json::Value::Null)
+        }
+        data => data.clone(),
+    };
+    info!("Admin command {} {:?}", command_type, data);
+
+    let result = run_command(&command_type, &data, state);
+    match result {
+        Ok(data) => create_event_json(data, command_type),
+        Err(text) => create_event_json(json!({ "errorText": text }), "error".into()),
+    }
+}
+
+pub(crate) fn run_command(
+    command_type: &str,
+    data: &serde_json::Value,
+    state: &mut ServerState,
+) -> Result<serde_json::Value, String> {
+    let mut state_lock = lock_state(state);
+    if !command_type.starts_with("list_") {
+        state_lock.audit.record("admin", command_type, data.clone());
+    }
+    match command_type {
+        "list_users" => Ok(users_json(&state_lock)),
+        "list_games" => Ok(games_json(&state_lock)),
+        "list_flags" => Ok(json!(state_lock.farming.flags())),
+        "review_flag" => {
+            let id = data["id"].as_u64().ok_or("Missing id")? as u32;
+            let clear = match data["verdict"].as_str() {
+                Some("clear") => true,
+                Some("confirm") => false,
+                _ => return Err("Verdict must be clear or confirm".to_owned()),
+            };
+            let held_wins = state_lock.farming.review(id, clear)?;
+            // Players who left since lost their wins anyway.
+            for (name, wins) in held_wins.iter() {
+                if let Some(user) = state_lock
+                    .users
+                    .values_mut()
+                    .find(|user| &user.name == name)
+                {
+                    user.wins += wins;
+                }
+            }
+            state_lock.add_update_winners_event();
+            Ok(json!({ "id": id, "creditedWins": held_wins }))
+        }
+        "finish_game" => {
+            let game_id = game_id(data)?;
+            let winner = data["winPlayer"].as_str().map(|winner| winner.to_owned());
+            let game = state_lock.games.get(&game_id).ok_or("Game not found")?;
+            if let Some(winner) = &winner {
+                if !game.is_player(winner) {
+                    return Err("Winner is not a player of the game".to_owned());
+                }
+            }
+            state_lock.end_game(&game_id, winner, FinishReason::Forced);
+            state_lock.add_update_room_event();
+            Ok(json!({ "gameId": game_id }))
+        }
+        "delete_game" => {
+            let game_id = game_id(data)?;
+            if !state_lock.games.contains_key(&game_id) {
+                return Err("Game not found".to_owned());
+            }
+            state_lock.delete_game(&game_id);
+            Ok(json!({ "gameId": game_id }))
+        }
+        "snapshot_game" => {
+            let game_id = game_id(data)?;
+            let game = state_lock.games.get(&game_id).ok_or("Game not found")?;
+            Ok(json!(game))
+        }
+        "restore_game" => {
+            let game = Game::deserialize(&data["snapshot"]).map_err(|err| err.to_string())?;
+            if state_lock.games.contains_key(&game.id) {
+                return Err("Game already exists".to_owned());
+            }
+            let game_id = game.id.clone();
+            // Players still connected under the same ids get the room back.
+            for user_id in game.players().into_iter().flatten() {
+                if let Some(user) = state_lock.users.get_mut(&user_id) {
+                    user.rooms.insert(game_id.clone());
+                }
+            }
+            state_lock.games.insert(game_id.clone(), game);
+            state_lock.cluster.claim(&game_id);
+            state_lock.schedule_deadlines(&game_id);
+            state_lock.add_update_room_event();
+            Ok(json!({ "gameId": game_id }))
+        }
+        "announce" => {
+            let text = data["text"].as_str().ok_or("Missing text")?;
+            let severity = match data["severity"].as_str() {
+                Some(severity) => severity.parse()?,
+                None => Severity::Info,
+            };
+            let expires_in = data["expiresInSeconds"].as_u64().map(Duration::from_secs);
+            let json = state_lock
+                .announcements
+                .announce(text, severity, expires_in);
+            state_lock.add_event(&ServerEvent::All(create_event_json(
+                json.clone(),
+                "server_announcement".into(),
+            )));
+            Ok(json)
+        }
+        "add_bot" => {
+            let name = data["name"].as_str().ok_or("Missing name")?;
+            let strategy = data["strategy"].as_str().ok_or("Missing strategy")?;
+            let bot = state_lock.add_resident_bot(name, strategy)?;
+            Ok(json!({ "name": bot.name, "index": bot.id, "strategy": strategy }))
+        }
+        "remove_bot" => {
+            let name = data["name"].as_str().ok_or("Missing name")?;
+            let bot_id = state_lock
+                .get_user_by_name(name)
+                .ok_or("Bot not found")?
+                .id
+                .clone();
+            // It leaves on a tick once its games are over.
+            if !state_lock.matchmaking.retire(&bot_id) {
+                return Err("Not a resident bot".to_owned());
+            }
+            Ok(json!({ "name": name, "index": bot_id }))
+        }
+        "maintenance" => {
+            let enabled = data["enabled"].as_bool().ok_or("Missing enabled")?;
+            state_lock.maintenance = enabled;
+            // Takes the rooms off or back on the lobby of the other nodes.
+            state_lock.add_update_room_event();
+            Ok(json!({ "enabled": enabled }))
+        }
+        "set_motd" => {
+            // An empty or missing text clears it.
+            state_lock.motd = data["text"]
+                .as_str()
+                .filter(|text| !text.is_empty())
+                .map(str::to_owned);
+            Ok(json!({ "motd": state_lock.motd }))
+        }
+        "kick" => {
+            let name = data["name"].as_str().ok_or("Missing name")?;
+            let user = state_lock
+                .get_user_by_name(name)
+                .ok_or("User not found")?
+                .clone();
+            // A kicked player doesn't get to resume their session.
+            state_lock.sessions.close(&user.id);
+            state_lock.add_event(&ServerEvent::Close(user.addr, CloseReason::Kicked));
+            Ok(json!({ "name": name }))
+        }
+        "inject_fault" => {
+            let point = data["point"].as_str().ok_or("Missing point")?;
+            let fault = Fault::parse(data["fault"].as_str().ok_or("Missing fault")?)?;
+            faults::inject(point, fault)?;
+            Ok(json!({ "faults": faults::list() }))
+        }
+        "list_faults" => Ok(json!({ "faults": faults::list() })),
+        "clear_faults" => {
+            faults::clear();
+            Ok(json!({ "faults": [] }))
+        }
+        "shutdown" => {
+            let addrs = state_lock
+                .users
+                .values()
+                .map(|user| user.addr)
+                .collect::<Vec<_>>();
+            for addr in addrs {
+                state_lock.add_event(&ServerEvent::Close(addr, CloseReason::Shutdown));
+            }
+            state_lock.shutting_down = true;
+            Ok(json!({ "players": state_lock.users.len() }))
+        }
+        _ => Err("Unknown command".to_owned()),
+    }
+}
+
+/// Admin REST API, nested under `/api`.
+pub(crate) fn router() -> Router<ServerState> {
+    Router::new()
+        .route("/games", get(list_games))
+        .route("/games/{game_id}", delete(remove_game))
+        .route("/users", get(list_users))
+        .route_layer(middleware::from_fn_with_state(
+            is_authorized as Authorize,
+            http::require,
+        ))
+}
+
+async fn list_games(AxumState(state): AxumState<ServerState>) -> Response {
+    Response::json(200, games_json(&lock_state(&state)))
+}
+
+async fn list_users(AxumState(state): AxumState<ServerState>) -> Response {
+    Response::json(200, users_json(&lock_state(&state)))
+}
+
+async fn remove_game(
+    AxumState(state): AxumState<ServerState>,
+    Path(game_id): Path<GameId>,
+) -> Response {
+    let mut state_lock = lock_state(&state);
+    if !state_lock.games.contains_key(&game_id) {
+        return Response::error(404, "Game not found");
+    }
+    state_lock
+        .audit
+        .record("admin", "delete_game", json!({ "gameId": game_id }));
+    state_lock.delete_game(&game_id);
+    Response::json(200, json!({ "gameId": game_id }))
+}
+
+pub(crate) fn users_json(state: &State) -> serde_json::Value {
+    json!(state
+        .users
+        .values()
+        .map(|user| json!({
+            "index": user.id,
+            "name": user.name,
+            "addr": user.addr.to_string(),
+            "wins": user.wins,
+            "rooms": user.rooms,
+        }))
+        .collect::<Vec<serde_json::Value>>())
+}
+
+pub(crate) fn games_json(state: &State) -> serde_json::Value {
+    json!(state
+        .games
+        .values()
+        .map(|game| json!({
+            "gameId": game.id,
+            "status": game.status(),
+            "players": game.players(),
+            "settings": game.settings,
+        }))
+        .collect::<Vec<serde_json::Value>>())
+}
+
+fn game_id(data: &serde_json::Value) -> Result<String, String> {
+    data["gameId"]
+        .as_str()
+        .map(|game_id| game_id.to_owned())
+        .ok_or_else(|| "Missing gameId".to_owned())
+}
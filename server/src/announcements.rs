@@ -0,0 +1,100 @@
+//! Operator announcements, pushed to every client as `server_announcement`
+//! by the `announce` admin command: maintenance warnings, tournaments, rule
+//! changes.
+//!
+//! An announcement has a `severity` for the client to style it by and may
+//! expire. One that expires is kept until then and also sent to clients
+//! that register in the meantime, with `expiresInMs` counting down; one
+//! that doesn't is only sent to the clients connected at the time.
+
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::clock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "info" => Ok(Self::Info),
+            "warning" => Ok(Self::Warning),
+            "critical" => Ok(Self::Critical),
+            _ => Err(format!("Unknown severity {}", name)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Announcement {
+    id: u32,
+    text: String,
+    severity: Severity,
+    expires_at: Option<Instant>,
+}
+
+impl Announcement {
+    fn json(&self) -> Value {
+        let expires_in = self
+            .expires_at
+            .map(|expires_at| expires_at.saturating_duration_since(clock::now()));
+        json!({
+            "id": self.id,
+            "text": self.text,
+            "severity": self.severity,
+            "expiresInMs": expires_in.map(|expires_in| expires_in.as_millis() as u64),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Announcements {
+    /// Announcements that didn't expire yet.
+    active: Vec<Announcement>,
+    last_id: u32,
+}
+
+impl Announcements {
+    /// Makes an announcement and returns the event data to broadcast.
+    pub fn announce(
+        &mut self,
+        text: &str,
+        severity: Severity,
+        expires_in: Option<Duration>,
+    ) -> Value {
+        self.last_id += 1;
+        let announcement = Announcement {
+            id: self.last_id,
+            text: text.to_owned(),
+            severity,
+            expires_at: expires_in.map(|expires_in| clock::now() + expires_in),
+        };
+        let json = announcement.json();
+        if announcement.expires_at.is_some() {
+            self.active.push(announcement);
+        }
+        json
+    }
+
+    /// The event data of the announcements still in effect, for a client
+    /// that just registered.
+    pub fn active(&mut self) -> Vec<Value> {
+        let now = clock::now();
+        self.active
+            .retain(|announcement| announcement.expires_at.is_some_and(|at| at > now));
+        self.active.iter().map(Announcement::json).collect()
+    }
+}
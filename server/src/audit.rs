@@ -0,0 +1,134 @@
+//! Append-only audit trail of state-changing events.
+//!
+//! Enabled by pointing `AUDIT_LOG` at a file. Every record is one JSON line
+//! with a millisecond timestamp, the acting user (or `server`/`admin`), the
+//! action and its details. The file is rotated to `<path>.1` ... `<path>.N`
+//! once it grows past `MAX_FILE_SIZE`.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::Duration,
+};
+
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::now_ms;
+
+const AUDIT_LOG_VAR: &str = "AUDIT_LOG";
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const MAX_ROTATED_FILES: usize = 5;
+
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    /// `None` when auditing is disabled or the file could not be opened.
+    file: Option<File>,
+    path: PathBuf,
+    size: u64,
+}
+
+impl AuditLog {
+    pub fn from_env() -> Self {
+        match std::env::var(AUDIT_LOG_VAR) {
+            Ok(path) if !path.is_empty() => Self::open(PathBuf::from(path)),
+            _ => Self::default(),
+        }
+    }
+
+    fn open(path: PathBuf) -> Self {
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => {
+                let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+                Self {
+                    file: Some(file),
+                    path,
+                    size,
+                }
+            }
+            Err(err) => {
+                warn!("Audit log {} is disabled: {}", path.display(), err);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn record(&mut self, actor: &str, action: &str, details: serde_json::Value) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let line = json!({
+            "ts": now_ms(),
+            "actor": actor,
+            "action": action,
+            "details": details,
+        })
+        .to_string()
+            + "\n";
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write the audit log: {}", err);
+            return;
+        }
+        self.size += line.len() as u64;
+        if self.size >= MAX_FILE_SIZE {
+            self.rotate();
+        }
+    }
+
+    /// The whole trail at `AUDIT_LOG`, oldest rotated file first, for tools
+    /// reading it back.
+    pub fn read_from_env() -> Result<String, String> {
+        let path = std::env::var(AUDIT_LOG_VAR)
+            .ok()
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| format!("{} is not set", AUDIT_LOG_VAR))?;
+        let log = Self {
+            path: PathBuf::from(path),
+            ..Self::default()
+        };
+        let mut text = String::new();
+        for index in (1..=MAX_ROTATED_FILES).rev() {
+            if let Ok(part) = fs::read_to_string(log.rotated(index)) {
+                text += &part;
+            }
+        }
+        text += &fs::read_to_string(&log.path)
+            .map_err(|err| format!("Can't read {}: {}", log.path.display(), err))?;
+        Ok(text)
+    }
+
+    /// Deletes rotated files last written more than `max_age` ago.
+    pub fn remove_rotated_older_than(&self, max_age: Duration) {
+        if self.file.is_none() {
+            return;
+        }
+        for index in 1..=MAX_ROTATED_FILES {
+            let path = self.rotated(index);
+            let expired = fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .is_ok_and(|modified| modified.elapsed().unwrap_or_default() > max_age);
+            if expired && fs::remove_file(&path).is_ok() {
+                info!("Removed expired audit log {}", path.display());
+            }
+        }
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    /// Shifts `<path>.N-1` to `<path>.N` and so on, then starts a new file.
+    fn rotate(&mut self) {
+        self.file = None;
+        for index in (1..MAX_ROTATED_FILES).rev() {
+            let _ = fs::rename(self.rotated(index), self.rotated(index + 1));
+        }
+        if let Err(err) = fs::rename(&self.path, self.rotated(1)) {
+            warn!("Failed to rotate the audit log: {}", err);
+        }
+        *self = Self::open(self.path.clone());
+    }
+}
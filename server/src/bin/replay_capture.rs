@@ -0,0 +1,115 @@
+//! Plays traffic captured with `--capture` against a fresh server.
+//!
+//!     cargo run --bin replay_capture -- captures/ --url ws://127.0.0.1:3000
+//!
+//! Every captured connection opens its own WebSocket and sends its frames
+//! in the captured order, at the captured pace unless `--fast` is given.
+//! What was sent and what the server answered is printed as it happens.
+
+use std::{collections::HashMap, env, path::PathBuf, process, time::Duration};
+
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use server::capture::{self, Frame};
+use tokio::{net::TcpStream, time};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+const DEFAULT_URL: &str = "ws://127.0.0.1:3000";
+/// Answers to the last frames get this long to arrive.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+type Sink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
+struct Options {
+    dir: PathBuf,
+    url: String,
+    fast: bool,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut dir = None;
+    let mut url = DEFAULT_URL.to_owned();
+    let mut fast = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--url" => url = args.next().ok_or("Missing value for --url")?,
+            "--fast" => fast = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            _ if dir.is_none() => dir = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+    Ok(Options {
+        dir: dir.ok_or("Missing capture directory")?,
+        url,
+        fast,
+    })
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_options().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        eprintln!(
+            "Usage: replay_capture <dir> [--url {}] [--fast]",
+            DEFAULT_URL
+        );
+        process::exit(2);
+    });
+    if let Err(err) = run(options).await {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+async fn run(options: Options) -> Result<(), String> {
+    let records = capture::load(&options.dir)?;
+    let mut connections: HashMap<String, Sink> = HashMap::new();
+    let mut last_ms = records.first().map(|(_, record)| record.at_ms);
+    for (connection, record) in records {
+        if !options.fast {
+            let pause = record.at_ms.saturating_sub(last_ms.unwrap_or(record.at_ms));
+            time::sleep(Duration::from_millis(pause)).await;
+        }
+        last_ms = Some(record.at_ms);
+        match record.frame {
+            Frame::Open(_) => {
+                let (ws_stream, _) = connect_async(options.url.as_str())
+                    .await
+                    .map_err(|err| format!("Can't connect to {}: {}", options.url, err))?;
+                println!("{} connects", connection);
+                let (sink, mut stream) = ws_stream.split();
+                let name = connection.clone();
+                tokio::spawn(async move {
+                    while let Some(Ok(message)) = stream.next().await {
+                        match message {
+                            Message::Text(text) => println!("{} <- {}", name, text.as_str()),
+                            Message::Close(frame) => println!("{} closed {:?}", name, frame),
+                            _ => {}
+                        }
+                    }
+                });
+                connections.insert(connection, sink);
+            }
+            Frame::Text(text) => {
+                // The capture may have started after the connection.
+                let Some(sink) = connections.get_mut(&connection) else {
+                    continue;
+                };
+                println!("{} -> {}", connection, text);
+                if let Err(err) = sink.send(Message::text(text)).await {
+                    println!("{} can't send: {}", connection, err);
+                    connections.remove(&connection);
+                }
+            }
+            Frame::Close(_) => {
+                if let Some(mut sink) = connections.remove(&connection) {
+                    println!("{} disconnects", connection);
+                    let _ = sink.close().await;
+                }
+            }
+        }
+    }
+    time::sleep(SETTLE_TIME).await;
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! Runs batches of AI-vs-AI games and writes one row per game.
+//!
+//!     cargo run --release --bin simulate -- --games 1000 --format jsonl --out results.jsonl
+//!
+//! Every combination of `--strategies` (for both players), `--board-sizes`
+//! and `--no-touching` (`false`, `true` or `both`) plays `--games` games.
+//! `--seed` makes the whole batch reproducible.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter, Write},
+    process,
+};
+
+use server::simulation::{self, GameRecord, Rules, Strategy};
+
+struct Options {
+    games: usize,
+    strategies: Vec<Strategy>,
+    board_sizes: Vec<usize>,
+    no_touching: Vec<bool>,
+    jsonl: bool,
+    out: Option<String>,
+    seed: u64,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        games: 100,
+        strategies: vec![Strategy::Random, Strategy::Hunt],
+        board_sizes: vec![10],
+        no_touching: vec![false],
+        jsonl: false,
+        out: None,
+        seed: rand::random(),
+    };
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", flag))?;
+        match flag.as_str() {
+            "--games" => options.games = value.parse().map_err(|_| "Invalid --games")?,
+            "--strategies" => {
+                options.strategies = value.split(',').map(str::parse).collect::<Result<_, _>>()?
+            }
+            "--board-sizes" => {
+                options.board_sizes = value
+                    .split(',')
+                    .map(|size| size.parse().map_err(|_| "Invalid --board-sizes"))
+                    .collect::<Result<_, _>>()?
+            }
+            "--no-touching" => {
+                options.no_touching = match value.as_str() {
+                    "false" => vec![false],
+                    "true" => vec![true],
+                    "both" => vec![false, true],
+                    _ => return Err("--no-touching must be false, true or both".to_owned()),
+                }
+            }
+            "--format" => {
+                options.jsonl = match value.as_str() {
+                    "csv" => false,
+                    "jsonl" => true,
+                    _ => return Err("--format must be csv or jsonl".to_owned()),
+                }
+            }
+            "--out" => options.out = Some(value),
+            "--seed" => options.seed = value.parse().map_err(|_| "Invalid --seed")?,
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+    }
+    for &board_size in options.board_sizes.iter() {
+        for &no_touching in options.no_touching.iter() {
+            Rules {
+                board_size,
+                no_touching,
+            }
+            .validate()?;
+        }
+    }
+    Ok(options)
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let mut out: Box<dyn Write> = match &options.out {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    if !options.jsonl {
+        writeln!(out, "{}", GameRecord::CSV_HEADER)?;
+    }
+    eprintln!("Batch seed {}", options.seed);
+
+    let mut seeds = simulation::seeds(options.seed);
+    let mut game = 0;
+    for &board_size in options.board_sizes.iter() {
+        for &no_touching in options.no_touching.iter() {
+            let rules = Rules {
+                board_size,
+                no_touching,
+            };
+            for &strategy1 in options.strategies.iter() {
+                for &strategy2 in options.strategies.iter() {
+                    for _ in 0..options.games {
+                        let seed = seeds.next().unwrap();
+                        let record = simulation::play(game, rules, [strategy1, strategy2], seed);
+                        if options.jsonl {
+                            record.write_jsonl(&mut out)?;
+                        } else {
+                            record.write_csv(&mut out)?;
+                        }
+                        game += 1;
+                    }
+                }
+            }
+        }
+    }
+    out.flush()?;
+    eprintln!("Played {} games", game);
+    Ok(())
+}
+
+fn main() {
+    let options = parse_options().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(2);
+    });
+    if let Err(err) = run(options) {
+        eprintln!("Failed to write results: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,457 @@
+//! Plays one short game against a running server, for checking a deploy.
+//!
+//!     cargo run --bin smoketest -- --url wss://battleships.example.com
+//!
+//! Two clients register under throwaway names, meet in a room with one
+//! single-deck ship each on an 8x8 board and fire until one ship sinks.
+//! Every step is checked: an `error` event, a malformed message, an
+//! unexpected result or a reply taking longer than `--timeout` seconds
+//! fails the run with a non-zero exit code.
+//!
+//! With `--soak MINUTES` it keeps `--pairs` games going at once for that
+//! long, to check that the server's cleanup keeps up:
+//!
+//!     cargo run --bin smoketest -- --soak 240 --pairs 20 --metrics 127.0.0.1:3001
+//!
+//! Every `--report` seconds the state table sizes, event queue depth and
+//! resident memory from `/metrics` are printed. Players come from a fixed
+//! pool of names, so no table has a reason to grow with the number of
+//! games played. Once the churn stops every table has to be back at its
+//! size after the first round of games, or the run fails.
+
+use std::{
+    collections::BTreeMap,
+    env, process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinSet,
+    time,
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+const DEFAULT_URL: &str = "ws://127.0.0.1:3000";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_METRICS: &str = "127.0.0.1:3001";
+const DEFAULT_PAIRS: usize = 10;
+const DEFAULT_REPORT: Duration = Duration::from_secs(60);
+/// Time the server gets to clean up after the last soak game.
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+const BOARD_SIZE: u8 = 8;
+
+#[derive(Clone)]
+struct Options {
+    url: String,
+    timeout: Duration,
+    soak: Option<Duration>,
+    pairs: usize,
+    /// Address of the HTTP server with `/metrics`.
+    metrics: String,
+    report: Duration,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        url: DEFAULT_URL.to_owned(),
+        timeout: DEFAULT_TIMEOUT,
+        soak: None,
+        pairs: DEFAULT_PAIRS,
+        metrics: DEFAULT_METRICS.to_owned(),
+        report: DEFAULT_REPORT,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        let number = |value: String| {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} takes a number", arg))
+        };
+        match arg.as_str() {
+            "--url" => options.url = value()?,
+            "--timeout" => options.timeout = Duration::from_secs(number(value()?)?),
+            "--soak" => options.soak = Some(Duration::from_secs(number(value()?)? * 60)),
+            "--pairs" => options.pairs = number(value()?)?.max(1) as usize,
+            "--metrics" => options.metrics = value()?,
+            "--report" => options.report = Duration::from_secs(number(value()?)?.max(1)),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_options().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        eprintln!(
+            "Usage: smoketest [--url {}] [--timeout {}] [--soak MINUTES [--pairs {}] \
+             [--metrics {}] [--report {}]]",
+            DEFAULT_URL,
+            DEFAULT_TIMEOUT.as_secs(),
+            DEFAULT_PAIRS,
+            DEFAULT_METRICS,
+            DEFAULT_REPORT.as_secs()
+        );
+        process::exit(2);
+    });
+    let started = Instant::now();
+    let suffix = &Uuid::new_v4().simple().to_string()[..8];
+    let result = match options.soak {
+        Some(duration) => soak(&options, duration, suffix).await,
+        None => play(&options, &format!("smoke-{}", suffix)).await,
+    };
+    match result {
+        Ok(()) => println!("Smoke test passed in {:?}", started.elapsed()),
+        Err(err) => {
+            eprintln!("Smoke test failed: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+struct Client {
+    name: String,
+    ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    timeout: Duration,
+    /// Prints the conversation, off while soaking.
+    verbose: bool,
+    /// Set by `reg`.
+    index: String,
+}
+
+impl Client {
+    async fn connect(options: &Options, name: String) -> Result<Self, String> {
+        let (ws_stream, _) = connect_async(options.url.as_str())
+            .await
+            .map_err(|err| format!("{} can't connect to {}: {}", name, options.url, err))?;
+        let verbose = options.soak.is_none();
+        if verbose {
+            println!("{} connected", name);
+        }
+        Ok(Self {
+            name,
+            ws_stream,
+            timeout: options.timeout,
+            verbose,
+            index: String::new(),
+        })
+    }
+
+    async fn send(&mut self, event_type: &str, data: Value) -> Result<(), String> {
+        let frame = json!({ "type": event_type, "data": data.to_string(), "id": 0 });
+        if self.verbose {
+            println!("{} -> {} {}", self.name, event_type, data);
+        }
+        self.ws_stream
+            .send(Message::text(frame.to_string()))
+            .await
+            .map_err(|err| format!("{} can't send {}: {}", self.name, event_type, err))
+    }
+
+    /// Reads until one of `event_types` arrives and returns its type and
+    /// data. Anything else the server sends meanwhile, like lobby updates,
+    /// is skipped, save for errors.
+    async fn expect(&mut self, event_types: &[&str]) -> Result<(String, Value), String> {
+        let deadline = time::Instant::now() + self.timeout;
+        loop {
+            let message = time::timeout_at(deadline, self.ws_stream.next())
+                .await
+                .map_err(|_| format!("{} got no {} in time", self.name, event_types.join("/")))?;
+            let text = match message {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(frame))) => {
+                    return Err(format!("{} was disconnected: {:?}", self.name, frame))
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => {
+                    return Err(format!("{} lost the connection: {}", self.name, err))
+                }
+                None => return Err(format!("{} was disconnected", self.name)),
+            };
+            let (event_type, data) = decode(text.as_str())
+                .ok_or_else(|| format!("{} got a malformed message: {}", self.name, text))?;
+            if event_type == "error" || data["error"] == true {
+                return Err(format!("{} got an error: {}", self.name, data));
+            }
+            if event_types.contains(&event_type.as_str()) {
+                if self.verbose {
+                    println!("{} <- {} {}", self.name, event_type, data);
+                }
+                return Ok((event_type, data));
+            }
+        }
+    }
+
+    async fn close(mut self) {
+        let _ = self.ws_stream.close(None).await;
+    }
+}
+
+/// Type and data of an event, `None` unless it has the envelope every
+/// event has.
+fn decode(text: &str) -> Option<(String, Value)> {
+    let frame: Value = serde_json::from_str(text).ok()?;
+    frame["id"].as_u64()?;
+    frame["ts"].as_u64()?;
+    let event_type = frame["type"].as_str()?.to_owned();
+    let data = serde_json::from_str(frame["data"].as_str()?).ok()?;
+    Some((event_type, data))
+}
+
+fn check(condition: bool, what: impl FnOnce() -> String) -> Result<(), String> {
+    if condition {
+        Ok(())
+    } else {
+        Err(what())
+    }
+}
+
+/// Plays one game between `<prefix>-a` and `<prefix>-b`.
+async fn play(options: &Options, prefix: &str) -> Result<(), String> {
+    let mut clients = Vec::new();
+    for role in ["a", "b"] {
+        let mut client = Client::connect(options, format!("{}-{}", prefix, role)).await?;
+        let name = client.name.clone();
+        client.send("reg", json!({ "name": name })).await?;
+        let (_, reg) = client.expect(&["reg"]).await?;
+        check(reg["name"] == name.as_str(), || {
+            format!("{} was registered as {}", name, reg["name"])
+        })?;
+        client.index = reg["index"]
+            .as_str()
+            .ok_or_else(|| format!("reg of {} has no index", name))?
+            .to_owned();
+        clients.push(client);
+    }
+    let [mut a, mut b]: [Client; 2] = clients.try_into().ok().unwrap();
+
+    a.send(
+        "create_room",
+        json!({ "boardSize": BOARD_SIZE, "fleet": { "1": 1 } }),
+    )
+    .await?;
+    let room_id = loop {
+        let (_, rooms) = a.expect(&["update_room", "room_added"]).await?;
+        let rooms = rooms.as_array().cloned().unwrap_or_else(|| vec![rooms]);
+        let own = rooms
+            .into_iter()
+            .find(|room| room["roomUsers"][0]["index"] == a.index.as_str());
+        if let Some(room) = own {
+            break room["roomId"].clone();
+        }
+    };
+    b.send("add_user_to_room", json!({ "indexRoom": room_id }))
+        .await?;
+    let mut game_id = Value::Null;
+    for client in [&mut a, &mut b] {
+        let (_, game) = client.expect(&["create_game"]).await?;
+        check(game["idGame"] == room_id, || {
+            format!(
+                "{} was put in game {} instead of {}",
+                client.name, game["idGame"], room_id
+            )
+        })?;
+        game_id = game["idGame"].clone();
+    }
+
+    // Both fire from the top left corner, where only a's ship sits, so the
+    // game takes at most one shot from b.
+    let ships = [(0, 0), (BOARD_SIZE - 1, BOARD_SIZE - 1)];
+    for (client, (x, y)) in [&mut a, &mut b].into_iter().zip(ships) {
+        let ship = json!({
+            "position": { "x": x, "y": y },
+            "direction": false,
+            "type": "small",
+            "length": 1,
+        });
+        client
+            .send("add_ships", json!({ "gameId": game_id, "ships": [ship] }))
+            .await?;
+    }
+    for client in [&mut a, &mut b] {
+        client.expect(&["start_game"]).await?;
+    }
+
+    let mut targets = [0u8; 2];
+    let mut last_shooter = 0;
+    let (mut event_type, mut data) = a.expect(&["turn"]).await?;
+    while event_type == "turn" {
+        let shooter = match data["currentPlayer"].as_str() {
+            Some(index) if index == a.index => 0,
+            Some(index) if index == b.index => 1,
+            _ => return Err(format!("turn names neither player: {}", data)),
+        };
+        let cell = targets[shooter];
+        check(cell < BOARD_SIZE * BOARD_SIZE, || {
+            "every cell was fired at and the game goes on".to_owned()
+        })?;
+        targets[shooter] += 1;
+        last_shooter = shooter;
+        let (x, y) = (cell % BOARD_SIZE, cell / BOARD_SIZE);
+        let client = if shooter == 0 { &mut a } else { &mut b };
+        client
+            .send("attack", json!({ "gameId": game_id, "x": x, "y": y }))
+            .await?;
+        let (_, attack) = a.expect(&["attack"]).await?;
+        let expected = if (x, y) == ships[1 - shooter] {
+            "killed"
+        } else {
+            "miss"
+        };
+        check(attack["status"] == expected, || {
+            format!(
+                "shot at ({}, {}) should be a {}: {}",
+                x, y, expected, attack
+            )
+        })?;
+        (event_type, data) = a.expect(&["turn", "finish"]).await?;
+    }
+
+    let winner = data["winPlayer"].clone();
+    let sinker = [&a, &b][last_shooter];
+    check(winner == sinker.index.as_str(), || {
+        format!("{} sank the last ship but lost: {}", sinker.name, data)
+    })?;
+    let (_, finish) = b.expect(&["finish"]).await?;
+    check(finish["winPlayer"] == winner, || {
+        format!(
+            "players were told different winners: {} and {}",
+            winner, finish["winPlayer"]
+        )
+    })?;
+    a.close().await;
+    b.close().await;
+    Ok(())
+}
+
+/// Plays games on `options.pairs` pairs of connections until `duration` is
+/// over, reporting the server's state sizes meanwhile, and fails if any of
+/// them didn't shrink back afterwards.
+async fn soak(options: &Options, duration: Duration, suffix: &str) -> Result<(), String> {
+    let prefixes = (0..options.pairs)
+        .map(|pair| format!("soak-{}-{}", suffix, pair))
+        .collect::<Vec<String>>();
+    // The first game of every pair leaves its players' names behind for
+    // good, so sizes are only compared from then on.
+    for prefix in prefixes.iter() {
+        play(options, prefix).await?;
+    }
+    time::sleep(SETTLE_TIME).await;
+    let baseline = sample(&options.metrics).await?;
+    println!("Baseline: {}", describe(&baseline));
+
+    let started = Instant::now();
+    let games = Arc::new(AtomicU64::new(0));
+    let mut pairs = JoinSet::new();
+    for prefix in prefixes {
+        let options = options.clone();
+        let games = games.clone();
+        pairs.spawn(async move {
+            while started.elapsed() < duration {
+                play(&options, &prefix).await?;
+                games.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok::<(), String>(())
+        });
+    }
+    let mut report = time::interval(options.report);
+    report.tick().await;
+    while !pairs.is_empty() {
+        tokio::select! {
+            Some(result) = pairs.join_next() => {
+                result.map_err(|err| err.to_string())??;
+            }
+            _ = report.tick() => {
+                let sizes = sample(&options.metrics).await?;
+                println!(
+                    "{:>5}s {:>7} games  {}",
+                    started.elapsed().as_secs(),
+                    games.load(Ordering::Relaxed),
+                    describe(&sizes)
+                );
+            }
+        }
+    }
+
+    time::sleep(SETTLE_TIME).await;
+    let last = sample(&options.metrics).await?;
+    println!(
+        "After {} games: {}",
+        games.load(Ordering::Relaxed),
+        describe(&last)
+    );
+    let grown = last
+        .iter()
+        .filter(|(name, value)| {
+            name.as_str() != "memory" && **value > baseline.get(*name).copied().unwrap_or(0)
+        })
+        .map(|(name, value)| format!("{} {} -> {}", name, baseline.get(name).unwrap_or(&0), value))
+        .collect::<Vec<String>>();
+    check(grown.is_empty(), || {
+        format!("state didn't shrink back: {}", grown.join(", "))
+    })
+}
+
+/// The gauges of `/metrics` a leak would show in, by short name.
+async fn sample(addr: &str) -> Result<BTreeMap<String, u64>, String> {
+    let failed = |err: std::io::Error| format!("can't read metrics from {}: {}", addr, err);
+    let mut stream = TcpStream::connect(addr).await.map_err(failed)?;
+    let request = format!(
+        "GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        addr
+    );
+    stream.write_all(request.as_bytes()).await.map_err(failed)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.map_err(failed)?;
+
+    let mut sizes = BTreeMap::new();
+    for line in response.lines().filter(|line| !line.starts_with('#')) {
+        let Some((metric, value)) = line.rsplit_once(' ') else {
+            continue;
+        };
+        let name = match metric.strip_prefix("battleships_") {
+            Some("connections") => "connections",
+            Some("event_queue_depth") => "queue",
+            Some("resident_memory_bytes") => "memory",
+            Some(gauge) => match gauge
+                .strip_prefix("state_entries{table=\"")
+                .and_then(|table| table.strip_suffix("\"}"))
+            {
+                Some(table) => table,
+                None => continue,
+            },
+            None => continue,
+        };
+        if let Ok(value) = value.parse() {
+            sizes.insert(name.to_owned(), value);
+        }
+    }
+    check(!sizes.is_empty(), || {
+        format!("{} serves no battleships metrics", addr)
+    })?;
+    Ok(sizes)
+}
+
+/// Memory in MiB and every size that isn't zero.
+fn describe(sizes: &BTreeMap<String, u64>) -> String {
+    sizes
+        .iter()
+        .filter(|(_, value)| **value > 0)
+        .map(|(name, value)| match name.as_str() {
+            "memory" => format!("memory={:.1}MiB", *value as f64 / (1024.0 * 1024.0)),
+            _ => format!("{}={}", name, value),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
@@ -0,0 +1,78 @@
+//! The channel for bots written by players: the same WebSocket protocol,
+//! with a registration of its own and a compact view of the boards, so a
+//! bot never has to piece its game together from the events meant for
+//! human clients.
+//!
+//! A bot registers with `bot_reg` instead of `reg`, with `name` and one of
+//! the keys of `BOT_API_KEYS`, a comma-separated list. The reply is a
+//! `reg` like any other, with `"bot": true`; a wrong key gets the
+//! `invalid_api_key` error and no account. Bots play every game a player
+//! can and show on the leaderboard, marked with `"bot": true`.
+//!
+//! Moves (`attack`, `randomAttack` and `scan`) are limited per second: by
+//! `MOVES_PER_SECOND` for players and by `BOT_MOVES_PER_SECOND`, unlimited
+//! by default, for bots.
+//!
+//! `board_view {gameId}` is answered with a `board_view` of the game as the
+//! caller sees it:
+//!
+//! - `own` and `enemy`: the boards row by row, one character per cell, `.`
+//!   for water, `o` for a miss, `x` for a hit, `#` for a sunk ship's cell
+//!   and, on `own` only, `S` for a ship not hit yet;
+//! - `enemyShipsLeft`: the lengths of the opponent's ships still afloat;
+//! - `yourTurn` and `shotsLeft`, the shots left in the caller's turn;
+//! - `status`, `boardSize` and the `checksum` of `sync`.
+//!
+//! A bot typically asks for one on `start_game` and on every `turn`.
+
+use std::collections::HashSet;
+
+use serde_json::{json, Value};
+
+use crate::{
+    game::{Game, GameStatus},
+    UserId,
+};
+
+const BOT_API_KEYS_VAR: &str = "BOT_API_KEYS";
+
+/// `BOT_API_KEYS`, none when unset, which closes `bot_reg`.
+pub fn api_keys_from_env() -> HashSet<String> {
+    std::env::var(BOT_API_KEYS_VAR)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The `board_view` of `game` for the player `user_id`.
+pub fn board_view(game: &Game, user_id: &UserId) -> Value {
+    let opponent = game.opponent_of(user_id);
+    let your_turn = matches!(game.status(), GameStatus::Started)
+        && game.current_player().as_ref() == Some(user_id);
+    let mut ships_left = opponent
+        .as_ref()
+        .map(|opponent| {
+            game.afloat_of(opponent)
+                .iter()
+                .map(|ship| ship.hp)
+                .collect::<Vec<u8>>()
+        })
+        .unwrap_or_default();
+    ships_left.sort_unstable_by(|a, b| b.cmp(a));
+    json!({
+        "gameId": game.id,
+        "status": game.status(),
+        "boardSize": game.settings.board_size,
+        "yourTurn": your_turn,
+        "shotsLeft": if your_turn { game.shots_left() } else { 0 },
+        "own": game.board_rows(user_id, false),
+        "enemy": opponent
+            .map(|opponent| game.board_rows(&opponent, true))
+            .unwrap_or_default(),
+        "enemyShipsLeft": ships_left,
+        "checksum": game.checksum(),
+    })
+}
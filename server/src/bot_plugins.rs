@@ -0,0 +1,24 @@
+//! Bot strategies an embedder plugs into matchmaking, next to the built-in
+//! `random` and `hunt` ones.
+//!
+//! A strategy is registered under a name with
+//! `State::register_bot_strategy`, after which resident bots (see
+//! `matchmaking`) can play it. It picks each shot from the bot's
+//! `board_view`, the same view bots playing over the WebSocket ask for,
+//! see `bot_api`.
+
+use std::fmt;
+
+use serde_json::Value;
+
+pub trait BotStrategy: Send + Sync {
+    /// The cell to fire at next, given the bot's `board_view`; `None` fires
+    /// at a random cell instead.
+    fn target(&self, view: &Value) -> Option<(u8, u8)>;
+}
+
+impl fmt::Debug for dyn BotStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BotStrategy")
+    }
+}
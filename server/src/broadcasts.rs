@@ -0,0 +1,71 @@
+//! The last lobby snapshot of each kind, encoded, and who got it.
+//!
+//! Snapshots are rebuilt on every trigger, but most triggers don't change
+//! what they show. A snapshot with the same data as the last one of its
+//! kind, or as the last one sent, reuses its encoded text, so it is the very
+//! same message, and only goes to the clients that connected since; when
+//! they all have it, it isn't sent at all.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+use serde_json::Value;
+
+use crate::create_event_json;
+
+#[derive(Debug, Default)]
+pub struct Broadcasts {
+    encoded: HashMap<&'static str, (Value, String)>,
+    sent: HashMap<&'static str, (Value, String, HashSet<SocketAddr>)>,
+}
+
+impl Broadcasts {
+    /// The event text of a `kind` snapshot showing `data`, the one of the
+    /// last snapshot or of the last one sent if it showed the same.
+    pub fn encode(&mut self, kind: &'static str, data: Value) -> String {
+        let encoded = self.encoded.get(kind).map(|(last, json)| (last, json));
+        let sent = self.sent.get(kind).map(|(last, json, _)| (last, json));
+        if let Some((_, json)) = [encoded, sent]
+            .into_iter()
+            .flatten()
+            .find(|(last, _)| **last == data)
+        {
+            return json.clone();
+        }
+        let json = create_event_json(data.clone(), kind.into());
+        self.encoded.insert(kind, (data, json.clone()));
+        json
+    }
+
+    /// Of the `connected` sockets, those still to get the snapshot `json`,
+    /// and takes note that they are getting it. `None` for a new snapshot,
+    /// which goes to everyone.
+    pub fn missing(
+        &mut self,
+        kind: &'static str,
+        json: &str,
+        connected: HashSet<SocketAddr>,
+    ) -> Option<Vec<SocketAddr>> {
+        match self.sent.get_mut(kind) {
+            Some((_, last, sent_to)) if last == json => {
+                sent_to.retain(|addr| connected.contains(addr));
+                let missing = connected
+                    .into_iter()
+                    .filter(|addr| !sent_to.contains(addr))
+                    .collect::<Vec<SocketAddr>>();
+                sent_to.extend(missing.iter().copied());
+                Some(missing)
+            }
+            _ => {
+                let data = match self.encoded.get(kind) {
+                    Some((data, encoded)) if encoded == json => data.clone(),
+                    _ => Value::Null,
+                };
+                self.sent.insert(kind, (data, json.to_owned(), connected));
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Raw inbound traffic, recorded to reproduce the message sequences behind
+//! bug reports.
+//!
+//! Started with `--capture <dir>`, the server writes one file per
+//! connection to `dir`, named after the connection time and the peer
+//! address. Every line is a JSON record with the wall clock time in
+//! `atMs`: `{"open": true}` first, then `{"text": ...}` for each frame the
+//! peer sent and `{"close": true}` once the connection is dropped. The
+//! `replay_capture` binary plays a directory of captures against a fresh
+//! server.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::now_ms;
+
+const EXTENSION: &str = "jsonl";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Frame {
+    Open(bool),
+    Text(String),
+    Close(bool),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub frame: Frame,
+}
+
+/// Where the captures of new connections go.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    dir: PathBuf,
+}
+
+impl Capture {
+    pub fn new(dir: &str) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    /// Starts the capture of a connection, `None` when its file can't be
+    /// created.
+    pub fn connection(&self, addr: &SocketAddr) -> Option<ConnectionCapture> {
+        let name = format!("{}-{}", now_ms(), addr).replace([':', '[', ']'], "_");
+        let path = self.dir.join(format!("{}.{}", name, EXTENSION));
+        match File::create(&path) {
+            Ok(file) => {
+                let mut capture = ConnectionCapture { file: Some(file) };
+                capture.record(Frame::Open(true));
+                Some(capture)
+            }
+            Err(err) => {
+                warn!("Capture {} is disabled: {}", path.display(), err);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionCapture {
+    /// `None` once writing failed.
+    file: Option<File>,
+}
+
+impl ConnectionCapture {
+    pub fn text(&mut self, text: &str) {
+        self.record(Frame::Text(text.to_owned()));
+    }
+
+    fn record(&mut self, frame: Frame) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let record = Record {
+            at_ms: now_ms(),
+            frame,
+        };
+        let line = serde_json::to_string(&record).unwrap() + "\n";
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write a capture: {}", err);
+            self.file = None;
+        }
+    }
+}
+
+impl Drop for ConnectionCapture {
+    fn drop(&mut self) {
+        self.record(Frame::Close(true));
+    }
+}
+
+/// Every record in the captures of `dir`, oldest first, with the name of
+/// the connection it belongs to.
+pub fn load(dir: &Path) -> Result<Vec<(String, Record)>, String> {
+    let mut paths = fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<PathBuf>>>()
+        })
+        .map_err(|err| format!("Can't read {}: {}", dir.display(), err))?;
+    paths.retain(|path| {
+        path.extension()
+            .is_some_and(|extension| extension == EXTENSION)
+    });
+    // Names start with the connection time, which breaks ties between
+    // connections.
+    paths.sort();
+    let mut records = Vec::new();
+    for path in paths {
+        let connection = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let text = fs::read_to_string(&path)
+            .map_err(|err| format!("Can't read {}: {}", path.display(), err))?;
+        for (number, line) in text.lines().enumerate() {
+            let record = serde_json::from_str::<Record>(line)
+                .map_err(|err| format!("{} line {}: {}", path.display(), number + 1, err))?;
+            records.push((connection.clone(), record));
+        }
+    }
+    // Stable, so frames of one connection logged in the same millisecond
+    // keep their order.
+    records.sort_by_key(|(_, record)| record.at_ms);
+    Ok(records)
+}
@@ -0,0 +1,73 @@
+//! The time the server's timers go by.
+//!
+//! Turn clocks, pauses, the reconnect grace, matchmaking, presence, rate
+//! limits and the cleanup jobs read the time through `now` and `since`
+//! instead of `Instant`, so tests can put in a `ManualClock` and move time
+//! forward at once instead of sleeping. Only latency metrics measure real
+//! time.
+//!
+//! Each thread has its own clock, the system clock unless set otherwise.
+//! The test harness drives the server on the test's thread, so tests
+//! running side by side keep their own time.
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Stands still until advanced. Clones share the time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self {
+            now: Rc::new(Cell::new(Instant::now())),
+        }
+    }
+}
+
+impl ManualClock {
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+thread_local! {
+    static CLOCK: RefCell<Box<dyn Clock>> = RefCell::new(Box::new(SystemClock));
+}
+
+/// Makes `clock` the clock of the calling thread.
+pub fn set(clock: impl Clock + 'static) {
+    CLOCK.with(|current| *current.borrow_mut() = Box::new(clock));
+}
+
+pub fn now() -> Instant {
+    CLOCK.with(|clock| clock.borrow().now())
+}
+
+/// Time passed since `earlier`, zero for a time still to come.
+pub fn since(earlier: Instant) -> Duration {
+    now().saturating_duration_since(earlier)
+}
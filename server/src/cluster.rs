@@ -0,0 +1,375 @@
+//! Several server nodes sharing one lobby, in builds with the `cluster`
+//! feature.
+//!
+//! A game lives on the node it was created on until it is over. With
+//! `CLUSTER_REDIS_URL` set, nodes share through Redis:
+//!
+//! - the registry: the `battleships:games` hash maps every game id to the
+//!   node hosting it, and `battleships:node:<id>` holds the WebSocket URL
+//!   clients reach each node at (`CLUSTER_NODE_URL`), kept alive by a
+//!   heartbeat;
+//! - the `battleships:lobby` channel, where each node publishes its open
+//!   rooms whenever they change and with every heartbeat.
+//!
+//! `update_room` lists the open rooms of every node. A player joining a
+//! room or watching a game hosted by another node gets a `redirect` with
+//! that node's URL to reconnect to; games never move. Leaderboards,
+//! friends and sessions stay with each node.
+//!
+//! For a rolling deploy, put a node in maintenance: it stops offering its
+//! rooms to the other nodes and `/readyz` sends new players elsewhere.
+//! Shut it down once its games are over. Its rooms and games leave the
+//! registry when its heartbeat key expires.
+//!
+//! ```text
+//! CLUSTER_REDIS_URL=redis://127.0.0.1/ CLUSTER_NODE_URL=ws://node-a:3000 cargo run --features cluster
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+
+use futures_channel::mpsc::UnboundedSender;
+use serde_json::Value;
+
+use crate::game::GameId;
+
+pub type NodeId = String;
+
+/// A change of this node for the others to know about.
+#[derive(Debug)]
+pub enum Update {
+    /// A game was created here.
+    Claim(GameId),
+    /// A game here was closed.
+    Release(GameId),
+    /// The open rooms of this node are now these.
+    Rooms(Vec<Value>),
+}
+
+/// The lobby of another node.
+#[derive(Debug)]
+struct Lobby {
+    url: String,
+    rooms: Vec<Value>,
+}
+
+/// What this node knows of the others. Without clustering it knows of
+/// none and every call does nothing.
+#[derive(Debug, Default)]
+pub struct Cluster {
+    /// Changes for the Redis task, set when clustering is configured.
+    link: Option<UnboundedSender<Update>>,
+    /// Rooms last published, so unchanged lists aren't sent again.
+    published: Vec<Value>,
+    /// Lobbies of the other nodes, by node.
+    lobbies: BTreeMap<NodeId, Lobby>,
+    /// Games of the other nodes with the URL of their node, as the registry
+    /// had them at the last heartbeat.
+    games: HashMap<GameId, String>,
+}
+
+impl Cluster {
+    pub fn is_joined(&self) -> bool {
+        self.link.is_some()
+    }
+
+    pub fn claim(&self, game_id: &GameId) {
+        self.send(Update::Claim(game_id.clone()));
+    }
+
+    pub fn release(&self, game_id: &GameId) {
+        self.send(Update::Release(game_id.clone()));
+    }
+
+    /// Offers these rooms of this node to the others.
+    pub fn publish_rooms(&mut self, rooms: Vec<Value>) {
+        if self.is_joined() && rooms != self.published {
+            self.published = rooms.clone();
+            self.send(Update::Rooms(rooms));
+        }
+    }
+
+    fn send(&self, update: Update) {
+        if let Some(link) = &self.link {
+            let _ = link.unbounded_send(update);
+        }
+    }
+
+    /// Open rooms of the other nodes.
+    pub fn rooms(&self) -> impl Iterator<Item = &Value> {
+        self.lobbies.values().flat_map(|lobby| &lobby.rooms)
+    }
+
+    /// The id of a room or game of another node, looked up by its id or
+    /// room code, and the URL of its node.
+    pub fn locate(&self, room: &str) -> Option<(GameId, &str)> {
+        if let Some(url) = self.games.get(room) {
+            return Some((room.to_owned(), url));
+        }
+        self.lobbies.values().find_map(|lobby| {
+            lobby
+                .rooms
+                .iter()
+                .find(|json| json["roomId"] == room || json["code"] == room)
+                .and_then(|json| json["roomId"].as_str())
+                .map(|room_id| (room_id.to_owned(), lobby.url.as_str()))
+        })
+    }
+
+    /// Takes the rooms another node published. Returns whether the lobby
+    /// changed.
+    pub fn set_lobby(&mut self, node: NodeId, url: String, rooms: Vec<Value>) -> bool {
+        if self
+            .lobbies
+            .get(&node)
+            .is_some_and(|lobby| lobby.rooms == rooms)
+        {
+            return false;
+        }
+        self.lobbies.insert(node, Lobby { url, rooms });
+        true
+    }
+
+    /// Takes the games of the registry and forgets the lobbies of nodes
+    /// that are gone. Returns whether the lobby changed.
+    pub fn set_registry(&mut self, games: HashMap<GameId, String>, live: &[NodeId]) -> bool {
+        self.games = games;
+        let before = self.lobbies.len();
+        self.lobbies.retain(|node, _| live.contains(node));
+        self.lobbies.len() != before
+    }
+}
+
+#[cfg(feature = "cluster")]
+pub use self::redis_link::spawn;
+
+/// The task keeping the registry and relaying lobbies through Redis.
+#[cfg(feature = "cluster")]
+mod redis_link {
+    use std::{
+        collections::{HashMap, HashSet},
+        time::Duration,
+    };
+
+    use futures_channel::mpsc::{unbounded, UnboundedReceiver};
+    use futures_util::StreamExt;
+    use redis::{aio::MultiplexedConnection, AsyncCommands, Client, RedisResult};
+    use serde_json::json;
+    use tokio::time;
+    use tracing::{info, warn};
+    use uuid::Uuid;
+
+    use super::{NodeId, Update};
+    use crate::{game::GameId, lock_state, ServerState};
+
+    const CLUSTER_REDIS_URL_VAR: &str = "CLUSTER_REDIS_URL";
+    const CLUSTER_NODE_URL_VAR: &str = "CLUSTER_NODE_URL";
+    const GAMES_KEY: &str = "battleships:games";
+    const NODE_KEY_PREFIX: &str = "battleships:node:";
+    const LOBBY_CHANNEL: &str = "battleships:lobby";
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+    /// A node that missed this many heartbeats is gone.
+    const NODE_TTL: Duration = Duration::from_secs(15);
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+    struct Node {
+        id: NodeId,
+        url: String,
+    }
+
+    /// Joins the cluster when `CLUSTER_REDIS_URL` is set.
+    pub fn spawn(state: &ServerState) {
+        let redis_url = match std::env::var(CLUSTER_REDIS_URL_VAR) {
+            Ok(url) if !url.is_empty() => url,
+            _ => return,
+        };
+        let url = match std::env::var(CLUSTER_NODE_URL_VAR) {
+            Ok(url) if !url.is_empty() => url,
+            _ => {
+                warn!(
+                    "{} must be set with {}, clustering is disabled",
+                    CLUSTER_NODE_URL_VAR, CLUSTER_REDIS_URL_VAR
+                );
+                return;
+            }
+        };
+        let client = match Client::open(redis_url) {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("Clustering is disabled: {}", err);
+                return;
+            }
+        };
+        let node = Node {
+            id: Uuid::new_v4().to_string(),
+            url,
+        };
+        info!("Joining the cluster as node {} at {}", node.id, node.url);
+        let (tx, rx) = unbounded();
+        lock_state(state).cluster.link = Some(tx);
+        tokio::spawn(run(client, node, state.clone(), rx));
+    }
+
+    async fn run(
+        client: Client,
+        node: Node,
+        state: ServerState,
+        mut updates: UnboundedReceiver<Update>,
+    ) {
+        loop {
+            if let Err(err) = serve(&client, &node, &state, &mut updates).await {
+                warn!("Lost the cluster, retrying: {}", err);
+            }
+            time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Relays until the connection fails. Starts by registering every game
+    /// of this node, in case Redis lost them.
+    async fn serve(
+        client: &Client,
+        node: &Node,
+        state: &ServerState,
+        updates: &mut UnboundedReceiver<Update>,
+    ) -> RedisResult<()> {
+        let mut conn = client.get_multiplexed_async_connection().await?;
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(LOBBY_CHANNEL).await?;
+        let mut messages = pubsub.into_on_message();
+
+        heartbeat(&mut conn, node).await?;
+        let (game_ids, mut rooms) = {
+            let state_lock = lock_state(state);
+            let game_ids = state_lock.games.keys().cloned().collect::<Vec<_>>();
+            (game_ids, state_lock.cluster.published.clone())
+        };
+        for game_id in game_ids {
+            let _: () = conn.hset(GAMES_KEY, game_id, &node.id).await?;
+        }
+        publish(&mut conn, node, &rooms).await?;
+        refresh(&mut conn, node, state).await?;
+
+        let mut interval = time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                update = updates.next() => match update {
+                    Some(Update::Claim(game_id)) => {
+                        let _: () = conn.hset(GAMES_KEY, game_id, &node.id).await?;
+                    }
+                    Some(Update::Release(game_id)) => {
+                        let _: () = conn.hdel(GAMES_KEY, game_id).await?;
+                    }
+                    Some(Update::Rooms(new_rooms)) => {
+                        rooms = new_rooms;
+                        publish(&mut conn, node, &rooms).await?;
+                    }
+                    None => return Ok(()),
+                },
+                message = messages.next() => {
+                    let Some(message) = message else {
+                        return Err((redis::ErrorKind::IoError, "Lobby channel closed").into());
+                    };
+                    let payload = message.get_payload::<String>()?;
+                    receive(node, state, &payload);
+                }
+                _ = interval.tick() => {
+                    heartbeat(&mut conn, node).await?;
+                    publish(&mut conn, node, &rooms).await?;
+                    refresh(&mut conn, node, state).await?;
+                }
+            }
+        }
+    }
+
+    async fn heartbeat(conn: &mut MultiplexedConnection, node: &Node) -> RedisResult<()> {
+        let key = format!("{}{}", NODE_KEY_PREFIX, node.id);
+        conn.set_ex(key, &node.url, NODE_TTL.as_secs()).await
+    }
+
+    async fn publish(
+        conn: &mut MultiplexedConnection,
+        node: &Node,
+        rooms: &[serde_json::Value],
+    ) -> RedisResult<()> {
+        let message = json!({ "node": node.id, "url": node.url, "rooms": rooms });
+        conn.publish(LOBBY_CHANNEL, message.to_string()).await
+    }
+
+    /// Takes the rooms another node published.
+    fn receive(node: &Node, state: &ServerState, payload: &str) {
+        let Ok(message) = serde_json::from_str::<serde_json::Value>(payload) else {
+            warn!("Unreadable lobby message: {}", payload);
+            return;
+        };
+        let (Some(other), Some(url), Some(rooms)) = (
+            message["node"].as_str(),
+            message["url"].as_str(),
+            message["rooms"].as_array(),
+        ) else {
+            return;
+        };
+        if other == node.id {
+            return;
+        }
+        let mut state_lock = lock_state(state);
+        if state_lock
+            .cluster
+            .set_lobby(other.to_owned(), url.to_owned(), rooms.clone())
+        {
+            state_lock.add_update_room_event();
+        }
+    }
+
+    /// Reads the registry, dropping the games of nodes that are gone.
+    async fn refresh(
+        conn: &mut MultiplexedConnection,
+        node: &Node,
+        state: &ServerState,
+    ) -> RedisResult<()> {
+        let registry: HashMap<GameId, NodeId> = conn.hgetall(GAMES_KEY).await?;
+        let nodes = registry
+            .values()
+            .filter(|id| **id != node.id)
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let urls: Vec<Option<String>> = if nodes.is_empty() {
+            Vec::new()
+        } else {
+            let keys = nodes
+                .iter()
+                .map(|id| format!("{}{}", NODE_KEY_PREFIX, id))
+                .collect::<Vec<_>>();
+            redis::cmd("MGET").arg(keys).query_async(conn).await?
+        };
+        let urls = nodes
+            .iter()
+            .zip(urls)
+            .filter_map(|(id, url)| Some((id.clone(), url?)))
+            .collect::<HashMap<_, _>>();
+
+        let mut games = HashMap::new();
+        let mut gone = Vec::new();
+        for (game_id, owner) in registry {
+            if owner == node.id {
+                continue;
+            }
+            match urls.get(&owner) {
+                Some(url) => {
+                    games.insert(game_id, url.clone());
+                }
+                None => gone.push(game_id),
+            }
+        }
+        if !gone.is_empty() {
+            info!("Dropping {} games of nodes that are gone", gone.len());
+            let _: () = conn.hdel(GAMES_KEY, gone).await?;
+        }
+        let live = urls.into_keys().collect::<Vec<_>>();
+        let mut state_lock = lock_state(state);
+        if state_lock.cluster.set_registry(games, &live) {
+            state_lock.add_update_room_event();
+        }
+        Ok(())
+    }
+}
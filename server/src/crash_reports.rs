@@ -0,0 +1,130 @@
+//! Optional crash reports to a Sentry-compatible service.
+//!
+//! Enabled by setting `SENTRY_DSN` to a project DSN like
+//! `http://<key>@sentry.example.com:9000/<project>`. Panics are reported
+//! with the span fields of the thread that panicked, so a report names the
+//! peer, user and game it happened for, as are connections closed for
+//! breaking the protocol. Like the Discord notifications, reports are
+//! posted from a background task over plain `http://`.
+
+use std::{
+    panic::{self, PanicHookInfo},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_util::StreamExt;
+use serde_json::json;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{discord::Webhook, logging};
+
+const SENTRY_DSN_VAR: &str = "SENTRY_DSN";
+
+static REPORTS: OnceLock<UnboundedSender<serde_json::Value>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        }
+    }
+}
+
+/// Installs the panic hook and starts the sender task when a DSN is
+/// configured. Must run inside the runtime.
+pub fn init() {
+    let dsn = match std::env::var(SENTRY_DSN_VAR) {
+        Ok(dsn) if !dsn.is_empty() => dsn,
+        _ => return,
+    };
+    let Some(endpoint) = store_endpoint(&dsn) else {
+        warn!("SENTRY_DSN must be an http:// DSN with a key, crash reports are disabled");
+        return;
+    };
+    let (tx, rx) = unbounded();
+    if REPORTS.set(tx).is_err() {
+        return;
+    }
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        capture(Level::Error, &panic_message(info));
+        default_hook(info);
+    }));
+    info!("Sending crash reports to {}", endpoint.host);
+    tokio::spawn(send_reports(endpoint, rx));
+}
+
+/// Reports an unexpected failure along with the current span fields. Does
+/// nothing unless crash reporting is enabled.
+pub fn capture(level: Level, message: &str) {
+    let Some(reports) = REPORTS.get() else {
+        return;
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let tags = logging::fields()
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), json!(value)))
+        .collect::<serde_json::Map<_, _>>();
+    let _ = reports.unbounded_send(json!({
+        "event_id": Uuid::new_v4().simple().to_string(),
+        "timestamp": timestamp,
+        "platform": "other",
+        "level": level.as_str(),
+        "logger": "server",
+        "release": concat!("server@", env!("CARGO_PKG_VERSION")),
+        "message": { "formatted": message },
+        "tags": tags,
+    }));
+}
+
+/// The store endpoint of a DSN, `http://<key>@<host>/<project>`. The key
+/// goes in the query, so no extra header is needed.
+fn store_endpoint(dsn: &str) -> Option<Webhook> {
+    let rest = dsn.strip_prefix("http://")?;
+    let (key, rest) = rest.split_once('@')?;
+    let (host, project) = rest.rsplit_once('/')?;
+    let key = key.split(':').next().filter(|key| !key.is_empty())?;
+    if project.is_empty() {
+        return None;
+    }
+    Webhook::parse(&format!(
+        "http://{}/api/{}/store/?sentry_version=7&sentry_key={}",
+        host, project, key
+    ))
+}
+
+fn panic_message(info: &PanicHookInfo) -> String {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    match info.location() {
+        Some(location) => format!("Panic at {}: {}", location, message),
+        None => format!("Panic: {}", message),
+    }
+}
+
+async fn send_reports(endpoint: Webhook, mut reports: UnboundedReceiver<serde_json::Value>) {
+    while let Some(report) = reports.next().await {
+        match endpoint.post(&report.to_string()).await {
+            Ok(status) if (200..300).contains(&status) => {}
+            Ok(status) => warn!("Crash report endpoint answered {}", status),
+            Err(err) => warn!("Crash report failed: {}", err),
+        }
+    }
+}
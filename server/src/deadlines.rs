@@ -0,0 +1,65 @@
+//! Deadlines of games, rooms and sessions in the order they fall due, so
+//! the tick only looks at the ones whose time is up instead of going
+//! through every game and session.
+//!
+//! An entry is a hint: a game's deadline moves with every move, pause and
+//! resume, a room's with every event of its owner, and scheduling it again
+//! replaces the old entry. When one falls due the tick checks the game,
+//! room or session itself, and schedules it again if the deadline turns
+//! out to have moved later.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Instant,
+};
+
+use crate::{game::GameId, UserId};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Deadline {
+    /// The clock of the player to move runs out.
+    TurnClock(GameId),
+    /// A paused game resumes by itself.
+    Pause(GameId),
+    /// A waiting room closes when its owner has been idle too long.
+    IdleRoom(GameId),
+    /// A player held for a reconnect runs out of time to come back.
+    Grace(UserId),
+}
+
+#[derive(Debug, Default)]
+pub struct Deadlines {
+    queue: BTreeSet<(Instant, Deadline)>,
+    due_at: HashMap<Deadline, Instant>,
+}
+
+impl Deadlines {
+    /// Sets the deadline to `at`, or cancels it for `None`.
+    pub fn schedule(&mut self, deadline: Deadline, at: Option<Instant>) {
+        if let Some(previous) = self.due_at.remove(&deadline) {
+            self.queue.remove(&(previous, deadline.clone()));
+        }
+        if let Some(at) = at {
+            self.due_at.insert(deadline.clone(), at);
+            self.queue.insert((at, deadline));
+        }
+    }
+
+    /// Takes the deadlines due by `now`, earliest first.
+    pub fn due(&mut self, now: Instant) -> Vec<Deadline> {
+        let mut due = Vec::new();
+        while let Some((at, _)) = self.queue.first() {
+            if *at > now {
+                break;
+            }
+            let (_, deadline) = self.queue.pop_first().unwrap();
+            self.due_at.remove(&deadline);
+            due.push(deadline);
+        }
+        due
+    }
+
+    pub fn count(&self) -> usize {
+        self.due_at.len()
+    }
+}
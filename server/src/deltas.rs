@@ -0,0 +1,127 @@
+//! Lobby lists as deltas, for clients that can apply them.
+//!
+//! A client asks for deltas by listing capabilities in its `reg` and finds
+//! the ones the server accepted in the reply:
+//!
+//! - `roomDeltas`: the full `update_room` list once, then `room_added` and
+//!   `room_changed` with the room and `room_removed` with its `roomId`.
+//! - `winnerDeltas`: the top `TOP_WINNERS` of `update_winners`, with their
+//!   `rank`, once, then `winners_changed` with the entries that changed and
+//!   the names that dropped out. The `winners` request pages through the
+//!   rest.
+//!
+//! Every `FULL_LIST_INTERVAL` and after a reconnect the full list is sent
+//! again, in case a client got out of step. Everyone else keeps getting
+//! the whole list on every change.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::{clock, UserId};
+
+pub const ROOM_DELTAS: &str = "roomDeltas";
+pub const WINNER_DELTAS: &str = "winnerDeltas";
+pub const TOP_WINNERS: usize = 10;
+const FULL_LIST_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq)]
+pub enum Delta {
+    Added(Value),
+    Changed(Value),
+    Removed(String),
+}
+
+/// Clients following one list, and the list as they were shown it last.
+#[derive(Debug)]
+pub struct Deltas {
+    pub capability: &'static str,
+    /// Field that identifies an entry.
+    key: &'static str,
+    /// Entries shown last, by key.
+    shown: HashMap<String, Value>,
+    clients: HashSet<UserId>,
+    /// Clients that have the full list and can follow the deltas.
+    synced: HashSet<UserId>,
+    last_full_list: Instant,
+}
+
+impl Deltas {
+    pub fn new(capability: &'static str, key: &'static str) -> Self {
+        Self {
+            capability,
+            key,
+            shown: HashMap::new(),
+            clients: HashSet::new(),
+            synced: HashSet::new(),
+            last_full_list: clock::now(),
+        }
+    }
+
+    pub fn join(&mut self, user_id: &UserId) {
+        self.clients.insert(user_id.clone());
+    }
+
+    pub fn leave(&mut self, user_id: &UserId) {
+        self.clients.remove(user_id);
+        self.synced.remove(user_id);
+    }
+
+    /// The client may have missed deltas and needs the full list again.
+    pub fn resync(&mut self, user_id: &UserId) {
+        self.synced.remove(user_id);
+    }
+
+    pub fn clients(&self) -> &HashSet<UserId> {
+        &self.clients
+    }
+
+    /// Takes note that the client was sent the full list; `false` when it
+    /// already had it.
+    pub fn sync(&mut self, user_id: &UserId) -> bool {
+        self.synced.insert(user_id.clone())
+    }
+
+    /// Whether it is time to send every client the full list again, in
+    /// which case they are all due for it.
+    pub fn is_full_list_due(&mut self) -> bool {
+        if self.clients.is_empty() || clock::since(self.last_full_list) < FULL_LIST_INTERVAL {
+            return false;
+        }
+        self.last_full_list = clock::now();
+        self.synced.clear();
+        true
+    }
+
+    /// Changes from the entries shown last to `entries`, which are shown
+    /// from now on.
+    pub fn update(&mut self, entries: Vec<Value>) -> Vec<Delta> {
+        let mut entries = entries
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry[self.key].as_str().unwrap_or_default().to_owned(),
+                    entry,
+                )
+            })
+            .collect::<HashMap<String, Value>>();
+        let mut deltas = Vec::new();
+        for (key, shown) in self.shown.iter() {
+            match entries.get(key) {
+                None => deltas.push(Delta::Removed(key.clone())),
+                Some(entry) if entry != shown => deltas.push(Delta::Changed(entry.clone())),
+                Some(_) => {}
+            }
+        }
+        for (key, entry) in entries.iter() {
+            if !self.shown.contains_key(key) {
+                deltas.push(Delta::Added(entry.clone()));
+            }
+        }
+        std::mem::swap(&mut self.shown, &mut entries);
+        deltas
+    }
+}
@@ -0,0 +1,125 @@
+//! Optional Discord notifications for game results and leaderboard changes.
+//!
+//! Enabled by setting `DISCORD_WEBHOOK_URL`. Messages are posted as embeds
+//! from a background task, so a slow webhook never holds the state lock.
+//! The server has no TLS stack, so the URL must be plain `http://`; point it
+//! at a local relay (e.g. a TLS-terminating proxy) for `discord.com`.
+
+use futures_channel::mpsc::{unbounded, UnboundedReceiver};
+use futures_util::StreamExt;
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::{info, warn};
+
+use crate::{game::FinishReason, lock_state, ServerState};
+
+const DISCORD_WEBHOOK_VAR: &str = "DISCORD_WEBHOOK_URL";
+const RESULT_COLOR: u32 = 0x3498db;
+const LEADER_COLOR: u32 = 0xf1c40f;
+
+/// Starts the poster task when a webhook is configured.
+pub fn spawn(state: &ServerState) {
+    let url = match std::env::var(DISCORD_WEBHOOK_VAR) {
+        Ok(url) if !url.is_empty() => url,
+        _ => return,
+    };
+    let Some(target) = Webhook::parse(&url) else {
+        warn!("Discord webhook must be an http:// URL, notifications are disabled");
+        return;
+    };
+    let (tx, rx) = unbounded();
+    lock_state(state).discord = Some(tx);
+    info!("Posting game results to the Discord webhook");
+    tokio::spawn(post_embeds(target, rx));
+}
+
+pub(crate) fn game_result_embed(
+    winner: Option<&str>,
+    players: &[String],
+    reason: FinishReason,
+) -> serde_json::Value {
+    let description = match winner {
+        Some(winner) => format!("**{}** won", winner),
+        None => "No winner".to_owned(),
+    };
+    json!({
+        "title": "Game finished",
+        "description": description,
+        "color": RESULT_COLOR,
+        "fields": [
+            { "name": "Players", "value": players.join(" vs "), "inline": true },
+            { "name": "Reason", "value": format!("{:?}", reason), "inline": true },
+        ],
+    })
+}
+
+pub(crate) fn new_leader_embed(name: &str, wins: u32) -> serde_json::Value {
+    json!({
+        "title": "New leader",
+        "description": format!("**{}** tops the leaderboard with {} wins", name, wins),
+        "color": LEADER_COLOR,
+    })
+}
+
+/// A plain HTTP endpoint taking JSON posts.
+pub(crate) struct Webhook {
+    pub host: String,
+    path: String,
+}
+
+impl Webhook {
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("http://")?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self {
+            host: host.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+
+    /// Posts `body` and returns the response status.
+    pub async fn post(&self, body: &str) -> std::io::Result<u16> {
+        let address = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            format!("{}:80", self.host)
+        };
+        let mut stream = TcpStream::connect(address).await?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let status = String::from_utf8_lossy(&response)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .unwrap_or(0);
+        Ok(status)
+    }
+}
+
+async fn post_embeds(webhook: Webhook, mut embeds: UnboundedReceiver<serde_json::Value>) {
+    while let Some(embed) = embeds.next().await {
+        let body = json!({ "embeds": [embed] }).to_string();
+        match webhook.post(&body).await {
+            Ok(status) if (200..300).contains(&status) => {}
+            Ok(status) => warn!("Discord webhook answered {}", status),
+            Err(err) => warn!("Discord webhook failed: {}", err),
+        }
+    }
+}
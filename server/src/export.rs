@@ -0,0 +1,188 @@
+//! Read-only exports of the leaderboard and server-wide statistics for
+//! community sites and tournament organizers, served under `/export`.
+//!
+//! Requests authenticate with an `Authorization: Bearer` header holding
+//! `EXPORT_TOKEN`, or the admin token. Exports are JSON unless the query asks
+//! for `format=csv`.
+//!
+//! The leaderboard and player profiles are also public, without a token,
+//! as `GET /api/leaderboard` and `GET /api/players/{name}`.
+
+use std::sync::PoisonError;
+
+use axum::{
+    extract::{Query, State as AxumState},
+    middleware,
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    admin,
+    http::{self, Authorize, Response},
+    rank, winner_json, ServerState, State, User,
+};
+
+const EXPORT_TOKEN_VAR: &str = "EXPORT_TOKEN";
+
+pub fn is_authorized(authorization: Option<&str>) -> bool {
+    admin::is_authorized(authorization) || admin::token_matches(EXPORT_TOKEN_VAR, authorization)
+}
+
+/// Exports, nested under `/export`.
+pub(crate) fn router() -> Router<ServerState> {
+    Router::new()
+        .route("/leaderboard", get(leaderboard))
+        .route("/stats", get(stats))
+        .route_layer(middleware::from_fn_with_state(
+            is_authorized as Authorize,
+            http::require,
+        ))
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+}
+
+impl ExportQuery {
+    fn csv(&self) -> bool {
+        self.format.as_deref() == Some("csv")
+    }
+}
+
+async fn leaderboard(
+    AxumState(state): AxumState<ServerState>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+    if query.csv() {
+        return Response::csv(leaderboard_csv(&state_lock));
+    }
+    Response::json(200, leaderboard_json(&state_lock))
+}
+
+async fn stats(
+    AxumState(state): AxumState<ServerState>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+    if query.csv() {
+        return Response::csv(stats_csv(&state_lock));
+    }
+    Response::json(200, stats_json(&state_lock))
+}
+
+/// Rated players in leaderboard order, one-off bots left out.
+pub(crate) fn ranked(state: &State) -> Vec<&User> {
+    let mut users = state
+        .users
+        .values()
+        .filter(|user| !state.matchmaking.is_one_off_bot(&user.id))
+        .collect::<Vec<&User>>();
+    users.sort_by(|a, b| rank(a, b));
+    users
+}
+
+pub(crate) fn leaderboard_json(state: &State) -> serde_json::Value {
+    json!(ranked(state)
+        .into_iter()
+        .enumerate()
+        .map(|(i, user)| {
+            let mut json = winner_json(user);
+            json["rank"] = json!(i + 1);
+            json
+        })
+        .collect::<Vec<_>>())
+}
+
+/// A connected player's standing, statistics and last games.
+pub(crate) fn profile_json(state: &State, name: &str) -> Option<serde_json::Value> {
+    let ranked = ranked(state);
+    let (i, user) = ranked
+        .into_iter()
+        .enumerate()
+        .find(|(_, user)| user.name == name)?;
+    let mut json = winner_json(user);
+    json["rank"] = json!(i + 1);
+    json["presence"] = json!(state.presence_of(user));
+    json["recentGames"] = json!(user.recent_games);
+    Some(json)
+}
+
+fn leaderboard_csv(state: &State) -> String {
+    let mut csv = "rank,name,wins,accuracy,average_shots_to_win\n".to_owned();
+    for (i, user) in ranked(state).into_iter().enumerate() {
+        let average = user
+            .stats
+            .average_shots_to_win()
+            .map(|average| format!("{:.2}", average))
+            .unwrap_or_default();
+        csv += &format!(
+            "{},{},{},{:.4},{}\n",
+            i + 1,
+            csv_field(&user.name),
+            user.wins,
+            user.stats.accuracy(),
+            average
+        );
+    }
+    csv
+}
+
+fn stats_json(state: &State) -> serde_json::Value {
+    let totals = &state.game_totals;
+    let mut json = json!(totals);
+    json["accuracy"] = json!(ratio(totals.hits, totals.shots));
+    json["averageDurationMs"] = json!(totals.duration_ms.checked_div(totals.games as u64));
+    json["population"] = state.population_json();
+    json
+}
+
+/// One `key,value` row per figure; breakdowns use `by_ruleset.<name>` keys.
+fn stats_csv(state: &State) -> String {
+    let totals = &state.game_totals;
+    let mut rows = vec![
+        ("games".to_owned(), totals.games.to_string()),
+        ("shots".to_owned(), totals.shots.to_string()),
+        ("hits".to_owned(), totals.hits.to_string()),
+        (
+            "accuracy".to_owned(),
+            format!("{:.4}", ratio(totals.hits, totals.shots)),
+        ),
+        ("duration_ms".to_owned(), totals.duration_ms.to_string()),
+    ];
+    for (ruleset, games) in totals.by_ruleset.iter() {
+        rows.push((format!("by_ruleset.{}", ruleset), games.to_string()));
+    }
+    for (reason, games) in totals.by_reason.iter() {
+        let reason = json!(reason);
+        rows.push((
+            format!("by_reason.{}", reason.as_str().unwrap_or_default()),
+            games.to_string(),
+        ));
+    }
+    let mut csv = "key,value\n".to_owned();
+    for (key, value) in rows {
+        csv += &format!("{},{}\n", csv_field(&key), value);
+    }
+    csv
+}
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 / whole as f64,
+    }
+}
+
+/// Quotes a field holding a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
@@ -0,0 +1,165 @@
+//! Detection of players trading wins to climb the leaderboard.
+//!
+//! A result is suspicious when the loser barely played: they fired at most
+//! `MAX_LOSER_SHOTS` shots before losing. Once the same two accounts, or
+//! two players on the same IP address, collect `FARMING_THRESHOLD`
+//! suspicious results within `FARMING_WINDOW`, the pair is flagged. Results
+//! of a flagged pair don't count towards the leaderboard until an operator
+//! reviews the flag: clearing it credits the held wins, confirming it
+//! drops them for good.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::clock;
+
+const MAX_LOSER_SHOTS: usize = 2;
+const FARMING_THRESHOLD: usize = 12;
+const FARMING_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What the suspicious results have in common.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind", content = "value")]
+pub enum FarmingKey {
+    /// Account names, sorted.
+    Accounts(String, String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlagStatus {
+    Pending,
+    Cleared,
+    Confirmed,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FarmingFlag {
+    pub id: u32,
+    pub key: FarmingKey,
+    pub status: FlagStatus,
+    /// Wins held back from the leaderboard, by account name.
+    pub held_wins: HashMap<String, u32>,
+}
+
+/// How a finished game counts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rating {
+    Rated,
+    /// Held back for the review of this flag.
+    Held(u32),
+    Excluded,
+}
+
+#[derive(Debug, Default)]
+pub struct Farming {
+    suspicious: HashMap<FarmingKey, VecDeque<Instant>>,
+    flags: Vec<FarmingFlag>,
+    /// Keys an operator cleared, never flagged again.
+    cleared: HashSet<FarmingKey>,
+}
+
+impl Farming {
+    /// Records a result with a winner and decides whether it is rated.
+    /// `shared_ip` is the address both players connected from, if they did.
+    pub fn record(
+        &mut self,
+        winner: &str,
+        loser: &str,
+        shared_ip: Option<IpAddr>,
+        loser_shots: usize,
+    ) -> Rating {
+        let mut pair = [winner.to_owned(), loser.to_owned()];
+        pair.sort();
+        let [first, second] = pair;
+        let keys = [
+            Some(FarmingKey::Accounts(first, second)),
+            shared_ip.map(FarmingKey::Ip),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|key| !self.cleared.contains(key))
+        .collect::<Vec<FarmingKey>>();
+
+        if loser_shots <= MAX_LOSER_SHOTS {
+            for key in keys.iter() {
+                let times = self.suspicious.entry(key.clone()).or_default();
+                times.push_back(clock::now());
+                while times
+                    .front()
+                    .is_some_and(|time| clock::since(*time) > FARMING_WINDOW)
+                {
+                    times.pop_front();
+                }
+                if times.len() >= FARMING_THRESHOLD && self.flag_of(key).is_none() {
+                    let id = self.flags.len() as u32 + 1;
+                    self.flags.push(FarmingFlag {
+                        id,
+                        key: key.clone(),
+                        status: FlagStatus::Pending,
+                        held_wins: HashMap::new(),
+                    });
+                }
+            }
+        }
+
+        let Some(flag) = keys
+            .iter()
+            .find_map(|key| self.flag_of(key))
+            .and_then(|id| self.flags.iter_mut().find(|flag| flag.id == id))
+        else {
+            return Rating::Rated;
+        };
+        match flag.status {
+            FlagStatus::Pending => {
+                *flag.held_wins.entry(winner.to_owned()).or_default() += 1;
+                Rating::Held(flag.id)
+            }
+            FlagStatus::Confirmed => Rating::Excluded,
+            FlagStatus::Cleared => Rating::Rated,
+        }
+    }
+
+    /// The flag still in effect for the key.
+    fn flag_of(&self, key: &FarmingKey) -> Option<u32> {
+        self.flags
+            .iter()
+            .find(|flag| &flag.key == key && flag.status != FlagStatus::Cleared)
+            .map(|flag| flag.id)
+    }
+
+    /// The review queue, pending flags first.
+    pub fn flags(&self) -> Vec<&FarmingFlag> {
+        let mut flags = self.flags.iter().collect::<Vec<_>>();
+        flags.sort_by_key(|flag| (flag.status != FlagStatus::Pending, flag.id));
+        flags
+    }
+
+    /// Settles a pending flag. Returns the wins to credit, which are only
+    /// there when the flag was cleared.
+    pub fn review(&mut self, id: u32, clear: bool) -> Result<HashMap<String, u32>, String> {
+        let flag = self
+            .flags
+            .iter_mut()
+            .find(|flag| flag.id == id)
+            .ok_or("Flag not found")?;
+        if flag.status != FlagStatus::Pending {
+            return Err("Flag was already reviewed".to_owned());
+        }
+        if !clear {
+            flag.status = FlagStatus::Confirmed;
+            return Ok(HashMap::new());
+        }
+        flag.status = FlagStatus::Cleared;
+        self.cleared.insert(flag.key.clone());
+        self.suspicious.remove(&flag.key);
+        Ok(std::mem::take(&mut flag.held_wins))
+    }
+}
@@ -0,0 +1,171 @@
+//! Faults injected on purpose at named points of the server, to exercise
+//! reconnection, resync and supervision deliberately. They only take
+//! effect in builds with the `faults` feature; otherwise every hook
+//! returns at once.
+//!
+//! `FAULTS` sets them at startup as a comma separated list of
+//! `point:fault`, and the `inject_fault`, `list_faults` and `clear_faults`
+//! admin commands change them at runtime:
+//!
+//! ```text
+//! FAULTS=send:drop=0.1,attack:delay=500,state_lock:panic=0.01 cargo run --features faults
+//! ```
+//!
+//! Points:
+//! - `handle_event`: a player message, before it is handled
+//! - `attack`: an attack, with the state locked
+//! - `tick`: the timer work, with the state locked
+//! - `state_lock`: every taking of the state lock
+//! - `send`: a message on its way to a peer
+//!
+//! Faults:
+//! - `delay=MS`: waits that long. Outside `send` this blocks the thread
+//!   and keeps whatever it holds, the state lock included
+//! - `drop=P`: the message at `send` is never sent, with probability `P`
+//! - `panic=P`: panics with probability `P`, which defaults to 1. Under
+//!   the state lock this poisons it
+
+use std::{fmt, sync::Mutex, thread, time::Duration};
+
+use rand::Rng;
+use tracing::warn;
+
+const FAULTS_VAR: &str = "FAULTS";
+pub const ENABLED: bool = cfg!(feature = "faults");
+pub const POINTS: [&str; 5] = ["handle_event", "attack", "tick", "state_lock", "send"];
+
+static FAULTS: Mutex<Vec<(&'static str, Fault)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    Delay(Duration),
+    Drop(f64),
+    Panic(f64),
+}
+
+impl Fault {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (kind, value) = text.split_once('=').unwrap_or((text, ""));
+        let probability = || match value {
+            "" => Ok(1.0),
+            _ => value
+                .parse::<f64>()
+                .ok()
+                .filter(|p| (0.0..=1.0).contains(p))
+                .ok_or_else(|| format!("{} takes a probability from 0 to 1", kind)),
+        };
+        match kind {
+            "delay" => value
+                .parse()
+                .map(|millis| Fault::Delay(Duration::from_millis(millis)))
+                .map_err(|_| "delay takes milliseconds".to_owned()),
+            "drop" => probability().map(Fault::Drop),
+            "panic" => probability().map(Fault::Panic),
+            _ => Err(format!("Unknown fault {}", kind)),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Delay(delay) => write!(f, "delay={}", delay.as_millis()),
+            Fault::Drop(p) => write!(f, "drop={}", p),
+            Fault::Panic(p) => write!(f, "panic={}", p),
+        }
+    }
+}
+
+/// Reads `FAULTS`; called once at startup.
+pub fn init_from_env() {
+    let Ok(spec) = std::env::var(FAULTS_VAR) else {
+        return;
+    };
+    if !ENABLED {
+        warn!(
+            "{} is ignored, the server was built without the faults feature",
+            FAULTS_VAR
+        );
+        return;
+    }
+    for entry in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let result = entry
+            .split_once(':')
+            .ok_or_else(|| "Expected point:fault".to_owned())
+            .and_then(|(point, fault)| inject(point, Fault::parse(fault)?));
+        if let Err(err) = result {
+            warn!("Ignoring fault {}: {}", entry, err);
+        }
+    }
+}
+
+/// Adds a fault at `point`, replacing one of the same kind.
+pub fn inject(point: &str, fault: Fault) -> Result<(), String> {
+    if !ENABLED {
+        return Err("The server was built without the faults feature".to_owned());
+    }
+    let point = POINTS
+        .into_iter()
+        .find(|known| *known == point)
+        .ok_or_else(|| format!("Unknown point {}", point))?;
+    warn!("Injecting {} at {}", fault, point);
+    let mut faults = FAULTS.lock().unwrap_or_else(|err| err.into_inner());
+    faults.retain(|(other, existing)| {
+        *other != point || std::mem::discriminant(existing) != std::mem::discriminant(&fault)
+    });
+    faults.push((point, fault));
+    Ok(())
+}
+
+pub fn clear() {
+    FAULTS.lock().unwrap_or_else(|err| err.into_inner()).clear();
+}
+
+/// Every fault as `point:fault`.
+pub fn list() -> Vec<String> {
+    faults()
+        .into_iter()
+        .map(|(point, fault)| format!("{}:{}", point, fault))
+        .collect()
+}
+
+/// Delays and panics as configured for `point`.
+pub fn hit(point: &str) {
+    if let Some(delay) = delay(point) {
+        thread::sleep(delay);
+    }
+    for (_, fault) in faults().into_iter().filter(|(at, _)| *at == point) {
+        if let Fault::Panic(p) = fault {
+            if rand::thread_rng().gen_bool(p) {
+                panic!("Fault injected at {}", point);
+            }
+        }
+    }
+}
+
+/// The delay at `point`, for callers that wait without blocking.
+pub fn delay(point: &str) -> Option<Duration> {
+    faults().into_iter().find_map(|(at, fault)| match fault {
+        Fault::Delay(delay) if at == point => Some(delay),
+        _ => None,
+    })
+}
+
+/// Whether to drop the message at `point`.
+pub fn drops(point: &str) -> bool {
+    faults().into_iter().any(|(at, fault)| match fault {
+        Fault::Drop(p) if at == point => rand::thread_rng().gen_bool(p),
+        _ => false,
+    })
+}
+
+fn faults() -> Vec<(&'static str, Fault)> {
+    if !ENABLED {
+        return Vec::new();
+    }
+    FAULTS.lock().unwrap_or_else(|err| err.into_inner()).clone()
+}
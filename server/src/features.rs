@@ -0,0 +1,103 @@
+//! Experimental modes that a deployment opts into.
+//!
+//! `FEATURES` lists the enabled flags, comma separated, e.g.
+//! `FEATURES=variants,powerups`. Everything is off by default. Clients learn
+//! the enabled set from the `reg` reply and only offer those modes.
+
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    game::RoomSettings,
+    messages::{Message, MessageId},
+    rules::DEFAULT_RULESET,
+};
+
+const FEATURES_VAR: &str = "FEATURES";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Feature {
+    /// Rulesets other than the classic one.
+    Variants,
+    /// Matchmaking instead of picking a room from the lobby.
+    Matchmaking,
+    /// Sonar scans.
+    PowerUps,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::Variants, Feature::Matchmaking, Feature::PowerUps];
+}
+
+impl FromStr for Feature {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "variants" => Ok(Self::Variants),
+            "matchmaking" => Ok(Self::Matchmaking),
+            "powerups" => Ok(Self::PowerUps),
+            _ => Err(format!("Unknown feature {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Variants => write!(f, "variants"),
+            Self::Matchmaking => write!(f, "matchmaking"),
+            Self::PowerUps => write!(f, "powerups"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Features(BTreeSet<Feature>);
+
+impl Features {
+    pub fn from_env() -> Self {
+        match std::env::var(FEATURES_VAR) {
+            Ok(names) => Self::parse(&names),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Unknown names are logged and skipped.
+    pub fn parse(names: &str) -> Self {
+        Self(
+            names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .filter_map(|name| {
+                    name.parse()
+                        .map_err(|err| warn!("{} in {}", err, FEATURES_VAR))
+                        .ok()
+                })
+                .collect(),
+        )
+    }
+
+    pub fn all() -> Self {
+        Self(Feature::ALL.into_iter().collect())
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.0.contains(&feature)
+    }
+
+    /// Checks that room settings only use enabled modes.
+    pub fn allow(&self, settings: &RoomSettings) -> Result<(), Message> {
+        if settings.ruleset != DEFAULT_RULESET && !self.is_enabled(Feature::Variants) {
+            return Err(Message::new(MessageId::RulesetUnavailable).arg(&settings.ruleset));
+        }
+        if settings.scan && !self.is_enabled(Feature::PowerUps) {
+            return Err(MessageId::ScansUnavailable.into());
+        }
+        Ok(())
+    }
+}
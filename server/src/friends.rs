@@ -0,0 +1,360 @@
+//! Accounts and their friend lists: requests, acceptance and removal.
+//!
+//! An account outlives connections. The first `reg` of a client opens one
+//! and the reply carries its id as `account` and a secret `accountKey`;
+//! a client that sends the key with a later `reg` signs back in to the same
+//! account, whatever name it registers under this time. Friends are shown
+//! by the name their account last registered with.
+//!
+//! With `FRIENDS_FILE` set, accounts that have friends or pending requests
+//! are written there as JSON after every change and loaded at startup, so
+//! they survive restarts. Without it they last as long as the server runs.
+//! A key the server doesn't know, for instance of an account that had no
+//! friends when the server restarted, gets a new account. Only a hash of
+//! each key is kept, and the file is written by a thread of its own so
+//! nobody waits on the disk.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use uuid::Uuid;
+
+const FRIENDS_FILE_VAR: &str = "FRIENDS_FILE";
+
+/// Requests an account may have waiting, sent or received, so a client
+/// can't grow the list without end.
+pub const MAX_PENDING_REQUESTS: usize = 50;
+
+pub type AccountId = String;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Account {
+    /// Name the account last registered with.
+    name: String,
+    /// SHA-256 of the account key, in hex.
+    key_hash: String,
+    /// When the account was last connected, in Unix milliseconds, so the
+    /// retention job can forget it after a restart. 0 when not known.
+    #[serde(default)]
+    last_seen_ms: u64,
+}
+
+/// Friendships are kept per account, so they survive disconnects and
+/// players changing their names.
+#[derive(Debug, Default, Deserialize)]
+pub struct Friends {
+    accounts: HashMap<AccountId, Account>,
+    /// Pending requests: target -> set of requesters.
+    requests: HashMap<AccountId, HashSet<AccountId>>,
+    links: HashMap<AccountId, HashSet<AccountId>>,
+    /// Accounts by the hash of their key.
+    #[serde(skip)]
+    keys: HashMap<String, AccountId>,
+    /// Where saved lists go to be written, `None` to keep them in memory.
+    #[serde(skip)]
+    writer: Option<Sender<String>>,
+}
+
+impl Friends {
+    pub fn from_env() -> Self {
+        match std::env::var(FRIENDS_FILE_VAR) {
+            Ok(path) if !path.is_empty() => Self::open(PathBuf::from(path)),
+            _ => Self::default(),
+        }
+    }
+
+    /// Friendships saved at `path`, none if there is no file yet. A file
+    /// that can't be read is left alone and nothing is saved.
+    pub fn open(path: PathBuf) -> Self {
+        let loaded = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|err| err.to_string()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.to_string()),
+        };
+        match loaded {
+            Ok(friends) => {
+                let keys = friends
+                    .accounts
+                    .iter()
+                    .map(|(id, account)| (account.key_hash.clone(), id.clone()))
+                    .collect();
+                Self {
+                    keys,
+                    writer: Some(spawn_writer(path)),
+                    ..friends
+                }
+            }
+            Err(err) => {
+                warn!("Friends file {} is disabled: {}", path.display(), err);
+                Self::default()
+            }
+        }
+    }
+
+    /// Queues the accounts that have friends or requests for writing.
+    fn save(&self) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let accounts = self
+            .accounts
+            .iter()
+            .filter(|(id, _)| self.is_involved(id))
+            .collect::<HashMap<_, _>>();
+        let text = json!({
+            "accounts": accounts,
+            "requests": self.requests,
+            "links": self.links,
+        })
+        .to_string();
+        let _ = writer.send(text);
+    }
+
+    /// The account of `key` under its new `name`, or a new account if
+    /// there is no key or nobody has it. Returns the account and its key.
+    pub fn sign_in(&mut self, key: Option<&str>, name: &str) -> (AccountId, String) {
+        if let Some(key) = key {
+            if let Some(id) = self.account_of(key).cloned() {
+                if let Some(account) = self.accounts.get_mut(&id) {
+                    if account.name != name {
+                        account.name = name.to_owned();
+                        if self.is_involved(&id) {
+                            self.save();
+                        }
+                    }
+                    return (id, key.to_owned());
+                }
+            }
+        }
+        let id = Uuid::new_v4().to_string();
+        let key = Uuid::new_v4().to_string();
+        self.keys.insert(hash(&key), id.clone());
+        self.accounts.insert(
+            id.clone(),
+            Account {
+                name: name.to_owned(),
+                key_hash: hash(&key),
+                last_seen_ms: 0,
+            },
+        );
+        (id, key)
+    }
+
+    /// Forgets an account going offline unless it has friends or requests,
+    /// as there is nothing to come back to.
+    pub fn sign_out(&mut self, id: &AccountId) {
+        if !self.is_involved(id) {
+            self.remove_account(id);
+        }
+    }
+
+    /// Notes that the account was connected at `at_ms`, in Unix
+    /// milliseconds.
+    pub fn seen(&mut self, id: &AccountId, at_ms: u64) {
+        if let Some(account) = self.accounts.get_mut(id) {
+            account.last_seen_ms = at_ms;
+            if self.is_involved(id) {
+                self.save();
+            }
+        }
+    }
+
+    /// When each account was last connected, in Unix milliseconds, 0 for
+    /// accounts saved before that was kept.
+    pub fn last_seen(&self) -> impl Iterator<Item = (&AccountId, u64)> {
+        self.accounts
+            .iter()
+            .map(|(id, account)| (id, account.last_seen_ms))
+    }
+
+    pub fn account_of(&self, key: &str) -> Option<&AccountId> {
+        self.keys.get(&hash(key))
+    }
+
+    pub fn has_account(&self, id: &AccountId) -> bool {
+        self.accounts.contains_key(id)
+    }
+
+    pub fn name_of(&self, id: &AccountId) -> Option<&str> {
+        self.accounts.get(id).map(|account| account.name.as_str())
+    }
+
+    fn is_involved(&self, id: &AccountId) -> bool {
+        let has = |lists: &HashMap<AccountId, HashSet<AccountId>>| {
+            lists.get(id).is_some_and(|others| !others.is_empty())
+                || lists.values().any(|others| others.contains(id))
+        };
+        has(&self.links) || has(&self.requests)
+    }
+
+    fn remove_account(&mut self, id: &AccountId) {
+        if let Some(account) = self.accounts.remove(id) {
+            self.keys.remove(&account.key_hash);
+        }
+    }
+
+    /// A request to someone who already asked us is accepted right away.
+    pub fn request(&mut self, from: &AccountId, to: &AccountId) -> FriendRequestResult {
+        if from == to || !self.has_account(from) || !self.has_account(to) {
+            return FriendRequestResult::Invalid;
+        }
+        if self.are_friends(from, to) {
+            return FriendRequestResult::AlreadyFriends;
+        }
+        if self.has_request(from, to) {
+            self.accept(from, to);
+            return FriendRequestResult::Accepted;
+        }
+        if self.has_request(to, from) {
+            return FriendRequestResult::AlreadySent;
+        }
+        if self.requests_from(from).len() >= MAX_PENDING_REQUESTS
+            || self.requests_to(to).len() >= MAX_PENDING_REQUESTS
+        {
+            return FriendRequestResult::TooMany;
+        }
+        self.requests
+            .entry(to.clone())
+            .or_default()
+            .insert(from.clone());
+        self.save();
+        FriendRequestResult::Sent
+    }
+
+    /// Accepts a pending request sent by `from` to `to`.
+    pub fn accept(&mut self, to: &AccountId, from: &AccountId) -> bool {
+        let removed = match self.requests.get_mut(to) {
+            Some(requesters) => requesters.remove(from),
+            None => false,
+        };
+        if !removed {
+            return false;
+        }
+        self.links
+            .entry(to.clone())
+            .or_default()
+            .insert(from.clone());
+        self.links
+            .entry(from.clone())
+            .or_default()
+            .insert(to.clone());
+        self.save();
+        true
+    }
+
+    /// Removes a friendship or declines a pending request in either direction.
+    pub fn remove(&mut self, id: &AccountId, other: &AccountId) -> bool {
+        let mut removed = false;
+        for (a, b) in [(id, other), (other, id)] {
+            if let Some(friends) = self.links.get_mut(a) {
+                removed |= friends.remove(b);
+            }
+            if let Some(requesters) = self.requests.get_mut(a) {
+                removed |= requesters.remove(b);
+            }
+        }
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    pub fn are_friends(&self, a: &AccountId, b: &AccountId) -> bool {
+        self.links
+            .get(a)
+            .map(|friends| friends.contains(b))
+            .unwrap_or(false)
+    }
+
+    fn has_request(&self, to: &AccountId, from: &AccountId) -> bool {
+        self.requests
+            .get(to)
+            .map(|requesters| requesters.contains(from))
+            .unwrap_or(false)
+    }
+
+    /// Friends ordered by name.
+    pub fn friends_of(&self, id: &AccountId) -> Vec<AccountId> {
+        self.by_name(self.links.get(id).into_iter().flatten())
+    }
+
+    pub fn requests_from(&self, id: &AccountId) -> Vec<AccountId> {
+        self.by_name(
+            self.requests
+                .iter()
+                .filter(|(_, requesters)| requesters.contains(id))
+                .map(|(target, _)| target),
+        )
+    }
+
+    pub fn requests_to(&self, id: &AccountId) -> Vec<AccountId> {
+        self.by_name(self.requests.get(id).into_iter().flatten())
+    }
+
+    fn by_name<'a>(&self, ids: impl Iterator<Item = &'a AccountId>) -> Vec<AccountId> {
+        let mut ids = ids.cloned().collect::<Vec<_>>();
+        ids.sort_by(|a, b| self.name_of(a).cmp(&self.name_of(b)).then_with(|| a.cmp(b)));
+        ids
+    }
+
+    /// Drops the account with every friendship and pending request
+    /// involving it and returns the former friends.
+    pub fn forget(&mut self, id: &AccountId) -> Vec<AccountId> {
+        let friends = self.friends_of(id);
+        self.links.remove(id);
+        self.requests.remove(id);
+        for others in self.links.values_mut().chain(self.requests.values_mut()) {
+            others.remove(id);
+        }
+        self.remove_account(id);
+        self.save();
+        friends
+    }
+}
+
+fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Starts the thread writing saved lists to `path`.
+fn spawn_writer(path: PathBuf) -> Sender<String> {
+    let (tx, rx) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        while let Ok(mut text) = rx.recv() {
+            // Only the latest list matters when several changes queued up.
+            while let Ok(newer) = rx.try_recv() {
+                text = newer;
+            }
+            write(&path, &text);
+        }
+    });
+    tx
+}
+
+/// Writes the list next to the file and moves it in place, so a crash
+/// never leaves half a file behind.
+fn write(path: &Path, text: &str) {
+    let partial = path.with_extension("partial");
+    if let Err(err) = fs::write(&partial, text).and_then(|_| fs::rename(&partial, path)) {
+        warn!("Friends not saved to {}: {}", path.display(), err);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FriendRequestResult {
+    Sent,
+    Accepted,
+    AlreadyFriends,
+    AlreadySent,
+    TooMany,
+    Invalid,
+}
@@ -1,113 +1,1259 @@
-use crate::{ships::Ships, User, UserId};
-
-const BOARD_SIZE: usize = 10;
-
-pub type GameId = String;
-
-#[derive(Debug)]
-pub struct Game {
-    pub id: GameId,
-    pub status: GameStatus,
-    pub player1: Option<UserId>,
-    pub player2: Option<UserId>,
-
-    is_p1_turn: bool,
-    p1_board: Board,
-    p2_board: Board,
-}
-
-impl Game {
-    pub fn create(id: &GameId, p1: &User) -> Self {
-        Self {
-            id: id.to_string(),
-            status: GameStatus::Waiting,
-            player1: Some(p1.id.clone()),
-            player2: None,
-            is_p1_turn: rand::random::<bool>(),
-
-            p1_board: Board::default(),
-            p2_board: Board::default(),
-        }
-    }
-
-    pub fn add_ships(&mut self, ships: &Ships, user_id: &UserId) {
-        let board: &mut Board = if user_id.clone() == self.player1.clone().unwrap() {
-            &mut self.p1_board
-        } else {
-            &mut self.p2_board
-        };
-
-        let mut i = 0;
-
-        for ship in ships.ships.clone() {
-            let mut pos = ship.position.clone();
-            for _ in 0..ship.hp {
-                board.set_cell(pos.x, pos.y, Cell::Alive(i));
-                if ship.is_vertical {
-                    pos.y += 1;
-                } else {
-                    pos.x += 1;
-                }
-            }
-            i += 1;
-            board.ships.ships.push(ship);
-        }
-        println!("{}", board.to_string());
-    }
-}
-
-#[derive(Debug)]
-struct Board {
-    ships: Ships,
-    cells: Vec<Cell>,
-}
-
-impl Board {
-    fn set_cell(&mut self, x: u8, y: u8, cell: Cell) {
-        self.cells[x as usize + y as usize * BOARD_SIZE] = cell;
-    }
-}
-
-impl Default for Board {
-    fn default() -> Self {
-        Self {
-            ships: Ships::default(),
-            cells: Vec::from([Cell::Empty; BOARD_SIZE * BOARD_SIZE]),
-        }
-    }
-}
-
-impl ToString for Board {
-    fn to_string(&self) -> String {
-        let mut s = String::new();
-        for y in (0..BOARD_SIZE) {
-            for x in 0..BOARD_SIZE {
-                match self.cells[x as usize + y as usize * BOARD_SIZE] {
-                    Cell::Empty => s.push('-'),
-                    Cell::Alive(v) => s.push_str(&v.to_string()),
-                    Cell::Miss => s.push('x'),
-                    Cell::Shot => s.push('+'),
-                    Cell::Killed => s.push('D'),
-                };
-            }
-            s.push('\n');
-        }
-        s
-    }
-}
-
-#[derive(Debug, Clone, Copy)]
-enum Cell {
-    Empty,
-    Alive(usize),
-    Miss,
-    Shot,
-    Killed,
-}
-
-#[derive(Debug)]
-pub enum GameStatus {
-    Waiting,
-    PlacingShips,
-    Started,
-}
+use std::{
+    collections::HashSet,
+    fmt,
+    ops::{Index, IndexMut},
+    time::{Duration, Instant},
+};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tracing::debug;
+
+use smallvec::SmallVec;
+
+use crate::{
+    clock,
+    messages::{Message, MessageId},
+    rules::{self, Ruleset, DEFAULT_RULESET},
+    ships::{Coord, Fleet, Position, Ship, Ships, MAX_AROUND, MAX_FLEET_SIZE, MAX_SHIP_LENGTH},
+    User, UserId,
+};
+
+pub const DEFAULT_BOARD_SIZE: usize = 10;
+pub const MIN_BOARD_SIZE: usize = 8;
+pub const MAX_BOARD_SIZE: usize = 15;
+pub const MAX_BEST_OF: u8 = 5;
+pub const MAX_CLOCK_SECONDS: u64 = 60 * 60;
+pub const MAX_PAUSE: Duration = Duration::from_secs(10 * 60);
+
+pub type GameId = String;
+
+/// Options chosen by the room creator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RoomSettings {
+    pub board_size: usize,
+    pub fleet: Fleet,
+    /// Forbids ships from touching each other, even diagonally.
+    pub no_touching: bool,
+    /// Gives each player one sonar scan per game.
+    pub scan: bool,
+    /// Number of games in the series, odd.
+    pub best_of: u8,
+    pub clock: Option<TimeControl>,
+    pub repeat_shot: RepeatShot,
+    /// Name of the game variant, see `rules`.
+    pub ruleset: String,
+    /// Spectators allowed at once, on top of the server-wide cap.
+    pub max_spectators: Option<usize>,
+    pub handicap: Option<Handicap>,
+}
+
+/// Evens out a game between players of different strength: one of them,
+/// usually the stronger, plays with a smaller fleet or fewer shots. Games
+/// with a handicap are unrated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Handicap {
+    pub player: HandicapPlayer,
+    /// Fleet the player places instead of the room fleet, smaller than it.
+    pub fleet: Option<Fleet>,
+    /// Shots taken off each of the player's turns, which keep at least one.
+    /// Only matters in rulesets with several shots a turn, like salvo.
+    #[serde(default)]
+    pub fewer_shots: u8,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HandicapPlayer {
+    /// The player who created the room.
+    Creator,
+    /// The player who joined it.
+    Opponent,
+}
+
+/// What happens when a player fires at a cell that was already revealed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatShot {
+    /// The shot is refused and the player keeps the turn.
+    #[default]
+    Reject,
+    /// The shot changes nothing but costs the turn.
+    Waste,
+}
+
+/// Chess-clock budget: every player starts with `initial_ms` and gains
+/// `increment_ms` after each move that passes the turn.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeControl {
+    pub initial_ms: u64,
+    pub increment_ms: u64,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            board_size: DEFAULT_BOARD_SIZE,
+            fleet: Fleet::default(),
+            no_touching: false,
+            scan: false,
+            best_of: 1,
+            clock: None,
+            repeat_shot: RepeatShot::default(),
+            ruleset: DEFAULT_RULESET.to_owned(),
+            max_spectators: None,
+            handicap: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Game {
+    pub id: GameId,
+    /// Only changed by `apply`.
+    status: GameStatus,
+    player1: Option<UserId>,
+    player2: Option<UserId>,
+    pub settings: RoomSettings,
+    /// Seeds every random choice of the game, published when it ends so
+    /// players can check the first turn and random shots were fair.
+    pub seed: u64,
+
+    rng: ChaCha8Rng,
+    rules: &'static dyn Ruleset,
+    is_p1_turn: bool,
+    /// Shots the player to move has left this turn.
+    shots_left: usize,
+    p1_board: Board,
+    p2_board: Board,
+    /// Players that already used their scan.
+    scanned: HashSet<UserId>,
+    /// Players that placed their fleet this round.
+    ships_submitted: HashSet<UserId>,
+    /// Games won in the series by player 1 and player 2.
+    pub score: [u8; 2],
+    p1_started: bool,
+    /// Remaining time of player 1 and player 2, when playing with a clock.
+    clocks: [Duration; 2],
+    turn_started: Instant,
+    pause_requests: HashSet<UserId>,
+    /// Set while both players agreed to pause; clocks don't run meanwhile.
+    paused_at: Option<Instant>,
+    abort_requested_by: Option<UserId>,
+    /// When the first game of the series started.
+    started_at: Option<Instant>,
+    /// Every shot of the series, in order.
+    shots: Vec<Shot>,
+}
+
+/// A shot as shown in the post-game summary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Shot {
+    /// Game of the series, from 1.
+    pub round: u8,
+    #[serde(rename = "index")]
+    pub player: UserId,
+    pub position: Position,
+    pub status: AttackStatus,
+    /// Whether the shot hit a ship that was still afloat there.
+    pub hit: bool,
+    /// Time since the series started.
+    pub at_ms: u64,
+}
+
+impl Game {
+    pub fn create(id: &GameId, p1: &User, settings: &RoomSettings) -> Self {
+        Self::with_seed(id, &p1.id, settings, rand::random())
+    }
+
+    /// Creates a game whose random choices all follow from `seed`.
+    pub fn with_seed(id: &GameId, p1: &UserId, settings: &RoomSettings, seed: u64) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let is_p1_turn = rng.gen::<bool>();
+        Self {
+            id: id.to_string(),
+            status: GameStatus::Waiting,
+            player1: Some(p1.clone()),
+            player2: None,
+            settings: settings.clone(),
+            seed,
+            rng,
+            rules: rules::ruleset(&settings.ruleset)
+                .or_else(|| rules::ruleset(DEFAULT_RULESET))
+                .unwrap(),
+            is_p1_turn,
+            shots_left: 1,
+
+            p1_board: Board::new(settings.board_size),
+            p2_board: Board::new(settings.board_size),
+            scanned: HashSet::new(),
+            ships_submitted: HashSet::new(),
+            score: [0, 0],
+            p1_started: is_p1_turn,
+            clocks: [Duration::ZERO; 2],
+            turn_started: clock::now(),
+            pause_requests: HashSet::new(),
+            paused_at: None,
+            abort_requested_by: None,
+            started_at: None,
+            shots: Vec::new(),
+        }
+    }
+
+    /// Counts a won game and returns the series winner once one player has
+    /// won the majority of `best_of` games.
+    pub fn record_win(&mut self, winner: &UserId) -> Option<UserId> {
+        let i = if self.player1.as_ref() == Some(winner) {
+            0
+        } else {
+            1
+        };
+        self.score[i] += 1;
+        if self.score[i] > self.settings.best_of / 2 {
+            Some(winner.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn status(&self) -> GameStatus {
+        self.status
+    }
+
+    /// Player 1, who created the room, and player 2.
+    pub fn players(&self) -> [Option<UserId>; 2] {
+        [self.player1.clone(), self.player2.clone()]
+    }
+
+    /// Moves the game on to another phase, the only way phases change:
+    ///
+    /// - `Waiting` → `PlacingShips` when a second player joins;
+    /// - `PlacingShips` → `Waiting` when a player leaves, the other one
+    ///   keeping the room;
+    /// - `PlacingShips` → `Started` once both fleets are placed;
+    /// - `Started` → `PlacingShips` for the next game of a series;
+    /// - anything but `Finished` → `Finished`.
+    pub fn apply(&mut self, transition: Transition) -> Result<(), IllegalTransition> {
+        match (self.status, &transition) {
+            (GameStatus::Waiting, Transition::Join(user_id)) if !self.is_player(user_id) => {
+                self.player2 = Some(user_id.clone());
+                self.status = GameStatus::PlacingShips;
+            }
+            (GameStatus::PlacingShips, Transition::Leave(user_id)) if self.is_player(user_id) => {
+                // Both seats are taken while placing ships.
+                let owner = self.opponent_of(user_id).unwrap();
+                self.reopen(&owner);
+            }
+            (GameStatus::PlacingShips, Transition::Start) if self.is_ready() => self.start(),
+            (GameStatus::Started, Transition::NextRound) => self.next_round(),
+            (status, Transition::Finish) if status != GameStatus::Finished => {
+                self.status = GameStatus::Finished;
+            }
+            (from, _) => return Err(IllegalTransition { from, transition }),
+        }
+        Ok(())
+    }
+
+    /// Resets the boards for the next game of the series, handing the first
+    /// turn to the player who moved second last time.
+    fn next_round(&mut self) {
+        self.status = GameStatus::PlacingShips;
+        self.pause_requests.clear();
+        self.paused_at = None;
+        self.abort_requested_by = None;
+        self.p1_board = Board::new(self.settings.board_size);
+        self.p2_board = Board::new(self.settings.board_size);
+        self.scanned.clear();
+        self.ships_submitted.clear();
+        self.p1_started = !self.p1_started;
+        self.is_p1_turn = self.p1_started;
+    }
+
+    /// Puts a room whose opponent left before the start back in the lobby,
+    /// owned by `owner`.
+    fn reopen(&mut self, owner: &UserId) {
+        self.player1 = Some(owner.clone());
+        self.player2 = None;
+        self.status = GameStatus::Waiting;
+        self.p1_board = Board::new(self.settings.board_size);
+        self.p2_board = Board::new(self.settings.board_size);
+        self.ships_submitted.clear();
+        self.score = [0, 0];
+        self.pause_requests.clear();
+        self.paused_at = None;
+        self.abort_requested_by = None;
+    }
+
+    pub fn add_ships(&mut self, ships: &Ships, user_id: &UserId) -> Result<(), Message> {
+        if !matches!(self.status, GameStatus::PlacingShips) {
+            return Err(MessageId::PlacementClosed.into());
+        }
+        if !self.is_player(user_id) {
+            return Err(MessageId::NotAPlayer.into());
+        }
+        if self.ships_submitted.contains(user_id) {
+            return Err(MessageId::ShipsAlreadyPlaced.into());
+        }
+        self.rules
+            .validate_placement(ships, self.fleet_of(user_id))?;
+        let no_touching = self.settings.no_touching;
+        let board = self.board_mut(user_id);
+
+        let (cells, problems) = board.place(ships, no_touching);
+        if let Some(problem) = problems.first() {
+            return Err(problem.message());
+        }
+        board.cells = cells;
+        for ship in ships.ships.iter() {
+            board.ships.ships.push(ship.clone());
+            board.hits.push(0);
+        }
+        debug!("Board of {}:\n{}", user_id, board);
+        self.ships_submitted.insert(user_id.clone());
+        Ok(())
+    }
+
+    /// Checks a placement of the user without placing it: the fleet against
+    /// the ruleset, then every ship against the board.
+    pub fn check_placement(
+        &self,
+        ships: &Ships,
+        user_id: &UserId,
+    ) -> (Result<(), Message>, Vec<ShipProblem>) {
+        let fleet = self.rules.validate_placement(ships, self.fleet_of(user_id));
+        let board = Board::new(self.settings.board_size);
+        let (_, problems) = board.place(ships, self.settings.no_touching);
+        (fleet, problems)
+    }
+
+    /// Both players have placed their fleets.
+    pub fn is_ready(&self) -> bool {
+        self.ships_submitted.len() == 2
+    }
+
+    fn start(&mut self) {
+        self.status = GameStatus::Started;
+        if let Some(clock) = self.settings.clock {
+            self.clocks = [Duration::from_millis(clock.initial_ms); 2];
+        }
+        self.turn_started = clock::now();
+        self.started_at.get_or_insert_with(clock::now);
+        self.reset_shots();
+    }
+
+    pub fn shots_left(&self) -> usize {
+        self.shots_left
+    }
+
+    /// The player taking the handicap, if there is one.
+    pub fn handicapped_player(&self) -> Option<&UserId> {
+        match self.settings.handicap.as_ref()?.player {
+            HandicapPlayer::Creator => self.player1.as_ref(),
+            HandicapPlayer::Opponent => self.player2.as_ref(),
+        }
+    }
+
+    /// The fleet the user has to place.
+    pub fn fleet_of(&self, user_id: &UserId) -> &Fleet {
+        let handicap_fleet = self
+            .settings
+            .handicap
+            .as_ref()
+            .and_then(|handicap| handicap.fleet.as_ref());
+        match handicap_fleet {
+            Some(fleet) if self.handicapped_player() == Some(user_id) => fleet,
+            _ => &self.settings.fleet,
+        }
+    }
+
+    pub fn ships_of(&self, user_id: &UserId) -> &Ships {
+        &self.board(user_id).ships
+    }
+
+    /// Whether play began: the game started, or a series is between games.
+    /// A player leaving such a game loses it rather than reopening the room.
+    pub fn is_under_way(&self) -> bool {
+        matches!(self.status, GameStatus::Started) || self.score != [0, 0]
+    }
+
+    pub fn is_player(&self, user_id: &UserId) -> bool {
+        self.player1.as_ref() == Some(user_id) || self.player2.as_ref() == Some(user_id)
+    }
+
+    pub fn current_player(&self) -> Option<UserId> {
+        if self.is_p1_turn {
+            self.player1.clone()
+        } else {
+            self.player2.clone()
+        }
+    }
+
+    pub fn opponent_of(&self, user_id: &UserId) -> Option<UserId> {
+        if self.player1.as_ref() == Some(user_id) {
+            self.player2.clone()
+        } else {
+            self.player1.clone()
+        }
+    }
+
+    /// Fires at the opponent's board and returns every cell whose state changed.
+    pub fn attack(&mut self, user_id: &UserId, x: u8, y: u8) -> Result<AttackResult, Message> {
+        self.check_move(user_id)?;
+        let repeat_shot = self.settings.repeat_shot;
+        let rules = self.rules;
+        let opponent = self.opponent_of(user_id).unwrap();
+        let board = self.board_mut(&opponent);
+        let target = board
+            .coord(x, y)
+            .ok_or_else(|| Message::new(MessageId::AttackOutOfBounds).arg(x).arg(y))?;
+
+        let Some(Fired { status, hit, cells }) = board.fire(target) else {
+            if repeat_shot == RepeatShot::Reject {
+                return Err(Message::new(MessageId::CellAlreadyShot).arg(x).arg(y));
+            }
+            let status = match board[target] {
+                Cell::Shot => AttackStatus::Shot,
+                Cell::Killed => AttackStatus::Killed,
+                _ => AttackStatus::Miss,
+            };
+            self.record_shot(user_id, x, y, status, false);
+            self.end_shot(false);
+            return Ok(AttackResult {
+                cells: [(target.position(), status)].into_iter().collect(),
+                is_won: false,
+            });
+        };
+
+        debug!("Shot at ({}, {}): {:?}", x, y, status);
+        let is_won = rules.is_defeated(&board.afloat());
+        let effect = rules.resolve_shot(hit.as_ref());
+        self.record_shot(user_id, x, y, status, hit.is_some());
+        self.end_shot(effect.extra_shot);
+        Ok(AttackResult { cells, is_won })
+    }
+
+    fn record_shot(&mut self, user_id: &UserId, x: u8, y: u8, status: AttackStatus, hit: bool) {
+        let at = self.started_at.map(clock::since);
+        self.shots.push(Shot {
+            round: self.score[0] + self.score[1] + 1,
+            player: user_id.clone(),
+            position: Position { x, y },
+            status,
+            hit,
+            at_ms: at.unwrap_or_default().as_millis() as u64,
+        });
+    }
+
+    pub fn shots(&self) -> &[Shot] {
+        &self.shots
+    }
+
+    /// Time since the first game of the series started.
+    pub fn duration(&self) -> Duration {
+        self.started_at.map(clock::since).unwrap_or_default()
+    }
+
+    /// Ships of the player that are still afloat.
+    pub fn afloat_of(&self, user_id: &UserId) -> Vec<Ship> {
+        self.board(user_id)
+            .afloat()
+            .iter()
+            .copied()
+            .cloned()
+            .collect()
+    }
+
+    /// Counts ship cells in the 3x3 area around (`x`, `y`) on the opponent's
+    /// board. Uses up the player's scan and their turn.
+    pub fn scan(&mut self, user_id: &UserId, x: u8, y: u8) -> Result<usize, Message> {
+        if !self.settings.scan {
+            return Err(MessageId::ScansDisabled.into());
+        }
+        self.check_move(user_id)?;
+        if self.scanned.contains(user_id) {
+            return Err(MessageId::ScanUsed.into());
+        }
+        let opponent = self.opponent_of(user_id).unwrap();
+        let board = self.board(&opponent);
+        let target = board
+            .coord(x, y)
+            .ok_or_else(|| Message::new(MessageId::ScanOutOfBounds).arg(x).arg(y))?;
+
+        let count = std::iter::once(target)
+            .chain(target.neighbors())
+            .filter(|&coord| matches!(board[coord], Cell::Alive(_) | Cell::Shot | Cell::Killed))
+            .count();
+
+        self.scanned.insert(user_id.clone());
+        self.end_move(true);
+        Ok(count)
+    }
+
+    fn check_move(&self, user_id: &UserId) -> Result<(), Message> {
+        if !matches!(self.status, GameStatus::Started) {
+            return Err(MessageId::GameNotStarted.into());
+        }
+        if self.paused_at.is_some() {
+            return Err(MessageId::GamePaused.into());
+        }
+        if self.current_player().as_ref() != Some(user_id) {
+            return Err(MessageId::NotYourTurn.into());
+        }
+        Ok(())
+    }
+
+    /// Counts a shot against the turn unless it earned an extra one, and
+    /// passes the turn once no shots are left.
+    fn end_shot(&mut self, extra_shot: bool) {
+        if !extra_shot {
+            self.shots_left = self.shots_left.saturating_sub(1);
+        }
+        self.end_move(self.shots_left == 0);
+    }
+
+    /// Charges the time spent on the move to the mover's clock and optionally
+    /// passes the turn.
+    fn end_move(&mut self, pass_turn: bool) {
+        self.charge_time();
+        if pass_turn {
+            if let Some(clock) = self.settings.clock {
+                let i = self.current_index();
+                self.clocks[i] += Duration::from_millis(clock.increment_ms);
+            }
+            self.is_p1_turn = !self.is_p1_turn;
+            self.reset_shots();
+        }
+    }
+
+    fn reset_shots(&mut self) {
+        let Some(current) = self.current_player() else {
+            return;
+        };
+        let ships_afloat = self.board(&current).afloat().len();
+        let mut shots = self.rules.shots_per_turn(ships_afloat);
+        if self.handicapped_player() == Some(&current) {
+            let fewer_shots = self.settings.handicap.as_ref().unwrap().fewer_shots;
+            shots = shots.saturating_sub(fewer_shots as usize).max(1);
+        }
+        self.shots_left = shots;
+    }
+
+    fn charge_time(&mut self) {
+        if self.settings.clock.is_some() {
+            let i = self.current_index();
+            self.clocks[i] = self.clocks[i].saturating_sub(clock::since(self.turn_started));
+        }
+        self.turn_started = clock::now();
+    }
+
+    /// Records a pause request and returns `true` once both players asked for
+    /// it and the game got paused.
+    pub fn request_pause(&mut self, user_id: &UserId) -> Result<bool, Message> {
+        if !matches!(self.status, GameStatus::Started) {
+            return Err(MessageId::GameNotStarted.into());
+        }
+        if self.paused_at.is_some() {
+            return Err(MessageId::GameAlreadyPaused.into());
+        }
+        self.pause_requests.insert(user_id.clone());
+        if self.pause_requests.len() < 2 {
+            return Ok(false);
+        }
+        self.charge_time();
+        self.pause_requests.clear();
+        self.paused_at = Some(clock::now());
+        Ok(true)
+    }
+
+    pub fn resume(&mut self) -> Result<(), Message> {
+        if self.paused_at.take().is_none() {
+            return Err(MessageId::GameNotPaused.into());
+        }
+        self.turn_started = clock::now();
+        Ok(())
+    }
+
+    pub fn request_abort(&mut self, user_id: &UserId) -> Result<(), Message> {
+        if matches!(self.status, GameStatus::Waiting) {
+            return Err(MessageId::NoOpponent.into());
+        }
+        self.abort_requested_by = Some(user_id.clone());
+        Ok(())
+    }
+
+    /// Agrees to an abort the opponent asked for.
+    pub fn accept_abort(&mut self, user_id: &UserId) -> Result<(), Message> {
+        match &self.abort_requested_by {
+            Some(requester) if requester != user_id => Ok(()),
+            _ => Err(MessageId::NoAbortRequest.into()),
+        }
+    }
+
+    pub fn is_pause_expired(&self) -> bool {
+        self.paused_at
+            .is_some_and(|paused_at| clock::since(paused_at) >= MAX_PAUSE)
+    }
+
+    /// The players of `set`, player 1 first.
+    fn in_player_order(&self, set: &HashSet<UserId>) -> Vec<UserId> {
+        self.players()
+            .into_iter()
+            .flatten()
+            .filter(|user_id| set.contains(user_id))
+            .collect()
+    }
+
+    fn current_index(&self) -> usize {
+        if self.is_p1_turn {
+            0
+        } else {
+            1
+        }
+    }
+
+    /// Remaining time of player 1 and player 2, including the running move.
+    pub fn remaining_time(&self) -> Option<[Duration; 2]> {
+        self.settings.clock?;
+        let mut clocks = self.clocks;
+        if matches!(self.status, GameStatus::Started) && self.paused_at.is_none() {
+            let i = self.current_index();
+            clocks[i] = clocks[i].saturating_sub(clock::since(self.turn_started));
+        }
+        Some(clocks)
+    }
+
+    /// The player to move if their clock ran out.
+    pub fn timed_out_player(&self) -> Option<UserId> {
+        let clocks = self.remaining_time()?;
+        if clocks[self.current_index()].is_zero() {
+            self.current_player()
+        } else {
+            None
+        }
+    }
+
+    /// When the clock of the player to move runs out, unless the move ends
+    /// first.
+    pub fn turn_deadline(&self) -> Option<Instant> {
+        if !matches!(self.status, GameStatus::Started) || self.paused_at.is_some() {
+            return None;
+        }
+        let clocks = self.remaining_time()?;
+        Some(clock::now() + clocks[self.current_index()])
+    }
+
+    /// When the pause ends by itself.
+    pub fn pause_deadline(&self) -> Option<Instant> {
+        self.paused_at.map(|paused_at| paused_at + MAX_PAUSE)
+    }
+
+    /// ASCII renderings of player 1's and player 2's boards.
+    pub fn boards(&self) -> [String; 2] {
+        [self.p1_board.to_string(), self.p2_board.to_string()]
+    }
+
+    /// Cells of the player's board that were fired at, as the opponent saw
+    /// them.
+    pub fn revealed_cells(&self, user_id: &UserId) -> Vec<(Position, AttackStatus)> {
+        revealed_cells(self.board(user_id))
+    }
+
+    /// The player's board row by row, one character per cell: `.` water,
+    /// `o` a miss, `x` a hit and `#` a sunk ship's cell. Ships not hit yet
+    /// show as `S`, unless `hide_ships` keeps them to what the opponent saw.
+    pub fn board_rows(&self, user_id: &UserId, hide_ships: bool) -> Vec<String> {
+        self.board(user_id).rows(hide_ships)
+    }
+
+    /// Short digest of what both players can see, sent with `turn` and
+    /// `sync` so clients can tell when their copy drifted. It is the 32-bit
+    /// FNV-1a hash, as 8 hex digits, of the fired-at cells of player 1's
+    /// and then player 2's board, as in `players` of `sync`, each cell in
+    /// row order as `x,y,status;` and each board followed by a `/`, and
+    /// then `1` or `2` for the player to move.
+    pub fn checksum(&self) -> String {
+        let mut text = String::new();
+        for board in [&self.p1_board, &self.p2_board] {
+            for (position, status) in revealed_cells(board) {
+                let status = match status {
+                    AttackStatus::Miss => "miss",
+                    AttackStatus::Shot => "shot",
+                    AttackStatus::Killed => "killed",
+                };
+                text.push_str(&format!("{},{},{};", position.x, position.y, status));
+            }
+            text.push('/');
+        }
+        text.push(if self.is_p1_turn { '1' } else { '2' });
+        let hash = text.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        });
+        format!("{:08x}", hash)
+    }
+
+    /// Picks a random cell of the opponent's board that was not fired at yet.
+    pub fn random_target(&mut self, user_id: &UserId) -> Option<Position> {
+        let opponent = self.opponent_of(user_id)?;
+        let board = self.board(&opponent);
+        let targets = (0..board.cells.len())
+            .filter(|&i| matches!(board.cells[i], Cell::Empty | Cell::Alive(_)))
+            .collect::<Vec<usize>>();
+        if targets.is_empty() {
+            return None;
+        }
+        let size = board.size;
+        let i = targets[self.rng.gen_range(0..targets.len())];
+        Coord::from_index(i, size).map(Coord::position)
+    }
+
+    pub fn board(&self, user_id: &UserId) -> &Board {
+        if self.player1.as_ref() == Some(user_id) {
+            &self.p1_board
+        } else {
+            &self.p2_board
+        }
+    }
+
+    fn board_mut(&mut self, user_id: &UserId) -> &mut Board {
+        if self.player1.as_ref() == Some(user_id) {
+            &mut self.p1_board
+        } else {
+            &mut self.p2_board
+        }
+    }
+}
+
+/// Cells of the board that were fired at, as the opponent saw them.
+fn revealed_cells(board: &Board) -> Vec<(Position, AttackStatus)> {
+    board
+        .cells
+        .iter()
+        .enumerate()
+        .filter_map(|(i, cell)| {
+            let status = match cell {
+                Cell::Miss => AttackStatus::Miss,
+                Cell::Shot => AttackStatus::Shot,
+                Cell::Killed => AttackStatus::Killed,
+                Cell::Empty | Cell::Alive(_) => return None,
+            };
+            let coord = Coord::from_index(i, board.size)?;
+            Some((coord.position(), status))
+        })
+        .collect()
+}
+
+/// Most cells a single shot changes: those of a sunk ship and around it.
+const MAX_EFFECT_CELLS: usize = MAX_SHIP_LENGTH as usize + MAX_AROUND;
+
+#[derive(Debug)]
+pub struct AttackResult {
+    pub cells: SmallVec<[(Position, AttackStatus); MAX_EFFECT_CELLS]>,
+    pub is_won: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttackStatus {
+    Miss,
+    Shot,
+    Killed,
+}
+
+/// What is wrong with one ship of a placement.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ShipProblem {
+    /// Index of the ship in the submitted list.
+    pub ship: usize,
+    pub problem: PlacementProblem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PlacementProblem {
+    OutOfBounds,
+    Overlap,
+    Touching,
+}
+
+impl ShipProblem {
+    pub fn message(&self) -> Message {
+        let id = match self.problem {
+            PlacementProblem::OutOfBounds => MessageId::ShipOutOfBounds,
+            PlacementProblem::Overlap => MessageId::ShipOverlaps,
+            PlacementProblem::Touching => MessageId::ShipTouches,
+        };
+        Message::new(id).arg(self.ship)
+    }
+}
+
+/// What a shot at a cell not fired at before did.
+struct Fired {
+    status: AttackStatus,
+    /// The ship hit, as it was before the shot.
+    hit: Option<Ship>,
+    /// Every cell whose state changed.
+    cells: SmallVec<[(Position, AttackStatus); MAX_EFFECT_CELLS]>,
+}
+
+/// A player's board with their fleet, as in game snapshots and `sync`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Board {
+    size: usize,
+    ships: Ships,
+    /// Number of hits taken by each ship, indexed like `ships`.
+    hits: Vec<u8>,
+    cells: Vec<Cell>,
+}
+
+impl Board {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            ships: Ships::default(),
+            hits: Vec::new(),
+            cells: vec![Cell::Empty; size * size],
+        }
+    }
+
+    fn coord(&self, x: u8, y: u8) -> Option<Coord> {
+        Coord::new(x, y, self.size)
+    }
+
+    /// The cells with the ships added, and every problem found, at most one
+    /// of each kind per ship. Cells out of bounds or taken are skipped.
+    fn place(&self, ships: &Ships, no_touching: bool) -> (Vec<Cell>, Vec<ShipProblem>) {
+        let mut cells = self.cells.clone();
+        let mut problems = Vec::new();
+        let offset = self.ships.ships.len();
+        for (i, ship) in ships.ships.iter().enumerate() {
+            let mut found = Vec::new();
+            for &pos in ship.cells().iter() {
+                let problem = match Coord::of(&pos, self.size) {
+                    None => PlacementProblem::OutOfBounds,
+                    Some(coord) if !matches!(cells[coord.index()], Cell::Empty) => {
+                        PlacementProblem::Overlap
+                    }
+                    Some(coord) => {
+                        cells[coord.index()] = Cell::Alive(offset + i);
+                        continue;
+                    }
+                };
+                if !found.contains(&problem) {
+                    found.push(problem);
+                }
+            }
+            problems.extend(
+                found
+                    .into_iter()
+                    .map(|problem| ShipProblem { ship: i, problem }),
+            );
+        }
+        if no_touching {
+            for (i, ship) in ships.ships.iter().enumerate() {
+                let on_board = ship
+                    .cells()
+                    .iter()
+                    .filter_map(|pos| Coord::of(pos, self.size))
+                    .collect::<Vec<Coord>>();
+                let touches = Coord::around(&on_board)
+                    .into_iter()
+                    .any(|coord| matches!(cells[coord.index()], Cell::Alive(j) if j != offset + i));
+                if touches {
+                    problems.push(ShipProblem {
+                        ship: i,
+                        problem: PlacementProblem::Touching,
+                    });
+                }
+            }
+        }
+        (cells, problems)
+    }
+
+    /// A board with `ships` placed, which must fit on it without
+    /// overlapping, as those of `simulation::random_fleet` do.
+    pub fn with_ships(size: usize, ships: &Ships) -> Self {
+        let mut board = Self::new(size);
+        let (cells, problems) = board.place(ships, false);
+        debug_assert!(problems.is_empty(), "ships don't fit: {:?}", problems);
+        board.cells = cells;
+        board.ships = ships.clone();
+        board.hits = vec![0; ships.ships.len()];
+        board
+    }
+
+    /// Fires at (`x`, `y`) outside of a game, rejecting cells fired at
+    /// before, and returns every cell whose state changed.
+    pub fn fire_at(
+        &mut self,
+        x: u8,
+        y: u8,
+    ) -> Result<SmallVec<[(Position, AttackStatus); MAX_EFFECT_CELLS]>, Message> {
+        let target = self
+            .coord(x, y)
+            .ok_or_else(|| Message::new(MessageId::AttackOutOfBounds).arg(x).arg(y))?;
+        self.fire(target)
+            .map(|fired| fired.cells)
+            .ok_or_else(|| Message::new(MessageId::CellAlreadyShot).arg(x).arg(y))
+    }
+
+    /// `None` if the cell was fired at before.
+    fn fire(&mut self, target: Coord) -> Option<Fired> {
+        let mut cells = SmallVec::new();
+        let mut hit = None;
+        let status = match self[target] {
+            Cell::Alive(ship) => {
+                hit = Some(self.ships.ships[ship].clone());
+                self.hits[ship] += 1;
+                if self.hits[ship] >= self.ships.ships[ship].hp {
+                    let sunk = self.ships.ships[ship]
+                        .coords(self.size)
+                        .expect("placed ships are on the board");
+                    for &coord in sunk.iter() {
+                        self[coord] = Cell::Killed;
+                        cells.push((coord.position(), AttackStatus::Killed));
+                    }
+                    for &coord in Coord::around(&sunk).iter() {
+                        if matches!(self[coord], Cell::Empty) {
+                            self[coord] = Cell::Miss;
+                            cells.push((coord.position(), AttackStatus::Miss));
+                        }
+                    }
+                    AttackStatus::Killed
+                } else {
+                    self[target] = Cell::Shot;
+                    cells.push((target.position(), AttackStatus::Shot));
+                    AttackStatus::Shot
+                }
+            }
+            Cell::Miss | Cell::Shot | Cell::Killed => return None,
+            Cell::Empty => {
+                self[target] = Cell::Miss;
+                cells.push((target.position(), AttackStatus::Miss));
+                AttackStatus::Miss
+            }
+        };
+        Some(Fired { status, hit, cells })
+    }
+
+    /// Row by row, one character per cell, see `Game::board_rows`.
+    pub fn rows(&self, hide_ships: bool) -> Vec<String> {
+        self.cells
+            .chunks(self.size)
+            .map(|row| {
+                row.iter()
+                    .map(|cell| match cell {
+                        Cell::Empty => '.',
+                        Cell::Alive(_) if hide_ships => '.',
+                        Cell::Alive(_) => 'S',
+                        Cell::Miss => 'o',
+                        Cell::Shot => 'x',
+                        Cell::Killed => '#',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Whether every ship was sunk.
+    pub fn is_cleared(&self) -> bool {
+        self.afloat().is_empty()
+    }
+
+    /// Checks a board read from a snapshot: its ships are on it without
+    /// overlapping, hits don't exceed ship lengths and every cell agrees
+    /// with the ship under it, so firing at it can't index past its fleet.
+    fn check(&self) -> Result<(), String> {
+        let mut owners = vec![None; self.cells.len()];
+        for (i, ship) in self.ships.ships.iter().enumerate() {
+            let coords = ship.coords(self.size).ok_or("Ship off the board")?;
+            for coord in coords.iter() {
+                let owner = &mut owners[coord.index()];
+                if owner.is_some() {
+                    return Err("Ships overlap".to_owned());
+                }
+                *owner = Some(i);
+            }
+            if self.hits[i] > ship.hp {
+                return Err("Ship hit more often than it has cells".to_owned());
+            }
+        }
+        for (cell, owner) in self.cells.iter().zip(&owners) {
+            let matches = match cell {
+                Cell::Empty | Cell::Miss => owner.is_none(),
+                Cell::Alive(ship) => *owner == Some(*ship),
+                Cell::Shot | Cell::Killed => owner.is_some(),
+            };
+            if !matches {
+                return Err("Cells don't match the ships".to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn afloat(&self) -> SmallVec<[&Ship; MAX_FLEET_SIZE]> {
+        self.ships
+            .ships
+            .iter()
+            .zip(&self.hits)
+            .filter(|(ship, hits)| **hits < ship.hp)
+            .map(|(ship, _)| ship)
+            .collect()
+    }
+}
+
+impl Index<Coord> for Board {
+    type Output = Cell;
+
+    fn index(&self, coord: Coord) -> &Cell {
+        &self.cells[coord.index()]
+    }
+}
+
+impl IndexMut<Coord> for Board {
+    fn index_mut(&mut self, coord: Coord) -> &mut Cell {
+        &mut self.cells[coord.index()]
+    }
+}
+
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                match self.cells[x + y * self.size] {
+                    Cell::Empty => write!(f, "-")?,
+                    Cell::Alive(v) => write!(f, "{}", v)?,
+                    Cell::Miss => write!(f, "x")?,
+                    Cell::Shot => write!(f, "+")?,
+                    Cell::Killed => write!(f, "D")?,
+                };
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cell {
+    /// Water not fired at yet.
+    Empty,
+    /// Index of the ship in the board's fleet.
+    Alive(usize),
+    Miss,
+    Shot,
+    Killed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    Win,
+    Timeout,
+    Aborted,
+    /// Finished by an operator.
+    Forced,
+    /// Ended by the server after it failed while handling the game.
+    Crashed,
+    /// Thrown away by an operator, without a result.
+    Deleted,
+    /// Lost by a player who left for good: their reconnect grace ran out,
+    /// they were kicked or they deleted their account.
+    Forfeit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+#[serde(rename_all = "snake_case")]
+pub enum GameStatus {
+    Waiting,
+    PlacingShips,
+    Started,
+    Finished,
+}
+
+/// A change of phase, see `Game::apply`.
+#[derive(Debug)]
+pub enum Transition {
+    /// A second player takes the open seat.
+    Join(UserId),
+    /// A player leaves before the game started.
+    Leave(UserId),
+    /// Both fleets are placed.
+    Start,
+    /// A game of the series was decided and the series goes on.
+    NextRound,
+    Finish,
+}
+
+#[derive(Debug)]
+pub struct IllegalTransition {
+    pub from: GameStatus,
+    pub transition: Transition,
+}
+
+impl fmt::Display for IllegalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} is not allowed while {:?}",
+            self.transition, self.from
+        )
+    }
+}
+
+/// Schema version of `GameSnapshot`, raised whenever a field changes meaning
+/// or goes away. Snapshots of another version are refused.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Everything needed to rebuild a game, for storing games across restarts.
+/// Instants are kept as times elapsed when the snapshot was taken, and the
+/// random generator as its seed and position in the stream. Unlike `sync`,
+/// it holds both fleets and the seed, so it must not reach players before
+/// the game is over. Sets of players list them in player order, so equal
+/// games give equal snapshots.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GameSnapshot {
+    version: u32,
+    id: GameId,
+    status: GameStatus,
+    player1: Option<UserId>,
+    player2: Option<UserId>,
+    settings: RoomSettings,
+    // As strings: JavaScript numbers can't hold every u64.
+    seed: String,
+    rng_word_pos: String,
+    is_p1_turn: bool,
+    shots_left: usize,
+    boards: [Board; 2],
+    scanned: Vec<UserId>,
+    ships_submitted: Vec<UserId>,
+    score: [u8; 2],
+    p1_started: bool,
+    clocks_ms: [u64; 2],
+    turn_elapsed_ms: u64,
+    pause_requests: Vec<UserId>,
+    paused_for_ms: Option<u64>,
+    abort_requested_by: Option<UserId>,
+    elapsed_ms: Option<u64>,
+    shots: Vec<Shot>,
+}
+
+impl From<&Game> for GameSnapshot {
+    fn from(game: &Game) -> Self {
+        let ms = |duration: Duration| duration.as_millis() as u64;
+        Self {
+            version: SNAPSHOT_VERSION,
+            id: game.id.clone(),
+            status: game.status,
+            player1: game.player1.clone(),
+            player2: game.player2.clone(),
+            settings: game.settings.clone(),
+            seed: game.seed.to_string(),
+            rng_word_pos: game.rng.get_word_pos().to_string(),
+            is_p1_turn: game.is_p1_turn,
+            shots_left: game.shots_left,
+            boards: [game.p1_board.clone(), game.p2_board.clone()],
+            scanned: game.in_player_order(&game.scanned),
+            ships_submitted: game.in_player_order(&game.ships_submitted),
+            score: game.score,
+            p1_started: game.p1_started,
+            clocks_ms: game.clocks.map(ms),
+            turn_elapsed_ms: ms(clock::since(game.turn_started)),
+            pause_requests: game.in_player_order(&game.pause_requests),
+            paused_for_ms: game.paused_at.map(|at| ms(clock::since(at))),
+            abort_requested_by: game.abort_requested_by.clone(),
+            elapsed_ms: game.started_at.map(|at| ms(clock::since(at))),
+            shots: game.shots.clone(),
+        }
+    }
+}
+
+impl TryFrom<GameSnapshot> for Game {
+    type Error = String;
+
+    fn try_from(snapshot: GameSnapshot) -> Result<Self, String> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Snapshot version {} is not supported, expected {}",
+                snapshot.version, SNAPSHOT_VERSION
+            ));
+        }
+        let rules = rules::ruleset(&snapshot.settings.ruleset)
+            .ok_or_else(|| format!("Unknown ruleset {}", snapshot.settings.ruleset))?;
+        let size = snapshot.settings.board_size;
+        if !(MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(&size) {
+            return Err(format!("Board size {} is out of range", size));
+        }
+        for board in snapshot.boards.iter() {
+            if board.size != size
+                || board.cells.len() != size * size
+                || board.hits.len() != board.ships.ships.len()
+            {
+                return Err("Boards don't match the room settings".to_owned());
+            }
+            board.check()?;
+        }
+        let seed = snapshot
+            .seed
+            .parse::<u64>()
+            .map_err(|err| err.to_string())?;
+        let word_pos = snapshot
+            .rng_word_pos
+            .parse::<u128>()
+            .map_err(|err| err.to_string())?;
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_word_pos(word_pos);
+        let ago = |ms: u64| {
+            let now = clock::now();
+            now.checked_sub(Duration::from_millis(ms)).unwrap_or(now)
+        };
+        let [p1_board, p2_board] = snapshot.boards;
+        Ok(Self {
+            id: snapshot.id,
+            status: snapshot.status,
+            player1: snapshot.player1,
+            player2: snapshot.player2,
+            settings: snapshot.settings,
+            seed,
+            rng,
+            rules,
+            is_p1_turn: snapshot.is_p1_turn,
+            shots_left: snapshot.shots_left,
+            p1_board,
+            p2_board,
+            scanned: snapshot.scanned.into_iter().collect(),
+            ships_submitted: snapshot.ships_submitted.into_iter().collect(),
+            score: snapshot.score,
+            p1_started: snapshot.p1_started,
+            clocks: snapshot.clocks_ms.map(Duration::from_millis),
+            turn_started: ago(snapshot.turn_elapsed_ms),
+            pause_requests: snapshot.pause_requests.into_iter().collect(),
+            paused_at: snapshot.paused_for_ms.map(ago),
+            abort_requested_by: snapshot.abort_requested_by,
+            started_at: snapshot.elapsed_ms.map(ago),
+            shots: snapshot.shots,
+        })
+    }
+}
+
+impl Serialize for Game {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        GameSnapshot::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Game {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        GameSnapshot::deserialize(deserializer)?
+            .try_into()
+            .map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,350 @@
+//! GraphQL query API at `POST /graphql`, in builds with the `graphql`
+//! feature, for integrations that want more than the fixed exports.
+//!
+//! It answers queries over what the server keeps: the connected players in
+//! leaderboard order, the games running or waiting for a player and the
+//! shared replays, each by id or name and as filtered lists. There is no
+//! storage behind the server, so there is nothing older to query, and no
+//! tournaments to query either. Requests authenticate like the exports,
+//! with `EXPORT_TOKEN` or the admin token.
+//!
+//! Lists are Relay connections paged with `first`/`after` or
+//! `last`/`before`. Cursors are positions in the filtered list, so a page
+//! may skip or repeat an entry when the list changed in between.
+//!
+//! ```text
+//! { players(first: 10, nameContains: "al") { edges { node { name rank wins } } } }
+//! ```
+
+use std::sync::PoisonError;
+
+use async_graphql::{
+    connection::{Connection, Edge},
+    Context, EmptyMutation, EmptySubscription, Object, OutputType, Result, Schema, SimpleObject,
+};
+use axum::{extract::State as AxumState, middleware, routing::post, Router};
+use serde_json::json;
+
+use crate::{
+    export,
+    game::{Game as GameEntry, GameStatus},
+    http::{self, Authorize, Response},
+    presence::Status,
+    stats::RecentGame,
+    ServerState, State, User,
+};
+
+/// Entries of a page when the query doesn't say, and the most it may ask.
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+const MAX_DEPTH: usize = 10;
+const MAX_COMPLEXITY: usize = 1000;
+
+pub type ApiSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+/// The schema the endpoint serves. Requests need the `ServerState` as
+/// their data.
+pub fn schema() -> ApiSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// The endpoint, merged into the HTTP router.
+pub(crate) fn router() -> Router<ServerState> {
+    let schema = schema();
+    Router::new()
+        .route(
+            "/graphql",
+            post(move |state, body| execute(schema.clone(), state, body)),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            export::is_authorized as Authorize,
+            http::require,
+        ))
+}
+
+async fn execute(
+    schema: ApiSchema,
+    AxumState(state): AxumState<ServerState>,
+    body: String,
+) -> Response {
+    let request = match serde_json::from_str::<async_graphql::Request>(&body) {
+        Ok(request) => request,
+        Err(err) => return Response::error(400, &err.to_string()),
+    };
+    let response = schema.execute(request.data(state)).await;
+    Response::json(200, json!(response))
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// A connected player by name.
+    async fn player(&self, ctx: &Context<'_>, name: String) -> Option<Player> {
+        read(ctx, |state| {
+            players(state).find(|player| player.name == name)
+        })
+    }
+
+    /// Connected players in leaderboard order, one-off bots left out.
+    #[allow(clippy::too_many_arguments)]
+    async fn players(
+        &self,
+        ctx: &Context<'_>,
+        name_contains: Option<String>,
+        bot: Option<bool>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<usize, Player>> {
+        let players = read(ctx, |state| {
+            players(state)
+                .filter(|player| {
+                    name_contains
+                        .as_ref()
+                        .is_none_or(|part| player.name.contains(part.as_str()))
+                })
+                .filter(|player| bot.is_none_or(|bot| player.bot == bot))
+                .collect()
+        });
+        page(players, after, before, first, last).await
+    }
+
+    /// A game or open room by id.
+    async fn game(&self, ctx: &Context<'_>, id: String) -> Option<Game> {
+        read(ctx, |state| {
+            state.games.get(&id).map(|game| Game::new(state, game))
+        })
+    }
+
+    /// Games and open rooms, by id.
+    #[allow(clippy::too_many_arguments)]
+    async fn games(
+        &self,
+        ctx: &Context<'_>,
+        status: Option<GameStatus>,
+        ruleset: Option<String>,
+        player: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<usize, Game>> {
+        let mut games = read(ctx, |state| {
+            state
+                .games
+                .values()
+                .filter(|game| status.is_none_or(|status| game.status() == status))
+                .filter(|game| {
+                    ruleset
+                        .as_ref()
+                        .is_none_or(|ruleset| &game.settings.ruleset == ruleset)
+                })
+                .map(|game| Game::new(state, game))
+                .filter(|game| {
+                    player
+                        .as_ref()
+                        .is_none_or(|player| game.players.contains(player))
+                })
+                .collect::<Vec<_>>()
+        });
+        games.sort_by(|a, b| a.id.cmp(&b.id));
+        page(games, after, before, first, last).await
+    }
+
+    /// A shared replay by the id `share_replay` handed out.
+    async fn replay(&self, ctx: &Context<'_>, id: String) -> Option<Replay> {
+        read(ctx, |state| {
+            state.shared_replays.watch(&id).map(Replay::new)
+        })
+    }
+
+    /// Shared replays, newest first. Anonymized replays don't match a
+    /// player's name.
+    async fn replays(
+        &self,
+        ctx: &Context<'_>,
+        player: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<usize, Replay>> {
+        let replays = read(ctx, |state| {
+            state
+                .shared_replays
+                .shared()
+                .into_iter()
+                .map(Replay::new)
+                .filter(|replay| {
+                    player
+                        .as_ref()
+                        .is_none_or(|player| replay.players.contains(player))
+                })
+                .collect()
+        });
+        page(replays, after, before, first, last).await
+    }
+}
+
+#[derive(SimpleObject)]
+pub(crate) struct Player {
+    name: String,
+    rank: usize,
+    wins: u32,
+    accuracy: f64,
+    average_shots_to_win: Option<f64>,
+    bot: bool,
+    presence: Status,
+    /// Newest first.
+    recent_games: Vec<RecentGame>,
+}
+
+#[derive(SimpleObject)]
+pub(crate) struct Game {
+    id: String,
+    status: GameStatus,
+    /// Names of the players, the room creator first.
+    players: Vec<String>,
+    ruleset: String,
+    board_size: usize,
+    best_of: u8,
+    /// Code to join the room by while it is open.
+    code: Option<String>,
+}
+
+impl Game {
+    fn new(state: &State, game: &GameEntry) -> Self {
+        let players = game
+            .players()
+            .into_iter()
+            .flatten()
+            .filter_map(|id| state.users.get(&id).map(|user| user.name.clone()))
+            .collect();
+        Self {
+            id: game.id.clone(),
+            status: game.status(),
+            players,
+            ruleset: game.settings.ruleset.clone(),
+            board_size: game.settings.board_size,
+            best_of: game.settings.best_of,
+            code: state.room_codes.code_of(&game.id).map(str::to_owned),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub(crate) struct Replay {
+    id: String,
+    /// Player 1 and player 2, or "Player 1" and "Player 2" when anonymized.
+    players: Vec<String>,
+    /// 1 or 2, none when nobody won.
+    winner: Option<u8>,
+    seed: String,
+    shots: Vec<ReplayShot>,
+}
+
+impl Replay {
+    /// Reads the JSON `watch_replay` sends.
+    fn new(json: serde_json::Value) -> Self {
+        let number = |value: &serde_json::Value| value.as_u64().unwrap_or_default();
+        let shots = json["timeline"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|shot| ReplayShot {
+                round: number(&shot["round"]) as u8,
+                player: number(&shot["player"]) as u8,
+                x: number(&shot["position"]["x"]) as u8,
+                y: number(&shot["position"]["y"]) as u8,
+                status: shot["status"].as_str().unwrap_or_default().to_owned(),
+                hit: shot["hit"].as_bool().unwrap_or_default(),
+                at_ms: number(&shot["atMs"]),
+            })
+            .collect();
+        Self {
+            id: json["replayId"].as_str().unwrap_or_default().to_owned(),
+            players: serde_json::from_value(json["players"].clone()).unwrap_or_default(),
+            winner: json["winner"].as_u64().map(|winner| winner as u8),
+            seed: json["seed"].as_str().unwrap_or_default().to_owned(),
+            shots,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub(crate) struct ReplayShot {
+    round: u8,
+    /// 1 or 2.
+    player: u8,
+    x: u8,
+    y: u8,
+    /// `miss`, `shot` or `killed`, as in the `attack` event.
+    status: String,
+    hit: bool,
+    at_ms: u64,
+}
+
+/// Runs `f` on the state, read-locked only as long as it takes.
+fn read<T>(ctx: &Context<'_>, f: impl FnOnce(&State) -> T) -> T {
+    let state = ctx.data_unchecked::<ServerState>();
+    f(&state.read().unwrap_or_else(PoisonError::into_inner))
+}
+
+fn players(state: &State) -> impl Iterator<Item = Player> + '_ {
+    export::ranked(state)
+        .into_iter()
+        .enumerate()
+        .map(|(i, user): (usize, &User)| Player {
+            name: user.name.clone(),
+            rank: i + 1,
+            wins: user.wins,
+            accuracy: user.stats.accuracy(),
+            average_shots_to_win: user.stats.average_shots_to_win(),
+            bot: user.bot,
+            presence: state.presence_of(user),
+            recent_games: user.recent_games.iter().cloned().collect(),
+        })
+}
+
+/// The page of `items` the arguments ask for, with the positions as
+/// cursors.
+async fn page<T: OutputType>(
+    items: Vec<T>,
+    after: Option<String>,
+    before: Option<String>,
+    first: Option<i32>,
+    last: Option<i32>,
+) -> Result<Connection<usize, T>> {
+    async_graphql::connection::query(
+        after,
+        before,
+        first,
+        last,
+        |after, before, first, last| async move {
+            let mut end = before.unwrap_or(items.len()).min(items.len());
+            let mut start = after.map_or(0, |after| after.saturating_add(1)).min(end);
+            match (first, last) {
+                (_, Some(last)) => start = start.max(end.saturating_sub(last.min(MAX_PAGE_SIZE))),
+                (first, None) => {
+                    end = end.min(start + first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE))
+                }
+            }
+            let mut connection = Connection::new(start > 0, end < items.len());
+            connection.edges.extend(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .skip(start)
+                    .take(end - start)
+                    .map(|(i, item)| Edge::new(i, item)),
+            );
+            Ok::<_, async_graphql::Error>(connection)
+        },
+    )
+    .await
+}
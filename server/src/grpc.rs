@@ -0,0 +1,368 @@
+//! gRPC service for backend integrations, in builds with the `grpc`
+//! feature, so other services can list games, read the leaderboard and
+//! shared replays and make announcements without speaking the player
+//! protocol.
+//!
+//! It is served at `GRPC_ADDR` when that is set and takes the admin token
+//! in an `authorization: Bearer` metadata entry, like the admin API. The
+//! service is described in `proto/integration.proto`; its messages and the
+//! routing below are written out by hand rather than generated, so the
+//! build needs neither `protoc` nor a build script.
+
+use std::{convert::Infallible, future, net::SocketAddr};
+
+use serde_json::json;
+use tonic::{
+    codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError},
+    server::{Grpc, NamedService},
+    transport::Server,
+    Status,
+};
+use tonic_prost::ProstCodec;
+use tracing::{info, warn};
+
+use crate::{admin, export, lock_state, ServerState, State};
+
+const GRPC_ADDR_VAR: &str = "GRPC_ADDR";
+const SERVICE_NAME: &str = "battleships.Integration";
+
+/// Starts serving when `GRPC_ADDR` is set.
+pub fn spawn(state: &ServerState) {
+    let addr = match std::env::var(GRPC_ADDR_VAR) {
+        Ok(addr) if !addr.is_empty() => addr,
+        _ => return,
+    };
+    let Ok(addr) = addr.parse::<SocketAddr>() else {
+        warn!("{} must be an address like 127.0.0.1:3002", GRPC_ADDR_VAR);
+        return;
+    };
+    let service = Integration::new(state.clone());
+    info!("gRPC listening on: {}", addr);
+    tokio::spawn(async move {
+        if let Err(err) = Server::builder().add_service(service).serve(addr).await {
+            warn!("gRPC server stopped: {}", err);
+        }
+    });
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListGamesRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListGamesResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub games: Vec<GameSummary>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GameSummary {
+    #[prost(string, tag = "1")]
+    pub game_id: String,
+    #[prost(string, tag = "2")]
+    pub status: String,
+    #[prost(message, repeated, tag = "3")]
+    pub players: Vec<Player>,
+    #[prost(string, tag = "4")]
+    pub ruleset: String,
+    #[prost(uint32, tag = "5")]
+    pub board_size: u32,
+    #[prost(uint32, tag = "6")]
+    pub best_of: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Player {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// Empty when the player left.
+    #[prost(string, tag = "2")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetLeaderboardRequest {
+    /// Standings from the top, all of them when 0.
+    #[prost(uint32, tag = "1")]
+    pub limit: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetLeaderboardResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub standings: Vec<Standing>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Standing {
+    #[prost(uint32, tag = "1")]
+    pub rank: u32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(uint32, tag = "3")]
+    pub wins: u32,
+    #[prost(double, tag = "4")]
+    pub accuracy: f64,
+    #[prost(double, optional, tag = "5")]
+    pub average_shots_to_win: Option<f64>,
+    #[prost(bool, tag = "6")]
+    pub bot: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetReplayRequest {
+    #[prost(string, tag = "1")]
+    pub replay_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Replay {
+    #[prost(string, tag = "1")]
+    pub replay_id: String,
+    #[prost(string, repeated, tag = "2")]
+    pub players: Vec<String>,
+    /// 1 or 2, 0 when nobody won.
+    #[prost(uint32, tag = "3")]
+    pub winner: u32,
+    #[prost(string, tag = "4")]
+    pub seed: String,
+    #[prost(string, tag = "5")]
+    pub settings_json: String,
+    #[prost(message, repeated, tag = "6")]
+    pub shots: Vec<ReplayShot>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ReplayShot {
+    #[prost(uint32, tag = "1")]
+    pub round: u32,
+    #[prost(uint32, tag = "2")]
+    pub player: u32,
+    #[prost(uint32, tag = "3")]
+    pub x: u32,
+    #[prost(uint32, tag = "4")]
+    pub y: u32,
+    #[prost(string, tag = "5")]
+    pub status: String,
+    #[prost(bool, tag = "6")]
+    pub hit: bool,
+    #[prost(uint64, tag = "7")]
+    pub at_ms: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AnnounceRequest {
+    #[prost(string, tag = "1")]
+    pub text: String,
+    /// `info` when empty.
+    #[prost(string, tag = "2")]
+    pub severity: String,
+    /// Never expires when 0.
+    #[prost(uint64, tag = "3")]
+    pub expires_in_seconds: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AnnounceResponse {
+    #[prost(uint32, tag = "1")]
+    pub id: u32,
+}
+
+/// The service, routing each method to its handler.
+#[derive(Clone)]
+pub struct Integration {
+    state: ServerState,
+}
+
+impl Integration {
+    pub fn new(state: ServerState) -> Self {
+        Self { state }
+    }
+}
+
+impl NamedService for Integration {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+impl<B> Service<http::Request<B>> for Integration
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let authorization = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if !admin::is_authorized(authorization) {
+            let status = Status::unauthenticated("Missing or wrong admin token");
+            return Box::pin(future::ready(Ok(status.into_http())));
+        }
+        let state = self.state.clone();
+        match request.uri().path() {
+            "/battleships.Integration/ListGames" => unary(request, move |_: ListGamesRequest| {
+                Ok(list_games(&lock_state(&state)))
+            }),
+            "/battleships.Integration/GetLeaderboard" => {
+                unary(request, move |request: GetLeaderboardRequest| {
+                    Ok(leaderboard(&lock_state(&state), request.limit))
+                })
+            }
+            "/battleships.Integration/GetReplay" => {
+                unary(request, move |request: GetReplayRequest| {
+                    replay(&lock_state(&state), &request.replay_id)
+                })
+            }
+            "/battleships.Integration/Announce" => {
+                unary(request, move |request: AnnounceRequest| {
+                    announce(state.clone(), request)
+                })
+            }
+            _ => Box::pin(future::ready(Ok(
+                Status::unimplemented("Unknown method").into_http()
+            ))),
+        }
+    }
+}
+
+/// Answers a unary call with `handler`.
+fn unary<B, Request, Response, F>(
+    request: http::Request<B>,
+    handler: F,
+) -> BoxFuture<http::Response<tonic::body::Body>, Infallible>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    Request: prost::Message + Default + Send + 'static,
+    Response: prost::Message + Send + 'static,
+    F: FnMut(Request) -> Result<Response, Status> + Send + 'static,
+{
+    Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::<Response, Request>::default());
+        Ok(grpc.unary(Handler(handler), request).await)
+    })
+}
+
+/// A handler as the per-method service `Grpc::unary` calls.
+struct Handler<F>(F);
+
+impl<Request, Response, F> Service<tonic::Request<Request>> for Handler<F>
+where
+    F: FnMut(Request) -> Result<Response, Status>,
+{
+    type Response = tonic::Response<Response>;
+    type Error = Status;
+    type Future = future::Ready<Result<Self::Response, Status>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: tonic::Request<Request>) -> Self::Future {
+        future::ready((self.0)(request.into_inner()).map(tonic::Response::new))
+    }
+}
+
+fn list_games(state: &State) -> ListGamesResponse {
+    let games = state
+        .games
+        .values()
+        .map(|game| GameSummary {
+            game_id: game.id.clone(),
+            status: json!(game.status()).as_str().unwrap_or_default().to_owned(),
+            players: game
+                .players()
+                .into_iter()
+                .flatten()
+                .map(|id| Player {
+                    name: state
+                        .users
+                        .get(&id)
+                        .map(|user| user.name.clone())
+                        .unwrap_or_default(),
+                    id,
+                })
+                .collect(),
+            ruleset: game.settings.ruleset.clone(),
+            board_size: game.settings.board_size as u32,
+            best_of: game.settings.best_of.into(),
+        })
+        .collect();
+    ListGamesResponse { games }
+}
+
+fn leaderboard(state: &State, limit: u32) -> GetLeaderboardResponse {
+    let limit = match limit {
+        0 => usize::MAX,
+        limit => limit as usize,
+    };
+    let standings = export::ranked(state)
+        .into_iter()
+        .take(limit)
+        .enumerate()
+        .map(|(i, user)| Standing {
+            rank: i as u32 + 1,
+            name: user.name.clone(),
+            wins: user.wins,
+            accuracy: user.stats.accuracy(),
+            average_shots_to_win: user.stats.average_shots_to_win(),
+            bot: user.bot,
+        })
+        .collect();
+    GetLeaderboardResponse { standings }
+}
+
+/// A shared replay, read back from the JSON `watch_replay` sends.
+fn replay(state: &State, replay_id: &str) -> Result<Replay, Status> {
+    let json = state
+        .shared_replays
+        .watch(replay_id)
+        .ok_or_else(|| Status::not_found("Replay not found"))?;
+    let number = |value: &serde_json::Value| value.as_u64().unwrap_or_default();
+    let shots = json["timeline"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|shot| ReplayShot {
+            round: number(&shot["round"]) as u32,
+            player: number(&shot["player"]) as u32,
+            x: number(&shot["position"]["x"]) as u32,
+            y: number(&shot["position"]["y"]) as u32,
+            status: shot["status"].as_str().unwrap_or_default().to_owned(),
+            hit: shot["hit"].as_bool().unwrap_or_default(),
+            at_ms: number(&shot["atMs"]),
+        })
+        .collect();
+    Ok(Replay {
+        replay_id: replay_id.to_owned(),
+        players: serde_json::from_value(json["players"].clone()).unwrap_or_default(),
+        winner: number(&json["winner"]) as u32,
+        seed: json["seed"].as_str().unwrap_or_default().to_owned(),
+        settings_json: json["settings"].to_string(),
+        shots,
+    })
+}
+
+/// Runs the `announce` admin command, so it is audited and broadcast the
+/// same way.
+fn announce(mut state: ServerState, request: AnnounceRequest) -> Result<AnnounceResponse, Status> {
+    let mut data = json!({ "text": request.text });
+    if !request.severity.is_empty() {
+        data["severity"] = json!(request.severity);
+    }
+    if request.expires_in_seconds > 0 {
+        data["expiresInSeconds"] = json!(request.expires_in_seconds);
+    }
+    let announcement =
+        admin::run_command("announce", &data, &mut state).map_err(Status::invalid_argument)?;
+    Ok(AnnounceResponse {
+        id: announcement["id"].as_u64().unwrap_or_default() as u32,
+    })
+}
@@ -0,0 +1,305 @@
+//! HTTP server running next to the WebSocket server, built on axum.
+//!
+//! Besides the operator endpoints and the REST API of `admin` and `export`,
+//! it serves `GET /games/{id}/events`, a server-sent events stream of a
+//! game's public events for web pages and stream overlays, which stays open
+//! until the game is closed or the viewer goes away.
+
+use std::{
+    collections::HashMap, convert::Infallible, net::SocketAddr, sync::PoisonError, time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Path, Request, State},
+    http::{
+        header::{ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, CONTENT_TYPE},
+        StatusCode,
+    },
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::get,
+    Router,
+};
+use futures_util::{stream, StreamExt};
+use serde_json::json;
+use tokio::net::TcpListener;
+use tracing::{error_span, info, warn, Instrument};
+
+use crate::{
+    admin, clock, export,
+    game::GameId,
+    lock_state,
+    messages::{Message, MessageId},
+    metrics::METRICS,
+    ServerState, PROTOCOL_VERSION,
+};
+
+/// The tick loop is stuck when it missed this many ticks in a row.
+const MAX_MISSED_TICKS: u32 = 25;
+/// Comments sent to idle event streams, so proxies keep them open and
+/// viewers that went away are noticed.
+const STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Checks the `Authorization` header of a request, see `require`.
+pub type Authorize = fn(Option<&str>) -> bool;
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    pub fn error(status: u16, text: &str) -> Self {
+        Self::json(status, json!({ "errorText": text }))
+    }
+
+    pub fn csv(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/csv; charset=utf-8",
+            body,
+        }
+    }
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> axum::response::Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, [(CONTENT_TYPE, self.content_type)], self.body).into_response()
+    }
+}
+
+pub async fn serve(addr: String, state: ServerState) {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .expect("Failed to bind HTTP listener");
+    info!("HTTP listening on: {}", addr);
+
+    let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(err) = axum::serve(listener, app).await {
+        warn!("HTTP server stopped: {}", err);
+    }
+}
+
+fn router(state: ServerState) -> Router {
+    let router = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status))
+        .route("/metrics", get(metrics))
+        .route("/debug/state", get(debug_state))
+        .route("/games/{game_id}/events", get(stream_events))
+        .route("/api/leaderboard", get(leaderboard))
+        .route("/api/players/{name}", get(profile))
+        .nest("/api", admin::router())
+        .nest("/export", export::router());
+    #[cfg(feature = "graphql")]
+    let router = router.merge(crate::graphql::router());
+    router
+        .fallback(|| async { Response::error(404, "Not found") })
+        .layer(middleware::from_fn(log_request))
+        .with_state(state)
+}
+
+/// Logs every request and runs it in a span naming the peer.
+async fn log_request(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let span = error_span!("peer", peer = %peer);
+    span.in_scope(|| info!("HTTP {} {}", request.method(), request.uri().path()));
+    next.run(request).instrument(span).await
+}
+
+/// Answers 401 unless `authorized` accepts the request's `Authorization`
+/// header, for `middleware::from_fn_with_state`.
+pub(crate) async fn require(
+    State(authorized): State<Authorize>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let authorization = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !authorized(authorization) {
+        return Response::error(401, "Unauthorized").into_response();
+    }
+    next.run(request).await
+}
+
+async fn metrics(State(state): State<ServerState>) -> Response {
+    let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+    Response {
+        status: 200,
+        content_type: "text/plain; version=0.0.4",
+        body: METRICS.render(state_lock.events.len(), &state_lock.collection_sizes()),
+    }
+}
+
+async fn leaderboard(State(state): State<ServerState>) -> Response {
+    let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+    Response::json(200, export::leaderboard_json(&state_lock))
+}
+
+async fn profile(State(state): State<ServerState>, Path(name): Path<String>) -> Response {
+    let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+    match export::profile_json(&state_lock, &name) {
+        Some(profile) => Response::json(200, profile),
+        None => Response::error(404, "Player not found"),
+    }
+}
+
+/// Streams a game's public events as server-sent events, starting with the
+/// current round so far. Each event is named after its type and carries
+/// its data.
+async fn stream_events(
+    State(state): State<ServerState>,
+    Path(game_id): Path<GameId>,
+) -> axum::response::Response {
+    let opened = lock_state(&state).open_stream(&game_id);
+    let (history, events) = match opened {
+        Ok(opened) => opened,
+        Err(err) => {
+            let status = match err {
+                MessageId::GameNotFound => 404,
+                MessageId::RoomFull => 503,
+                _ => 409,
+            };
+            let text = Message::from(err).to_string();
+            return Response::error(status, &text).into_response();
+        }
+    };
+    info!("Event stream of game '{}' opened", game_id);
+    let closed = StreamClosed(game_id);
+    let events = stream::iter(history).chain(events).map(move |json| {
+        // Goes with the stream, to log its end.
+        let _closed = &closed;
+        Ok::<_, Infallible>(sse_event(&json))
+    });
+    let keep_alive = KeepAlive::new()
+        .interval(STREAM_KEEPALIVE)
+        .text("keepalive");
+    (
+        [(ACCESS_CONTROL_ALLOW_ORIGIN, "*")],
+        Sse::new(events).keep_alive(keep_alive),
+    )
+        .into_response()
+}
+
+/// Logs the end of an event stream, whichever side ends it.
+struct StreamClosed(GameId);
+
+impl Drop for StreamClosed {
+    fn drop(&mut self) {
+        info!("Event stream of game '{}' closed", self.0);
+    }
+}
+
+/// An encoded event as an SSE event. Event data never holds line breaks,
+/// JSON escapes them.
+fn sse_event(json: &str) -> Event {
+    let event: serde_json::Value = serde_json::from_str(json).unwrap_or_default();
+    Event::default()
+        .event(event["type"].as_str().unwrap_or("message"))
+        .data(event["data"].as_str().unwrap_or("null"))
+}
+
+/// Liveness: the tick loop keeps running, if there is one, and the state
+/// lock is usable.
+async fn healthz(State(state): State<ServerState>) -> Response {
+    let (last_tick, tick_interval) = match state.read() {
+        Ok(state_lock) => (state_lock.last_tick, state_lock.tick_interval),
+        Err(_) => return Response::error(503, "State lock is poisoned"),
+    };
+    if tick_interval.is_some_and(|interval| clock::since(last_tick) > interval * MAX_MISSED_TICKS) {
+        return Response::error(503, "Event loop is not ticking");
+    }
+    Response::json(200, json!({ "status": "ok" }))
+}
+
+/// Readiness: alive and not in maintenance mode. The server keeps all state
+/// in memory, so there is no storage to check yet.
+async fn readyz(State(state): State<ServerState>) -> Response {
+    let health = healthz(State(state.clone())).await;
+    if health.status != 200 {
+        return health;
+    }
+    if state
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .maintenance
+    {
+        return Response::error(503, "Maintenance mode");
+    }
+    Response::json(200, json!({ "status": "ready" }))
+}
+
+/// Public server population, safe to poll from community sites.
+async fn status(State(state): State<ServerState>) -> Response {
+    let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+    let mut json = state_lock.population_json();
+    json["players"] = state_lock.users.len().into();
+    json["uptimeSeconds"] = clock::since(state_lock.started_at).as_secs().into();
+    json["version"] = env!("CARGO_PKG_VERSION").into();
+    json["protocolVersion"] = PROTOCOL_VERSION.into();
+    json["features"] = json!(state_lock.features);
+    Response::json(200, json)
+}
+
+/// Everything the server holds, for inspecting a development server.
+/// Boards are the ASCII renderings from the debug log, one string per row.
+async fn debug_state(State(state): State<ServerState>) -> Response {
+    let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+    if !state_lock.debug {
+        return Response::error(404, "Not found");
+    }
+    let games = state_lock
+        .games
+        .values()
+        .map(|game| {
+            let boards = game
+                .boards()
+                .map(|board| board.lines().map(str::to_owned).collect::<Vec<_>>());
+            json!({
+                "gameId": game.id,
+                "status": game.status(),
+                "players": game.players(),
+                "settings": game.settings,
+                "score": game.score,
+                "seed": game.seed.to_string(),
+                "currentPlayer": game.current_player(),
+                "boards": boards,
+            })
+        })
+        .collect::<Vec<_>>();
+    Response::json(
+        200,
+        json!({
+            "users": admin::users_json(&state_lock),
+            "userIds": state_lock
+                .user_ids
+                .iter()
+                .map(|(addr, user_id)| (addr.to_string(), user_id))
+                .collect::<HashMap<_, _>>(),
+            "games": games,
+            "friends": format!("{:?}", state_lock.friends),
+            "queuedEvents": state_lock.events.len(),
+            "maintenance": state_lock.maintenance,
+            "uptimeSeconds": clock::since(state_lock.started_at).as_secs(),
+        }),
+    )
+}
@@ -1,364 +1,3716 @@
-use queue::Queue;
-use serde_json::json;
-use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, RwLock},
-};
-use uuid::Uuid;
-
-mod game;
-mod ships;
-
-use game::{Game, GameId, GameStatus};
-use ships::Ships;
-
-use serde::Serialize;
-
-#[derive(Debug)]
-enum ClientEvent {
-    Player(PlayerEvent),
-    Room(RoomEvent),
-    Game(GameEvent),
-}
-
-#[derive(Debug)]
-enum PlayerEvent {
-    Reg(Registration),
-}
-
-#[derive(Debug)]
-struct Registration {
-    username: String,
-}
-
-#[derive(Debug)]
-enum RoomEvent {
-    Create,
-    AddUser(String),
-}
-
-#[derive(Debug)]
-enum GameEvent {
-    AddShips(Ships),
-    Start,
-    Attack,
-    RandomAttack,
-    Turn,
-}
-struct Error {
-    text: String,
-}
-
-pub type ServerState = Arc<RwLock<State>>;
-
-type UserId = String;
-
-#[derive(Debug)]
-pub struct State {
-    pub events: Queue<ServerEvent>,
-    user_ids: HashMap<SocketAddr, UserId>,
-    users: HashMap<UserId, User>,
-    games: HashMap<GameId, Game>,
-}
-
-impl Default for State {
-    fn default() -> Self {
-        Self {
-            events: Queue::new(),
-            user_ids: HashMap::new(),
-            users: HashMap::new(),
-            games: HashMap::new(),
-        }
-    }
-}
-
-impl State {
-    fn add_user(&mut self, user: &User) -> User {
-        let user_id = self.user_ids.entry(user.addr).or_insert(user.id.clone());
-        let user = self
-            .users
-            .entry(user_id.to_string())
-            .or_insert(user.clone())
-            .clone();
-        self.add_update_winners_event();
-        self.add_update_room_event();
-        user
-    }
-    fn get_user_by_addr(&self, addr: &SocketAddr) -> Option<&User> {
-        match self.user_ids.get(addr) {
-            Some(user_id) => self.get_user(user_id),
-            None => None,
-        }
-    }
-    fn remove_user_by_addr(&mut self, addr: &SocketAddr) -> Option<User> {
-        match self.user_ids.remove(addr) {
-            Some(user_id) => self.users.remove(&user_id),
-            None => None,
-        }
-    }
-    fn get_user(&self, user_id: &String) -> Option<&User> {
-        self.users.get(user_id)
-    }
-    fn update_user(&mut self, user: &User) {
-        self.users.insert(user.id.clone(), user.clone());
-    }
-    fn add_event(&mut self, event: &ServerEvent) {
-        let _ = self.events.queue(event.clone());
-    }
-
-    fn add_update_winners_event(&mut self) {
-        let json = serde_json::Value::Array(
-            self.users
-                .values()
-                .into_iter()
-                .map(|user| {
-                    json!({
-                        "name": user.name,
-                        "wins": user.wins,
-                    })
-                })
-                .collect::<Vec<serde_json::Value>>(),
-        );
-        self.add_event(&ServerEvent::All(create_event_json(
-            json,
-            "update_winners".into(),
-        )));
-    }
-
-    fn add_update_room_event(&mut self) {
-        let json = serde_json::Value::Array(
-            self.games
-                .values()
-                .into_iter()
-                .filter_map(|game| match game.status {
-                    GameStatus::Waiting => Some(json!({
-                        "roomId": game.id,
-                        "roomUsers": serde_json::Value::Array([&game.player1, &game.player2]
-                            .into_iter()
-                            .filter_map(|user_id| {
-                                match user_id {
-                                    Some(user_id) => match self.get_user(&user_id) {
-                                        Some(user) => Some(json!({
-                                            "name": user.name,
-                                            "index": user_id.clone(),
-                                        })),
-                                        None => None
-                                    },
-                                    None => None
-                                }
-                            })
-                            .collect::<Vec<serde_json::Value>>(),
-                        ),
-                    })),
-                    _ => None,
-                })
-                .collect::<Vec<serde_json::Value>>(),
-        );
-        self.add_event(&ServerEvent::All(create_event_json(
-            json,
-            "update_room".into(),
-        )));
-    }
-
-    fn create_game(&mut self, user: &User) {
-        let game_id = Uuid::new_v4();
-        self.join_game(game_id.to_string(), user, true);
-    }
-
-    fn join_game(&mut self, game_id: String, user: &User, is_owner: bool) {
-        if let Some(room) = user.in_room.clone() {
-            if self.games.contains_key(&room) {
-                println!("USER IN ROOM");
-                return;
-            }
-        }
-
-        if is_owner {
-            self.games
-                .insert(game_id.clone(), Game::create(&game_id, user));
-        } else {
-            {
-                let game = self.games.get_mut(&game_id).unwrap();
-                game.player2 = Some(user.id.clone());
-                game.status = GameStatus::PlacingShips;
-            }
-
-            if let Some(game) = self.games.get(&game_id) {
-                let json = json!([{
-                    "idGame": game.id.clone(),
-                    "idPlayer": game.player1.clone(),
-                }, {
-                    "idGame": game.id.clone(),
-                    "idPlayer": game.player2.clone(),
-                }]);
-
-                self.add_event(&ServerEvent::All(create_event_json(
-                    json,
-                    "create_game".into(),
-                )));
-            }
-        }
-        let user = User {
-            in_room: Some(game_id.to_string()),
-            ..user.clone()
-        };
-        self.update_user(&user);
-        self.add_update_room_event();
-    }
-
-    fn add_ships_to_game(&mut self, user: &User, ships: Ships) {
-        let game = self.games.get_mut(&user.in_room.clone().unwrap()).unwrap();
-        game.add_ships(&ships, &user.id);
-    }
-}
-
-#[derive(Debug, Clone)]
-struct User {
-    id: UserId,
-    name: String,
-    addr: SocketAddr,
-    wins: u32,
-    in_room: Option<GameId>,
-}
-
-#[derive(Serialize)]
-struct ServerEventData {
-    #[serde(rename = "type")]
-    type_str: String,
-    data: String,
-    id: u32,
-}
-
-#[derive(Debug, Clone)]
-pub enum ServerEvent {
-    User(SocketAddr, String),
-    // Game(String),
-    All(String),
-}
-
-pub fn tick(_state: &mut ServerState) {}
-
-pub fn handle_event(addr: &SocketAddr, event_json: &str, state: &mut ServerState) {
-    let json: serde_json::Value =
-        serde_json::from_str(event_json).unwrap_or(serde_json::Value::Null);
-    println!("Received json: {:?}", json);
-    let event = parse_event(json);
-
-    match event {
-        Ok(event) => {
-            println!("Event {:?}", event);
-            let user = {
-                let state_lock = state.read().unwrap();
-                state_lock.get_user_by_addr(addr).cloned()
-            };
-
-            match event {
-                ClientEvent::Player(player_event) => match player_event {
-                    PlayerEvent::Reg(reg) => match user {
-                        Some(_user) => {}
-                        None => {
-                            let uuid = Uuid::new_v4();
-                            let user = User {
-                                id: uuid.to_string(),
-                                name: reg.username.clone(),
-                                addr: addr.clone(),
-                                wins: 0,
-                                in_room: None,
-                            };
-
-                            let mut state_lock = state.write().unwrap();
-                            let user = state_lock.add_user(&user);
-                            state_lock.add_update_room_event();
-
-                            let data = json!({
-                                "name": user.name,
-                                "index": user.id,
-                                "error": false,
-                                "errorText": "",
-                            });
-                            let json = create_event_json(data, "reg".into());
-
-                            state_lock.add_event(&ServerEvent::User(user.addr, json));
-                        }
-                    },
-                },
-                ClientEvent::Room(room_event) => match room_event {
-                    RoomEvent::Create => {
-                        let user = user.unwrap();
-                        state.write().unwrap().create_game(&user);
-                    }
-                    RoomEvent::AddUser(game_id) => {
-                        let user = user.unwrap();
-                        state.write().unwrap().join_game(game_id, &user, false);
-                    }
-                },
-                ClientEvent::Game(game_event) => match game_event {
-                    GameEvent::AddShips(ships) => {
-                        let user = user.unwrap();
-                        state.write().unwrap().add_ships_to_game(&user, ships);
-                    }
-                    _ => {}
-                },
-            }
-        }
-        Err(err) => {
-            eprintln!("{}", err.text)
-        }
-    };
-}
-
-pub fn handle_disconnect(addr: &SocketAddr, state: &mut ServerState) {
-    let mut state_lock = state.write().unwrap();
-    if let Some(user) = state_lock.remove_user_by_addr(&addr) {
-        if let Some(room_id) = user.in_room {
-            state_lock.games.remove(&room_id);
-            state_lock.add_update_room_event();
-            println!("Room '{}' closed - owner left", room_id.to_string());
-        }
-        println!("User '{}' disconnected ({})", user.name, user.addr);
-        state_lock.add_update_winners_event();
-    }
-}
-// fn create_room(User)
-
-fn create_event_json(data: serde_json::Value, event_type: String) -> String {
-    let data = serde_json::to_string(&data).unwrap();
-
-    let json = serde_json::to_string(&ServerEventData {
-        type_str: event_type,
-        data: data,
-        id: 0,
-    })
-    .unwrap();
-
-    json
-}
-
-fn parse_event(json: serde_json::Value) -> Result<ClientEvent, Error> {
-    println!("json <- {:?}", json);
-
-    let event_type: &str = json["type"].as_str().unwrap_or("unknown");
-
-    let data_json: serde_json::Value = serde_json::from_str(json["data"].as_str().unwrap_or(""))
-        .unwrap_or(serde_json::Value::Null);
-    println!("Event Type - {:?}", event_type);
-    println!("Data: {:?}", data_json);
-
-    match event_type {
-        "reg" => Ok(ClientEvent::Player(PlayerEvent::Reg(Registration {
-            username: data_json["name"].as_str().unwrap().to_owned(),
-        }))),
-        "create_room" => Ok(ClientEvent::Room(RoomEvent::Create)),
-        "add_user_to_room" => Ok(ClientEvent::Room(RoomEvent::AddUser(
-            data_json["indexRoom"].as_str().unwrap().to_owned(),
-        ))),
-        "add_ships" => {
-            let ships: Ships = serde_json::from_value(data_json).unwrap();
-            Ok(ClientEvent::Game(GameEvent::AddShips(ships.clone())))
-        }
-        &_ => Err(Error {
-            text: "Unknown event type".to_owned(),
-        }),
-    }
-}
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use queue::Queue;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, PoisonError, RwLock, RwLockWriteGuard},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, error_span, info, warn};
+use uuid::Uuid;
+
+pub mod admin;
+mod announcements;
+mod audit;
+mod bot_api;
+pub mod bot_plugins;
+mod broadcasts;
+pub mod capture;
+pub mod clock;
+pub mod cluster;
+pub mod crash_reports;
+mod deadlines;
+mod deltas;
+pub mod discord;
+mod export;
+mod farming;
+pub mod faults;
+pub mod features;
+mod friends;
+mod game;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod http;
+pub mod limits;
+pub mod logging;
+mod matchmaking;
+mod messages;
+pub mod metrics;
+mod practice;
+mod presence;
+pub mod replay;
+pub mod retention;
+mod room_codes;
+mod rules;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod sessions;
+mod shared_replays;
+mod ships;
+pub mod simulation;
+mod spectators;
+mod stats;
+mod subscriptions;
+
+use announcements::Announcements;
+use audit::AuditLog;
+use bot_plugins::BotStrategy;
+use broadcasts::Broadcasts;
+use cluster::Cluster;
+use deadlines::{Deadline, Deadlines};
+use deltas::{Delta, Deltas, ROOM_DELTAS, TOP_WINNERS, WINNER_DELTAS};
+use farming::{Farming, Rating};
+use features::{Feature, Features};
+use friends::{AccountId, FriendRequestResult, Friends};
+use game::{
+    AttackStatus, FinishReason, Game, GameId, GameStatus, Handicap, RoomSettings, TimeControl,
+    Transition, DEFAULT_BOARD_SIZE, MAX_BEST_OF, MAX_BOARD_SIZE, MAX_CLOCK_SECONDS, MAX_PAUSE,
+    MIN_BOARD_SIZE,
+};
+use limits::{Limits, Throttle, MOVE_WINDOW, ROOM_CREATION_WINDOW};
+use matchmaking::Matchmaking;
+use messages::{Locale, Message, MessageId};
+use metrics::METRICS;
+use practice::PracticeRanges;
+use presence::{Presence, Status};
+use retention::RetentionPolicy;
+use room_codes::RoomCodes;
+use rules::DEFAULT_RULESET;
+use sessions::{Sessions, RECONNECT_GRACE};
+use shared_replays::SharedReplays;
+use ships::{Fleet, Position, Ships, MAX_FLEET_SIZE, MAX_SHIP_LENGTH};
+use spectators::Spectators;
+use stats::{GameTotals, PlayerStats, RecentGame, RecentGames};
+use subscriptions::{Feed, Subscriptions};
+
+use serde::Serialize;
+
+#[derive(Debug)]
+enum ClientEvent {
+    Player(PlayerEvent),
+    Room(RoomEvent),
+    Game(GameEvent),
+    Friend(FriendEvent),
+}
+
+#[derive(Debug)]
+enum PlayerEvent {
+    Reg(Registration),
+    /// Picks up the session of a dropped connection by its token.
+    Reconnect(String),
+    /// Latency probe carrying the client's clock, answered right away.
+    Ping(serde_json::Value),
+    /// Asks for the caller's full current view. Carries the game and the
+    /// checksum the client had when it found itself out of step.
+    Sync(Option<(GameId, String)>),
+    /// Asks for the lobby head count, answered right away.
+    Population,
+    /// Asks for a page of the winners list, answered right away.
+    Winners {
+        offset: usize,
+        limit: usize,
+    },
+    /// Looks for any opponent, see `matchmaking`.
+    Quickplay,
+    CancelSearch,
+    /// Follows a running game, see `spectators`.
+    Spectate(GameId),
+    StopSpectating(GameId),
+    /// Asks for the games open to spectators.
+    ListLiveGames,
+    /// Follows a lobby feed again, see `subscriptions`.
+    Subscribe(Feed),
+    Unsubscribe(Feed),
+    /// Publishes a finished game, see `shared_replays`.
+    ShareReplay(GameId, bool),
+    /// Loads a shared replay, registered or not.
+    WatchReplay(String),
+    ExportData,
+    /// Sets up a practice range, see `practice`, of the board size if given.
+    PracticeStart(Option<usize>),
+    PracticeAttack(u8, u8),
+    PracticeStop,
+    /// Without a code the server replies with one to confirm the deletion.
+    DeleteAccount(Option<String>),
+}
+
+#[derive(Debug)]
+struct Registration {
+    username: String,
+    locale: Locale,
+    capabilities: Vec<String>,
+    /// Room to join once registered, see `room_codes`.
+    join: Option<String>,
+    /// Set by `bot_reg`, see `bot_api`.
+    api_key: Option<String>,
+    /// Signs in to an existing account, see `friends`.
+    account_key: Option<String>,
+}
+
+#[derive(Debug)]
+enum RoomEvent {
+    Create(RoomSettings),
+    /// Joins by the room's id or its code, see `room_codes`.
+    AddUser(String),
+}
+
+#[derive(Debug)]
+enum GameEvent {
+    AddShips(GameId, Ships),
+    /// Checks a placement without submitting it.
+    ValidateShips(GameId, Ships),
+    Attack(GameId, u8, u8),
+    RandomAttack(GameId),
+    Scan(GameId, u8, u8),
+    AbortRequest(GameId),
+    AbortAccept(GameId),
+    PauseRequest(GameId),
+    Resume(GameId),
+    /// Asks for the caller's view of the game, see `bot_api`.
+    BoardView(GameId),
+}
+
+impl GameEvent {
+    fn game_id(&self) -> &GameId {
+        match self {
+            GameEvent::AddShips(game_id, _)
+            | GameEvent::ValidateShips(game_id, _)
+            | GameEvent::Attack(game_id, _, _)
+            | GameEvent::RandomAttack(game_id)
+            | GameEvent::Scan(game_id, _, _)
+            | GameEvent::AbortRequest(game_id)
+            | GameEvent::AbortAccept(game_id)
+            | GameEvent::PauseRequest(game_id)
+            | GameEvent::Resume(game_id)
+            | GameEvent::BoardView(game_id) => game_id,
+        }
+    }
+
+    /// Whether the event counts against `moves_per_second`.
+    fn is_move(&self) -> bool {
+        matches!(
+            self,
+            GameEvent::Attack(..) | GameEvent::RandomAttack(_) | GameEvent::Scan(..)
+        )
+    }
+}
+
+#[derive(Debug)]
+enum FriendEvent {
+    Request(FriendRef),
+    Accept(FriendRef),
+    Remove(FriendRef),
+    Challenge(FriendRef),
+}
+
+/// Who a friend command is about: an `account`, or a player's `name`.
+#[derive(Debug)]
+enum FriendRef {
+    Account(AccountId),
+    Name(String),
+}
+
+const TICK_INTERVAL_VAR: &str = "TICK_INTERVAL_MS";
+/// Most entries one `winners` request gets.
+const MAX_WINNERS_PAGE: usize = 100;
+const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(200);
+const ROOM_IDLE_SECONDS_VAR: &str = "ROOM_IDLE_SECONDS";
+const DEFAULT_ROOM_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const MOTD_VAR: &str = "MOTD";
+
+/// Version of the client protocol, bumped on incompatible wire changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub type ServerState = Arc<RwLock<State>>;
+
+type UserId = String;
+
+#[derive(Debug)]
+pub struct State {
+    pub events: Queue<ServerEvent>,
+    user_ids: HashMap<SocketAddr, UserId>,
+    users: HashMap<UserId, User>,
+    games: HashMap<GameId, Game>,
+    friends: Friends,
+    started_at: Instant,
+    /// Last time the tick loop ran, used as the liveness signal.
+    last_tick: Instant,
+    /// How often `tick` should run, `None` when nothing calls it.
+    pub tick_interval: Option<Duration>,
+    /// How long a waiting room outlives the activity of its owner, `None`
+    /// to keep it until they leave. Set through `set_room_idle_timeout`.
+    room_idle_timeout: Option<Duration>,
+    /// Set by operators to take the server out of rotation.
+    maintenance: bool,
+    /// Set by the `shutdown` admin command; the dispatcher exits once the
+    /// close frames are sent.
+    pub shutting_down: bool,
+    /// Set by the `--debug` flag to expose the state dump endpoint.
+    pub debug: bool,
+    /// Experimental modes enabled on this deployment.
+    pub features: Features,
+    pub limits: Limits,
+    /// Message of the day, shown by clients after registering.
+    pub motd: Option<String>,
+    announcements: Announcements,
+    room_throttle: Throttle,
+    move_throttle: Throttle,
+    /// Keys that open `bot_reg`, see `bot_api`.
+    pub bot_api_keys: HashSet<String>,
+    /// Seed of every practice range instead of a random one, to reproduce
+    /// a range from the logs.
+    pub practice_seed: Option<u64>,
+    audit: AuditLog,
+    /// Embeds for the Discord webhook, set when one is configured.
+    discord: Option<UnboundedSender<serde_json::Value>>,
+    /// The other nodes, when several share the lobby, see `cluster`.
+    cluster: Cluster,
+    /// Confirmation codes handed out for pending account deletions.
+    deletion_codes: HashMap<UserId, String>,
+    /// When each account was last connected, for the retention job. Seeded
+    /// from the friends file, see `seen`.
+    last_seen: HashMap<AccountId, Instant>,
+    sessions: Sessions,
+    matchmaking: Matchmaking,
+    spectators: Spectators,
+    farming: Farming,
+    shared_replays: SharedReplays,
+    game_totals: GameTotals,
+    presence: Presence,
+    room_deltas: Deltas,
+    winner_deltas: Deltas,
+    subscriptions: Subscriptions,
+    room_codes: RoomCodes,
+    /// Rooms of invite links connections were opened with, joined when
+    /// they register.
+    pending_joins: HashMap<SocketAddr, String>,
+    deadlines: Deadlines,
+    broadcasts: Broadcasts,
+    practice: PracticeRanges,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        let mut state = Self {
+            events: Queue::new(),
+            user_ids: HashMap::new(),
+            users: HashMap::new(),
+            games: HashMap::new(),
+            friends: Friends::from_env(),
+            started_at: clock::now(),
+            last_tick: clock::now(),
+            tick_interval: tick_interval_from_env(),
+            room_idle_timeout: room_idle_timeout_from_env(),
+            maintenance: false,
+            shutting_down: false,
+            debug: false,
+            features: Features::from_env(),
+            limits: Limits::from_env(),
+            motd: motd_from_env(),
+            announcements: Announcements::default(),
+            room_throttle: Throttle::new(ROOM_CREATION_WINDOW),
+            move_throttle: Throttle::new(MOVE_WINDOW),
+            bot_api_keys: bot_api::api_keys_from_env(),
+            practice_seed: None,
+            audit: AuditLog::from_env(),
+            discord: None,
+            cluster: Cluster::default(),
+            deletion_codes: HashMap::new(),
+            last_seen: HashMap::new(),
+            sessions: Sessions::default(),
+            matchmaking: Matchmaking::from_env(),
+            spectators: Spectators::from_env(),
+            farming: Farming::default(),
+            shared_replays: SharedReplays::default(),
+            game_totals: GameTotals::default(),
+            presence: Presence::from_env(),
+            room_deltas: Deltas::new(ROOM_DELTAS, "roomId"),
+            winner_deltas: Deltas::new(WINNER_DELTAS, "name"),
+            subscriptions: Subscriptions::default(),
+            room_codes: RoomCodes::default(),
+            pending_joins: HashMap::new(),
+            deadlines: Deadlines::default(),
+            broadcasts: Broadcasts::default(),
+            practice: PracticeRanges::default(),
+        };
+        state.load_last_seen();
+        for (name, strategy) in matchmaking::resident_bots_from_env() {
+            if let Err(err) = state.add_resident_bot(&name, &strategy) {
+                warn!("Bot '{}' not added: {}", name, err);
+            }
+        }
+        state
+    }
+}
+
+impl State {
+    fn add_user(&mut self, user: &User) -> User {
+        let user_id = self.user_ids.entry(user.addr).or_insert(user.id.clone());
+        let user = self
+            .users
+            .entry(user_id.to_string())
+            .or_insert(user.clone())
+            .clone();
+        self.add_update_winners_event();
+        self.add_update_room_event();
+        user
+    }
+    fn get_user_by_addr(&self, addr: &SocketAddr) -> Option<&User> {
+        match self.user_ids.get(addr) {
+            Some(user_id) => self.get_user(user_id),
+            None => None,
+        }
+    }
+    fn get_user(&self, user_id: &String) -> Option<&User> {
+        self.users.get(user_id)
+    }
+    fn add_event(&mut self, event: &ServerEvent) {
+        let _ = self.events.queue(event.clone());
+    }
+
+    /// Empties the event queue. Snapshots superseded by a newer one of the
+    /// same kind are dropped, so a burst of registrations costs each client
+    /// one lobby update rather than one per registration. Player events are
+    /// resolved to the player's current socket; those of players that are
+    /// gone are dropped. Clients that asked for room deltas get them in
+    /// place of the room list, and those that unsubscribed from a feed get
+    /// none of its snapshots. Snapshots that didn't change since the last
+    /// one only go to whoever missed that.
+    pub fn take_events(&mut self) -> Vec<ServerEvent> {
+        let mut events = Vec::with_capacity(self.events.len());
+        while let Some(event) = self.events.dequeue() {
+            let event = match event {
+                ServerEvent::Player(user_id, json) => match self.users.get(&user_id) {
+                    Some(user) => ServerEvent::User(user.addr, json),
+                    None => continue,
+                },
+                event => event,
+            };
+            events.push(event);
+        }
+        let mut seen = HashSet::new();
+        let mut kept = events
+            .into_iter()
+            .rev()
+            .filter(|event| match event {
+                ServerEvent::Snapshot(kind, _) if !seen.insert(*kind) => {
+                    METRICS.broadcasts_coalesced.inc();
+                    false
+                }
+                _ => true,
+            })
+            .collect::<Vec<ServerEvent>>();
+        kept.reverse();
+        kept.into_iter()
+            .flat_map(|event| match event {
+                ServerEvent::Snapshot("update_room", json) => self.room_update_events(json),
+                ServerEvent::Snapshot("update_winners", json) => self.winner_update_events(json),
+                ServerEvent::Snapshot(kind, json) => self.feed_event(kind, json, Vec::new()),
+                event => vec![event],
+            })
+            .collect()
+    }
+
+    /// The full room list for most clients and only what changed since the
+    /// last one for those that asked for deltas, see `deltas`.
+    fn room_update_events(&mut self, json: String) -> Vec<ServerEvent> {
+        let deltas = self
+            .room_deltas
+            .update(self.rooms_json())
+            .into_iter()
+            .map(|delta| match delta {
+                Delta::Added(room) => create_event_json(room, "room_added".into()),
+                Delta::Changed(room) => create_event_json(room, "room_changed".into()),
+                Delta::Removed(room_id) => {
+                    create_event_json(json!({ "roomId": room_id }), "room_removed".into())
+                }
+            })
+            .collect::<Vec<String>>();
+        let clients = self.delta_clients(&self.room_deltas, Feed::Rooms);
+        let mut events = self.feed_event(
+            "update_room",
+            json.clone(),
+            clients.iter().map(|(_, addr)| *addr).collect(),
+        );
+        for (user_id, addr) in clients {
+            if self.room_deltas.sync(&user_id) {
+                events.push(ServerEvent::User(addr, json.clone()));
+                continue;
+            }
+            events.extend(
+                deltas
+                    .iter()
+                    .map(|delta| ServerEvent::User(addr, delta.clone())),
+            );
+        }
+        events
+    }
+
+    /// The whole winners list for most clients; the top of it and then what
+    /// changed there for those that asked for deltas.
+    fn winner_update_events(&mut self, json: String) -> Vec<ServerEvent> {
+        let top = ranked(self.winners_json(), 0, TOP_WINNERS);
+        let mut changed = Vec::new();
+        let mut removed = Vec::new();
+        for delta in self.winner_deltas.update(top.clone()) {
+            match delta {
+                Delta::Added(entry) | Delta::Changed(entry) => changed.push(entry),
+                Delta::Removed(name) => removed.push(name),
+            }
+        }
+        changed.sort_by_key(|entry| entry["rank"].as_u64());
+        removed.sort();
+        let clients = self.delta_clients(&self.winner_deltas, Feed::Winners);
+        let mut events = self.feed_event(
+            "update_winners",
+            json,
+            clients.iter().map(|(_, addr)| *addr).collect(),
+        );
+        let full = create_event_json(json!(top), "update_winners".into());
+        let delta = (!changed.is_empty() || !removed.is_empty()).then(|| {
+            create_event_json(
+                json!({ "changed": changed, "removed": removed }),
+                "winners_changed".into(),
+            )
+        });
+        for (user_id, addr) in clients {
+            if self.winner_deltas.sync(&user_id) {
+                events.push(ServerEvent::User(addr, full.clone()));
+            } else if let Some(delta) = &delta {
+                events.push(ServerEvent::User(addr, delta.clone()));
+            }
+        }
+        events
+    }
+
+    /// A lobby snapshot for everyone but `except` and the clients that
+    /// unsubscribed from its feed. A snapshot the same as the last one only
+    /// goes to the clients that didn't get that, see `broadcasts`.
+    fn feed_event(
+        &mut self,
+        kind: &'static str,
+        json: String,
+        mut except: Vec<SocketAddr>,
+    ) -> Vec<ServerEvent> {
+        if let Some(feed) = Feed::of_snapshot(kind) {
+            except.extend(
+                self.subscriptions
+                    .unsubscribed(feed)
+                    .filter_map(|user_id| self.users.get(user_id))
+                    .map(|user| user.addr),
+            );
+        }
+        let connected = self
+            .users
+            .values()
+            .map(|user| user.addr)
+            .filter(|addr| !except.contains(addr))
+            .collect::<HashSet<SocketAddr>>();
+        if let Some(missing) = self.broadcasts.missing(kind, &json, connected) {
+            if missing.is_empty() {
+                METRICS.broadcasts_skipped.inc();
+            }
+            return missing
+                .into_iter()
+                .map(|addr| ServerEvent::User(addr, json.clone()))
+                .collect();
+        }
+        if except.is_empty() {
+            vec![ServerEvent::Snapshot(kind, json)]
+        } else {
+            vec![ServerEvent::AllExcept(except, json)]
+        }
+    }
+
+    /// Connected clients following `deltas` and its feed.
+    fn delta_clients(&self, deltas: &Deltas, feed: Feed) -> Vec<(UserId, SocketAddr)> {
+        deltas
+            .clients()
+            .iter()
+            .filter(|user_id| self.subscriptions.follows(user_id, feed))
+            .filter_map(|user_id| self.users.get(user_id))
+            .map(|user| (user.id.clone(), user.addr))
+            .collect()
+    }
+
+    /// Lobby head count. Searching players wait in a room of their own;
+    /// players held for a reconnect aren't online.
+    fn population_json(&self) -> serde_json::Value {
+        let mut open_rooms = 0;
+        let mut searching = HashSet::new();
+        let mut playing = HashSet::new();
+        for game in self.games.values() {
+            let players = game.players().into_iter().flatten();
+            if matches!(game.status(), GameStatus::Waiting) {
+                open_rooms += 1;
+                searching.extend(players);
+            } else {
+                playing.extend(players);
+            }
+        }
+        searching.extend(self.matchmaking.searching().cloned());
+        playing.retain(|user_id| !self.matchmaking.is_bot(user_id));
+        json!({
+            "online": self.users.len()
+                - self.sessions.absent_count()
+                - self.matchmaking.bot_ids().len(),
+            "searching": searching.difference(&playing).count(),
+            "playing": playing.len(),
+            "openRooms": open_rooms,
+            "activeGames": self.games.len() - open_rooms,
+        })
+    }
+
+    pub(crate) fn presence_of(&self, user: &User) -> Status {
+        if self.sessions.remaining_grace(&user.id).is_some() {
+            return Status::Away;
+        }
+        if self.is_in_game(user) {
+            return Status::InGame;
+        }
+        let in_open_room = user.rooms.iter().any(|room| {
+            self.games
+                .get(room)
+                .is_some_and(|game| matches!(game.status(), GameStatus::Waiting))
+        });
+        if in_open_room || self.matchmaking.is_searching(&user.id) {
+            Status::Searching
+        } else if self.presence.is_away(&user.id) {
+            Status::Away
+        } else {
+            Status::Online
+        }
+    }
+
+    /// Broadcasts the presence of every connected player if any changed
+    /// since the last broadcast.
+    fn refresh_presence(&mut self) {
+        let statuses = self.statuses();
+        if !self.presence.update(statuses.clone()) {
+            return;
+        }
+        let json = self.presence_json(&statuses);
+        self.add_snapshot_event("update_presence", json);
+    }
+
+    /// Broadcasts presence if the status of `user` changed, as their own
+    /// events are what usually changes it. Changes that come with the time
+    /// or with other players' events wait for the tick.
+    fn refresh_presence_of(&mut self, user: &User) {
+        let status = self.get_user(&user.id).map(|user| self.presence_of(user));
+        if status != self.presence.shown(&user.id) {
+            self.refresh_presence();
+        }
+    }
+
+    /// The status of every connected player, bots left out.
+    fn statuses(&self) -> HashMap<UserId, Status> {
+        self.users
+            .values()
+            .filter(|user| !self.matchmaking.is_bot(&user.id))
+            .map(|user| (user.id.clone(), self.presence_of(user)))
+            .collect()
+    }
+
+    /// The players' statuses by name.
+    fn presence_json(&self, statuses: &HashMap<UserId, Status>) -> serde_json::Value {
+        let mut players = statuses
+            .iter()
+            .filter_map(|(user_id, status)| Some((&self.get_user(user_id)?.name, status)))
+            .collect::<Vec<_>>();
+        players.sort_by_key(|(name, _)| *name);
+        json!(players
+            .into_iter()
+            .map(|(name, status)| json!({ "name": name, "status": status }))
+            .collect::<Vec<serde_json::Value>>())
+    }
+
+    /// Entries of the tables that grow with connections and games, which
+    /// should shrink back once they are gone. Sampled for `/metrics`.
+    fn collection_sizes(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("users", self.users.len()),
+            ("connections", self.user_ids.len()),
+            ("games", self.games.len()),
+            ("sessions", self.sessions.token_count()),
+            ("absent_players", self.sessions.absent_count()),
+            ("presence", self.presence.player_count()),
+            ("spectated_games", self.spectators.game_count()),
+            ("matchmaking_queue", self.matchmaking.searching().count()),
+            ("room_codes", self.room_codes.count()),
+            ("room_delta_clients", self.room_deltas.clients().len()),
+            ("winner_delta_clients", self.winner_deltas.clients().len()),
+            ("feed_opt_outs", self.subscriptions.opt_out_count()),
+            ("pending_joins", self.pending_joins.len()),
+            ("deletion_codes", self.deletion_codes.len()),
+            ("last_seen", self.last_seen.len()),
+            ("deadlines", self.deadlines.count()),
+            ("practice_ranges", self.practice.count()),
+        ]
+    }
+
+    fn add_population_event(&mut self) {
+        self.add_snapshot_event("update_population", self.population_json());
+    }
+
+    fn add_snapshot_event(&mut self, kind: &'static str, data: serde_json::Value) {
+        let json = self.broadcasts.encode(kind, data);
+        self.add_event(&ServerEvent::Snapshot(kind, json));
+    }
+
+    /// Players in leaderboard order, one-off bots left out.
+    fn winners_json(&self) -> Vec<serde_json::Value> {
+        let mut users = self
+            .users
+            .values()
+            .filter(|user| !self.matchmaking.is_one_off_bot(&user.id))
+            .collect::<Vec<&User>>();
+        users.sort_by(|a, b| rank(a, b));
+        users.into_iter().map(winner_json).collect()
+    }
+
+    /// Also refreshes the population, which changes with the user list.
+    fn add_update_winners_event(&mut self) {
+        let json = serde_json::Value::Array(self.winners_json());
+        self.add_snapshot_event("update_winners", json);
+        self.add_population_event();
+    }
+
+    /// Also refreshes the population, which changes with the rooms. A
+    /// node in maintenance stops offering its rooms to the other nodes.
+    fn add_update_room_event(&mut self) {
+        if self.cluster.is_joined() {
+            let rooms = match self.maintenance {
+                true => Vec::new(),
+                false => self.local_rooms_json(),
+            };
+            self.cluster.publish_rooms(rooms);
+        }
+        let json = serde_json::Value::Array(self.rooms_json());
+        self.add_snapshot_event("update_room", json);
+        self.add_population_event();
+    }
+
+    /// Follows the lists the client asked deltas of.
+    fn join_deltas(&mut self, user_id: &UserId, capabilities: &[String]) {
+        for deltas in [&mut self.room_deltas, &mut self.winner_deltas] {
+            if capabilities
+                .iter()
+                .any(|capability| capability == deltas.capability)
+            {
+                deltas.join(user_id);
+            }
+        }
+    }
+
+    /// Announcements still in effect, for a client that just connected.
+    fn add_announcement_events(&mut self, user_id: &UserId) {
+        for json in self.announcements.active() {
+            self.add_player_event(user_id, "server_announcement", json);
+        }
+    }
+
+    /// What this server runs and allows, sent with the `reg` reply so
+    /// clients can adapt instead of assuming the defaults.
+    fn server_json(&self) -> serde_json::Value {
+        let rulesets = if self.features.is_enabled(Feature::Variants) {
+            rules::ruleset_names()
+        } else {
+            vec![DEFAULT_RULESET]
+        };
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocolVersion": PROTOCOL_VERSION,
+            "rulesets": rulesets,
+            "limits": {
+                "minBoardSize": MIN_BOARD_SIZE,
+                "maxBoardSize": MAX_BOARD_SIZE,
+                "defaultBoardSize": DEFAULT_BOARD_SIZE,
+                "defaultFleet": Fleet::default(),
+                "maxShipLength": MAX_SHIP_LENGTH,
+                "maxFleetSize": MAX_FLEET_SIZE,
+                "maxBestOf": MAX_BEST_OF,
+                "maxClockSeconds": MAX_CLOCK_SECONDS,
+                "maxOpenRooms": self.limits.max_open_rooms_per_user,
+                "maxSpectators": self.spectators.limit(),
+            },
+            "motd": self.motd,
+        })
+    }
+
+    /// Optional protocol features the user's client asked for and got.
+    fn capabilities_json(&self, user_id: &UserId) -> serde_json::Value {
+        json!([&self.room_deltas, &self.winner_deltas]
+            .into_iter()
+            .filter(|deltas| deltas.clients().contains(user_id))
+            .map(|deltas| deltas.capability)
+            .collect::<Vec<&str>>())
+    }
+
+    /// Rooms waiting for an opponent, as shown in the lobby.
+    /// Open rooms of this node and, in a cluster, of the other nodes.
+    fn rooms_json(&self) -> Vec<serde_json::Value> {
+        let mut rooms = self.local_rooms_json();
+        rooms.extend(self.cluster.rooms().cloned());
+        rooms
+    }
+
+    fn local_rooms_json(&self) -> Vec<serde_json::Value> {
+        self.games
+            .values()
+            .filter_map(|game| match game.status() {
+                GameStatus::Waiting => Some(with_settings(
+                    json!({
+                        "roomId": game.id,
+                        "code": self.room_codes.code_of(&game.id),
+                        "roomUsers": serde_json::Value::Array(game.players()
+                            .into_iter()
+                            .filter_map(|user_id| {
+                                user_id.as_ref().and_then(|user_id| {
+                                    self.get_user(user_id).map(|user| {
+                                        json!({
+                                            "name": user.name,
+                                            "index": user_id.clone(),
+                                        })
+                                    })
+                                })
+                            })
+                            .collect::<Vec<serde_json::Value>>(),
+                        ),
+                    }),
+                    &game.settings,
+                )),
+                _ => None,
+            })
+            .collect::<Vec<serde_json::Value>>()
+    }
+
+    fn create_game(&mut self, user: &User, settings: &RoomSettings) -> Option<GameId> {
+        if let Err(err) = self.features.allow(settings) {
+            warn!("Room of '{}' rejected: {}", user.name, err);
+            self.add_error_event(&user.id, err);
+            return None;
+        }
+        if !self.check_room_limit(user) {
+            return None;
+        }
+        if !limits::has_room(self.limits.max_games, self.games.len()) {
+            warn!("Room of '{}' rejected: too many games", user.name);
+            self.add_error_event(&user.id, MessageId::ServerFull);
+            return None;
+        }
+        let game_id = Uuid::new_v4().to_string();
+        let game = Game::create(&game_id, user, settings);
+        // Logged here too so games that never finish can be replayed.
+        let seed = game.seed.to_string();
+        self.games.insert(game_id.clone(), game);
+        self.schedule_idle_room(&game_id);
+        self.room_codes.assign(&game_id);
+        self.cluster.claim(&game_id);
+        METRICS.games_created.inc();
+        let _game_span = error_span!("game", game = %game_id).entered();
+        info!("Room '{}' created by '{}'", game_id, user.name);
+        self.audit.record(
+            &user.name,
+            "create_room",
+            json!({ "game": game_id, "settings": settings, "seed": seed }),
+        );
+        match self.join_game(game_id.clone(), user, true) {
+            true => Some(game_id),
+            false => None,
+        }
+    }
+
+    /// A room asked for by the user, as opposed to one opened by matchmaking.
+    fn create_room(&mut self, user: &User, settings: &RoomSettings) {
+        if !self
+            .room_throttle
+            .allow(&user.id, self.limits.room_creations_per_minute)
+        {
+            warn!("User '{}' creates rooms too fast", user.name);
+            self.add_error_event(&user.id, MessageId::CreatingRoomsTooFast);
+            return;
+        }
+        let limit = self.limits.max_open_rooms_per_user;
+        let open_rooms = user
+            .rooms
+            .iter()
+            .filter(|room| {
+                self.games
+                    .get(*room)
+                    .is_some_and(|game| matches!(game.status(), GameStatus::Waiting))
+            })
+            .count();
+        if !limits::has_room(limit, open_rooms) {
+            warn!("User '{}' has too many open rooms", user.name);
+            let message = Message::new(MessageId::TooManyOpenRooms).arg(limit.unwrap_or_default());
+            self.add_error_event(&user.id, message);
+            return;
+        }
+        self.create_game(user, settings);
+    }
+
+    /// Tells the user off when they send moves faster than they may, bots
+    /// being allowed more.
+    fn check_move_rate(&mut self, user: &User) -> bool {
+        let limit = match user.bot {
+            true => self.limits.bot_moves_per_second,
+            false => self.limits.moves_per_second,
+        };
+        if self.move_throttle.allow(&user.id, limit) {
+            return true;
+        }
+        warn!("User '{}' moves too fast", user.name);
+        let message = Message::new(MessageId::MovingTooFast).arg(limit.unwrap_or_default());
+        self.add_error_event(&user.id, message);
+        false
+    }
+
+    /// Tells the user off when they are in as many rooms as they may be.
+    fn check_room_limit(&mut self, user: &User) -> bool {
+        let limit = self.limits.max_rooms_per_user;
+        // Server bots play as many games as they are matched into.
+        if self.matchmaking.is_bot(&user.id) || limits::has_room(limit, user.rooms.len()) {
+            return true;
+        }
+        warn!("User '{}' is in too many rooms", user.name);
+        let message = Message::new(MessageId::TooManyRooms).arg(limit.unwrap_or_default());
+        self.add_error_event(&user.id, message);
+        false
+    }
+
+    /// Works on the stored user rather than the caller's copy, which may
+    /// predate changes made since, such as rooms joined or games won.
+    fn join_game(&mut self, game_id: String, user: &User, is_owner: bool) -> bool {
+        let Some(user) = self.get_user(&user.id).cloned() else {
+            return false;
+        };
+        if user.rooms.contains(&game_id) {
+            warn!("User '{}' is already in room '{}'", user.name, game_id);
+            return false;
+        }
+        if !is_owner {
+            if !self.check_room_limit(&user) {
+                return false;
+            }
+            let joined = self
+                .games
+                .get_mut(&game_id)
+                .map(|game| game.apply(Transition::Join(user.id.clone())));
+            match joined {
+                Some(Ok(())) => {
+                    self.audit
+                        .record(&user.name, "join_room", json!({ "game": game_id }));
+                }
+                Some(Err(err)) => {
+                    warn!("Room '{}' is not available: {}", game_id, err);
+                    return false;
+                }
+                None => {
+                    if !self.redirect(&user.id, &game_id) {
+                        warn!("Room '{}' is not available", game_id);
+                    }
+                    return false;
+                }
+            }
+
+            self.add_create_game_event(&game_id);
+        }
+        if let Some(user) = self.users.get_mut(&user.id) {
+            user.rooms.insert(game_id.clone());
+        }
+        self.add_update_room_event();
+
+        if !is_owner {
+            if let Some(game) = self.games.get(&game_id) {
+                let players = game.players();
+                for user_id in players.into_iter().flatten() {
+                    if let Some(player) = self.get_user(&user_id).cloned() {
+                        self.notify_friends(
+                            &player,
+                            "friend_in_game",
+                            json!({
+                                "name": player.name,
+                                "account": player.account,
+                                "inGame": true,
+                                "roomId": game_id,
+                            }),
+                        );
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Tells each player the game and their own index in it.
+    fn add_create_game_event(&mut self, game_id: &GameId) {
+        let Some(game) = self.games.get(game_id) else {
+            return;
+        };
+        let players = game.players();
+        for user_id in players.into_iter().flatten() {
+            let json = with_settings(
+                json!({
+                    "idGame": game_id,
+                    "idPlayer": user_id,
+                }),
+                &self.games[game_id].settings,
+            );
+            self.add_player_event(&user_id, "create_game", json);
+        }
+    }
+
+    /// Checks against the game itself rather than the client-facing room
+    /// list, so nobody can act on a game they don't play in.
+    fn is_player_of(&self, user: &User, game_id: &GameId) -> bool {
+        let is_player = self
+            .games
+            .get(game_id)
+            .is_some_and(|game| game.is_player(&user.id));
+        if !is_player {
+            warn!("User '{}' is not a player of '{}'", user.name, game_id);
+        }
+        is_player
+    }
+
+    fn add_ships_to_game(&mut self, user: &User, game_id: GameId, ships: Ships) {
+        if !self.is_player_of(user, &game_id) {
+            return;
+        }
+        let game = self.games.get_mut(&game_id).unwrap();
+        if let Err(err) = game.add_ships(&ships, &user.id) {
+            warn!("Ships of '{}' rejected: {}", user.name, err);
+            self.add_error_event(&user.id, err);
+            return;
+        }
+        self.audit.record(
+            &user.name,
+            "add_ships",
+            json!({ "game": game_id, "ships": ships.ships }),
+        );
+        if game.is_ready() {
+            self.start_game(&game_id);
+        }
+    }
+
+    fn validate_ships(&mut self, user: &User, game_id: GameId, ships: Ships) {
+        if !self.is_player_of(user, &game_id) {
+            return;
+        }
+        let (fleet, problems) = self.games[&game_id].check_placement(&ships, &user.id);
+        let json = json!({
+            "gameId": game_id,
+            "valid": fleet.is_ok() && problems.is_empty(),
+            "fleetError": fleet.err().map(|err| err.text(user.locale)),
+            "problems": problems,
+        });
+        self.add_player_event(&user.id, "validate_ships", json);
+    }
+
+    fn board_view(&mut self, user: &User, game_id: GameId) {
+        if !self.is_player_of(user, &game_id) {
+            return;
+        }
+        let json = bot_api::board_view(&self.games[&game_id], &user.id);
+        self.add_player_event(&user.id, "board_view", json);
+    }
+
+    fn start_game(&mut self, game_id: &GameId) {
+        let game = self.games.get_mut(game_id).unwrap();
+        if let Err(err) = game.apply(Transition::Start) {
+            warn!("Game '{}' not started: {}", game_id, err);
+            return;
+        }
+        info!("Game '{}' started", game_id);
+        self.spectators.new_round(game_id);
+
+        let current_player = game.current_player();
+        let players = game.players();
+        for user_id in players.into_iter().flatten() {
+            let ships = self.games[game_id].ships_of(&user_id).ships.clone();
+            self.add_player_event(
+                &user_id,
+                "start_game",
+                json!({
+                    "ships": ships,
+                    "currentPlayerIndex": current_player,
+                }),
+            );
+        }
+        self.add_turn_event(game_id);
+    }
+
+    fn add_turn_event(&mut self, game_id: &GameId) {
+        self.schedule_deadlines(game_id);
+        let game = &self.games[game_id];
+        let players = game.players();
+        let mut json = json!({
+            "currentPlayer": game.current_player(),
+            "shotsLeft": game.shots_left(),
+            "checksum": game.checksum(),
+        });
+        json["rtt"] = players
+            .iter()
+            .flatten()
+            .filter_map(|user_id| {
+                let rtt_ms = self.get_user(user_id)?.rtt_ms?;
+                Some(json!({ "index": user_id, "rttMs": rtt_ms }))
+            })
+            .collect();
+        if let Some(clocks) = clocks_json(game) {
+            json["clocks"] = clocks;
+        }
+        for user_id in players.into_iter().flatten() {
+            self.add_player_event(&user_id, "turn", json.clone());
+        }
+        self.add_spectator_event(game_id, "turn", json);
+    }
+
+    fn attack(&mut self, user: &User, game_id: GameId, target: Option<(u8, u8)>) {
+        faults::hit("attack");
+        if !self.is_player_of(user, &game_id) {
+            return;
+        }
+        let game = self.games.get_mut(&game_id).unwrap();
+        let (x, y) = match target {
+            Some(target) => target,
+            None => match game.random_target(&user.id) {
+                Some(pos) => (pos.x, pos.y),
+                None => return,
+            },
+        };
+        let result = match game.attack(&user.id, x, y) {
+            Ok(result) => result,
+            Err(err) => {
+                warn!("Attack of '{}' rejected: {}", user.name, err);
+                self.add_error_event(&user.id, err);
+                return;
+            }
+        };
+
+        let players = game.players();
+        self.audit.record(
+            &user.name,
+            "attack",
+            json!({
+                "game": game_id,
+                "x": x,
+                "y": y,
+                "random": target.is_none(),
+                "cells": result.cells,
+            }),
+        );
+        for &(position, status) in result.cells.iter() {
+            let json = json!({
+                "position": position,
+                "currentPlayer": user.id,
+                "status": status,
+            });
+            for user_id in players.iter().flatten() {
+                self.add_player_event(user_id, "attack", json.clone());
+            }
+            self.add_spectator_event(&game_id, "attack", json);
+        }
+
+        if result.is_won {
+            self.finish_game(&game_id, &user.id, FinishReason::Win);
+        } else {
+            self.add_turn_event(&game_id);
+        }
+    }
+
+    fn scan(&mut self, user: &User, game_id: GameId, x: u8, y: u8) {
+        if !self.is_player_of(user, &game_id) {
+            return;
+        }
+        let game = self.games.get_mut(&game_id).unwrap();
+        match game.scan(&user.id, x, y) {
+            Ok(count) => {
+                self.audit.record(
+                    &user.name,
+                    "scan",
+                    json!({ "game": game_id, "x": x, "y": y, "count": count }),
+                );
+                self.add_player_event(
+                    &user.id,
+                    "scan_result",
+                    json!({
+                        "position": { "x": x, "y": y },
+                        "count": count,
+                    }),
+                );
+                self.add_turn_event(&game_id);
+            }
+            Err(err) => {
+                warn!("Scan of '{}' rejected: {}", user.name, err);
+                self.add_error_event(&user.id, err);
+            }
+        }
+    }
+
+    fn request_abort(&mut self, user: &User, game_id: GameId) {
+        if !self.is_player_of(user, &game_id) {
+            return;
+        }
+        let game = self.games.get_mut(&game_id).unwrap();
+        if let Err(err) = game.request_abort(&user.id) {
+            warn!("Abort of '{}' rejected: {}", user.name, err);
+            self.add_error_event(&user.id, err);
+            return;
+        }
+        self.audit
+            .record(&user.name, "abort_request", json!({ "game": game_id }));
+        if let Some(opponent) = game.opponent_of(&user.id) {
+            self.add_player_event(&opponent, "abort_requested", json!({ "index": user.id }));
+        }
+    }
+
+    fn accept_abort(&mut self, user: &User, game_id: GameId) {
+        if !self.is_player_of(user, &game_id) {
+            return;
+        }
+        if let Err(err) = self.games.get_mut(&game_id).unwrap().accept_abort(&user.id) {
+            warn!("Abort of '{}' rejected: {}", user.name, err);
+            self.add_error_event(&user.id, err);
+            return;
+        }
+        info!("Game '{}' aborted", game_id);
+        self.audit
+            .record(&user.name, "abort_accept", json!({ "game": game_id }));
+        self.end_game(&game_id, None, FinishReason::Aborted);
+    }
+
+    fn request_pause(&mut self, user: &User, game_id: GameId) {
+        if !self.is_player_of(user, &game_id) {
+            return;
+        }
+        let game = self.games.get_mut(&game_id).unwrap();
+        let players = game.players();
+        let (event_type, json) = match game.request_pause(&user.id) {
+            Ok(false) => ("pause_requested", json!({ "index": user.id })),
+            Ok(true) => (
+                "paused",
+                json!({ "maxPauseMs": MAX_PAUSE.as_millis() as u64 }),
+            ),
+            Err(err) => {
+                warn!("Pause of '{}' rejected: {}", user.name, err);
+                self.add_error_event(&user.id, err);
+                return;
+            }
+        };
+        self.schedule_deadlines(&game_id);
+        self.audit
+            .record(&user.name, event_type, json!({ "game": game_id }));
+        for user_id in players.iter().flatten() {
+            self.add_player_event(user_id, event_type, json.clone());
+        }
+    }
+
+    /// Puts the game's turn clock and pause limit in the deadline queue, or
+    /// takes them out when they don't run.
+    fn schedule_deadlines(&mut self, game_id: &GameId) {
+        let game = self.games.get(game_id);
+        self.deadlines.schedule(
+            Deadline::TurnClock(game_id.clone()),
+            game.and_then(|game| game.turn_deadline()),
+        );
+        self.deadlines.schedule(
+            Deadline::Pause(game_id.clone()),
+            game.and_then(|game| game.pause_deadline()),
+        );
+    }
+
+    /// Puts the room's expiry in the deadline queue, for when its owner
+    /// will have been idle for `room_idle_timeout`, or takes it out when
+    /// the room isn't waiting for an opponent.
+    fn schedule_idle_room(&mut self, game_id: &GameId) {
+        let at = self.room_idle_timeout.and_then(|timeout| {
+            let owner = self
+                .games
+                .get(game_id)
+                .filter(|game| matches!(game.status(), GameStatus::Waiting))?
+                .players()[0]
+                .clone()?;
+            (!self.matchmaking.is_bot(&owner)).then(|| self.presence.idle_at(&owner, timeout))
+        });
+        self.deadlines
+            .schedule(Deadline::IdleRoom(game_id.clone()), at);
+    }
+
+    fn resume(&mut self, user: &User, game_id: GameId) {
+        if !self.is_player_of(user, &game_id) {
+            return;
+        }
+        if let Err(err) = self.games.get_mut(&game_id).unwrap().resume() {
+            warn!("Resume of '{}' rejected: {}", user.name, err);
+            self.add_error_event(&user.id, err);
+            return;
+        }
+        self.audit
+            .record(&user.name, "resume", json!({ "game": game_id }));
+        self.add_resumed_event(&game_id);
+    }
+
+    fn add_resumed_event(&mut self, game_id: &GameId) {
+        let game = &self.games[game_id];
+        let players = game.players();
+        for user_id in players.iter().flatten() {
+            self.add_player_event(user_id, "resumed", json!({ "gameId": game_id }));
+        }
+        self.add_turn_event(game_id);
+    }
+
+    fn finish_game(&mut self, game_id: &GameId, winner: &UserId, reason: FinishReason) {
+        let game = self.games.get_mut(game_id).unwrap();
+        let series_winner = game.record_win(winner);
+        let players = game.players();
+        if game.settings.best_of > 1 {
+            let json = json!({
+                "winPlayer": winner,
+                "bestOf": game.settings.best_of,
+                "score": players
+                    .iter()
+                    .zip(game.score)
+                    .filter_map(|(user_id, wins)| user_id.as_ref().map(|user_id| json!({
+                        "index": user_id,
+                        "wins": wins,
+                    })))
+                    .collect::<Vec<serde_json::Value>>(),
+            });
+            for user_id in players.iter().flatten() {
+                self.add_player_event(user_id, "series_score", json.clone());
+            }
+        }
+        match series_winner {
+            Some(winner) => self.end_game(game_id, Some(winner), reason),
+            None => {
+                let game = self.games.get_mut(game_id).unwrap();
+                if let Err(err) = game.apply(Transition::NextRound) {
+                    warn!("Game '{}' can't go on: {}", game_id, err);
+                }
+                self.add_create_game_event(game_id);
+            }
+        }
+    }
+
+    /// Sends `finish`, credits the winner if there is one and closes the room.
+    fn end_game(&mut self, game_id: &GameId, winner: Option<UserId>, reason: FinishReason) {
+        info!(
+            "Game '{}' finished ({:?}), winner {:?}",
+            game_id, reason, winner
+        );
+        let game = self.games.get_mut(game_id).unwrap();
+        if let Err(err) = game.apply(Transition::Finish) {
+            warn!("Game '{}': {}", game_id, err);
+        }
+        // As a string: JavaScript numbers can't hold every u64.
+        let seed = game.seed.to_string();
+        self.audit.record(
+            "server",
+            "finish_game",
+            json!({
+                "game": game_id,
+                "winner": winner,
+                "reason": reason,
+                "seed": seed,
+                "handicap": game.settings.handicap,
+                "checksum": game.checksum(),
+            }),
+        );
+        let players = game.players();
+        let json = json!({
+            "winPlayer": winner,
+            "reason": reason,
+            "seed": seed,
+        });
+        for user_id in players.iter().flatten() {
+            self.add_player_event(user_id, "finish", json.clone());
+        }
+        self.add_spectator_event(game_id, "finish", json);
+        let summary = summary_json(&self.games[game_id], winner.as_ref(), reason);
+        for user_id in players.iter().flatten() {
+            self.add_player_event(user_id, "game_summary", summary.clone());
+        }
+        // Ships are no secret anymore; show where the rest of them were.
+        for user_id in players.iter().flatten() {
+            let game = &self.games[game_id];
+            let Some(opponent) = game.opponent_of(user_id) else {
+                continue;
+            };
+            let json = json!({
+                "gameId": game_id,
+                "index": opponent,
+                "ships": game.ships_of(&opponent).ships,
+            });
+            self.add_player_event(user_id, "reveal", json);
+        }
+        let names = players
+            .iter()
+            .flatten()
+            .filter_map(|user_id| self.get_user(user_id).map(|user| user.name.clone()))
+            .collect::<Vec<String>>();
+        if let Ok(names) = <[String; 2]>::try_from(names.clone()) {
+            self.shared_replays
+                .keep(&self.games[game_id], names, winner.as_ref());
+        }
+        let winner_name = winner
+            .as_ref()
+            .and_then(|winner| self.get_user(winner))
+            .map(|user| user.name.clone());
+        self.post_to_discord(discord::game_result_embed(
+            winner_name.as_deref(),
+            &names,
+            reason,
+        ));
+        let game = &self.games[game_id];
+        let (shots, hits) = players
+            .iter()
+            .flatten()
+            .fold((0, 0), |(shots, hits), user_id| {
+                let (player_shots, player_hits) = shot_counts(game, user_id);
+                (shots + player_shots, hits + player_hits)
+            });
+        self.game_totals
+            .record_game(&game.settings.ruleset, reason, shots, hits, game.duration());
+        let leader = self.leader();
+        let rating = match &winner {
+            Some(winner) => self.rate_result(game_id, winner),
+            None => Rating::Rated,
+        };
+        // Handicapped games don't count towards the rankings at all.
+        let handicapped = self.games[game_id].settings.handicap.is_some();
+        for user_id in players.iter().flatten() {
+            let game = &self.games[game_id];
+            let (shots, hits) = shot_counts(game, user_id);
+            let opponent = game
+                .opponent_of(user_id)
+                .and_then(|opponent| self.get_user(&opponent))
+                .map(|opponent| opponent.name.clone());
+            let won = winner.as_ref() == Some(user_id);
+            let rated = rating == Rating::Rated && !handicapped;
+            let Some(user) = self.users.get_mut(user_id) else {
+                continue;
+            };
+            user.recent_games.push(RecentGame {
+                game_id: game_id.clone(),
+                opponent,
+                won,
+                reason,
+                rated,
+                shots: shots as u32,
+                hits: hits as u32,
+                finished_at: now_ms(),
+            });
+            if handicapped {
+                continue;
+            }
+            user.stats
+                .record_game(shots as u32, hits as u32, won && rated);
+            if won && rated {
+                user.wins += 1;
+            }
+        }
+        if let Some(new_leader) = self
+            .leader()
+            .filter(|new_leader| Some(new_leader) != leader.as_ref())
+        {
+            let wins = self.users[&new_leader].wins;
+            let name = self.users[&new_leader].name.clone();
+            self.post_to_discord(discord::new_leader_embed(&name, wins));
+        }
+        METRICS.games_finished.inc();
+        self.close_game(game_id);
+        self.add_update_winners_event();
+        self.add_left_game_events(game_id, &players);
+    }
+
+    /// Throws a game away on an operator's request, without a result or
+    /// credits. Its players and spectators get a `finish` first, so their
+    /// clients don't wait on a game that is gone.
+    fn delete_game(&mut self, game_id: &GameId) {
+        let Some(game) = self.games.get(game_id) else {
+            return;
+        };
+        info!("Game '{}' deleted by an operator", game_id);
+        let players = game.players();
+        let json = json!({
+            "winPlayer": null,
+            "reason": FinishReason::Deleted,
+            "seed": game.seed.to_string(),
+        });
+        for user_id in players.iter().flatten() {
+            self.add_player_event(user_id, "finish", json.clone());
+        }
+        self.add_spectator_event(game_id, "finish", json);
+        self.close_game(game_id);
+        self.add_update_room_event();
+        self.add_left_game_events(game_id, &players);
+    }
+
+    /// Sends the user to the node hosting a room or game of another node,
+    /// found by its id or code. Returns whether there is one.
+    fn redirect(&mut self, user_id: &UserId, room: &str) -> bool {
+        let Some((game_id, url)) = self.cluster.locate(room) else {
+            return false;
+        };
+        let json = json!({ "gameId": game_id, "url": url });
+        self.add_player_event(user_id, "redirect", json);
+        true
+    }
+
+    /// Tells the friends of the players of a game that is over.
+    fn add_left_game_events(&mut self, game_id: &GameId, players: &[Option<UserId>]) {
+        for user_id in players.iter().flatten() {
+            if let Some(player) = self.get_user(user_id).cloned() {
+                self.notify_friends(
+                    &player,
+                    "friend_in_game",
+                    json!({
+                        "name": player.name,
+                        "account": player.account,
+                        "inGame": false,
+                        "roomId": game_id,
+                    }),
+                );
+            }
+        }
+    }
+
+    /// Checks a result for win farming, see `farming`.
+    fn rate_result(&mut self, game_id: &GameId, winner: &UserId) -> Rating {
+        let game = &self.games[game_id];
+        if game.settings.handicap.is_some() {
+            return Rating::Excluded;
+        }
+        let Some(loser) = game.opponent_of(winner) else {
+            return Rating::Rated;
+        };
+        let (loser_shots, _) = shot_counts(game, &loser);
+        let (Some(winner), Some(loser)) = (self.get_user(winner), self.get_user(&loser)) else {
+            return Rating::Rated;
+        };
+        let shared_ip = (winner.addr.ip() == loser.addr.ip()).then(|| winner.addr.ip());
+        let (winner_name, loser_name) = (winner.name.clone(), loser.name.clone());
+        let rating = self
+            .farming
+            .record(&winner_name, &loser_name, shared_ip, loser_shots);
+        if rating != Rating::Rated {
+            warn!(
+                "Win of '{}' over '{}' not rated: {:?}",
+                winner_name, loser_name, rating
+            );
+            self.audit.record(
+                "server",
+                "unrated_result",
+                json!({ "game": game_id, "winner": winner_name, "loser": loser_name }),
+            );
+        }
+        rating
+    }
+
+    /// The user with strictly the most wins, if any.
+    fn leader(&self) -> Option<UserId> {
+        let mut users = self.users.values().collect::<Vec<&User>>();
+        users.sort_by(|a, b| rank(a, b));
+        match users.as_slice() {
+            [first, second, ..]
+                if first.wins == second.wins
+                    && stats::tie_break(&first.stats, &second.stats).is_eq() =>
+            {
+                None
+            }
+            [first, ..] if first.wins > 0 => Some(first.id.clone()),
+            _ => None,
+        }
+    }
+
+    fn post_to_discord(&self, embed: serde_json::Value) {
+        if let Some(discord) = &self.discord {
+            let _ = discord.unbounded_send(embed);
+        }
+    }
+
+    fn add_error_event(&mut self, user_id: &UserId, message: impl Into<Message>) {
+        let Some(user) = self.users.get(user_id) else {
+            return;
+        };
+        let json = error_json(&message.into(), user.locale);
+        self.add_player_event(user_id, "error", json);
+    }
+
+    /// Queues an event for one player, wherever they are connected when it
+    /// is dispatched.
+    fn add_player_event(&mut self, user_id: &UserId, event_type: &str, data: serde_json::Value) {
+        if self.users.contains_key(user_id) {
+            let json = create_event_json(data, event_type.into());
+            self.sessions.record(user_id, &json);
+            self.add_event(&ServerEvent::Player(user_id.clone(), json));
+        }
+    }
+
+    /// Sends a public event of the game to its spectators and keeps it for
+    /// those joining later.
+    fn add_spectator_event(&mut self, game_id: &GameId, event_type: &str, data: serde_json::Value) {
+        let json = create_event_json(data, event_type.into());
+        for user_id in self.spectators.record(game_id, &json) {
+            self.sessions.record(&user_id, &json);
+            self.add_event(&ServerEvent::Player(user_id, json.clone()));
+        }
+    }
+
+    fn share_replay(&mut self, user: &User, game_id: GameId, anonymize: bool) {
+        match self.shared_replays.share(&game_id, &user.id, anonymize) {
+            Ok((replay_id, anonymized)) => {
+                info!("User '{}' shared the replay of '{}'", user.name, game_id);
+                self.add_player_event(
+                    &user.id,
+                    "share_replay",
+                    json!({ "gameId": game_id, "replayId": replay_id, "anonymized": anonymized }),
+                );
+            }
+            Err(err) => self.add_error_event(&user.id, err),
+        }
+    }
+
+    fn spectate(&mut self, user: &User, game_id: GameId) {
+        let Some(game) = self.games.get(&game_id) else {
+            if self.redirect(&user.id, &game_id) {
+                return;
+            }
+            self.add_error_event(&user.id, MessageId::GameNotFound);
+            return;
+        };
+        if matches!(game.status(), GameStatus::Waiting) {
+            self.add_error_event(&user.id, MessageId::SpectatingBeforeStart);
+            return;
+        }
+        if game.is_player(&user.id) {
+            self.add_error_event(&user.id, MessageId::SpectatingOwnGame);
+            return;
+        }
+        let json = json!({
+            "gameId": game_id,
+            "players": game.players(),
+            "settings": game.settings,
+        });
+        let history = match self
+            .spectators
+            .join(&game_id, &user.id, game.settings.max_spectators)
+        {
+            Ok(history) => history,
+            Err(err) => {
+                info!(
+                    "User '{}' turned away from full game '{}'",
+                    user.name, game_id
+                );
+                self.add_error_event(&user.id, err);
+                return;
+            }
+        };
+        info!("User '{}' is spectating game '{}'", user.name, game_id);
+        self.add_player_event(&user.id, "spectate", json);
+        for json in history {
+            self.sessions.record(&user.id, &json);
+            self.add_event(&ServerEvent::Player(user.id.clone(), json));
+        }
+    }
+
+    /// A read-only stream of a game's public events, see `http`.
+    pub(crate) fn open_stream(
+        &mut self,
+        game_id: &GameId,
+    ) -> Result<(Vec<String>, UnboundedReceiver<String>), MessageId> {
+        let game = self.games.get(game_id).ok_or(MessageId::GameNotFound)?;
+        if matches!(game.status(), GameStatus::Waiting) {
+            return Err(MessageId::SpectatingBeforeStart);
+        }
+        let room_limit = game.settings.max_spectators;
+        self.spectators.stream(game_id, room_limit)
+    }
+
+    fn stop_spectating(&mut self, user: &User, game_id: GameId) {
+        if !self.spectators.leave(&game_id, &user.id) {
+            self.add_error_event(&user.id, MessageId::NotSpectating);
+            return;
+        }
+        self.add_player_event(&user.id, "stop_spectating", json!({ "gameId": game_id }));
+    }
+
+    /// Running games that still take spectators, for a "watch" tab. The
+    /// one whose players have the most wins between them comes first and
+    /// is marked as featured.
+    fn list_live_games(&mut self, user: &User) {
+        let mut games = self
+            .games
+            .values()
+            .filter(|game| {
+                matches!(
+                    game.status(),
+                    GameStatus::PlacingShips | GameStatus::Started
+                )
+            })
+            .filter(|game| {
+                self.spectators
+                    .has_room(&game.id, game.settings.max_spectators)
+            })
+            .map(|game| {
+                let players = game
+                    .players()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|user_id| self.get_user(&user_id))
+                    .map(|user| json!({ "index": user.id, "name": user.name, "wins": user.wins }))
+                    .collect::<Vec<_>>();
+                let wins = players
+                    .iter()
+                    .filter_map(|player| player["wins"].as_u64())
+                    .sum::<u64>();
+                let json = json!({
+                    "gameId": game.id,
+                    "status": game.status(),
+                    "players": players,
+                    "moves": game.shots().len(),
+                    "spectators": self.spectators.count(&game.id),
+                    "featured": false,
+                });
+                (wins, json)
+            })
+            .collect::<Vec<_>>();
+        games.sort_by(|(a_wins, a), (b_wins, b)| {
+            b_wins
+                .cmp(a_wins)
+                .then_with(|| a["gameId"].as_str().cmp(&b["gameId"].as_str()))
+        });
+        let mut games = games.into_iter().map(|(_, json)| json).collect::<Vec<_>>();
+        if let Some(featured) = games.first_mut() {
+            featured["featured"] = json!(true);
+        }
+        self.add_player_event(&user.id, "live_games", json!(games));
+    }
+
+    /// Follows the feed again and sends what it shows now, see
+    /// `subscriptions`.
+    fn subscribe(&mut self, user: &User, feed: Feed) {
+        if self.subscriptions.subscribe(&user.id, feed) {
+            match feed {
+                Feed::Rooms if self.room_deltas.clients().contains(&user.id) => {
+                    self.room_deltas.resync(&user.id);
+                    self.add_update_room_event();
+                }
+                Feed::Rooms => {
+                    self.add_player_event(&user.id, "update_room", json!(self.rooms_json()));
+                }
+                Feed::Winners if self.winner_deltas.clients().contains(&user.id) => {
+                    self.winner_deltas.resync(&user.id);
+                    self.add_update_winners_event();
+                }
+                Feed::Winners => {
+                    self.add_player_event(&user.id, "update_winners", json!(self.winners_json()));
+                }
+                Feed::Population => {
+                    self.add_player_event(&user.id, "update_population", self.population_json());
+                }
+                Feed::Presence => {
+                    let json = self.presence_json(&self.statuses());
+                    self.add_player_event(&user.id, "update_presence", json);
+                }
+            }
+        }
+        self.add_subscriptions_event(&user.id);
+    }
+
+    fn unsubscribe(&mut self, user: &User, feed: Feed) {
+        self.subscriptions.unsubscribe(&user.id, feed);
+        self.add_subscriptions_event(&user.id);
+    }
+
+    fn add_subscriptions_event(&mut self, user_id: &UserId) {
+        let feeds = self.subscriptions.feeds(user_id);
+        self.add_player_event(user_id, "subscriptions", json!({ "feeds": feeds }));
+    }
+
+    /// Closes the user's rooms and forgets the user.
+    fn remove_user(&mut self, user_id: &UserId) {
+        let Some(user) = self.get_user(user_id).cloned() else {
+            return;
+        };
+        // Left while the user is still known, so the games they forfeit are
+        // rated and checked for win farming like any other.
+        if !user.rooms.is_empty() {
+            for room_id in user.rooms.iter() {
+                self.leave_room(room_id, user_id);
+            }
+            self.add_update_room_event();
+        }
+        self.users.remove(user_id);
+        self.user_ids.remove(&user.addr);
+        self.sessions.close(user_id);
+        self.deadlines
+            .schedule(Deadline::Grace(user_id.clone()), None);
+        self.presence.leave(user_id);
+        self.matchmaking.cancel(user_id);
+        self.room_throttle.forget(user_id);
+        self.move_throttle.forget(user_id);
+        self.practice.stop(user_id);
+        self.room_deltas.leave(user_id);
+        self.winner_deltas.leave(user_id);
+        self.subscriptions.leave(user_id);
+        self.spectators.leave_all(user_id);
+        info!("User '{}' disconnected ({})", user.name, user.addr);
+        self.deletion_codes.remove(&user.id);
+        self.audit
+            .record(&user.name, "disconnect", json!({ "user": user.id }));
+        self.add_update_winners_event();
+        self.notify_friends(
+            &user,
+            "friend_online",
+            json!({
+                "name": user.name,
+                "account": user.account,
+                "online": false,
+            }),
+        );
+        if let Some(account) = &user.account {
+            self.seen(account);
+            self.friends.sign_out(account);
+        }
+    }
+
+    fn quickplay(&mut self, user: &User) {
+        if !self.features.is_enabled(Feature::Matchmaking) {
+            self.add_error_event(&user.id, MessageId::MatchmakingUnavailable);
+            return;
+        }
+        if self.is_in_game(user) {
+            self.add_error_event(&user.id, MessageId::AlreadyInGame);
+            return;
+        }
+        if self.matchmaking.is_searching(&user.id) {
+            return;
+        }
+        let Some(opponent) = self.matchmaking.search(&user.id, user.wins) else {
+            info!("User '{}' is searching for an opponent", user.name);
+            self.add_queue_status_event(&user.id);
+            self.add_population_event();
+            return;
+        };
+        let Some(owner) = self.get_user(&opponent).cloned() else {
+            // They left meanwhile, so the search goes on.
+            self.quickplay(user);
+            return;
+        };
+        info!("Matched '{}' with '{}'", owner.name, user.name);
+        if !limits::has_room(self.limits.max_games, self.games.len()) {
+            warn!(
+                "Match of '{}' and '{}' rejected: too many games",
+                owner.name, user.name
+            );
+            self.add_error_event(&owner.id, MessageId::ServerFull);
+            self.add_error_event(&user.id, MessageId::ServerFull);
+            self.add_population_event();
+            return;
+        }
+        if !self.check_room_limit(user) {
+            self.matchmaking.requeue(&owner.id, owner.wins);
+            return;
+        }
+        match self.create_game(&owner, &RoomSettings::default()) {
+            Some(game_id) => {
+                self.join_game(game_id, user, false);
+            }
+            // The owner was told why, the player searches on.
+            None => self.quickplay(user),
+        }
+    }
+
+    fn cancel_search(&mut self, user: &User) {
+        if !self.matchmaking.cancel(&user.id) {
+            self.add_error_event(&user.id, MessageId::NotSearching);
+            return;
+        }
+        info!("User '{}' stopped searching", user.name);
+        self.add_player_event(&user.id, "cancel_search", json!({ "cancelled": true }));
+        self.add_population_event();
+    }
+
+    fn add_queue_status_event(&mut self, user_id: &UserId) {
+        if let Some(status) = self.matchmaking.status_json(user_id) {
+            self.add_player_event(user_id, "queue_status", status);
+        }
+    }
+
+    /// Changes how long waiting rooms outlive the activity of their owner,
+    /// for the rooms already open too.
+    pub fn set_room_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.room_idle_timeout = timeout;
+        let waiting = self
+            .games
+            .values()
+            .filter(|game| matches!(game.status(), GameStatus::Waiting))
+            .map(|game| game.id.clone())
+            .collect::<Vec<GameId>>();
+        for game_id in waiting.iter() {
+            self.schedule_idle_room(game_id);
+        }
+    }
+
+    /// Makes a strategy registered by the embedder available to resident
+    /// bots under `name`, see `bot_plugins`.
+    pub fn register_bot_strategy(&mut self, name: &str, strategy: Arc<dyn BotStrategy>) {
+        self.matchmaking.register_strategy(name, strategy);
+    }
+
+    /// Adds a bot that stays between games, playing the strategy called
+    /// `strategy`.
+    pub(crate) fn add_resident_bot(&mut self, name: &str, strategy: &str) -> Result<User, String> {
+        if name.is_empty()
+            || matchmaking::is_reserved_name(name)
+            || self.get_user_by_name(name).is_some()
+        {
+            return Err(format!("Name {} is taken", name));
+        }
+        let brain = self
+            .matchmaking
+            .brain(strategy)
+            .ok_or_else(|| format!("Unknown strategy {}", strategy))?;
+        let bot = User {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_owned(),
+            account: None,
+            addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            wins: 0,
+            rooms: HashSet::new(),
+            rtt_ms: None,
+            stats: PlayerStats::default(),
+            recent_games: RecentGames::default(),
+            locale: Locale::default(),
+            bot: true,
+        };
+        info!("Resident bot '{}' playing {}", name, brain);
+        self.matchmaking.add_resident(&bot.id, brain);
+        self.users.insert(bot.id.clone(), bot.clone());
+        self.add_update_winners_event();
+        Ok(bot)
+    }
+
+    /// The resident bot closest to the player in wins.
+    fn resident_opponent(&self, user: &User) -> Option<User> {
+        self.matchmaking
+            .residents()
+            .filter_map(|bot_id| self.get_user(bot_id))
+            .min_by_key(|bot| (bot.wins.abs_diff(user.wins), bot.name.clone()))
+            .cloned()
+    }
+
+    /// Gives a player nobody was found for a bot to play against: a
+    /// resident one if there is any, or one just for them.
+    fn start_bot_game(&mut self, user_id: &UserId) {
+        let Some(user) = self.get_user(user_id).cloned() else {
+            return;
+        };
+        let bot = match self.resident_opponent(&user) {
+            Some(bot) => {
+                info!(
+                    "No opponent for '{}', matching them with '{}'",
+                    user.name, bot.name
+                );
+                bot
+            }
+            None => self.add_one_off_bot(&user),
+        };
+        let Some(game_id) = self.create_game(&user, &RoomSettings::default()) else {
+            return;
+        };
+        if self.join_game(game_id.clone(), &bot, false) {
+            let ships = matchmaking::bot_fleet(&self.games[&game_id]);
+            let bot = self.users[&bot.id].clone();
+            self.add_ships_to_game(&bot, game_id, ships);
+        }
+    }
+
+    fn add_one_off_bot(&mut self, user: &User) -> User {
+        let bot = User {
+            id: Uuid::new_v4().to_string(),
+            name: self.matchmaking.one_off_bot_name(),
+            account: None,
+            addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            wins: 0,
+            rooms: HashSet::new(),
+            rtt_ms: None,
+            stats: PlayerStats::default(),
+            recent_games: RecentGames::default(),
+            locale: Locale::default(),
+            bot: false,
+        };
+        let strategy = self.matchmaking.add_bot(&bot.id, user.wins);
+        // Bots have no socket, so they are never in `user_ids`.
+        self.users.insert(bot.id.clone(), bot.clone());
+        info!(
+            "No opponent for '{}', starting a {} bot",
+            user.name, strategy
+        );
+        bot
+    }
+
+    /// Makes the move of every bot whose turn it is, and lets bots without
+    /// a game go.
+    fn play_bots(&mut self) {
+        for bot_id in self.matchmaking.bot_ids() {
+            let Some(bot) = self.get_user(&bot_id).cloned() else {
+                self.matchmaking.remove_bot(&bot_id);
+                continue;
+            };
+            if bot.rooms.is_empty() && !self.matchmaking.is_resident(&bot_id) {
+                self.users.remove(&bot_id);
+                self.matchmaking.remove_bot(&bot_id);
+                if bot.bot {
+                    info!("Retired bot '{}' left", bot.name);
+                    self.add_update_winners_event();
+                }
+                continue;
+            }
+            for game_id in bot.rooms.iter() {
+                let Some(game) = self.games.get_mut(game_id) else {
+                    continue;
+                };
+                if !matches!(game.status(), GameStatus::Started)
+                    || game.current_player().as_ref() != Some(&bot_id)
+                {
+                    continue;
+                }
+                if let Some(target) = self.matchmaking.bot_target(game, &bot_id) {
+                    self.supervise(game_id, |state| {
+                        state.attack(&bot, game_id.clone(), Some((target.x, target.y)))
+                    });
+                }
+            }
+        }
+    }
+
+    /// Runs `work` on a game and ends the game if it panics, so one broken
+    /// game can't stop the timers or bots of every other.
+    fn supervise(&mut self, game_id: &GameId, work: impl FnOnce(&mut Self)) {
+        if panic::catch_unwind(AssertUnwindSafe(|| work(self))).is_err() {
+            error!("Handling game '{}' panicked", game_id);
+            self.end_failed_game(game_id);
+        }
+    }
+
+    /// Ends a game the server failed handling rather than leaving it half
+    /// updated. Its players and spectators get a `finish` without a winner.
+    fn end_failed_game(&mut self, game_id: &GameId) {
+        if !self.games.contains_key(game_id) {
+            return;
+        }
+        warn!("Ending game '{}' after a failure", game_id);
+        let ended = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.end_game(game_id, None, FinishReason::Crashed)
+        }));
+        if ended.is_err() {
+            error!("Ending game '{}' panicked too, dropping it", game_id);
+            self.close_game(game_id);
+        }
+        self.add_update_room_event();
+    }
+
+    /// Takes a leaving player out of a room. A game under way is won by the
+    /// other player; a room that hasn't started yet goes back to the lobby
+    /// with the other player as its owner; any other room is closed.
+    fn leave_room(&mut self, game_id: &GameId, user_id: &UserId) {
+        let forfeited = self
+            .games
+            .get(game_id)
+            .filter(|game| game.is_under_way())
+            .and_then(|game| game.opponent_of(user_id));
+        if let Some(winner) = forfeited {
+            info!("Game '{}' forfeited by '{}'", game_id, user_id);
+            self.end_game(game_id, Some(winner), FinishReason::Forfeit);
+            return;
+        }
+        let remaining = self.games.get_mut(game_id).and_then(|game| {
+            game.apply(Transition::Leave(user_id.clone())).ok()?;
+            game.players()[0].clone()
+        });
+        let Some(owner) = remaining else {
+            self.close_game(game_id);
+            info!("Room '{}' closed - player left", game_id);
+            return;
+        };
+        info!("Room '{}' reopened, owned by '{}'", game_id, owner);
+        self.schedule_idle_room(game_id);
+        if let Some(name) = self.get_user(&owner).map(|user| user.name.clone()) {
+            self.audit
+                .record(&name, "reopen_room", json!({ "game": game_id }));
+        }
+        self.add_player_event(
+            &owner,
+            "room_owner",
+            json!({ "roomId": game_id, "index": owner }),
+        );
+    }
+
+    /// Leaves every room of the user still waiting for an opponent, and
+    /// tells whether there was any.
+    fn leave_waiting_rooms(&mut self, user: &User) -> bool {
+        let waiting = user
+            .rooms
+            .iter()
+            .filter(|room| {
+                self.games
+                    .get(*room)
+                    .is_some_and(|game| matches!(game.status(), GameStatus::Waiting))
+            })
+            .cloned()
+            .collect::<Vec<GameId>>();
+        for room_id in waiting.iter() {
+            self.leave_room(room_id, &user.id);
+            if let Some(user) = self.users.get_mut(&user.id) {
+                user.rooms.remove(room_id);
+            }
+        }
+        !waiting.is_empty()
+    }
+
+    /// Closes the waiting rooms among `rooms` whose owner has been idle for
+    /// longer than `room_idle_timeout`, with a single `update_room` for all
+    /// of them. The others are scheduled again.
+    fn expire_idle_rooms(&mut self, rooms: Vec<GameId>) {
+        let Some(timeout) = self.room_idle_timeout else {
+            return;
+        };
+        let mut expired = false;
+        for game_id in rooms {
+            let owner = self
+                .games
+                .get(&game_id)
+                .filter(|game| matches!(game.status(), GameStatus::Waiting))
+                .and_then(|game| game.players()[0].clone())
+                .filter(|owner| !self.matchmaking.is_bot(owner))
+                .filter(|owner| self.presence.is_idle(owner, timeout));
+            let Some(owner) = owner else {
+                self.schedule_idle_room(&game_id);
+                continue;
+            };
+            info!("Room '{}' expired - owner idle", game_id);
+            self.close_game(&game_id);
+            self.add_player_event(&owner, "room_expired", json!({ "roomId": game_id }));
+            expired = true;
+        }
+        if expired {
+            self.add_update_room_event();
+        }
+    }
+
+    /// Closes a room and forgets it in the membership of every other player.
+    fn close_game(&mut self, game_id: &GameId) {
+        self.spectators.close(game_id);
+        self.room_codes.release(game_id);
+        self.cluster.release(game_id);
+        let game = self.games.remove(game_id);
+        self.schedule_deadlines(game_id);
+        self.schedule_idle_room(game_id);
+        if let Some(game) = game {
+            for user_id in game.players().into_iter().flatten() {
+                if let Some(user) = self.users.get_mut(&user_id) {
+                    user.rooms.remove(game_id);
+                }
+            }
+        }
+    }
+
+    fn get_user_by_name(&self, name: &str) -> Option<&User> {
+        self.users.values().find(|user| user.name == name)
+    }
+
+    fn is_in_game(&self, user: &User) -> bool {
+        user.rooms.iter().any(|room| {
+            self.games
+                .get(room)
+                .map(|game| !matches!(game.status(), GameStatus::Waiting))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Tells the opponents in the user's running games that the user's
+    /// connection dropped or came back. A dropped player's opponents learn
+    /// how long they may wait, in `graceMs`.
+    fn add_opponent_connection_event(&mut self, user: &User, event_type: &str) {
+        let grace = self.sessions.remaining_grace(&user.id);
+        let opponents = user
+            .rooms
+            .iter()
+            .filter_map(|room| self.games.get(room))
+            .filter(|game| !matches!(game.status(), GameStatus::Waiting))
+            .filter_map(|game| Some((game.id.clone(), game.opponent_of(&user.id)?)))
+            .collect::<Vec<(GameId, UserId)>>();
+        for (game_id, opponent) in opponents {
+            let mut json = json!({ "gameId": game_id, "index": user.id });
+            if let Some(grace) = grace {
+                json["graceMs"] = json!(grace.as_millis() as u64);
+            }
+            self.add_player_event(&opponent, event_type, json);
+        }
+    }
+
+    /// The player online under `account`, if any.
+    fn get_user_by_account(&self, account: &AccountId) -> Option<&User> {
+        self.users
+            .values()
+            .find(|user| user.account.as_ref() == Some(account))
+    }
+
+    fn friend_json(&self, account: &AccountId) -> serde_json::Value {
+        json!({ "account": account, "name": self.friends.name_of(account) })
+    }
+
+    /// The account a friend command is about. Names are looked up among
+    /// `known`, the accounts the player already has to do with, before the
+    /// players online.
+    fn resolve_friend(&self, friend: &FriendRef, known: &[AccountId]) -> Option<AccountId> {
+        match friend {
+            FriendRef::Account(account) => {
+                Some(account.clone()).filter(|account| self.friends.has_account(account))
+            }
+            FriendRef::Name(name) => known
+                .iter()
+                .find(|account| self.friends.name_of(account) == Some(name.as_str()))
+                .cloned()
+                .or_else(|| self.get_user_by_name(name)?.account.clone()),
+        }
+    }
+
+    fn add_update_friends_event(&mut self, user_id: &UserId) {
+        let Some(account) = self.get_user(user_id).and_then(|user| user.account.clone()) else {
+            return;
+        };
+        let friends = self
+            .friends
+            .friends_of(&account)
+            .into_iter()
+            .map(|friend| {
+                let online = self.get_user_by_account(&friend);
+                let mut json = self.friend_json(&friend);
+                json["online"] = json!(online.is_some());
+                json["inGame"] = json!(online
+                    .map(|friend| self.is_in_game(friend))
+                    .unwrap_or(false));
+                json["presence"] =
+                    json!(online.map_or(Status::Offline, |friend| self.presence_of(friend)));
+                json
+            })
+            .collect::<Vec<serde_json::Value>>();
+        let requests = self
+            .friends
+            .requests_to(&account)
+            .iter()
+            .map(|requester| self.friend_json(requester))
+            .collect::<Vec<serde_json::Value>>();
+        let json = json!({
+            "friends": friends,
+            "requests": requests,
+        });
+        self.add_player_event(user_id, "update_friends", json);
+    }
+
+    /// Updates the friend list of whoever is online under `account`.
+    fn add_update_friends_event_for(&mut self, account: &AccountId) {
+        if let Some(friend) = self.get_user_by_account(account) {
+            let friend_id = friend.id.clone();
+            self.add_update_friends_event(&friend_id);
+        }
+    }
+
+    fn notify_friends(&mut self, user: &User, event_type: &str, data: serde_json::Value) {
+        let Some(account) = &user.account else {
+            return;
+        };
+        for friend in self.friends.friends_of(account) {
+            if let Some(friend) = self.get_user_by_account(&friend) {
+                let friend_id = friend.id.clone();
+                self.add_player_event(&friend_id, event_type, data.clone());
+            }
+        }
+    }
+
+    fn request_friend(&mut self, user: &User, friend: FriendRef) {
+        let Some(account) = &user.account else {
+            return;
+        };
+        let Some(target) = self.resolve_friend(&friend, &[]) else {
+            warn!(
+                "Friend request from '{}' to unknown {:?}",
+                user.name, friend
+            );
+            self.add_error_event(&user.id, MessageId::PlayerNotFound);
+            return;
+        };
+        let result = self.friends.request(account, &target);
+        if matches!(
+            result,
+            FriendRequestResult::Sent | FriendRequestResult::Accepted
+        ) {
+            self.audit
+                .record(&user.name, "add_friend", json!({ "account": target }));
+        }
+        match result {
+            FriendRequestResult::Sent => {
+                if let Some(friend) = self.get_user_by_account(&target) {
+                    let friend_id = friend.id.clone();
+                    self.add_player_event(
+                        &friend_id,
+                        "friend_request",
+                        json!({ "name": user.name, "account": account }),
+                    );
+                }
+            }
+            FriendRequestResult::Accepted => {
+                self.add_update_friends_event_for(&target);
+            }
+            FriendRequestResult::TooMany => {
+                warn!(
+                    "Friend request from '{}' rejected: too many pending",
+                    user.name
+                );
+                self.add_error_event(&user.id, MessageId::TooManyFriendRequests);
+                return;
+            }
+            FriendRequestResult::AlreadyFriends
+            | FriendRequestResult::AlreadySent
+            | FriendRequestResult::Invalid => {
+                warn!("Friend request from '{}' to {} ignored", user.name, target);
+                return;
+            }
+        }
+        self.add_update_friends_event(&user.id);
+    }
+
+    fn accept_friend(&mut self, user: &User, friend: FriendRef) {
+        let Some(account) = &user.account else {
+            return;
+        };
+        let requesters = self.friends.requests_to(account);
+        let accepted = self
+            .resolve_friend(&friend, &requesters)
+            .filter(|requester| self.friends.accept(account, requester));
+        let Some(requester) = accepted else {
+            warn!("No friend request from {:?} to '{}'", friend, user.name);
+            return;
+        };
+        self.audit
+            .record(&user.name, "accept_friend", json!({ "account": requester }));
+        self.add_update_friends_event(&user.id);
+        self.add_update_friends_event_for(&requester);
+    }
+
+    fn remove_friend(&mut self, user: &User, friend: FriendRef) {
+        let Some(account) = &user.account else {
+            return;
+        };
+        let mut known = self.friends.friends_of(account);
+        known.extend(self.friends.requests_to(account));
+        known.extend(self.friends.requests_from(account));
+        let Some(other) = self.resolve_friend(&friend, &known) else {
+            return;
+        };
+        if self.friends.remove(account, &other) {
+            self.audit
+                .record(&user.name, "remove_friend", json!({ "account": other }));
+            self.add_update_friends_event(&user.id);
+            self.add_update_friends_event_for(&other);
+        }
+    }
+
+    /// Sends the caller everything they could have learned from past events,
+    /// so a client that reloaded or missed events can rebuild its view.
+    fn sync(&mut self, user: &User) {
+        let games = user
+            .rooms
+            .iter()
+            .filter_map(|game_id| self.games.get(game_id))
+            .map(|game| {
+                let opponent = game.opponent_of(&user.id);
+                let mut json = with_settings(
+                    json!({
+                        "idGame": game.id,
+                        "status": game.status(),
+                        "players": game.players(),
+                        "score": game.score,
+                        "ships": game.ships_of(&user.id).ships,
+                        "board": game.board(&user.id),
+                        "ownBoard": cells_json(game.revealed_cells(&user.id)),
+                        "enemyBoard": opponent
+                            .map(|opponent| cells_json(game.revealed_cells(&opponent)))
+                            .unwrap_or_default(),
+                        "currentPlayer": game.current_player(),
+                        "checksum": game.checksum(),
+                    }),
+                    &game.settings,
+                );
+                if let Some(clocks) = clocks_json(game) {
+                    json["clocks"] = clocks;
+                }
+                json
+            })
+            .collect::<Vec<_>>();
+        let json = json!({
+            "name": user.name,
+            "index": user.id,
+            "wins": user.wins,
+            "stats": user.stats.json(),
+            "rooms": user.rooms,
+            "games": games,
+        });
+        self.add_player_event(&user.id, "sync", json);
+    }
+
+    /// A client's view of a game no longer matches the server's, which
+    /// points at events lost or sent out of order. The client may also just
+    /// be a turn behind.
+    fn log_desync(&self, user: &User, game_id: &GameId, checksum: &str) {
+        let Some(game) = self.games.get(game_id) else {
+            return;
+        };
+        let expected = game.checksum();
+        if checksum == expected {
+            return;
+        }
+        METRICS.desyncs.inc();
+        let _game_span = error_span!("game", game = %game_id).entered();
+        warn!(
+            "Client of '{}' is out of step: checksum {}, expected {}",
+            user.name, checksum, expected
+        );
+    }
+
+    fn start_practice(&mut self, user: &User, board_size: Option<usize>) {
+        let board_size = board_size.unwrap_or(DEFAULT_BOARD_SIZE);
+        let seed = self.practice_seed.unwrap_or_else(rand::random);
+        let json = self.practice.start(&user.id, board_size, seed);
+        info!("User '{}' started practicing, seed {}", user.name, seed);
+        self.add_player_event(&user.id, "practice_start", json);
+    }
+
+    fn practice_attack(&mut self, user: &User, x: u8, y: u8) {
+        match self.practice.attack(&user.id, x, y) {
+            Ok(json) => self.add_player_event(&user.id, "practice_attack", json),
+            Err(err) => self.add_error_event(&user.id, err),
+        }
+    }
+
+    fn stop_practice(&mut self, user: &User) {
+        if !self.practice.stop(&user.id) {
+            self.add_error_event(&user.id, MessageId::NotPracticing);
+            return;
+        }
+        self.add_player_event(&user.id, "practice_stop", json!({ "stopped": true }));
+    }
+
+    fn export_data(&mut self, user: &User) {
+        let friends = |accounts: Vec<AccountId>| {
+            accounts
+                .iter()
+                .map(|account| self.friend_json(account))
+                .collect::<Vec<_>>()
+        };
+        let account = user.account.clone().unwrap_or_default();
+        let json = json!({
+            "name": user.name,
+            "index": user.id,
+            "wins": user.wins,
+            "stats": user.stats.json(),
+            "recentGames": user.recent_games,
+            "rooms": user.rooms,
+            "account": user.account,
+            "friends": friends(self.friends.friends_of(&account)),
+            "friendRequests": friends(self.friends.requests_to(&account)),
+            "sentFriendRequests": friends(self.friends.requests_from(&account)),
+        });
+        self.add_player_event(&user.id, "export_my_data", json);
+    }
+
+    /// Deletion is irreversible, so it takes two steps: the first request
+    /// returns a code and only a request repeating that code purges the data.
+    fn delete_account(&mut self, user: &User, code: Option<String>) {
+        let confirmed = match (&code, self.deletion_codes.get(&user.id)) {
+            (Some(code), Some(expected)) => code == expected,
+            _ => false,
+        };
+        if !confirmed {
+            if code.is_some() {
+                warn!("Wrong deletion code from '{}'", user.name);
+            }
+            let code = Uuid::new_v4().simple().to_string();
+            self.deletion_codes.insert(user.id.clone(), code.clone());
+            self.add_player_event(
+                &user.id,
+                "delete_account",
+                json!({ "deleted": false, "confirmationCode": code }),
+            );
+            return;
+        }
+
+        info!("Deleting account '{}'", user.name);
+        self.audit
+            .record(&user.name, "delete_account", json!({ "user": user.id }));
+        // Sent to the socket: the user is gone by the time player events
+        // are routed.
+        self.add_event(&ServerEvent::User(
+            user.addr,
+            create_event_json(json!({ "deleted": true }), "delete_account".into()),
+        ));
+        self.remove_user(&user.id);
+        self.shared_replays.forget(&user.id);
+        if let Some(account) = &user.account {
+            self.last_seen.remove(account);
+            for friend in self.friends.forget(account) {
+                self.add_update_friends_event_for(&friend);
+            }
+        }
+        self.add_update_room_event();
+        self.add_update_winners_event();
+        self.add_event(&ServerEvent::Close(user.addr, CloseReason::AccountDeleted));
+    }
+
+    /// Notes that `account` is connected now, in memory and in the friends
+    /// file.
+    fn seen(&mut self, account: &AccountId) {
+        self.last_seen.insert(account.clone(), clock::now());
+        self.friends.seen(account, now_ms());
+    }
+
+    /// Accounts loaded from the friends file as last seen when they were
+    /// saved, or now if that isn't known, so the retention job forgets
+    /// them even if they never connect again.
+    fn load_last_seen(&mut self) {
+        let (now, now_ms) = (clock::now(), now_ms());
+        self.last_seen = self
+            .friends
+            .last_seen()
+            .map(|(account, seen_ms)| {
+                let ago = Duration::from_millis(now_ms.saturating_sub(seen_ms));
+                let seen = match seen_ms {
+                    0 => now,
+                    _ => now.checked_sub(ago).unwrap_or(now),
+                };
+                (account.clone(), seen)
+            })
+            .collect();
+    }
+
+    fn apply_retention(&mut self, policy: &RetentionPolicy) {
+        if let Some(max_age) = policy.inactive_accounts {
+            let online = self
+                .users
+                .values()
+                .filter_map(|user| user.account.clone())
+                .collect::<HashSet<AccountId>>();
+            let expired = self
+                .last_seen
+                .iter()
+                .filter(|(account, seen)| {
+                    !online.contains(*account) && clock::since(**seen) > max_age
+                })
+                .map(|(account, _)| account.clone())
+                .collect::<Vec<AccountId>>();
+            for account in expired {
+                info!(
+                    "Forgetting inactive account '{}'",
+                    self.friends.name_of(&account).unwrap_or(&account)
+                );
+                self.last_seen.remove(&account);
+                for friend in self.friends.forget(&account) {
+                    self.add_update_friends_event_for(&friend);
+                }
+            }
+        }
+        if let Some(max_age) = policy.audit_files {
+            self.audit.remove_rotated_older_than(max_age);
+        }
+    }
+
+    fn challenge_friend(&mut self, user: &User, friend: FriendRef) {
+        let Some(account) = &user.account else {
+            return;
+        };
+        let friends = self.friends.friends_of(account);
+        let Some(other) = self
+            .resolve_friend(&friend, &friends)
+            .filter(|other| self.friends.are_friends(account, other))
+        else {
+            warn!("{:?} is not a friend of '{}'", friend, user.name);
+            return;
+        };
+        let friend = match self.get_user_by_account(&other) {
+            Some(friend) if !self.is_in_game(friend) => friend.clone(),
+            _ => {
+                warn!("Friend {} is not available", other);
+                return;
+            }
+        };
+        if let Some(game_id) = self.create_game(user, &RoomSettings::default()) {
+            self.audit.record(
+                &user.name,
+                "challenge_friend",
+                json!({ "name": friend.name, "game": game_id }),
+            );
+            let json = json!({
+                "name": user.name,
+                "account": account,
+                "roomId": game_id,
+                "code": self.room_codes.code_of(&game_id),
+            });
+            self.add_player_event(&friend.id, "friend_challenge", json);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct User {
+    id: UserId,
+    name: String,
+    /// Account the player signed in to, see `friends`. Server bots have none.
+    account: Option<AccountId>,
+    addr: SocketAddr,
+    wins: u32,
+    /// Every room the user currently takes part in, waiting or running.
+    rooms: HashSet<GameId>,
+    /// Round-trip time of the last WebSocket ping.
+    rtt_ms: Option<u64>,
+    stats: PlayerStats,
+    recent_games: RecentGames,
+    /// Language of the texts the user is sent.
+    locale: Locale,
+    /// Registered through `bot_reg`, see `bot_api`.
+    bot: bool,
+}
+
+/// Leaderboard order: most wins first, then the tie-breaks of `stats`, then
+/// by name.
+pub(crate) fn rank(a: &User, b: &User) -> std::cmp::Ordering {
+    b.wins
+        .cmp(&a.wins)
+        .then_with(|| stats::tie_break(&a.stats, &b.stats))
+        .then_with(|| a.name.cmp(&b.name))
+}
+
+/// `limit` entries of a leaderboard from `offset` on, with their rank.
+fn ranked(winners: Vec<serde_json::Value>, offset: usize, limit: usize) -> Vec<serde_json::Value> {
+    winners
+        .into_iter()
+        .enumerate()
+        .skip(offset)
+        .take(limit)
+        .map(|(i, mut entry)| {
+            entry["rank"] = json!(i + 1);
+            entry
+        })
+        .collect()
+}
+
+/// A player's entry of the leaderboard.
+pub(crate) fn winner_json(user: &User) -> serde_json::Value {
+    let mut json = user.stats.json();
+    json["name"] = json!(user.name);
+    json["wins"] = json!(user.wins);
+    if user.bot {
+        json["bot"] = json!(true);
+    }
+    json
+}
+
+#[derive(Serialize)]
+struct ServerEventData {
+    #[serde(rename = "type")]
+    type_str: String,
+    data: String,
+    id: u32,
+    /// When the event was created, in milliseconds since the Unix epoch.
+    ts: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    User(SocketAddr, String),
+    /// An event for a player, resolved to their socket by
+    /// `State::take_events`, so it follows them across reconnects.
+    Player(UserId, String),
+    All(String),
+    /// A broadcast to everyone but the given sockets.
+    AllExcept(Vec<SocketAddr>, String),
+    /// A broadcast lobby snapshot of the given kind. Only the newest one of
+    /// each kind is sent per flush, see `State::take_events`.
+    Snapshot(&'static str, String),
+    /// Closes the connection with the given reason.
+    Close(SocketAddr, CloseReason),
+}
+
+/// Events of the lobby lists, which wait behind game events, see
+/// `ServerEvent::priority`.
+const LOBBY_EVENTS: &[&str] = &[
+    "update_room",
+    "room_added",
+    "room_changed",
+    "room_removed",
+    "update_winners",
+    "winners_changed",
+    "update_population",
+    "update_presence",
+];
+
+/// Outbound lane of an event. A connection writes everything waiting in
+/// the game lane before the next lobby refresh, so a busy lobby never
+/// holds up a move.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Moves, replies, errors and everything else that isn't a lobby list.
+    Game,
+    Lobby,
+}
+
+impl ServerEvent {
+    pub fn priority(&self) -> Priority {
+        match self {
+            Self::Snapshot(..) | Self::AllExcept(..) => Priority::Lobby,
+            Self::User(_, json) | Self::Player(_, json) if is_lobby_event(json) => Priority::Lobby,
+            _ => Priority::Game,
+        }
+    }
+}
+
+/// Whether the event text is of a lobby list. `create_event_json` writes
+/// the type first, so only the start of the text is looked at.
+fn is_lobby_event(json: &str) -> bool {
+    json.strip_prefix(r#"{"type":""#)
+        .and_then(|rest| rest.split_once('"'))
+        .is_some_and(|(event_type, _)| LOBBY_EVENTS.contains(&event_type))
+}
+
+/// Why the server closes a connection, sent in the WebSocket Close frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    Kicked,
+    AccountDeleted,
+    /// The client sent something that isn't a protocol frame.
+    ProtocolViolation,
+    UnsupportedData,
+    /// The client didn't read its messages fast enough.
+    TooSlow,
+    Shutdown,
+}
+
+impl CloseReason {
+    /// Close code as defined in RFC 6455, section 7.4.1.
+    pub fn code(&self) -> u16 {
+        match self {
+            CloseReason::AccountDeleted => 1000,
+            CloseReason::Shutdown => 1001,
+            CloseReason::ProtocolViolation => 1002,
+            CloseReason::UnsupportedData => 1003,
+            CloseReason::Kicked | CloseReason::TooSlow => 1008,
+        }
+    }
+
+    pub fn text(&self) -> &'static str {
+        match self {
+            CloseReason::Kicked => "Kicked by admin",
+            CloseReason::AccountDeleted => "Account deleted",
+            CloseReason::ProtocolViolation => "Malformed message",
+            CloseReason::UnsupportedData => "Messages must be UTF-8 JSON",
+            CloseReason::TooSlow => "Connection too slow",
+            CloseReason::Shutdown => "Server is shutting down",
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch, the timestamp format on the wire.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub fn record_rtt(addr: &SocketAddr, rtt: Duration, state: &mut ServerState) {
+    let mut state_lock = lock_state(state);
+    let Some(user_id) = state_lock.user_ids.get(addr).cloned() else {
+        return;
+    };
+    if let Some(user) = state_lock.users.get_mut(&user_id) {
+        user.rtt_ms = Some(rtt.as_millis() as u64);
+    }
+    state_lock.presence.heartbeat(&user_id);
+}
+
+/// Takes the state write lock, recording how long it took to get it. A lock
+/// poisoned by a panicking handler is taken anyway so the server keeps
+/// serving; `/healthz` still reports it.
+pub fn lock_state(state: &ServerState) -> RwLockWriteGuard<'_, State> {
+    let started = Instant::now();
+    let state_lock = state.write().unwrap_or_else(PoisonError::into_inner);
+    METRICS.lock_wait.observe(started.elapsed());
+    faults::hit("state_lock");
+    state_lock
+}
+
+/// `TICK_INTERVAL_MS`, 200 by default; 0 turns the timers off.
+fn tick_interval_from_env() -> Option<Duration> {
+    let millis = std::env::var(TICK_INTERVAL_VAR)
+        .ok()
+        .and_then(|millis| millis.parse::<u64>().ok());
+    match millis {
+        Some(0) => None,
+        Some(millis) => Some(Duration::from_millis(millis)),
+        None => Some(DEFAULT_TICK_INTERVAL),
+    }
+}
+
+/// `ROOM_IDLE_SECONDS`, 120 by default; 0 keeps idle rooms open.
+fn room_idle_timeout_from_env() -> Option<Duration> {
+    let seconds = std::env::var(ROOM_IDLE_SECONDS_VAR)
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok());
+    match seconds {
+        Some(0) => None,
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None => Some(DEFAULT_ROOM_IDLE_TIMEOUT),
+    }
+}
+
+/// `MOTD`, none when unset or empty.
+fn motd_from_env() -> Option<String> {
+    std::env::var(MOTD_VAR).ok().filter(|motd| !motd.is_empty())
+}
+
+/// Timer work: turn clocks, pause limits, expired sessions, idle rooms and
+/// the matchmaking queue. Replies to client messages don't wait for it, so an
+/// embedder without timers can leave it out and set `tick_interval` to
+/// `None`.
+pub fn tick(state: &mut ServerState) {
+    let mut state_lock = lock_state(state);
+    faults::hit("tick");
+    state_lock.last_tick = clock::now();
+    let mut idle_rooms = Vec::new();
+    for deadline in state_lock.deadlines.due(clock::now()) {
+        match deadline {
+            Deadline::TurnClock(game_id) => {
+                let Some(game) = state_lock.games.get(&game_id) else {
+                    continue;
+                };
+                let Some(loser) = game.timed_out_player() else {
+                    // The move ended or the game got paused in the meantime.
+                    state_lock.schedule_deadlines(&game_id);
+                    continue;
+                };
+                let Some(winner) = game.opponent_of(&loser) else {
+                    continue;
+                };
+                let _game_span = error_span!("game", game = %game_id).entered();
+                info!("Game '{}' finished on time", game_id);
+                state_lock.supervise(&game_id, |state| {
+                    state.finish_game(&game_id, &winner, FinishReason::Timeout)
+                });
+            }
+            Deadline::Pause(game_id) => {
+                let Some(game) = state_lock.games.get_mut(&game_id) else {
+                    continue;
+                };
+                if !game.is_pause_expired() {
+                    state_lock.schedule_deadlines(&game_id);
+                    continue;
+                }
+                let _ = game.resume();
+                let _game_span = error_span!("game", game = %game_id).entered();
+                info!("Game '{}' resumed after the maximum pause", game_id);
+                state_lock.add_resumed_event(&game_id);
+            }
+            Deadline::IdleRoom(game_id) => idle_rooms.push(game_id),
+            Deadline::Grace(user_id) => {
+                if state_lock.sessions.expire(&user_id) {
+                    info!("Session of '{}' expired", user_id);
+                    state_lock.remove_user(&user_id);
+                }
+            }
+        }
+    }
+    state_lock.expire_idle_rooms(idle_rooms);
+    if state_lock.room_deltas.is_full_list_due() {
+        state_lock.add_update_room_event();
+    }
+    if state_lock.winner_deltas.is_full_list_due() {
+        state_lock.add_update_winners_event();
+    }
+
+    for user_id in state_lock.matchmaking.timed_out() {
+        state_lock.start_bot_game(&user_id);
+    }
+    for user_id in state_lock.matchmaking.due_statuses() {
+        state_lock.add_queue_status_event(&user_id);
+    }
+    state_lock.play_bots();
+    state_lock.refresh_presence();
+}
+
+/// Handles one client message. Returns a reply the caller should send right
+/// away, bypassing the event queue: answers to latency probes and errors
+/// about the message itself. Text that isn't JSON at all is a protocol
+/// violation the caller should close the connection for.
+///
+/// A panic while handling the message is caught and answered with an error
+/// event, so one bad message costs neither the connection nor the server.
+/// The game the message was for, if any, is ended, as its state can't be
+/// trusted anymore.
+pub fn handle_event(
+    addr: &SocketAddr,
+    event_json: &str,
+    state: &mut ServerState,
+) -> Result<Option<String>, CloseReason> {
+    faults::hit("handle_event");
+    let json: serde_json::Value =
+        serde_json::from_str(event_json).map_err(|_| CloseReason::ProtocolViolation)?;
+    let event = match parse_event(json) {
+        Ok(event) => event,
+        Err(err) => {
+            warn!("Rejected event: {}", err);
+            let locale = {
+                let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+                state_lock
+                    .get_user_by_addr(addr)
+                    .map(|user| user.locale)
+                    .unwrap_or_default()
+            };
+            return Ok(Some(error_event_json(&err, locale)));
+        }
+    };
+    debug!("Event {:?}", event);
+    let game_id = match &event {
+        ClientEvent::Game(game_event) => Some(game_event.game_id().clone()),
+        _ => None,
+    };
+    panic::catch_unwind(AssertUnwindSafe(|| dispatch_event(addr, event, state))).unwrap_or_else(
+        |_| {
+            error!("Handling a message from {} panicked", addr);
+            if let Some(game_id) = &game_id {
+                lock_state(state).end_failed_game(game_id);
+            }
+            Ok(Some(error_event_json(
+                &MessageId::InternalError.into(),
+                Locale::default(),
+            )))
+        },
+    )
+}
+
+fn dispatch_event(
+    addr: &SocketAddr,
+    event: ClientEvent,
+    state: &mut ServerState,
+) -> Result<Option<String>, CloseReason> {
+    let user = {
+        let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+        state_lock.get_user_by_addr(addr).cloned()
+    };
+    let _user_span = user
+        .as_ref()
+        .map(|user| error_span!("user", user = %user.id, player = %user.name).entered());
+
+    let event = match event {
+        ClientEvent::Player(PlayerEvent::Reg(reg)) => {
+            if user.is_some() {
+                return Ok(None);
+            }
+            let locale = reg.locale;
+            return Ok(register(addr, reg, state)
+                .err()
+                .map(|id| error_event_json(&id.into(), locale)));
+        }
+        ClientEvent::Player(PlayerEvent::Reconnect(token)) => {
+            if user.is_some() {
+                return Ok(None);
+            }
+            return Ok(reconnect(addr, &token, state)
+                .err()
+                .map(|id| error_event_json(&id.into(), Locale::default())));
+        }
+        ClientEvent::Player(PlayerEvent::Ping(client_time)) => {
+            if let Some(user) = &user {
+                lock_state(state).presence.heartbeat(&user.id);
+            }
+            let data = json!({
+                "clientTime": client_time,
+                "serverTime": now_ms(),
+            });
+            return Ok(Some(create_event_json(data, "pong".into())));
+        }
+        ClientEvent::Player(PlayerEvent::Population) => {
+            let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+            let data = state_lock.population_json();
+            return Ok(Some(create_event_json(data, "update_population".into())));
+        }
+        ClientEvent::Player(PlayerEvent::Winners { offset, limit }) => {
+            let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+            let winners = state_lock.winners_json();
+            let data = json!({
+                "offset": offset,
+                "total": winners.len(),
+                "winners": ranked(winners, offset, limit),
+            });
+            return Ok(Some(create_event_json(data, "winners".into())));
+        }
+        ClientEvent::Player(PlayerEvent::WatchReplay(replay_id)) => {
+            let state_lock = state.read().unwrap_or_else(PoisonError::into_inner);
+            let locale = user.map(|user| user.locale).unwrap_or_default();
+            return Ok(Some(match state_lock.shared_replays.watch(&replay_id) {
+                Some(data) => create_event_json(data, "watch_replay".into()),
+                None => error_event_json(&MessageId::ReplayNotFound.into(), locale),
+            }));
+        }
+        event => event,
+    };
+    let Some(user) = user else {
+        warn!("Event from unregistered {} rejected", addr);
+        return Ok(Some(error_event_json(
+            &MessageId::RegisterFirst.into(),
+            Locale::default(),
+        )));
+    };
+    let mut state_lock = lock_state(state);
+    state_lock.presence.active(&user.id);
+
+    match event {
+        ClientEvent::Player(player_event) => match player_event {
+            PlayerEvent::Reg(_)
+            | PlayerEvent::Reconnect(_)
+            | PlayerEvent::Ping(_)
+            | PlayerEvent::Population
+            | PlayerEvent::Winners { .. }
+            | PlayerEvent::WatchReplay(_) => {}
+            PlayerEvent::Sync(desync) => {
+                if let Some((game_id, checksum)) = desync {
+                    state_lock.log_desync(&user, &game_id, &checksum);
+                }
+                state_lock.sync(&user);
+            }
+            PlayerEvent::Quickplay => state_lock.quickplay(&user),
+            PlayerEvent::CancelSearch => state_lock.cancel_search(&user),
+            PlayerEvent::ShareReplay(game_id, anonymize) => {
+                state_lock.share_replay(&user, game_id, anonymize)
+            }
+            PlayerEvent::Spectate(game_id) => state_lock.spectate(&user, game_id),
+            PlayerEvent::StopSpectating(game_id) => state_lock.stop_spectating(&user, game_id),
+            PlayerEvent::ListLiveGames => state_lock.list_live_games(&user),
+            PlayerEvent::Subscribe(feed) => state_lock.subscribe(&user, feed),
+            PlayerEvent::Unsubscribe(feed) => state_lock.unsubscribe(&user, feed),
+            PlayerEvent::ExportData => state_lock.export_data(&user),
+            PlayerEvent::PracticeStart(board_size) => state_lock.start_practice(&user, board_size),
+            PlayerEvent::PracticeAttack(x, y) => state_lock.practice_attack(&user, x, y),
+            PlayerEvent::PracticeStop => state_lock.stop_practice(&user),
+            PlayerEvent::DeleteAccount(code) => state_lock.delete_account(&user, code),
+        },
+        ClientEvent::Room(room_event) => match room_event {
+            RoomEvent::Create(settings) => state_lock.create_room(&user, &settings),
+            RoomEvent::AddUser(room) => {
+                let game_id = state_lock.room_codes.game_id(&room).cloned();
+                state_lock.join_game(game_id.unwrap_or(room), &user, false);
+            }
+        },
+        ClientEvent::Game(game_event) => {
+            let _game_span = error_span!("game", game = %game_event.game_id()).entered();
+            if game_event.is_move() && !state_lock.check_move_rate(&user) {
+                return Ok(None);
+            }
+            match game_event {
+                GameEvent::AddShips(game_id, ships) => {
+                    state_lock.add_ships_to_game(&user, game_id, ships);
+                }
+                GameEvent::ValidateShips(game_id, ships) => {
+                    state_lock.validate_ships(&user, game_id, ships);
+                }
+                GameEvent::Attack(game_id, x, y) => {
+                    let started = Instant::now();
+                    state_lock.attack(&user, game_id, Some((x, y)));
+                    METRICS.attack_latency.observe(started.elapsed());
+                }
+                GameEvent::AbortRequest(game_id) => {
+                    state_lock.request_abort(&user, game_id);
+                }
+                GameEvent::AbortAccept(game_id) => {
+                    state_lock.accept_abort(&user, game_id);
+                }
+                GameEvent::PauseRequest(game_id) => {
+                    state_lock.request_pause(&user, game_id);
+                }
+                GameEvent::Resume(game_id) => {
+                    state_lock.resume(&user, game_id);
+                }
+                GameEvent::Scan(game_id, x, y) => {
+                    state_lock.scan(&user, game_id, x, y);
+                }
+                GameEvent::RandomAttack(game_id) => {
+                    let started = Instant::now();
+                    state_lock.attack(&user, game_id, None);
+                    METRICS.attack_latency.observe(started.elapsed());
+                }
+                GameEvent::BoardView(game_id) => {
+                    state_lock.board_view(&user, game_id);
+                }
+            }
+        }
+        ClientEvent::Friend(friend_event) => match friend_event {
+            FriendEvent::Request(name) => state_lock.request_friend(&user, name),
+            FriendEvent::Accept(name) => state_lock.accept_friend(&user, name),
+            FriendEvent::Remove(name) => state_lock.remove_friend(&user, name),
+            FriendEvent::Challenge(name) => state_lock.challenge_friend(&user, name),
+        },
+    }
+    state_lock.refresh_presence_of(&user);
+    Ok(None)
+}
+
+fn register(
+    addr: &SocketAddr,
+    reg: Registration,
+    state: &mut ServerState,
+) -> Result<(), MessageId> {
+    let uuid = Uuid::new_v4();
+    let mut user = User {
+        id: uuid.to_string(),
+        name: reg.username.clone(),
+        account: None,
+        addr: *addr,
+        wins: 0,
+        rooms: HashSet::new(),
+        rtt_ms: None,
+        stats: PlayerStats::default(),
+        recent_games: RecentGames::default(),
+        locale: reg.locale,
+        bot: reg.api_key.is_some(),
+    };
+
+    let mut state_lock = lock_state(state);
+    if let Some(key) = &reg.api_key {
+        if !state_lock.bot_api_keys.contains(key) {
+            warn!(
+                "Bot registration of '{}' rejected: invalid API key",
+                user.name
+            );
+            return Err(MessageId::InvalidApiKey);
+        }
+    }
+    if matchmaking::is_reserved_name(&user.name) {
+        warn!("Registration of '{}' rejected: name reserved", user.name);
+        return Err(MessageId::NameReserved);
+    }
+    // The leaderboard, lobby and admin commands know players by name.
+    let name_taken = state_lock
+        .users
+        .values()
+        .any(|other| other.name == user.name && other.addr != user.addr);
+    if name_taken {
+        warn!("Registration of '{}' rejected: name taken", user.name);
+        return Err(MessageId::NameTaken);
+    }
+    let account = reg
+        .account_key
+        .as_deref()
+        .and_then(|key| state_lock.friends.account_of(key))
+        .cloned();
+    let account_in_use = account.is_some()
+        && state_lock
+            .users
+            .values()
+            .any(|other| other.account == account && other.addr != user.addr);
+    if account_in_use {
+        warn!("Registration of '{}' rejected: account in use", user.name);
+        return Err(MessageId::AccountInUse);
+    }
+    if !limits::has_room(state_lock.limits.max_users, state_lock.users.len()) {
+        warn!("Registration of '{}' rejected: server full", user.name);
+        return Err(MessageId::ServerFull);
+    }
+    // Registering again on the same connection keeps the user as they are.
+    let account_key = match state_lock.get_user_by_addr(addr) {
+        Some(_) => None,
+        None => {
+            let (account, key) = state_lock
+                .friends
+                .sign_in(reg.account_key.as_deref(), &user.name);
+            user.account = Some(account);
+            Some(key)
+        }
+    };
+    state_lock.join_deltas(&user.id, &reg.capabilities);
+    let user = state_lock.add_user(&user);
+    let _user_span = error_span!("user", user = %user.id, player = %user.name).entered();
+    info!("User '{}' registered", user.name);
+    if let Some(account) = &user.account {
+        state_lock.seen(account);
+    }
+    state_lock.audit.record(
+        &user.name,
+        "register",
+        json!({ "user": user.id, "addr": user.addr.to_string() }),
+    );
+    state_lock.add_update_room_event();
+    state_lock.presence.active(&user.id);
+    state_lock.refresh_presence();
+
+    let token = state_lock.sessions.open(&user.id);
+    let mut data = json!({
+        "name": user.name,
+        "index": user.id,
+        "error": false,
+        "errorText": "",
+        "features": state_lock.features,
+        "sessionToken": token,
+        "account": user.account,
+        "locale": user.locale,
+        "capabilities": state_lock.capabilities_json(&user.id),
+        "server": state_lock.server_json(),
+    });
+    if let Some(key) = account_key {
+        data["accountKey"] = json!(key);
+    }
+    if user.bot {
+        data["bot"] = json!(true);
+    }
+    let json = create_event_json(data, "reg".into());
+
+    state_lock.add_event(&ServerEvent::User(user.addr, json));
+
+    state_lock.add_update_friends_event(&user.id);
+    state_lock.add_announcement_events(&user.id);
+    state_lock.notify_friends(
+        &user,
+        "friend_online",
+        json!({
+            "name": user.name,
+            "account": user.account,
+            "online": true,
+        }),
+    );
+    let pending = state_lock.pending_joins.remove(addr);
+    if let Some(room) = reg.join.or(pending) {
+        let game_id = state_lock.room_codes.game_id(&room).cloned();
+        state_lock.join_game(game_id.unwrap_or(room), &user, false);
+    }
+    Ok(())
+}
+
+/// Remembers the room of the invite link in `uri`, if it has one, for when
+/// the connection registers.
+pub fn handle_join_link(addr: &SocketAddr, uri: &str, state: &mut ServerState) {
+    if let Some(room) = room_codes::from_link(uri) {
+        lock_state(state)
+            .pending_joins
+            .insert(*addr, room.to_owned());
+    }
+}
+
+/// Forgets a user right away unless they are in a running game, which is
+/// held for `RECONNECT_GRACE` in case they reconnect.
+pub fn handle_disconnect(addr: &SocketAddr, state: &mut ServerState) {
+    let mut state_lock = lock_state(state);
+    state_lock.pending_joins.remove(addr);
+    let Some(user_id) = state_lock.user_ids.remove(addr) else {
+        return;
+    };
+    let Some(user) = state_lock.get_user(&user_id).cloned() else {
+        return;
+    };
+    if state_lock.is_in_game(&user) && state_lock.sessions.disconnect(&user_id) {
+        info!(
+            "User '{}' disconnected ({}), holding their games for {:?}",
+            user.name, user.addr, RECONNECT_GRACE
+        );
+        let grace = state_lock.sessions.grace_deadline(&user_id);
+        state_lock
+            .deadlines
+            .schedule(Deadline::Grace(user_id.clone()), grace);
+        if let Some(account) = &user.account {
+            state_lock.seen(account);
+        }
+        state_lock.add_opponent_connection_event(&user, "opponent_disconnected");
+        // Nobody is going to play in their open rooms meanwhile.
+        if state_lock.leave_waiting_rooms(&user) {
+            state_lock.add_update_room_event();
+        }
+        state_lock.add_population_event();
+        state_lock.refresh_presence();
+        return;
+    }
+    state_lock.remove_user(&user_id);
+    state_lock.refresh_presence();
+}
+
+/// Binds a new connection to the session of `token` and delivers what the
+/// player missed, or a full sync if too much was missed to replay.
+fn reconnect(addr: &SocketAddr, token: &str, state: &mut ServerState) -> Result<(), MessageId> {
+    let mut state_lock = lock_state(state);
+    let (user_id, absence) = state_lock
+        .sessions
+        .resume(token)
+        .ok_or(MessageId::SessionExpired)?;
+    state_lock
+        .deadlines
+        .schedule(Deadline::Grace(user_id.clone()), None);
+    let user = state_lock
+        .users
+        .get_mut(&user_id)
+        .ok_or(MessageId::SessionExpired)?;
+    let old_addr = std::mem::replace(&mut user.addr, *addr);
+    let user = user.clone();
+    state_lock.user_ids.remove(&old_addr);
+    state_lock.user_ids.insert(*addr, user_id.clone());
+    let _user_span = error_span!("user", user = %user.id, player = %user.name).entered();
+    info!("User '{}' reconnected", user.name);
+    state_lock.audit.record(
+        &user.name,
+        "reconnect",
+        json!({ "user": user.id, "addr": addr.to_string() }),
+    );
+    if state_lock.room_deltas.clients().contains(&user.id) {
+        state_lock.room_deltas.resync(&user.id);
+        state_lock.add_update_room_event();
+    }
+    if state_lock.winner_deltas.clients().contains(&user.id) {
+        state_lock.winner_deltas.resync(&user.id);
+        state_lock.add_update_winners_event();
+    }
+
+    let data = json!({
+        "name": user.name,
+        "index": user.id,
+        "error": false,
+        "errorText": "",
+        "features": state_lock.features,
+        "sessionToken": token,
+        "account": user.account,
+        "locale": user.locale,
+        "capabilities": state_lock.capabilities_json(&user.id),
+        "server": state_lock.server_json(),
+    });
+    state_lock.add_event(&ServerEvent::User(
+        *addr,
+        create_event_json(data, "reg".into()),
+    ));
+    let noticed_absence = absence.is_some();
+    if let Some(absence) = absence {
+        info!("Replaying {} missed events", absence.missed.len());
+        for json in absence.missed {
+            state_lock.add_event(&ServerEvent::User(*addr, json));
+        }
+        if absence.overflowed {
+            state_lock.sync(&user);
+        }
+    }
+    if noticed_absence {
+        state_lock.add_opponent_connection_event(&user, "opponent_reconnected");
+    }
+    state_lock.presence.active(&user.id);
+    state_lock.refresh_presence();
+    // An opponent who dropped meanwhile is only known from the missed
+    // events, which don't tell how long they have left.
+    let away_opponents = user
+        .rooms
+        .iter()
+        .filter_map(|room| state_lock.games.get(room))
+        .filter_map(|game| {
+            let opponent = game.opponent_of(&user.id)?;
+            let grace = state_lock.sessions.remaining_grace(&opponent)?;
+            Some((game.id.clone(), opponent, grace))
+        })
+        .collect::<Vec<_>>();
+    for (game_id, opponent, grace) in away_opponents {
+        state_lock.add_player_event(
+            &user.id,
+            "opponent_disconnected",
+            json!({ "gameId": game_id, "index": opponent, "graceMs": grace.as_millis() as u64 }),
+        );
+    }
+    state_lock.add_update_winners_event();
+    state_lock.add_update_room_event();
+    state_lock.add_update_friends_event(&user.id);
+    state_lock.add_announcement_events(&user.id);
+    Ok(())
+}
+
+// fn create_room(User)
+
+fn create_event_json(data: serde_json::Value, event_type: String) -> String {
+    let data = serde_json::to_string(&data).unwrap();
+
+    serde_json::to_string(&ServerEventData {
+        type_str: event_type,
+        data,
+        id: 0,
+        ts: now_ms(),
+    })
+    .unwrap()
+}
+
+fn error_event_json(message: &Message, locale: Locale) -> String {
+    create_event_json(error_json(message, locale), "error".into())
+}
+
+fn error_json(message: &Message, locale: Locale) -> serde_json::Value {
+    json!({ "errorText": message.text(locale), "errorCode": message.id })
+}
+
+/// Remaining time of both players, when the game is played with a clock.
+fn clocks_json(game: &Game) -> Option<serde_json::Value> {
+    let clocks = game.remaining_time()?;
+    let players = game.players();
+    Some(
+        players
+            .iter()
+            .zip(clocks)
+            .filter_map(|(user_id, remaining)| {
+                user_id.as_ref().map(|user_id| {
+                    json!({
+                        "index": user_id,
+                        "remainingMs": remaining.as_millis() as u64,
+                    })
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Shots the player fired in the game and how many of them hit.
+fn shot_counts(game: &Game, user_id: &UserId) -> (usize, usize) {
+    let shots = game.shots().iter().filter(|shot| shot.player == *user_id);
+    shots.fold((0, 0), |(count, hits), shot| {
+        (count + 1, hits + usize::from(shot.hit))
+    })
+}
+
+/// Results screen of a finished game. Ranking is by wins, so the winner's
+/// rating moves by one win.
+fn summary_json(game: &Game, winner: Option<&UserId>, reason: FinishReason) -> serde_json::Value {
+    let players = game.players()
+        .into_iter()
+        .flatten()
+        .map(|user_id| {
+            let (shots, hits) = shot_counts(game, &user_id);
+            let accuracy = match shots {
+                0 => 0.0,
+                count => hits as f64 / count as f64,
+            };
+            json!({
+                "index": user_id,
+                "shots": shots,
+                "hits": hits,
+                "accuracy": accuracy,
+                "survivingShips": game.afloat_of(&user_id),
+                "winsDelta": u32::from(winner == Some(&user_id) && game.settings.handicap.is_none()),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "gameId": game.id,
+        "winPlayer": winner,
+        "reason": reason,
+        "durationMs": game.duration().as_millis() as u64,
+        "handicap": game.settings.handicap,
+        "players": players,
+        "timeline": game.shots(),
+    })
+}
+
+fn cells_json(cells: Vec<(Position, AttackStatus)>) -> serde_json::Value {
+    cells
+        .into_iter()
+        .map(|(position, status)| json!({ "position": position, "status": status }))
+        .collect()
+}
+
+/// Adds the room settings as top-level fields of a room or game object.
+fn with_settings(mut json: serde_json::Value, settings: &RoomSettings) -> serde_json::Value {
+    if let (Some(object), serde_json::Value::Object(settings)) = (
+        json.as_object_mut(),
+        serde_json::to_value(settings).unwrap(),
+    ) {
+        object.extend(settings);
+    }
+    json
+}
+
+fn parse_event(json: serde_json::Value) -> Result<ClientEvent, Message> {
+    debug!("json <- {:?}", json);
+
+    let event_type: &str = json["type"].as_str().unwrap_or("unknown");
+
+    let data_json: serde_json::Value = serde_json::from_str(json["data"].as_str().unwrap_or(""))
+        .unwrap_or(serde_json::Value::Null);
+
+    match event_type {
+        "reg" => Ok(ClientEvent::Player(PlayerEvent::Reg(Registration {
+            username: str_field(&data_json, "name")?,
+            locale: data_json["locale"]
+                .as_str()
+                .map(Locale::from_tag)
+                .unwrap_or_default(),
+            capabilities: data_json["capabilities"]
+                .as_array()
+                .map(|capabilities| {
+                    capabilities
+                        .iter()
+                        .filter_map(|capability| Some(capability.as_str()?.to_owned()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            join: data_json["joinLink"]
+                .as_str()
+                .map(|link| room_codes::from_link(link).unwrap_or(link).to_owned()),
+            api_key: None,
+            account_key: data_json["accountKey"].as_str().map(str::to_owned),
+        }))),
+        "bot_reg" => Ok(ClientEvent::Player(PlayerEvent::Reg(Registration {
+            username: str_field(&data_json, "name")?,
+            locale: Locale::default(),
+            capabilities: Vec::new(),
+            join: None,
+            api_key: Some(str_field(&data_json, "apiKey")?),
+            account_key: data_json["accountKey"].as_str().map(str::to_owned),
+        }))),
+        "ping" => Ok(ClientEvent::Player(PlayerEvent::Ping(
+            data_json["clientTime"].clone(),
+        ))),
+        "reconnect" => Ok(ClientEvent::Player(PlayerEvent::Reconnect(str_field(
+            &data_json,
+            "sessionToken",
+        )?))),
+        "sync" => Ok(ClientEvent::Player(PlayerEvent::Sync(
+            data_json["gameId"]
+                .as_str()
+                .zip(data_json["checksum"].as_str())
+                .map(|(game_id, checksum)| (game_id.to_owned(), checksum.to_owned())),
+        ))),
+        "population" => Ok(ClientEvent::Player(PlayerEvent::Population)),
+        "winners" => Ok(ClientEvent::Player(PlayerEvent::Winners {
+            offset: data_json["offset"].as_u64().unwrap_or(0) as usize,
+            limit: data_json["limit"]
+                .as_u64()
+                .map_or(TOP_WINNERS, |limit| (limit as usize).min(MAX_WINNERS_PAGE)),
+        })),
+        "quickplay" => Ok(ClientEvent::Player(PlayerEvent::Quickplay)),
+        "cancel_search" => Ok(ClientEvent::Player(PlayerEvent::CancelSearch)),
+        "share_replay" => Ok(ClientEvent::Player(PlayerEvent::ShareReplay(
+            str_field(&data_json, "gameId")?,
+            data_json["anonymize"].as_bool().unwrap_or(false),
+        ))),
+        "watch_replay" => Ok(ClientEvent::Player(PlayerEvent::WatchReplay(str_field(
+            &data_json, "replayId",
+        )?))),
+        "spectate" => Ok(ClientEvent::Player(PlayerEvent::Spectate(str_field(
+            &data_json, "gameId",
+        )?))),
+        "stop_spectating" => Ok(ClientEvent::Player(PlayerEvent::StopSpectating(str_field(
+            &data_json, "gameId",
+        )?))),
+        "list_live_games" => Ok(ClientEvent::Player(PlayerEvent::ListLiveGames)),
+        "subscribe" => Ok(ClientEvent::Player(PlayerEvent::Subscribe(feed_field(
+            &data_json,
+        )?))),
+        "unsubscribe" => Ok(ClientEvent::Player(PlayerEvent::Unsubscribe(feed_field(
+            &data_json,
+        )?))),
+        "export_my_data" => Ok(ClientEvent::Player(PlayerEvent::ExportData)),
+        "practice_start" => Ok(ClientEvent::Player(PlayerEvent::PracticeStart(
+            board_size_field(&data_json)?,
+        ))),
+        "practice_attack" => Ok(ClientEvent::Player(PlayerEvent::PracticeAttack(
+            coordinate(&data_json, "x")?,
+            coordinate(&data_json, "y")?,
+        ))),
+        "practice_stop" => Ok(ClientEvent::Player(PlayerEvent::PracticeStop)),
+        "delete_account" => Ok(ClientEvent::Player(PlayerEvent::DeleteAccount(
+            data_json["confirmationCode"]
+                .as_str()
+                .map(|code| code.to_owned()),
+        ))),
+        "create_room" => {
+            let mut settings = RoomSettings::default();
+            if let Some(board_size) = board_size_field(&data_json)? {
+                settings.board_size = board_size;
+            }
+            if !data_json["fleet"].is_null() {
+                let counts: BTreeMap<u8, u8> =
+                    serde_json::from_value(data_json["fleet"].clone())
+                        .map_err(|_| Message::new(MessageId::InvalidFleet))?;
+                settings.fleet = Fleet::new(counts);
+            }
+            if let Some(no_touching) = data_json["noTouching"].as_bool() {
+                settings.no_touching = no_touching;
+            }
+            if let Some(scan) = data_json["scan"].as_bool() {
+                settings.scan = scan;
+            }
+            if !data_json["clock"].is_null() {
+                let initial = data_json["clock"]["initial"].as_u64().unwrap_or(0);
+                let increment = data_json["clock"]["increment"].as_u64().unwrap_or(0);
+                if initial == 0 || initial > MAX_CLOCK_SECONDS || increment > MAX_CLOCK_SECONDS {
+                    return Err(Message::new(MessageId::ClockOutOfRange).arg(MAX_CLOCK_SECONDS));
+                }
+                settings.clock = Some(TimeControl {
+                    initial_ms: initial * 1000,
+                    increment_ms: increment * 1000,
+                });
+            }
+            if !data_json["repeatShot"].is_null() {
+                settings.repeat_shot = serde_json::from_value(data_json["repeatShot"].clone())
+                    .map_err(|_| Message::new(MessageId::InvalidRepeatShot))?;
+            }
+            if let Some(best_of) = data_json["bestOf"].as_u64() {
+                if best_of % 2 == 0 || best_of > MAX_BEST_OF as u64 {
+                    return Err(Message::new(MessageId::InvalidBestOf).arg(MAX_BEST_OF));
+                }
+                settings.best_of = best_of as u8;
+            }
+            if let Some(name) = data_json["ruleset"].as_str() {
+                settings.ruleset = name.to_owned();
+            }
+            if let Some(max_spectators) = data_json["maxSpectators"].as_u64() {
+                settings.max_spectators = Some(max_spectators as usize);
+            }
+            if !data_json["handicap"].is_null() {
+                let handicap: Handicap = serde_json::from_value(data_json["handicap"].clone())
+                    .map_err(|_| Message::new(MessageId::InvalidHandicap))?;
+                match &handicap.fleet {
+                    Some(fleet) => {
+                        fleet.validate(settings.board_size)?;
+                        if fleet.cell_count() >= settings.fleet.cell_count() {
+                            return Err(MessageId::HandicapFleetNotSmaller.into());
+                        }
+                    }
+                    None if handicap.fewer_shots == 0 => {
+                        return Err(MessageId::InvalidHandicap.into());
+                    }
+                    None => {}
+                }
+                settings.handicap = Some(handicap);
+            }
+            let ruleset = rules::ruleset(&settings.ruleset).ok_or_else(|| {
+                Message::new(MessageId::UnknownRuleset).arg(rules::ruleset_names().join(", "))
+            })?;
+            settings
+                .fleet
+                .validate(settings.board_size)
+                .and_then(|_| ruleset.validate_settings(&settings))?;
+            let fewer_shots = settings
+                .handicap
+                .as_ref()
+                .is_some_and(|handicap| handicap.fewer_shots > 0);
+            if fewer_shots && !ruleset.has_several_shots() {
+                return Err(Message::new(MessageId::FewerShotsNeedSeveralShots).arg(ruleset.name()));
+            }
+            Ok(ClientEvent::Room(RoomEvent::Create(settings)))
+        }
+        "add_user_to_room" => Ok(ClientEvent::Room(RoomEvent::AddUser(str_field(
+            &data_json,
+            "indexRoom",
+        )?))),
+        "add_ships" => Ok(ClientEvent::Game(GameEvent::AddShips(
+            str_field(&data_json, "gameId")?,
+            ships_field(data_json)?,
+        ))),
+        "validate_ships" => Ok(ClientEvent::Game(GameEvent::ValidateShips(
+            str_field(&data_json, "gameId")?,
+            ships_field(data_json)?,
+        ))),
+        "attack" => Ok(ClientEvent::Game(GameEvent::Attack(
+            str_field(&data_json, "gameId")?,
+            coordinate(&data_json, "x")?,
+            coordinate(&data_json, "y")?,
+        ))),
+        "scan" => Ok(ClientEvent::Game(GameEvent::Scan(
+            str_field(&data_json, "gameId")?,
+            coordinate(&data_json, "x")?,
+            coordinate(&data_json, "y")?,
+        ))),
+        "abort_request" => Ok(ClientEvent::Game(GameEvent::AbortRequest(str_field(
+            &data_json, "gameId",
+        )?))),
+        "abort_accept" => Ok(ClientEvent::Game(GameEvent::AbortAccept(str_field(
+            &data_json, "gameId",
+        )?))),
+        "pause_request" => Ok(ClientEvent::Game(GameEvent::PauseRequest(str_field(
+            &data_json, "gameId",
+        )?))),
+        "resume" => Ok(ClientEvent::Game(GameEvent::Resume(str_field(
+            &data_json, "gameId",
+        )?))),
+        "randomAttack" => Ok(ClientEvent::Game(GameEvent::RandomAttack(str_field(
+            &data_json, "gameId",
+        )?))),
+        "board_view" => Ok(ClientEvent::Game(GameEvent::BoardView(str_field(
+            &data_json, "gameId",
+        )?))),
+        "add_friend" => Ok(ClientEvent::Friend(FriendEvent::Request(friend_ref(
+            &data_json,
+        )?))),
+        "accept_friend" => Ok(ClientEvent::Friend(FriendEvent::Accept(friend_ref(
+            &data_json,
+        )?))),
+        "remove_friend" => Ok(ClientEvent::Friend(FriendEvent::Remove(friend_ref(
+            &data_json,
+        )?))),
+        "challenge_friend" => Ok(ClientEvent::Friend(FriendEvent::Challenge(friend_ref(
+            &data_json,
+        )?))),
+        &_ => Err(MessageId::UnknownEventType.into()),
+    }
+}
+
+fn str_field(data: &serde_json::Value, key: &str) -> Result<String, Message> {
+    data[key]
+        .as_str()
+        .map(|value| value.to_owned())
+        .ok_or_else(|| Message::new(MessageId::MissingField).arg(key))
+}
+
+fn friend_ref(data: &serde_json::Value) -> Result<FriendRef, Message> {
+    match data["account"].as_str() {
+        Some(account) => Ok(FriendRef::Account(account.to_owned())),
+        None => str_field(data, "name").map(FriendRef::Name),
+    }
+}
+
+fn feed_field(data: &serde_json::Value) -> Result<Feed, Message> {
+    let name = str_field(data, "feed")?;
+    Feed::from_name(&name).ok_or_else(|| {
+        let names = Feed::ALL.map(|feed| feed.name());
+        Message::new(MessageId::UnknownFeed).arg(names.join(", "))
+    })
+}
+
+fn ships_field(data: serde_json::Value) -> Result<Ships, Message> {
+    serde_json::from_value(data).map_err(|_| Message::new(MessageId::InvalidShips))
+}
+
+/// `boardSize`, if given.
+fn board_size_field(data: &serde_json::Value) -> Result<Option<usize>, Message> {
+    let Some(board_size) = data["boardSize"].as_u64() else {
+        return Ok(None);
+    };
+    let board_size = board_size as usize;
+    if !(MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(&board_size) {
+        return Err(Message::new(MessageId::BoardSizeOutOfRange)
+            .arg(MIN_BOARD_SIZE)
+            .arg(MAX_BOARD_SIZE));
+    }
+    Ok(Some(board_size))
+}
+
+/// Coordinates past the board are kept out of range rather than wrapped.
+fn coordinate(data: &serde_json::Value, key: &str) -> Result<u8, Message> {
+    let value = data[key]
+        .as_u64()
+        .ok_or_else(|| Message::new(MessageId::MissingField).arg(key))?;
+    Ok(u8::try_from(value).unwrap_or(u8::MAX))
+}
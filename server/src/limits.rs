@@ -0,0 +1,116 @@
+//! Caps on what the server keeps in memory, so a small host turns players
+//! away with a "server full" error instead of running out of memory.
+//!
+//! `MAX_GAMES` caps the rooms and games at once, `MAX_USERS` the users
+//! registered at once and `MAX_ROOMS_PER_USER` the rooms one user takes part
+//! in. Unset or 0 means unlimited.
+//!
+//! Against lobby spam, `MAX_OPEN_ROOMS_PER_USER` caps the waiting rooms one
+//! user has open (1 by default) and `ROOM_CREATIONS_PER_MINUTE` how often
+//! they may try to create one (5 by default). 0 means unlimited.
+//!
+//! `MOVES_PER_SECOND` caps the attacks and scans of a player (10 by
+//! default), `BOT_MOVES_PER_SECOND` those of a bot (unlimited by default),
+//! see `bot_api`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{clock, UserId};
+
+const MAX_GAMES_VAR: &str = "MAX_GAMES";
+const MAX_USERS_VAR: &str = "MAX_USERS";
+const MAX_ROOMS_PER_USER_VAR: &str = "MAX_ROOMS_PER_USER";
+const MAX_OPEN_ROOMS_PER_USER_VAR: &str = "MAX_OPEN_ROOMS_PER_USER";
+const ROOM_CREATIONS_PER_MINUTE_VAR: &str = "ROOM_CREATIONS_PER_MINUTE";
+const MOVES_PER_SECOND_VAR: &str = "MOVES_PER_SECOND";
+const BOT_MOVES_PER_SECOND_VAR: &str = "BOT_MOVES_PER_SECOND";
+const DEFAULT_MAX_OPEN_ROOMS_PER_USER: usize = 1;
+const DEFAULT_ROOM_CREATIONS_PER_MINUTE: usize = 5;
+const DEFAULT_MOVES_PER_SECOND: usize = 10;
+pub const ROOM_CREATION_WINDOW: Duration = Duration::from_secs(60);
+pub const MOVE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub max_games: Option<usize>,
+    pub max_users: Option<usize>,
+    pub max_rooms_per_user: Option<usize>,
+    pub max_open_rooms_per_user: Option<usize>,
+    pub room_creations_per_minute: Option<usize>,
+    pub moves_per_second: Option<usize>,
+    pub bot_moves_per_second: Option<usize>,
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        Self {
+            max_games: limit_from_env(MAX_GAMES_VAR, None),
+            max_users: limit_from_env(MAX_USERS_VAR, None),
+            max_rooms_per_user: limit_from_env(MAX_ROOMS_PER_USER_VAR, None),
+            max_open_rooms_per_user: limit_from_env(
+                MAX_OPEN_ROOMS_PER_USER_VAR,
+                Some(DEFAULT_MAX_OPEN_ROOMS_PER_USER),
+            ),
+            room_creations_per_minute: limit_from_env(
+                ROOM_CREATIONS_PER_MINUTE_VAR,
+                Some(DEFAULT_ROOM_CREATIONS_PER_MINUTE),
+            ),
+            moves_per_second: limit_from_env(MOVES_PER_SECOND_VAR, Some(DEFAULT_MOVES_PER_SECOND)),
+            bot_moves_per_second: limit_from_env(BOT_MOVES_PER_SECOND_VAR, None),
+        }
+    }
+}
+
+/// Whether one more fits next to `count` under `limit`.
+pub fn has_room(limit: Option<usize>, count: usize) -> bool {
+    limit.is_none_or(|limit| count < limit)
+}
+
+/// Attempts at something of the last `window`, per user.
+#[derive(Debug)]
+pub struct Throttle {
+    window: Duration,
+    attempts: HashMap<UserId, VecDeque<Instant>>,
+}
+
+impl Throttle {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Counts an attempt, unless the user already made `limit` of them in
+    /// the window.
+    pub fn allow(&mut self, user_id: &UserId, limit: Option<usize>) -> bool {
+        let now = clock::now();
+        let attempts = self.attempts.entry(user_id.clone()).or_default();
+        while attempts
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= self.window)
+        {
+            attempts.pop_front();
+        }
+        if !has_room(limit, attempts.len()) {
+            return false;
+        }
+        attempts.push_back(now);
+        true
+    }
+
+    pub fn forget(&mut self, user_id: &UserId) {
+        self.attempts.remove(user_id);
+    }
+}
+
+fn limit_from_env(var: &str, default: Option<usize>) -> Option<usize> {
+    match std::env::var(var).ok().and_then(|limit| limit.parse().ok()) {
+        Some(0) => None,
+        Some(limit) => Some(limit),
+        None => default,
+    }
+}
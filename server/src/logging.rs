@@ -0,0 +1,99 @@
+//! Log output through `tracing`.
+//!
+//! `LOG_LEVEL` sets the maximum level (`info` by default) and `LOG_FORMAT=json`
+//! writes one JSON object per line for log collectors. Events carry the
+//! fields of the spans they happen in, so connection, user and game ids can
+//! be correlated across events. Those spans are opened at `error` level so
+//! they are there whatever `LOG_LEVEL` says. Dependencies logging through
+//! `log` are heard from `warn` up.
+
+use std::fmt;
+
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::Context, prelude::*, registry::LookupSpan, EnvFilter, Layer,
+    Registry,
+};
+
+const LOG_LEVEL_VAR: &str = "LOG_LEVEL";
+const LOG_FORMAT_VAR: &str = "LOG_FORMAT";
+
+/// Field values of a span, kept in its extensions by `SpanFields`.
+#[derive(Default)]
+struct Fields(Vec<(&'static str, String)>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+/// Keeps the fields of every span, for `fields` to read back.
+struct SpanFields;
+
+impl<S> Layer<S> for SpanFields
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+}
+
+/// Fields of the spans the current thread is in, outermost first.
+pub fn fields() -> Vec<(&'static str, String)> {
+    tracing::dispatcher::get_default(|dispatch| {
+        let Some(registry) = dispatch.downcast_ref::<Registry>() else {
+            return Vec::new();
+        };
+        let Some(span) = tracing::Span::current()
+            .id()
+            .and_then(|id| registry.span(&id))
+        else {
+            return Vec::new();
+        };
+        span.scope()
+            .from_root()
+            .flat_map(|span| {
+                span.extensions()
+                    .get::<Fields>()
+                    .map(|fields| fields.0.clone())
+                    .unwrap_or_default()
+            })
+            .collect()
+    })
+}
+
+/// Installs the subscriber; called once at startup.
+pub fn init() {
+    let level = std::env::var(LOG_LEVEL_VAR)
+        .ok()
+        .and_then(|level| level.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::INFO);
+    // Dependencies like tungstenite are chatty below `warn`.
+    let filter = EnvFilter::new(format!("{},server={}", level.min(LevelFilter::WARN), level));
+    let json = std::env::var(LOG_FORMAT_VAR).is_ok_and(|format| format == "json");
+    let _ = tracing_subscriber::registry()
+        .with(filter)
+        .with(SpanFields)
+        .with(json.then(|| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(true)
+        }))
+        .with((!json).then(|| tracing_subscriber::fmt::layer().with_ansi(false)))
+        .try_init();
+}
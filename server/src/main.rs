@@ -1,129 +1,550 @@
-//! A chat server that broadcasts a message to all connections.
+//! The Battleship game server.
 //!
-//! This is a simple line-based server which accepts WebSocket connections,
-//! reads lines from those connections, and broadcasts the lines to all other
-//! connected clients.
+//!     cargo run -- 127.0.0.1:3000 127.0.0.1:3001
 //!
-//! You can test this out by running:
+//! Players connect over WebSocket at the first address (`127.0.0.1:3000`
+//! by default); the second (`127.0.0.1:3001`) serves health checks,
+//! metrics and the HTTP API. Each connection gets a task that reads its
+//! events into the shared state and writes back what the state queued for
+//! it. `--debug` exposes the whole state at `/debug/state` and
+//! `--capture <dir>` records all inbound traffic for `replay_capture`.
+//! Builds with the `grpc` feature also serve the integration API at
+//! `GRPC_ADDR` when it is set, and builds with the `cluster` feature share
+//! their lobby with other nodes, see `cluster`.
 //!
-//!     cargo run --example server 127.0.0.1:12345
+//! Two subcommands work on audit logs instead of serving:
 //!
-//! And then in another window run:
-//!
-//!     cargo run --example client ws://127.0.0.1:12345/
-//!
-//! You can run the second command in multiple windows and then chat between the
-//! two, seeing the messages from the other client as they're received. For all
-//! connected clients they'll all join the same room and see everyone else's
-//! messages.
+//!     cargo run -- replay <audit log> [game id] [--auto]
+//!     cargo run -- debug replay-state <game id> [--to-step N]
 
-use std::time::Duration;
+use bytes::Bytes;
+use std::time::{Duration, Instant};
 use std::{
     collections::HashMap,
     env,
     io::Error as IoError,
     net::SocketAddr,
-    sync::{Arc, Mutex, RwLock},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
 };
 use tokio::{task, time}; // 1.3.0
 
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
+use futures_channel::{
+    mpsc::{unbounded, UnboundedSender},
+    oneshot,
+};
+use futures_util::{
+    future::{self, Either},
+    pin_mut,
+    stream::{self, PollNext, SplitSink, TryStreamExt},
+    SinkExt, Stream, StreamExt,
+};
+use tracing::{debug, error_span, info, warn};
 
-use server::{ServerEvent, ServerState, State};
+use server::{
+    admin, capture::Capture, crash_reports, faults, logging, metrics::METRICS, CloseReason,
+    Priority, ServerEvent, ServerState, State,
+};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{header::AUTHORIZATION, StatusCode},
+        protocol::{frame::coding::CloseCode, CloseFrame, Message},
+        Utf8Bytes,
+    },
+    WebSocketStream,
+};
 
-type Tx = UnboundedSender<Message>;
-type PeerMap = Arc<Mutex<HashMap<SocketAddr, Tx>>>;
+type PeerMap = Arc<Mutex<HashMap<SocketAddr, Peer>>>;
+
+/// A peer is falling behind when the message being written to its socket
+/// was queued this long ago, or when this many messages pile up.
+const SLOW_SEND_LATENCY: Duration = Duration::from_secs(1);
+const MAX_QUEUE_DEPTH: usize = 1024;
+/// Slow peers stop getting lobby broadcasts at once and are disconnected
+/// when they don't catch up within this time.
+const SLOW_PEER_TIMEOUT: Duration = Duration::from_secs(10);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often peers are pinged to measure their round-trip time.
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+/// How often peers are checked when the timers are off.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sending half of a peer's outbound queue, one lane per `Priority`. The
+/// writer empties the game lane before taking from the lobby lane. Messages
+/// carry the time they were queued at to measure the send latency.
+#[derive(Clone)]
+struct Outbox {
+    game: UnboundedSender<(Instant, Message)>,
+    lobby: UnboundedSender<(Instant, Message)>,
+    stats: Arc<OutboxStats>,
+}
+
+#[derive(Default)]
+struct OutboxStats {
+    /// Messages queued but not written to the socket yet.
+    queued: AtomicUsize,
+    /// When the message being written now was queued.
+    in_flight_since: Mutex<Option<Instant>>,
+}
+
+impl Outbox {
+    /// Queues a message in the game lane.
+    fn send(&self, message: Message) {
+        self.queue(Priority::Game, message);
+    }
+
+    fn queue(&self, priority: Priority, message: Message) {
+        let lane = match priority {
+            Priority::Game => &self.game,
+            Priority::Lobby => &self.lobby,
+        };
+        if lane.unbounded_send((Instant::now(), message)).is_ok() {
+            self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn is_falling_behind(&self) -> bool {
+        let in_flight_since = *self.stats.in_flight_since.lock().unwrap();
+        in_flight_since.is_some_and(|queued_at| queued_at.elapsed() >= SLOW_SEND_LATENCY)
+            || self.stats.queued.load(Ordering::Relaxed) >= MAX_QUEUE_DEPTH
+    }
+}
+
+struct Peer {
+    outbox: Outbox,
+    /// Tells the writer to give up on the peer.
+    evict: Option<oneshot::Sender<()>>,
+    slow_since: Option<Instant>,
+    /// When the unanswered WebSocket ping was sent.
+    ping_sent: Arc<Mutex<Option<Instant>>>,
+}
 
+// The handshake callback has to return tungstenite's large `ErrorResponse`.
+#[allow(clippy::result_large_err)]
 async fn handle_connection(
     peer_map: PeerMap,
     raw_stream: TcpStream,
     addr: SocketAddr,
     mut state: ServerState,
+    capture: Option<Capture>,
 ) {
-    println!("Incoming TCP connection from: {}", addr);
+    info!("Incoming TCP connection from: {}", addr);
 
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
-    println!("WebSocket connection established: {}", addr);
+    let mut is_admin = false;
+    let mut uri = None;
+    let ws_stream =
+        tokio_tungstenite::accept_hdr_async(raw_stream, |request: &Request, response: Response| {
+            if request.uri().path() != admin::ADMIN_PATH {
+                uri = Some(request.uri().to_string());
+                return Ok(response);
+            }
+            let authorization = request
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok());
+            if !admin::is_authorized(authorization) {
+                let mut error = ErrorResponse::new(Some("Unauthorized".into()));
+                *error.status_mut() = StatusCode::UNAUTHORIZED;
+                return Err(error);
+            }
+            is_admin = true;
+            Ok(response)
+        })
+        .await;
+    // Rejected handshakes were already answered with an HTTP error status.
+    let ws_stream = match ws_stream {
+        Ok(ws_stream) => ws_stream,
+        Err(err) => {
+            warn!("WebSocket handshake with {} failed: {}", addr, err);
+            return;
+        }
+    };
+    info!("WebSocket connection established: {}", addr);
+
+    if is_admin {
+        info!("Admin connected: {}", addr);
+        handle_admin_connection(&peer_map, ws_stream, state).await;
+        info!("Admin {} disconnected", addr);
+        return;
+    }
+
+    METRICS.connections_total.inc();
+    METRICS.connections.inc();
+    let peer = addr.to_string();
+    if let Some(uri) = uri {
+        server::handle_join_link(&addr, &uri, &mut state);
+    }
 
     // Insert the write part of this peer to the peer map.
-    let (tx, rx) = unbounded();
-    peer_map.lock().unwrap().insert(addr, tx);
+    let (game_tx, game_rx) = unbounded();
+    let (lobby_tx, lobby_rx) = unbounded();
+    let (evict_tx, evict_rx) = oneshot::channel();
+    let outbox = Outbox {
+        game: game_tx,
+        lobby: lobby_tx,
+        stats: Arc::default(),
+    };
+    let rx = stream::select_with_strategy(game_rx, lobby_rx, |_: &mut ()| PollNext::Left);
+    let ping_sent = Arc::new(Mutex::new(None));
+    peer_map.lock().unwrap().insert(
+        addr,
+        Peer {
+            outbox: outbox.clone(),
+            evict: Some(evict_tx),
+            slow_since: None,
+            ping_sent: ping_sent.clone(),
+        },
+    );
 
     let (outgoing, incoming) = ws_stream.split();
+    // Writes the close record when dropped at the end.
+    let mut capture = capture.and_then(|capture| capture.connection(&addr));
 
     let broadcast_incoming = incoming.try_for_each(|msg| {
-        println!(
-            "Received a message from {}: {}",
-            addr,
-            msg.to_text().unwrap()
-        );
-        server::handle_event(&addr, msg.to_text().unwrap(), &mut state);
-        // println!("{:?}", state);
+        METRICS.messages_in.inc();
+        let _peer_span = error_span!("peer", peer = %peer).entered();
+        let result = match &msg {
+            Message::Text(text) => {
+                debug!("Received a message: {}", text.as_str());
+                if let Some(capture) = capture.as_mut() {
+                    capture.text(text.as_str());
+                }
+                server::handle_event(&addr, text.as_str(), &mut state)
+            }
+            // Clients speaking a binary protocol send the same JSON.
+            Message::Binary(data) => match std::str::from_utf8(data) {
+                Ok(text) => {
+                    if let Some(capture) = capture.as_mut() {
+                        capture.text(text);
+                    }
+                    server::handle_event(&addr, text, &mut state)
+                }
+                Err(_) => Err(CloseReason::UnsupportedData),
+            },
+            Message::Pong(_) => {
+                if let Some(sent) = ping_sent.lock().unwrap().take() {
+                    server::record_rtt(&addr, sent.elapsed(), &mut state);
+                }
+                Ok(None)
+            }
+            // Answered by tungstenite, which also ends the stream after a
+            // close frame.
+            Message::Ping(_) | Message::Close(_) => Ok(None),
+            // Raw frames only show up when writing.
+            Message::Frame(_) => Err(CloseReason::ProtocolViolation),
+        };
+        match result {
+            Ok(Some(reply)) => outbox.send(reply.into()),
+            Ok(None) => {}
+            Err(reason) => {
+                warn!("Closing connection: {}", reason.text());
+                crash_reports::capture(
+                    crash_reports::Level::Warning,
+                    &format!("Closing connection: {}", reason.text()),
+                );
+                outbox.send(close_message(reason));
+            }
+        }
+        flush(&peer_map, &state);
 
         future::ok(())
     });
 
-    let receive_from_others = rx.map(Ok).forward(outgoing);
+    let receive_from_others = write_messages(outgoing, rx, outbox.stats.clone(), evict_rx);
 
     pin_mut!(broadcast_incoming, receive_from_others);
     future::select(broadcast_incoming, receive_from_others).await;
 
-    println!("{} disconnected", &addr);
+    let _peer_span = error_span!("peer", peer = %peer).entered();
+    info!("Disconnected");
     server::handle_disconnect(&addr, &mut state);
     peer_map.lock().unwrap().remove(&addr);
+    flush(&peer_map, &state);
+    METRICS.connections.dec();
+}
+
+/// Writes queued messages to the socket until the queue closes or the
+/// dispatcher evicts the peer for being too slow.
+async fn write_messages(
+    mut outgoing: SplitSink<WebSocketStream<TcpStream>, Message>,
+    mut rx: impl Stream<Item = (Instant, Message)> + Unpin,
+    stats: Arc<OutboxStats>,
+    mut evicted: oneshot::Receiver<()>,
+) {
+    loop {
+        let (queued_at, message) = match future::select(rx.next(), &mut evicted).await {
+            Either::Left((Some(queued), _)) => queued,
+            Either::Left((None, _)) => return,
+            Either::Right(_) => break,
+        };
+        *stats.in_flight_since.lock().unwrap() = Some(queued_at);
+        if let Some(delay) = faults::delay("send") {
+            time::sleep(delay).await;
+        }
+        let send = outgoing.send(message);
+        pin_mut!(send);
+        match future::select(send, &mut evicted).await {
+            Either::Left((Ok(()), _)) => {
+                *stats.in_flight_since.lock().unwrap() = None;
+                stats.queued.fetch_sub(1, Ordering::Relaxed);
+                METRICS.send_latency.observe(queued_at.elapsed());
+            }
+            Either::Left((Err(_), _)) => return,
+            Either::Right(_) => break,
+        }
+    }
+    let close = outgoing.send(close_message(CloseReason::TooSlow));
+    let _ = time::timeout(CLOSE_TIMEOUT, close).await;
+}
+
+async fn handle_admin_connection(
+    peer_map: &PeerMap,
+    ws_stream: WebSocketStream<TcpStream>,
+    mut state: ServerState,
+) {
+    let (mut outgoing, mut incoming) = ws_stream.split();
+    while let Some(Ok(msg)) = incoming.next().await {
+        let command = match &msg {
+            Message::Text(text) => text.as_str(),
+            Message::Binary(data) => match std::str::from_utf8(data) {
+                Ok(text) => text,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        let reply = admin::handle_admin_command(command, &mut state);
+        flush(peer_map, &state);
+        if outgoing.send(reply.into()).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn close_message(reason: CloseReason) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::from(reason.code()),
+        reason: reason.text().into(),
+    }))
+}
+
+fn ping_peers(peer_map: &PeerMap) {
+    for peer in peer_map.lock().unwrap().values() {
+        let mut ping_sent = peer.ping_sent.lock().unwrap();
+        // A peer that hasn't answered the last ping keeps its start time, so
+        // the late answer still measures the whole delay.
+        if ping_sent.is_none() {
+            *ping_sent = Some(Instant::now());
+            peer.outbox.send(Message::Ping(Default::default()));
+        }
+    }
+}
+
+fn check_slow_peers(peer_map: &PeerMap) {
+    let now = Instant::now();
+    for (addr, peer) in peer_map.lock().unwrap().iter_mut() {
+        if !peer.outbox.is_falling_behind() {
+            peer.slow_since = None;
+            continue;
+        }
+        let slow_since = *peer.slow_since.get_or_insert_with(|| {
+            warn!("Peer {} is falling behind", addr);
+            now
+        });
+        if now - slow_since >= SLOW_PEER_TIMEOUT {
+            if let Some(evict) = peer.evict.take() {
+                warn!("Disconnecting slow peer {}", addr);
+                METRICS.slow_peers_disconnected.inc();
+                let _ = evict.send(());
+            }
+        }
+    }
+}
+
+/// Hands the events to the peers' outboxes, each encoded once by `encode`.
+fn dispatch(peer_map: &PeerMap, events: Vec<ServerEvent>) {
+    let peers = peer_map.lock().unwrap();
+    for event in events {
+        let priority = event.priority();
+        let mut except = Vec::new();
+        let (to, message) = match event {
+            ServerEvent::All(json) | ServerEvent::Snapshot(_, json) => (None, encode(json)),
+            ServerEvent::AllExcept(addrs, json) => {
+                except = addrs;
+                (None, encode(json))
+            }
+            ServerEvent::User(to, json) => (Some(to), encode(json)),
+            ServerEvent::Close(to, reason) => (Some(to), close_message(reason)),
+            // Resolved to `User` by `take_events`.
+            ServerEvent::Player(..) => continue,
+        };
+        match to {
+            Some(to) => {
+                if let Some(peer) = peers.get(&to).filter(|_| !faults::drops("send")) {
+                    peer.outbox.queue(priority, message);
+                    METRICS.messages_out.inc();
+                }
+            }
+            // Slow peers are skipped for lobby broadcasts so they can catch
+            // up on their own game.
+            None => {
+                let recipients = peers
+                    .iter()
+                    .filter(|(addr, peer)| peer.slow_since.is_none() && !except.contains(addr))
+                    .filter(|_| !faults::drops("send"))
+                    .map(|(_, peer)| peer);
+                for peer in recipients {
+                    peer.outbox.queue(priority, message.clone());
+                    METRICS.messages_out.inc();
+                }
+            }
+        }
+    }
+}
+
+/// Encodes an event once. The text moves into a reference-counted `Bytes`
+/// buffer without being copied and clones of the message share it, so a
+/// broadcast costs each recipient a reference count, not a copy.
+fn encode(json: String) -> Message {
+    let text = Utf8Bytes::try_from(Bytes::from(json)).expect("a String is UTF-8");
+    Message::Text(text)
+}
+
+/// Sends the events queued so far. Called right after every message that
+/// may have queued some, and by the tick for timers and background jobs.
+fn flush(peer_map: &PeerMap, state: &ServerState) -> bool {
+    let (events, shutting_down) = {
+        let mut lock = server::lock_state(state);
+        (lock.take_events(), lock.shutting_down)
+    };
+    dispatch(peer_map, events);
+    shutting_down
 }
 
 async fn tick(peer_map: PeerMap, mut state: ServerState) {
-    let mut interval = time::interval(Duration::from_millis(200));
+    let tick_interval = server::lock_state(&state).tick_interval;
+    if tick_interval.is_none() {
+        warn!("Timers are off: turn clocks, sessions and matchmaking won't expire");
+    }
+    let mut interval = time::interval(tick_interval.unwrap_or(HOUSEKEEPING_INTERVAL));
+    let mut last_ping = Instant::now();
 
     loop {
         interval.tick().await;
-        server::tick(&mut state);
-
-        let mut lock = state.write().unwrap();
-        while let Some(event) = lock.events.dequeue() {
-            let peers = peer_map.lock().unwrap();
-            // We want to broadcast the message to everyone except ourselves.
-            let broadcast_recipients = peers
-                .iter()
-                .filter(|(peer_addr, _)| match &event {
-                    ServerEvent::All(_) => true,
-                    ServerEvent::User(to, _) => peer_addr == &to,
-                })
-                .map(|(_, ws_sink)| ws_sink);
-
-            let json = match &event {
-                ServerEvent::All(json) | ServerEvent::User(_, json) => json,
-            };
-
-            for recp in broadcast_recipients {
-                recp.unbounded_send(json.clone().into()).unwrap();
-            }
+        if tick_interval.is_some() {
+            server::tick(&mut state);
+        }
+        // Checked before queueing this tick's messages, so only what a peer
+        // couldn't write since the last tick counts.
+        check_slow_peers(&peer_map);
+        if last_ping.elapsed() >= PING_INTERVAL {
+            ping_peers(&peer_map);
+            last_ping = Instant::now();
+        }
+
+        if flush(&peer_map, &state) {
+            // Give the connection tasks a moment to write the close frames.
+            time::sleep(Duration::from_secs(1)).await;
+            info!("Shut down by admin");
+            std::process::exit(0);
         }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), IoError> {
-    let addr = env::args()
-        .nth(1)
+    logging::init();
+    crash_reports::init();
+    faults::init_from_env();
+
+    let mut args = env::args().skip(1).collect::<Vec<String>>();
+    let capture_dir = args
+        .iter()
+        .position(|arg| arg == "--capture")
+        .map(|index| args.drain(index..(index + 2).min(args.len())).nth(1));
+    let to_step = args
+        .iter()
+        .position(|arg| arg == "--to-step")
+        .map(|index| args.drain(index..(index + 2).min(args.len())).nth(1));
+    let (flags, args): (Vec<String>, Vec<String>) =
+        args.into_iter().partition(|arg| arg.starts_with("--"));
+    if args.first().is_some_and(|command| command == "replay") {
+        let Some(path) = args.get(1) else {
+            eprintln!("Usage: server replay <audit log> [game id] [--auto]");
+            std::process::exit(2);
+        };
+        let auto = flags.iter().any(|flag| flag == "--auto");
+        if let Err(err) = server::replay::run(path, args.get(2).map(String::as_str), auto) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if args.first().is_some_and(|command| command == "debug") {
+        let to_step = to_step.map(|step| step.and_then(|step| step.parse::<usize>().ok()));
+        let (Some("replay-state"), Some(game_id), None | Some(Some(_))) =
+            (args.get(1).map(String::as_str), args.get(2), to_step)
+        else {
+            eprintln!("Usage: server debug replay-state <game id> [--to-step N]");
+            std::process::exit(2);
+        };
+        if let Err(err) = server::replay::print_state(game_id, to_step.flatten()) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let addr = args
+        .first()
+        .cloned()
         .unwrap_or_else(|| "127.0.0.1:3000".to_string());
+    let http_addr = args
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| "127.0.0.1:3001".to_string());
+    let debug = flags.iter().any(|flag| flag == "--debug");
+    let capture = match capture_dir {
+        Some(Some(dir)) => match Capture::new(&dir) {
+            Ok(capture) => {
+                warn!("Capturing all inbound traffic to {}", dir);
+                Some(capture)
+            }
+            Err(err) => {
+                eprintln!("Can't capture to {}: {}", dir, err);
+                std::process::exit(2);
+            }
+        },
+        Some(None) => {
+            eprintln!("Usage: server [addr] [http addr] --capture <dir>");
+            std::process::exit(2);
+        }
+        None => None,
+    };
 
     let state = PeerMap::new(Mutex::new(HashMap::new()));
 
     let server_state: ServerState = Arc::new(RwLock::new(State::default()));
+    if debug {
+        server::lock_state(&server_state).debug = true;
+        warn!("Debug mode: the whole state is exposed at /debug/state");
+    }
 
     // Create the event loop and TCP listener we'll accept connections on.
     let try_socket = TcpListener::bind(&addr).await;
     let listener = try_socket.expect("Failed to bind");
-    println!("Listening on: {}", addr);
+    info!("Listening on: {}", addr);
 
+    server::discord::spawn(&server_state);
+    #[cfg(feature = "grpc")]
+    server::grpc::spawn(&server_state);
+    #[cfg(feature = "cluster")]
+    server::cluster::spawn(&server_state);
+    task::spawn(server::retention::run(server_state.clone()));
     task::spawn(tick(state.clone(), server_state.clone()));
+    task::spawn(server::http::serve(http_addr, server_state.clone()));
 
     // Let's spawn the handling of each connection in a separate task.
     while let Ok((stream, addr)) = listener.accept().await {
@@ -132,6 +553,7 @@ async fn main() -> Result<(), IoError> {
             stream,
             addr,
             server_state.clone(),
+            capture.clone(),
         ));
     }
 
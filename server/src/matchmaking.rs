@@ -0,0 +1,373 @@
+//! Quickplay: players ask for any opponent instead of picking a room.
+//!
+//! Two searching players are paired as soon as their wins are close enough.
+//! The accepted range widens the longer a player waits, and a searching
+//! player gets a `queue_status` every few seconds. A player nobody was found
+//! for within `QUICKPLAY_BOT_SECONDS` (30 by default, 0 waits forever) gets
+//! a server-side bot instead: a random shooter for new players, a hunting
+//! one for players with `SKILLED_WINS` wins or more.
+//!
+//! Operators can also keep resident bots, which stay between games and play
+//! any number of them at once. They are listed in `QUICKPLAY_BOTS` as
+//! `name:strategy` pairs separated by commas, say `Rookie:random,Ace:hunt`,
+//! or added with the `add_bot` admin command, which also takes strategies
+//! registered by the embedder (see `bot_plugins`). When there are any, a
+//! player nobody was found for plays the resident bot closest to them in
+//! wins instead. Resident bots have leaderboard entries of their own,
+//! marked `"bot": true`; one-off bots don't.
+//!
+//! One-off bots are called `Bot-1`, `Bot-2` and so on. Names starting with
+//! `Bot-` are kept for them, so neither players nor resident bots can
+//! register one.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::thread_rng;
+use serde_json::json;
+
+use crate::{
+    bot_api,
+    bot_plugins::BotStrategy,
+    clock,
+    game::{AttackStatus, Game},
+    ships::{Coord, Position, Ships},
+    simulation::{self, Rules, Strategy},
+    UserId,
+};
+
+const BOT_SECONDS_VAR: &str = "QUICKPLAY_BOT_SECONDS";
+const RESIDENT_BOTS_VAR: &str = "QUICKPLAY_BOTS";
+const DEFAULT_BOT_SECONDS: u64 = 30;
+/// Players with this many wins are matched with the hunting bot.
+const SKILLED_WINS: u32 = 5;
+/// Start of the names of one-off bots, see `is_reserved_name`.
+const ONE_OFF_BOT_PREFIX: &str = "Bot-";
+
+/// Players start out matched with players within this many wins of theirs.
+const BASE_WIN_RANGE: u32 = 2;
+/// The range grows by one win every this long spent searching.
+const RANGE_WIDENS_EVERY: Duration = Duration::from_secs(5);
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+/// Waits of the latest matches, estimating the next ones.
+const RECENT_WAITS: usize = 20;
+
+/// How a bot picks its shots.
+#[derive(Debug, Clone)]
+pub enum Brain {
+    Builtin(Strategy),
+    /// A strategy registered by the embedder under this name.
+    Plugin(String),
+}
+
+impl fmt::Display for Brain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Builtin(strategy) => write!(f, "{}", strategy),
+            Self::Plugin(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Search {
+    user_id: UserId,
+    wins: u32,
+    since: Instant,
+    status_sent: Instant,
+}
+
+impl Search {
+    /// Wins of the opponents this player accepts by now.
+    fn win_range(&self) -> (u32, u32) {
+        let spread = BASE_WIN_RANGE
+            + (clock::since(self.since).as_secs() / RANGE_WIDENS_EVERY.as_secs()) as u32;
+        (self.wins.saturating_sub(spread), self.wins + spread)
+    }
+}
+
+#[derive(Debug)]
+pub struct Matchmaking {
+    /// Searching players, longest waiting first.
+    queue: VecDeque<Search>,
+    /// How long a search waits for a human, `None` when bots are disabled.
+    bot_timeout: Option<Duration>,
+    recent_waits: VecDeque<Duration>,
+    bots: HashMap<UserId, Brain>,
+    /// Bots that stay between games.
+    residents: HashSet<UserId>,
+    plugins: HashMap<String, Arc<dyn BotStrategy>>,
+    /// One-off bots started so far, numbering their names.
+    one_off_bots: u64,
+}
+
+impl Default for Matchmaking {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            bot_timeout: Some(Duration::from_secs(DEFAULT_BOT_SECONDS)),
+            recent_waits: VecDeque::new(),
+            bots: HashMap::new(),
+            residents: HashSet::new(),
+            plugins: HashMap::new(),
+            one_off_bots: 0,
+        }
+    }
+}
+
+impl Matchmaking {
+    pub fn from_env() -> Self {
+        let seconds = std::env::var(BOT_SECONDS_VAR)
+            .ok()
+            .and_then(|seconds| seconds.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_BOT_SECONDS);
+        Self {
+            bot_timeout: (seconds > 0).then(|| Duration::from_secs(seconds)),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_searching(&self, user_id: &UserId) -> bool {
+        self.queue.iter().any(|search| &search.user_id == user_id)
+    }
+
+    /// Pairs the player with the longest waiting one whose win range takes
+    /// them in, or queues them.
+    pub fn search(&mut self, user_id: &UserId, wins: u32) -> Option<UserId> {
+        let matched = self.queue.iter().position(|search| {
+            let (min, max) = search.win_range();
+            (min..=max).contains(&wins)
+        });
+        match matched.and_then(|index| self.queue.remove(index)) {
+            Some(search) => {
+                if self.recent_waits.len() == RECENT_WAITS {
+                    self.recent_waits.pop_front();
+                }
+                self.recent_waits.push_back(clock::since(search.since));
+                Some(search.user_id)
+            }
+            None => {
+                let now = clock::now();
+                self.queue.push_back(Search {
+                    user_id: user_id.clone(),
+                    wins,
+                    since: now,
+                    status_sent: now,
+                });
+                None
+            }
+        }
+    }
+
+    /// Puts the player `search` just matched back at the head of the
+    /// queue, keeping their wait, when their game couldn't be made.
+    pub fn requeue(&mut self, user_id: &UserId, wins: u32) {
+        let waited = self.recent_waits.pop_back().unwrap_or_default();
+        let now = clock::now();
+        self.queue.push_front(Search {
+            user_id: user_id.clone(),
+            wins,
+            since: now.checked_sub(waited).unwrap_or(now),
+            status_sent: now,
+        });
+    }
+
+    pub fn searching(&self) -> impl Iterator<Item = &UserId> {
+        self.queue.iter().map(|search| &search.user_id)
+    }
+
+    /// Returns `false` if the player wasn't searching.
+    pub fn cancel(&mut self, user_id: &UserId) -> bool {
+        let len = self.queue.len();
+        self.queue.retain(|search| &search.user_id != user_id);
+        self.queue.len() != len
+    }
+
+    /// Takes the players that waited too long for a human.
+    pub fn timed_out(&mut self) -> Vec<UserId> {
+        let Some(timeout) = self.bot_timeout else {
+            return Vec::new();
+        };
+        let mut timed_out = Vec::new();
+        while let Some(search) = self.queue.front() {
+            if clock::since(search.since) < timeout {
+                break;
+            }
+            timed_out.extend(self.queue.pop_front().map(|search| search.user_id));
+        }
+        timed_out
+    }
+
+    /// Searching players whose last `queue_status` is `STATUS_INTERVAL` old.
+    pub fn due_statuses(&mut self) -> Vec<UserId> {
+        self.queue
+            .iter_mut()
+            .filter(|search| clock::since(search.status_sent) >= STATUS_INTERVAL)
+            .map(|search| {
+                search.status_sent = clock::now();
+                search.user_id.clone()
+            })
+            .collect()
+    }
+
+    /// Where the player is in the queue. The estimated wait comes from the
+    /// latest matches, and no longer than until the bot takes over; it is
+    /// `null` with neither to go by.
+    pub fn status_json(&self, user_id: &UserId) -> Option<serde_json::Value> {
+        let index = self
+            .queue
+            .iter()
+            .position(|search| &search.user_id == user_id)?;
+        let search = &self.queue[index];
+        let waited = clock::since(search.since);
+        let average = (!self.recent_waits.is_empty())
+            .then(|| self.recent_waits.iter().sum::<Duration>() / self.recent_waits.len() as u32);
+        let estimate = [average, self.bot_timeout]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|wait| wait.saturating_sub(waited).as_secs_f64().ceil() as u64);
+        let (min, max) = search.win_range();
+        Some(json!({
+            "position": index + 1,
+            "estimatedWaitSeconds": estimate,
+            "winRange": { "min": min, "max": max },
+        }))
+    }
+
+    /// Registers a bot as an opponent for a player with `wins` wins.
+    pub fn add_bot(&mut self, bot_id: &UserId, wins: u32) -> Strategy {
+        let strategy = if wins >= SKILLED_WINS {
+            Strategy::Hunt
+        } else {
+            Strategy::Random
+        };
+        self.bots.insert(bot_id.clone(), Brain::Builtin(strategy));
+        strategy
+    }
+
+    /// A name no other one-off bot started by this server had.
+    pub fn one_off_bot_name(&mut self) -> String {
+        self.one_off_bots += 1;
+        format!("{}{}", ONE_OFF_BOT_PREFIX, self.one_off_bots)
+    }
+
+    pub fn register_strategy(&mut self, name: &str, strategy: Arc<dyn BotStrategy>) {
+        self.plugins.insert(name.to_owned(), strategy);
+    }
+
+    /// The built-in or registered strategy called `name`.
+    pub fn brain(&self, name: &str) -> Option<Brain> {
+        match name.parse::<Strategy>() {
+            Ok(strategy) => Some(Brain::Builtin(strategy)),
+            Err(_) => self
+                .plugins
+                .contains_key(name)
+                .then(|| Brain::Plugin(name.to_owned())),
+        }
+    }
+
+    pub fn add_resident(&mut self, bot_id: &UserId, brain: Brain) {
+        self.bots.insert(bot_id.clone(), brain);
+        self.residents.insert(bot_id.clone());
+    }
+
+    /// Stops matching players with a resident bot, which goes once its
+    /// games are over. Returns `false` if it wasn't a resident.
+    pub fn retire(&mut self, bot_id: &UserId) -> bool {
+        self.residents.remove(bot_id)
+    }
+
+    pub fn residents(&self) -> impl Iterator<Item = &UserId> {
+        self.residents.iter()
+    }
+
+    pub fn is_resident(&self, user_id: &UserId) -> bool {
+        self.residents.contains(user_id)
+    }
+
+    pub fn remove_bot(&mut self, bot_id: &UserId) {
+        self.bots.remove(bot_id);
+    }
+
+    pub fn is_bot(&self, user_id: &UserId) -> bool {
+        self.bots.contains_key(user_id)
+    }
+
+    /// A bot started for one player, which stays off the leaderboard.
+    pub fn is_one_off_bot(&self, user_id: &UserId) -> bool {
+        self.is_bot(user_id) && !self.is_resident(user_id)
+    }
+
+    pub fn bot_ids(&self) -> Vec<UserId> {
+        self.bots.keys().cloned().collect()
+    }
+
+    /// Where the bot fires next, `None` if it isn't a bot of this game.
+    pub fn bot_target(&self, game: &mut Game, bot_id: &UserId) -> Option<Position> {
+        let target = match self.bots.get(bot_id)? {
+            Brain::Builtin(Strategy::Random) => None,
+            Brain::Builtin(Strategy::Hunt) => hunt_target(game, bot_id),
+            Brain::Plugin(name) => self
+                .plugins
+                .get(name)
+                .and_then(|plugin| plugin.target(&bot_api::board_view(game, bot_id)))
+                .and_then(|(x, y)| open_target(game, bot_id, x, y)),
+        };
+        target.or_else(|| game.random_target(bot_id))
+    }
+}
+
+/// `QUICKPLAY_BOTS`, as pairs of a name and a strategy name.
+pub fn resident_bots_from_env() -> Vec<(String, String)> {
+    std::env::var(RESIDENT_BOTS_VAR)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|bot| bot.trim().split_once(':'))
+        .map(|(name, strategy)| (name.trim().to_owned(), strategy.trim().to_owned()))
+        .collect()
+}
+
+/// Whether `name` is kept for one-off bots, in any letter case.
+pub fn is_reserved_name(name: &str) -> bool {
+    name.get(..ONE_OFF_BOT_PREFIX.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(ONE_OFF_BOT_PREFIX))
+}
+
+/// A fleet for the bot, placed at random under the room's rules.
+pub fn bot_fleet(game: &Game) -> Ships {
+    let rules = Rules {
+        board_size: game.settings.board_size,
+        no_touching: game.settings.no_touching,
+    };
+    simulation::random_fleet(&game.settings.fleet, rules, &mut thread_rng())
+}
+
+/// (`x`, `y`) if it is on the opponent's board and wasn't fired at yet.
+fn open_target(game: &Game, bot_id: &UserId, x: u8, y: u8) -> Option<Position> {
+    let opponent = game.opponent_of(bot_id)?;
+    let target = Coord::new(x, y, game.settings.board_size)?.position();
+    game.revealed_cells(&opponent)
+        .iter()
+        .all(|(pos, _)| *pos != target)
+        .then_some(target)
+}
+
+/// A cell next to a hit of a ship that isn't sunk yet.
+fn hunt_target(game: &Game, bot_id: &UserId) -> Option<Position> {
+    let opponent = game.opponent_of(bot_id)?;
+    let revealed = game.revealed_cells(&opponent);
+    let size = game.settings.board_size;
+    revealed
+        .iter()
+        .filter(|(_, status)| matches!(status, AttackStatus::Shot))
+        .filter_map(|(pos, _)| Coord::of(pos, size))
+        .flat_map(|hit| [(1, 0), (-1, 0), (0, 1), (0, -1)].map(|(dx, dy)| hit.offset(dx, dy)))
+        .flatten()
+        .map(Coord::position)
+        .find(|target| revealed.iter().all(|(pos, _)| pos != target))
+}
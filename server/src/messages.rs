@@ -0,0 +1,350 @@
+//! Texts the server shows players, keyed by stable ids.
+//!
+//! A client names its preferred locale in `reg`; error events then carry the
+//! text in that locale as `errorText` and the id as `errorCode`, which stays
+//! the same across locales and releases so clients can match on it or
+//! bring their own translations. Every id has a text in every locale, the
+//! match in `MessageId::templates` won't compile otherwise. Placeholders are
+//! numbered, `{0}` being the first argument.
+
+use std::fmt;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Ru,
+}
+
+impl Locale {
+    /// The locale of a language tag like `ru` or `ru-RU`; languages without
+    /// a translation get English.
+    pub fn from_tag(tag: &str) -> Self {
+        let language = tag.split(['-', '_']).next().unwrap_or_default();
+        match language.to_ascii_lowercase().as_str() {
+            "ru" => Locale::Ru,
+            _ => Locale::En,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageId {
+    // Protocol
+    UnknownEventType,
+    MissingField,
+    InvalidShips,
+    RegisterFirst,
+    SessionExpired,
+    InternalError,
+    ServerFull,
+    InvalidApiKey,
+    NameTaken,
+    NameReserved,
+    AccountInUse,
+    // Room settings
+    BoardSizeOutOfRange,
+    InvalidFleet,
+    ClockOutOfRange,
+    InvalidRepeatShot,
+    InvalidBestOf,
+    UnknownRuleset,
+    RulesetUnavailable,
+    ScansUnavailable,
+    InvalidHandicap,
+    HandicapFleetNotSmaller,
+    FewerShotsNeedSeveralShots,
+    MinesNeedMixedFleet,
+    EmptyFleet,
+    ShipLengthOutOfRange,
+    TooManyShips,
+    FleetTooLarge,
+    // Games
+    GameNotFound,
+    NotAPlayer,
+    PlacementClosed,
+    ShipsAlreadyPlaced,
+    FleetMismatch,
+    ShipOutOfBounds,
+    ShipOverlaps,
+    ShipTouches,
+    PlacementRejected,
+    GameNotStarted,
+    GamePaused,
+    GameAlreadyPaused,
+    GameNotPaused,
+    NotYourTurn,
+    AttackOutOfBounds,
+    CellAlreadyShot,
+    ScanOutOfBounds,
+    ScansDisabled,
+    ScanUsed,
+    NoOpponent,
+    NoAbortRequest,
+    MovingTooFast,
+    // Lobby
+    MatchmakingUnavailable,
+    AlreadyInGame,
+    NotSearching,
+    SpectatingBeforeStart,
+    SpectatingOwnGame,
+    RoomFull,
+    TooManyRooms,
+    TooManyOpenRooms,
+    CreatingRoomsTooFast,
+    NotSpectating,
+    NotPracticing,
+    ReplayNotFound,
+    UnknownFeed,
+    PlayerNotFound,
+    TooManyFriendRequests,
+}
+
+impl MessageId {
+    /// Templates in the order of the `Locale` variants.
+    fn templates(self) -> [&'static str; 2] {
+        match self {
+            MessageId::UnknownEventType => ["Unknown event type", "Неизвестный тип события"],
+            MessageId::MissingField => ["Missing {0}", "Не указано поле {0}"],
+            MessageId::InvalidShips => [
+                "Ships must be a list of placed ships",
+                "Корабли должны быть списком расставленных кораблей",
+            ],
+            MessageId::RegisterFirst => ["Register first", "Сначала зарегистрируйтесь"],
+            MessageId::SessionExpired => ["Session expired", "Сессия истекла"],
+            MessageId::InternalError => ["Internal server error", "Внутренняя ошибка сервера"],
+            MessageId::ServerFull => [
+                "Server is full, try again later",
+                "Сервер заполнен, попробуйте позже",
+            ],
+            MessageId::InvalidApiKey => ["Invalid bot API key", "Неверный API-ключ бота"],
+            MessageId::NameTaken => [
+                "Name is taken by a player online",
+                "Имя занято игроком в сети",
+            ],
+            MessageId::NameReserved => [
+                "Names starting with \"Bot-\" are kept for server bots",
+                "Имена, начинающиеся с \"Bot-\", зарезервированы для ботов сервера",
+            ],
+            MessageId::AccountInUse => [
+                "This account is signed in on another connection",
+                "Этот аккаунт используется в другом подключении",
+            ],
+            MessageId::BoardSizeOutOfRange => [
+                "Board size must be between {0} and {1}",
+                "Размер поля должен быть от {0} до {1}",
+            ],
+            MessageId::InvalidFleet => [
+                "Fleet must map ship lengths to counts",
+                "Флот должен задавать число кораблей каждой длины",
+            ],
+            MessageId::ClockOutOfRange => [
+                "Clock must be between 1 and {0} seconds",
+                "Время на часах должно быть от 1 до {0} секунд",
+            ],
+            MessageId::InvalidRepeatShot => [
+                "Repeat shot rule must be \"reject\" or \"waste\"",
+                "Правило повторного выстрела должно быть \"reject\" или \"waste\"",
+            ],
+            MessageId::InvalidBestOf => [
+                "Series must be best of 1, 3 ... {0}",
+                "Серия должна состоять из 1, 3 ... {0} игр",
+            ],
+            MessageId::UnknownRuleset => [
+                "Ruleset must be one of: {0}",
+                "Набор правил должен быть одним из: {0}",
+            ],
+            MessageId::RulesetUnavailable => [
+                "Ruleset {0} is not available on this server",
+                "Набор правил {0} недоступен на этом сервере",
+            ],
+            MessageId::ScansUnavailable => [
+                "Scans are not available on this server",
+                "Сканирование недоступно на этом сервере",
+            ],
+            MessageId::InvalidHandicap => [
+                "Handicap must name the player and give them a smaller fleet or fewer shots",
+                "Фора должна указывать игрока и дать ему меньший флот или меньше выстрелов",
+            ],
+            MessageId::HandicapFleetNotSmaller => [
+                "Handicap fleet must be smaller than the room fleet",
+                "Флот с форой должен быть меньше флота комнаты",
+            ],
+            MessageId::FewerShotsNeedSeveralShots => [
+                "Ruleset {0} has one shot a turn, so there are no shots to take off",
+                "В правилах {0} один выстрел за ход, забирать нечего",
+            ],
+            MessageId::MinesNeedMixedFleet => [
+                "Mines need both single-deck ships and longer ones",
+                "Для мин нужны и однопалубные корабли, и более длинные",
+            ],
+            MessageId::EmptyFleet => [
+                "Fleet must contain at least one ship",
+                "Во флоте должен быть хотя бы один корабль",
+            ],
+            MessageId::ShipLengthOutOfRange => [
+                "Ship length {0} is not between 1 and {1}",
+                "Длина корабля {0} должна быть от 1 до {1}",
+            ],
+            MessageId::TooManyShips => [
+                "Fleet can't have more than {0} ships",
+                "Во флоте не может быть больше {0} кораблей",
+            ],
+            MessageId::FleetTooLarge => [
+                "Fleet is too large for the board",
+                "Флот слишком велик для этого поля",
+            ],
+            MessageId::GameNotFound => ["Game not found", "Игра не найдена"],
+            MessageId::NotAPlayer => ["Not a player of this game", "Вы не участник этой игры"],
+            MessageId::PlacementClosed => [
+                "Ships can only be placed before the game starts",
+                "Корабли можно расставить только до начала игры",
+            ],
+            MessageId::ShipsAlreadyPlaced => {
+                ["Ships were already placed", "Корабли уже расставлены"]
+            }
+            MessageId::FleetMismatch => [
+                "Ships don't match the room fleet",
+                "Корабли не совпадают с флотом комнаты",
+            ],
+            MessageId::ShipOutOfBounds => [
+                "Ship {0} is out of bounds",
+                "Корабль {0} выходит за пределы поля",
+            ],
+            MessageId::ShipOverlaps => [
+                "Ship {0} overlaps another ship",
+                "Корабль {0} пересекается с другим кораблём",
+            ],
+            MessageId::ShipTouches => [
+                "Ship {0} touches another ship",
+                "Корабль {0} касается другого корабля",
+            ],
+            MessageId::PlacementRejected => {
+                ["Placement rejected: {0}", "Расстановка отклонена: {0}"]
+            }
+            MessageId::GameNotStarted => ["Game is not started", "Игра не началась"],
+            MessageId::GamePaused => ["Game is paused", "Игра на паузе"],
+            MessageId::GameAlreadyPaused => ["Game is already paused", "Игра уже на паузе"],
+            MessageId::GameNotPaused => ["Game is not paused", "Игра не на паузе"],
+            MessageId::NotYourTurn => ["Not your turn", "Сейчас не ваш ход"],
+            MessageId::AttackOutOfBounds => [
+                "Attack at ({0}, {1}) is out of bounds",
+                "Выстрел по ({0}, {1}) за пределами поля",
+            ],
+            MessageId::CellAlreadyShot => [
+                "Cell ({0}, {1}) was already shot",
+                "По клетке ({0}, {1}) уже стреляли",
+            ],
+            MessageId::ScanOutOfBounds => [
+                "Scan at ({0}, {1}) is out of bounds",
+                "Сканирование ({0}, {1}) за пределами поля",
+            ],
+            MessageId::ScansDisabled => [
+                "Scans are disabled in this room",
+                "В этой комнате сканирование отключено",
+            ],
+            MessageId::ScanUsed => ["Scan was already used", "Сканирование уже использовано"],
+            MessageId::NoOpponent => ["Game has no opponent yet", "У игры ещё нет соперника"],
+            MessageId::NoAbortRequest => [
+                "Opponent didn't ask to abort",
+                "Соперник не предлагал прервать игру",
+            ],
+            MessageId::MovingTooFast => [
+                "Moves are sent too often, at most {0} per second",
+                "Ходы отправляются слишком часто, не больше {0} в секунду",
+            ],
+            MessageId::MatchmakingUnavailable => [
+                "Matchmaking is not available on this server",
+                "Подбор соперника недоступен на этом сервере",
+            ],
+            MessageId::AlreadyInGame => ["Already in a game", "Вы уже в игре"],
+            MessageId::NotSearching => ["Not searching for an opponent", "Поиск соперника не идёт"],
+            MessageId::SpectatingBeforeStart => {
+                ["Game has not started yet", "Игра ещё не началась"]
+            }
+            MessageId::SpectatingOwnGame => [
+                "Players can't spectate their own game",
+                "Игроки не могут наблюдать за своей игрой",
+            ],
+            MessageId::RoomFull => [
+                "Room is full, no more spectators can join",
+                "Комната заполнена, новые зрители не допускаются",
+            ],
+            MessageId::TooManyRooms => [
+                "Can't be in more than {0} rooms at once",
+                "Нельзя быть больше чем в {0} комнатах сразу",
+            ],
+            MessageId::TooManyOpenRooms => [
+                "Too many rooms waiting for an opponent, at most {0}",
+                "Слишком много комнат в ожидании соперника, не больше {0}",
+            ],
+            MessageId::CreatingRoomsTooFast => [
+                "Rooms are created too often, wait a minute",
+                "Комнаты создаются слишком часто, подождите минуту",
+            ],
+            MessageId::NotSpectating => {
+                ["Not spectating this game", "Вы не наблюдаете за этой игрой"]
+            }
+            MessageId::NotPracticing => {
+                ["Start a practice range first", "Сначала начните тренировку"]
+            }
+            MessageId::ReplayNotFound => ["Replay not found", "Запись игры не найдена"],
+            MessageId::UnknownFeed => [
+                "Feed must be one of: {0}",
+                "Лента должна быть одной из: {0}",
+            ],
+            MessageId::PlayerNotFound => ["Player not found", "Игрок не найден"],
+            MessageId::TooManyFriendRequests => [
+                "Too many pending friend requests",
+                "Слишком много неотвеченных заявок в друзья",
+            ],
+        }
+    }
+}
+
+/// A text to show, not yet rendered in a locale. Displays in English, for
+/// logs and operator tools.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub id: MessageId,
+    args: Vec<String>,
+}
+
+impl Message {
+    pub fn new(id: MessageId) -> Self {
+        Self {
+            id,
+            args: Vec::new(),
+        }
+    }
+
+    /// Fills the next placeholder.
+    pub fn arg(mut self, value: impl fmt::Display) -> Self {
+        self.args.push(value.to_string());
+        self
+    }
+
+    pub fn text(&self, locale: Locale) -> String {
+        let mut text = self.id.templates()[locale as usize].to_owned();
+        for (i, arg) in self.args.iter().enumerate() {
+            text = text.replace(&format!("{{{}}}", i), arg);
+        }
+        text
+    }
+}
+
+impl From<MessageId> for Message {
+    fn from(id: MessageId) -> Self {
+        Self::new(id)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text(Locale::En))
+    }
+}
@@ -0,0 +1,251 @@
+//! Process-wide counters exported in the Prometheus text format at `/metrics`.
+
+use std::{
+    fmt::Write,
+    fs,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// Upper bounds in seconds, shared by all latency histograms.
+const BUCKETS: [f64; 10] = [
+    0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+];
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    pub connections_total: Counter,
+    pub connections: Gauge,
+    pub messages_in: Counter,
+    pub messages_out: Counter,
+    pub games_created: Counter,
+    pub games_finished: Counter,
+    pub slow_peers_disconnected: Counter,
+    pub broadcasts_coalesced: Counter,
+    pub broadcasts_skipped: Counter,
+    pub desyncs: Counter,
+    pub lock_wait: Histogram,
+    pub attack_latency: Histogram,
+    pub send_latency: Histogram,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections_total: Counter::new(),
+            connections: Gauge::new(),
+            messages_in: Counter::new(),
+            messages_out: Counter::new(),
+            games_created: Counter::new(),
+            games_finished: Counter::new(),
+            slow_peers_disconnected: Counter::new(),
+            broadcasts_coalesced: Counter::new(),
+            broadcasts_skipped: Counter::new(),
+            desyncs: Counter::new(),
+            lock_wait: Histogram::new(),
+            attack_latency: Histogram::new(),
+            send_latency: Histogram::new(),
+        }
+    }
+
+    /// Renders every metric; `event_queue_depth` and the sizes of the state
+    /// tables are sampled by the caller.
+    pub fn render(&self, event_queue_depth: usize, state_sizes: &[(&str, usize)]) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "connections_total",
+                "Accepted WebSocket connections.",
+                &self.connections_total,
+            ),
+            (
+                "messages_in_total",
+                "Messages received from clients.",
+                &self.messages_in,
+            ),
+            (
+                "messages_out_total",
+                "Messages sent to clients.",
+                &self.messages_out,
+            ),
+            ("games_created_total", "Rooms created.", &self.games_created),
+            (
+                "games_finished_total",
+                "Games that reached a finish.",
+                &self.games_finished,
+            ),
+            (
+                "slow_peers_disconnected_total",
+                "Peers disconnected for not keeping up.",
+                &self.slow_peers_disconnected,
+            ),
+            (
+                "broadcasts_coalesced_total",
+                "Lobby snapshots dropped for a newer one in the same flush.",
+                &self.broadcasts_coalesced,
+            ),
+            (
+                "broadcasts_skipped_total",
+                "Lobby snapshots not sent because every client had them already.",
+                &self.broadcasts_skipped,
+            ),
+            (
+                "desyncs_total",
+                "Clients that reported a game view not matching the server's.",
+                &self.desyncs,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            write_header(&mut out, name, help, "counter");
+            let _ = writeln!(out, "battleships_{} {}", name, counter.get());
+        }
+
+        write_header(
+            &mut out,
+            "connections",
+            "Open WebSocket connections.",
+            "gauge",
+        );
+        let _ = writeln!(out, "battleships_connections {}", self.connections.get());
+        write_header(
+            &mut out,
+            "event_queue_depth",
+            "Server events waiting for dispatch.",
+            "gauge",
+        );
+        let _ = writeln!(out, "battleships_event_queue_depth {}", event_queue_depth);
+        write_header(
+            &mut out,
+            "state_entries",
+            "Entries of the in-memory state tables.",
+            "gauge",
+        );
+        for (table, entries) in state_sizes {
+            let _ = writeln!(
+                out,
+                "battleships_state_entries{{table=\"{}\"}} {}",
+                table, entries
+            );
+        }
+        if let Some(bytes) = resident_memory_bytes() {
+            write_header(
+                &mut out,
+                "resident_memory_bytes",
+                "Resident memory of the process.",
+                "gauge",
+            );
+            let _ = writeln!(out, "battleships_resident_memory_bytes {}", bytes);
+        }
+
+        let histograms = [
+            (
+                "lock_wait_seconds",
+                "Time spent waiting for the state lock.",
+                &self.lock_wait,
+            ),
+            (
+                "attack_duration_seconds",
+                "Time spent handling an attack.",
+                &self.attack_latency,
+            ),
+            (
+                "send_latency_seconds",
+                "Time from queueing a message to writing it to the peer.",
+                &self.send_latency,
+            ),
+        ];
+        for (name, help, histogram) in histograms {
+            write_header(&mut out, name, help, "histogram");
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "battleships_{}_bucket{{le=\"{}\"}} {}",
+                    name, bound, cumulative
+                );
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(out, "battleships_{}_bucket{{le=\"+Inf\"}} {}", name, count);
+            let _ = writeln!(out, "battleships_{}_sum {}", name, sum);
+            let _ = writeln!(out, "battleships_{}_count {}", name, count);
+        }
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP battleships_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE battleships_{} {}", name, kind);
+}
+
+/// Read from `/proc`, so only known on Linux. Assumes 4 KiB pages.
+fn resident_memory_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
@@ -0,0 +1,98 @@
+//! The practice range: a player fires at a hidden random fleet on their
+//! own, without an opponent, a clock or anything at stake, to learn the
+//! client.
+//!
+//! `practice_start` sets up a range, optionally with `boardSize`, and
+//! starting again throws the old one away. The fleet follows from a seed
+//! that never leaves the server, as the client could place the fleet
+//! itself from it; the seed is logged to reproduce a range. Each `practice_attack {x, y}` is answered with the cells it
+//! changed, the shots and hits so far and whether the fleet is `cleared`.
+//! `practice_stop` leaves the range. Nothing of it counts towards the
+//! leaderboard or the player's stats.
+
+use std::collections::HashMap;
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde_json::{json, Value};
+
+use crate::{
+    game::{AttackStatus, Board},
+    messages::{Message, MessageId},
+    ships::Fleet,
+    simulation::{self, Rules},
+    UserId,
+};
+
+#[derive(Debug)]
+struct Range {
+    board: Board,
+    shots: u32,
+    hits: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct PracticeRanges {
+    ranges: HashMap<UserId, Range>,
+}
+
+impl PracticeRanges {
+    /// Sets up a new range for the player, replacing the one they had.
+    pub fn start(&mut self, user_id: &UserId, board_size: usize, seed: u64) -> Value {
+        let fleet = Fleet::default();
+        let rules = Rules {
+            board_size,
+            no_touching: false,
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let ships = simulation::random_fleet(&fleet, rules, &mut rng);
+        let range = Range {
+            board: Board::with_ships(board_size, &ships),
+            shots: 0,
+            hits: 0,
+        };
+        let json = json!({
+            "boardSize": board_size,
+            "fleet": fleet,
+        });
+        self.ranges.insert(user_id.clone(), range);
+        json
+    }
+
+    pub fn attack(&mut self, user_id: &UserId, x: u8, y: u8) -> Result<Value, Message> {
+        let range = self
+            .ranges
+            .get_mut(user_id)
+            .ok_or(MessageId::NotPracticing)?;
+        let cells = range.board.fire_at(x, y)?;
+        range.shots += 1;
+        let status = cells
+            .iter()
+            .find(|(position, _)| position.x == x && position.y == y)
+            .map(|&(_, status)| status)
+            .unwrap_or(AttackStatus::Miss);
+        if !matches!(status, AttackStatus::Miss) {
+            range.hits += 1;
+        }
+        Ok(json!({
+            "position": { "x": x, "y": y },
+            "status": status,
+            "cells": cells
+                .iter()
+                .map(|(position, status)| json!({ "position": position, "status": status }))
+                .collect::<Vec<Value>>(),
+            "shots": range.shots,
+            "hits": range.hits,
+            "cleared": range.board.is_cleared(),
+        }))
+    }
+
+    /// Returns `false` if the player had no range.
+    pub fn stop(&mut self, user_id: &UserId) -> bool {
+        self.ranges.remove(user_id).is_some()
+    }
+
+    pub fn count(&self) -> usize {
+        self.ranges.len()
+    }
+}
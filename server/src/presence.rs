@@ -0,0 +1,134 @@
+//! What connected players are up to, for the lobby and friends lists.
+//!
+//! A player is `inGame` while playing, `searching` while queued for
+//! quickplay or waiting in an open room, and `online` otherwise. Events a
+//! player sends count as activity. Heartbeats, the WebSocket pongs and
+//! `ping` probes clients send on a timer, only show that the connection is
+//! alive. A player held for a reconnect is `away`, and so is one who is
+//! neither playing nor searching after `PRESENCE_AWAY_SECONDS` (300 by
+//! default) without activity or `HEARTBEAT_TIMEOUT` without a heartbeat.
+//! The next event brings them back.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{clock, UserId};
+
+const AWAY_SECONDS_VAR: &str = "PRESENCE_AWAY_SECONDS";
+const DEFAULT_AWAY_SECONDS: u64 = 300;
+/// Several missed pings, see `PING_INTERVAL` in the binary.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    Online,
+    Searching,
+    InGame,
+    Away,
+    Offline,
+}
+
+#[derive(Debug)]
+struct Activity {
+    last_active: Instant,
+    last_heartbeat: Instant,
+}
+
+#[derive(Debug)]
+pub struct Presence {
+    away_after: Duration,
+    players: HashMap<UserId, Activity>,
+    /// Statuses in the last `update_presence`.
+    shown: HashMap<UserId, Status>,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            away_after: Duration::from_secs(DEFAULT_AWAY_SECONDS),
+            players: HashMap::new(),
+            shown: HashMap::new(),
+        }
+    }
+}
+
+impl Presence {
+    pub fn from_env() -> Self {
+        let seconds = std::env::var(AWAY_SECONDS_VAR)
+            .ok()
+            .and_then(|seconds| seconds.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_AWAY_SECONDS);
+        Self {
+            away_after: Duration::from_secs(seconds),
+            ..Self::default()
+        }
+    }
+
+    /// Counts as both activity and a heartbeat; starts tracking the player.
+    pub fn active(&mut self, user_id: &UserId) {
+        let now = clock::now();
+        self.players.insert(
+            user_id.clone(),
+            Activity {
+                last_active: now,
+                last_heartbeat: now,
+            },
+        );
+    }
+
+    pub fn heartbeat(&mut self, user_id: &UserId) {
+        if let Some(activity) = self.players.get_mut(user_id) {
+            activity.last_heartbeat = clock::now();
+        }
+    }
+
+    pub fn leave(&mut self, user_id: &UserId) {
+        self.players.remove(user_id);
+    }
+
+    /// Players whose activity is tracked.
+    pub fn player_count(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn is_away(&self, user_id: &UserId) -> bool {
+        self.is_idle(user_id, self.away_after)
+    }
+
+    /// Like `is_away` with a threshold of the caller's choosing.
+    pub fn is_idle(&self, user_id: &UserId, after: Duration) -> bool {
+        self.players.get(user_id).is_none_or(|activity| {
+            clock::since(activity.last_active) >= after
+                || clock::since(activity.last_heartbeat) >= HEARTBEAT_TIMEOUT
+        })
+    }
+
+    /// The status of the player in the last `update_presence`.
+    pub fn shown(&self, user_id: &UserId) -> Option<Status> {
+        self.shown.get(user_id).copied()
+    }
+
+    /// When the player will count as idle with a threshold of `after`,
+    /// unless they are active again by then. Now for players not tracked.
+    pub fn idle_at(&self, user_id: &UserId, after: Duration) -> Instant {
+        self.players
+            .get(user_id)
+            .map_or_else(clock::now, |activity| {
+                (activity.last_active + after).min(activity.last_heartbeat + HEARTBEAT_TIMEOUT)
+            })
+    }
+
+    /// Keeps the statuses to show and tells whether any changed since they
+    /// were last shown.
+    pub fn update(&mut self, statuses: HashMap<UserId, Status>) -> bool {
+        let changed = statuses != self.shown;
+        self.shown = statuses;
+        changed
+    }
+}
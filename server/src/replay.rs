@@ -0,0 +1,335 @@
+//! Finished games played again on the engine from the audit log.
+//!
+//! The audit log records the room settings, both fleets, every shot and
+//! the seed of the game, which is all the engine needs to reach the same
+//! positions move by move:
+//!
+//! ```text
+//! cargo run -- replay audit.log [game-id] [--auto]
+//! ```
+//!
+//! Without a game id the last finished game of the file is shown. Enter
+//! steps one move, `a` plays the rest automatically and `q` quits.
+//!
+//! To look into a report like "the server said I missed but I hit", the
+//! state of any game, finished or not, can be printed as it was after a
+//! given move, from the log at `AUDIT_LOG`:
+//!
+//! ```text
+//! AUDIT_LOG=audit.log cargo run -- debug replay-state <game-id> [--to-step N]
+//! ```
+//!
+//! Every move up to there is listed, and shots whose result differs from
+//! the one logged are flagged.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Write},
+    thread,
+    time::Duration,
+};
+
+use serde_json::Value;
+
+use crate::{
+    audit::AuditLog,
+    game::{AttackStatus, Game, GameStatus, RoomSettings, Transition},
+    ships::{Position, Ships},
+};
+
+const AUTO_PLAY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+enum Move {
+    Ships(String, Ships),
+    /// Carries the cells the server reported back then.
+    Attack(String, u8, u8, Value),
+    Scan(String, u8, u8),
+}
+
+#[derive(Debug)]
+pub struct Replay {
+    game: Game,
+    moves: Vec<Move>,
+    next: usize,
+    winner: Option<String>,
+    /// Of the final position, as logged when the game finished.
+    checksum: Option<String>,
+    /// The series winner as the replay has it.
+    replayed_winner: Option<String>,
+    /// Moves whose result differs from the one logged, from 1.
+    diverged: Vec<usize>,
+}
+
+impl Replay {
+    /// Collects the records of `game_id`, or of the last finished game, from
+    /// the lines of an audit log.
+    pub fn from_audit_log(log: &str, game_id: Option<&str>) -> Result<Self, String> {
+        let records = log
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .collect::<Vec<Value>>();
+        let game_id = match game_id {
+            Some(game_id) => game_id.to_owned(),
+            None => records
+                .iter()
+                .rev()
+                .find(|record| record["action"] == "finish_game")
+                .and_then(|record| record["details"]["game"].as_str())
+                .ok_or("The log has no finished game")?
+                .to_owned(),
+        };
+        // The result names the winner by user id, registrations map it back.
+        let names = records
+            .iter()
+            .filter(|record| record["action"] == "register")
+            .filter_map(|record| {
+                Some((
+                    record["details"]["user"].as_str()?.to_owned(),
+                    actor(record),
+                ))
+            })
+            .collect::<HashMap<String, String>>();
+        let records = records
+            .into_iter()
+            .filter(|record| record["details"]["game"] == game_id.as_str())
+            .collect::<Vec<Value>>();
+        let find = |action: &str| {
+            records
+                .iter()
+                .find(|record| record["action"] == action)
+                .ok_or_else(|| format!("No {} record for game {}", action, game_id))
+        };
+
+        let created = find("create_room")?;
+        let settings: RoomSettings = serde_json::from_value(created["details"]["settings"].clone())
+            .map_err(|err| format!("Invalid settings of game {}: {}", game_id, err))?;
+        let finished = find("finish_game").ok();
+        let seed = [Some(created), finished]
+            .into_iter()
+            .flatten()
+            .find_map(|record| record["details"]["seed"].as_str()?.parse().ok())
+            .ok_or_else(|| format!("Game {} was logged without its seed", game_id))?;
+        // A room whose opponent left before the start was reopened by the
+        // player who stayed; only what happened after that counts.
+        let reopened = records
+            .iter()
+            .rposition(|record| record["action"] == "reopen_room");
+        let owner = match reopened {
+            Some(index) => actor(&records[index]),
+            None => actor(created),
+        };
+        let records = &records[reopened.unwrap_or(0)..];
+        let joined = records
+            .iter()
+            .find(|record| record["action"] == "join_room")
+            .ok_or_else(|| format!("No join_room record for game {}", game_id))?;
+
+        // Players go by name in the log, so names stand in for user ids.
+        let mut game = Game::with_seed(&game_id, &owner, &settings, seed);
+        game.apply(Transition::Join(actor(joined)))
+            .map_err(|err| err.to_string())?;
+
+        let mut moves = Vec::new();
+        for record in records.iter() {
+            let details = &record["details"];
+            let coordinate = |key: &str| details[key].as_u64().map(|value| value as u8);
+            let next_move = match record["action"].as_str() {
+                Some("add_ships") => serde_json::from_value(json_ships(details))
+                    .ok()
+                    .map(|ships| Move::Ships(actor(record), ships)),
+                Some("attack") => coordinate("x")
+                    .zip(coordinate("y"))
+                    .map(|(x, y)| Move::Attack(actor(record), x, y, details["cells"].clone())),
+                Some("scan") => coordinate("x")
+                    .zip(coordinate("y"))
+                    .map(|(x, y)| Move::Scan(actor(record), x, y)),
+                _ => continue,
+            };
+            moves.push(next_move.ok_or_else(|| format!("Malformed record {}", record))?);
+        }
+
+        Ok(Self {
+            game,
+            moves,
+            next: 0,
+            winner: finished
+                .and_then(|finished| finished["details"]["winner"].as_str())
+                .map(|winner| names.get(winner).cloned().unwrap_or(winner.to_owned())),
+            checksum: finished
+                .and_then(|finished| finished["details"]["checksum"].as_str())
+                .map(str::to_owned),
+            replayed_winner: None,
+            diverged: Vec::new(),
+        })
+    }
+
+    /// Plays the next move and describes it, `None` once all were played.
+    pub fn step(&mut self) -> Option<Result<String, String>> {
+        let next_move = self.moves.get(self.next)?;
+        self.next += 1;
+        let result = match next_move {
+            Move::Ships(name, ships) => self.game.add_ships(ships, name).map(|_| {
+                // Starts the game once both fleets are placed.
+                let _ = self.game.apply(Transition::Start);
+                format!("{} places {} ships", name, ships.ships.len())
+            }),
+            Move::Attack(name, x, y, logged) => self.game.attack(name, *x, *y).map(|result| {
+                let target = Position { x: *x, y: *y };
+                let status = result
+                    .cells
+                    .iter()
+                    .find(|(pos, _)| *pos == target)
+                    .map_or("?", |(_, status)| status_name(*status));
+                if result.is_won {
+                    // The next game of a series starts from empty boards.
+                    match self.game.record_win(name) {
+                        Some(winner) => self.replayed_winner = Some(winner),
+                        None => {
+                            let _ = self.game.apply(Transition::NextRound);
+                        }
+                    }
+                }
+                let mut description = format!("{} fires at ({}, {}): {}", name, x, y, status);
+                if !logged.is_null()
+                    && serde_json::to_value(&result.cells).ok().as_ref() != Some(logged)
+                {
+                    self.diverged.push(self.next);
+                    description += &format!(", but the server reported {}", logged);
+                }
+                description
+            }),
+            Move::Scan(name, x, y) => self
+                .game
+                .scan(name, *x, *y)
+                .map(|count| format!("{} scans ({}, {}): {} ship cells", name, x, y, count)),
+        };
+        Some(result.map_err(|err| format!("Move {} doesn't replay: {}", self.next, err)))
+    }
+
+    /// Plays every move and checks that the replay ends where the game did:
+    /// each shot with the result logged, the same winner and the same final
+    /// position, down to its checksum.
+    pub fn verify(mut self) -> Result<(), String> {
+        while let Some(result) = self.step() {
+            result?;
+        }
+        if let Some(&index) = self.diverged.first() {
+            return Err(format!("Move {} has a different result than logged", index));
+        }
+        // Games that were abandoned or ran out of time have a winner
+        // without a winning shot.
+        if self.replayed_winner.is_some() && self.replayed_winner != self.winner {
+            return Err(format!(
+                "Replay is won by {:?}, the log says {:?}",
+                self.replayed_winner, self.winner
+            ));
+        }
+        match &self.checksum {
+            Some(checksum) if *checksum != self.game.checksum() => Err(format!(
+                "Replay ends at checksum {}, the log says {}",
+                self.game.checksum(),
+                checksum
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Both boards side by side, with every ship shown.
+    pub fn render(&self) -> String {
+        let names = self.game.players().map(|name| name.clone().unwrap());
+        let boards = self.game.boards();
+        let width = self.game.settings.board_size.max(names[0].len()) + 4;
+        let mut text = format!("{:width$}{}\n", names[0], names[1], width = width);
+        for (left, right) in boards[0].lines().zip(boards[1].lines()) {
+            text += &format!("{:width$}{}\n", left, right, width = width);
+        }
+        text
+    }
+
+    /// Whose turn it is and the score of the series.
+    pub fn status(&self) -> String {
+        let score = self.game.score;
+        let turn = match self.game.status() {
+            GameStatus::Waiting => "waiting for an opponent".to_owned(),
+            GameStatus::PlacingShips => "placing ships".to_owned(),
+            GameStatus::Started => {
+                format!("{} to move", self.game.current_player().unwrap_or_default())
+            }
+            GameStatus::Finished => "finished".to_owned(),
+        };
+        format!(
+            "Move {} of {}, {}, score {}:{}",
+            self.next,
+            self.moves.len(),
+            turn,
+            score[0],
+            score[1]
+        )
+    }
+
+    pub fn summary(&self) -> String {
+        match &self.winner {
+            Some(winner) => format!("{} won, seed {}", winner, self.game.seed),
+            None => format!("No winner, seed {}", self.game.seed),
+        }
+    }
+}
+
+/// Shows the game in the terminal, waiting for Enter between moves unless
+/// `auto` is set.
+pub fn run(path: &str, game_id: Option<&str>, mut auto: bool) -> Result<(), String> {
+    let log = fs::read_to_string(path).map_err(|err| format!("Can't read {}: {}", path, err))?;
+    let mut replay = Replay::from_audit_log(&log, game_id)?;
+    let mut input = io::stdin().lock().lines();
+    while let Some(description) = replay.step() {
+        println!("{}\n{}", description?, replay.render());
+        if auto {
+            thread::sleep(AUTO_PLAY_DELAY);
+            continue;
+        }
+        print!("[Enter] next, [a] auto-play, [q] quit: ");
+        let _ = io::stdout().flush();
+        match input.next() {
+            Some(Ok(command)) if command.trim() == "a" => auto = true,
+            Some(Ok(command)) if command.trim() == "q" => return Ok(()),
+            Some(Ok(_)) => {}
+            _ => return Ok(()),
+        }
+    }
+    println!("{}", replay.summary());
+    Ok(())
+}
+
+/// Prints the moves of the game up to `to_step`, or all of them, and the
+/// state it was left in.
+pub fn print_state(game_id: &str, to_step: Option<usize>) -> Result<(), String> {
+    let log = AuditLog::read_from_env()?;
+    let mut replay = Replay::from_audit_log(&log, Some(game_id))?;
+    while to_step.is_none_or(|to_step| replay.next < to_step) {
+        let Some(description) = replay.step() else {
+            break;
+        };
+        println!("{:>4}. {}", replay.next, description?);
+    }
+    println!("\n{}\n{}", replay.status(), replay.render());
+    Ok(())
+}
+
+fn actor(record: &Value) -> String {
+    record["actor"].as_str().unwrap_or_default().to_owned()
+}
+
+/// `add_ships` records hold the bare list.
+fn json_ships(details: &Value) -> Value {
+    serde_json::json!({ "ships": details["ships"] })
+}
+
+fn status_name(status: AttackStatus) -> &'static str {
+    match status {
+        AttackStatus::Miss => "miss",
+        AttackStatus::Shot => "shot",
+        AttackStatus::Killed => "killed",
+    }
+}
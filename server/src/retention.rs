@@ -0,0 +1,53 @@
+//! Background job that forgets data past its retention age.
+//!
+//! `RETENTION_ACCOUNT_DAYS` bounds how long friendships of accounts that
+//! have not connected since are kept (90 days by default) and
+//! `RETENTION_AUDIT_DAYS` how long rotated audit log files are kept
+//! (30 days by default). Zero disables the corresponding cleanup.
+
+use std::time::Duration;
+
+use tokio::time;
+use tracing::info;
+
+use crate::{lock_state, ServerState};
+
+const ACCOUNT_DAYS_VAR: &str = "RETENTION_ACCOUNT_DAYS";
+const AUDIT_DAYS_VAR: &str = "RETENTION_AUDIT_DAYS";
+const DEFAULT_ACCOUNT_DAYS: u64 = 90;
+const DEFAULT_AUDIT_DAYS: u64 = 30;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    pub inactive_accounts: Option<Duration>,
+    pub audit_files: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            inactive_accounts: days_from_env(ACCOUNT_DAYS_VAR, DEFAULT_ACCOUNT_DAYS),
+            audit_files: days_from_env(AUDIT_DAYS_VAR, DEFAULT_AUDIT_DAYS),
+        }
+    }
+}
+
+fn days_from_env(var: &str, default: u64) -> Option<Duration> {
+    let days = std::env::var(var)
+        .ok()
+        .and_then(|days| days.parse::<u64>().ok())
+        .unwrap_or(default);
+    (days > 0).then(|| DAY * days as u32)
+}
+
+pub async fn run(state: ServerState) {
+    let policy = RetentionPolicy::from_env();
+    info!("Retention policy: {:?}", policy);
+    let mut interval = time::interval(CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        lock_state(&state).apply_retention(&policy);
+    }
+}
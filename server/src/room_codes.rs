@@ -0,0 +1,76 @@
+//! Short codes for rooms, so friends can join one by reading the code out
+//! instead of pasting its id.
+//!
+//! Every game gets a code of `CODE_LENGTH` characters when it is created
+//! and keeps it until it closes. The code is shown as `code` in the room
+//! list and in `friend_challenge`. `add_user_to_room` takes it as
+//! `indexRoom` in place of the id. Characters that are easy to mix up
+//! (`0` and `O`, `1`, `I` and `L`) are left out, and codes match
+//! whatever their case.
+//!
+//! Invite links carry the code too. A client connecting to `/ws?join=CODE`
+//! or `/join/CODE`, or registering with a `joinLink`, joins the room as
+//! soon as it is registered.
+
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use crate::game::GameId;
+
+const CODE_LENGTH: usize = 6;
+const ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+#[derive(Debug, Default)]
+pub struct RoomCodes {
+    games: HashMap<String, GameId>,
+    codes: HashMap<GameId, String>,
+}
+
+impl RoomCodes {
+    /// Gives the game a code no other open game has.
+    pub fn assign(&mut self, game_id: &GameId) -> String {
+        let mut rng = rand::thread_rng();
+        let code = loop {
+            let code = (0..CODE_LENGTH)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect::<String>();
+            if !self.games.contains_key(&code) {
+                break code;
+            }
+        };
+        self.games.insert(code.clone(), game_id.clone());
+        self.codes.insert(game_id.clone(), code.clone());
+        code
+    }
+
+    pub fn release(&mut self, game_id: &GameId) {
+        if let Some(code) = self.codes.remove(game_id) {
+            self.games.remove(&code);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn code_of(&self, game_id: &GameId) -> Option<&str> {
+        self.codes.get(game_id).map(String::as_str)
+    }
+
+    /// The game a code stands for.
+    pub fn game_id(&self, code: &str) -> Option<&GameId> {
+        self.games.get(&code.trim().to_ascii_uppercase())
+    }
+}
+
+/// The room an invite link points at, by the `join` query parameter or the
+/// path segment after `/join/`.
+pub fn from_link(link: &str) -> Option<&str> {
+    let (path, query) = link.split_once('?').unwrap_or((link, ""));
+    let from_query = query.split('&').find_map(|pair| pair.strip_prefix("join="));
+    let from_path = path
+        .split_once("/join/")
+        .and_then(|(_, rest)| rest.split('/').next());
+    from_query.or(from_path).filter(|room| !room.is_empty())
+}
@@ -0,0 +1,166 @@
+//! Game variants. A room picks its ruleset by name at creation; the engine
+//! in `game` asks it how many shots a turn has, what a shot earns and when a
+//! player is beaten. Builds with the `scripting` feature add the rulesets
+//! of Rhai scripts, see `scripting`.
+
+use std::fmt;
+
+use crate::{
+    game::RoomSettings,
+    messages::{Message, MessageId},
+    ships::{Fleet, Ship, Ships},
+};
+
+pub const DEFAULT_RULESET: &str = "classic";
+
+static RULESETS: &[&dyn Ruleset] = &[&Classic, &Salvo, &Mines];
+
+pub trait Ruleset: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Checks the room settings at creation.
+    fn validate_settings(&self, _settings: &RoomSettings) -> Result<(), Message> {
+        Ok(())
+    }
+
+    /// Checks ships before they are placed against the `fleet` the player
+    /// has to place. Bounds, overlaps and touching ships are checked by the
+    /// engine.
+    fn validate_placement(&self, ships: &Ships, fleet: &Fleet) -> Result<(), Message> {
+        fleet.matches(ships)
+    }
+
+    /// Shots of a turn, given how many of the shooter's ships are afloat.
+    fn shots_per_turn(&self, _ships_afloat: usize) -> usize {
+        1
+    }
+
+    /// Whether a turn can have more than one shot, which a `fewerShots`
+    /// handicap takes from.
+    fn has_several_shots(&self) -> bool {
+        false
+    }
+
+    /// What a shot that hit `ship`, or nothing, earns the shooter.
+    fn resolve_shot(&self, hit: Option<&Ship>) -> ShotEffect {
+        ShotEffect {
+            extra_shot: hit.is_some(),
+        }
+    }
+
+    /// Whether a player with these ships still afloat lost the game.
+    fn is_defeated(&self, afloat: &[&Ship]) -> bool {
+        afloat.is_empty()
+    }
+}
+
+impl fmt::Debug for dyn Ruleset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShotEffect {
+    /// The shot doesn't count against the shots of the turn.
+    pub extra_shot: bool,
+}
+
+pub fn ruleset(name: &str) -> Option<&'static dyn Ruleset> {
+    all().find(|ruleset| ruleset.name() == name)
+}
+
+pub fn ruleset_names() -> Vec<&'static str> {
+    all().map(|ruleset| ruleset.name()).collect()
+}
+
+/// Whether a built-in ruleset is called `name`, which scripts can't take.
+#[cfg(feature = "scripting")]
+pub fn is_built_in(name: &str) -> bool {
+    RULESETS.iter().any(|ruleset| ruleset.name() == name)
+}
+
+/// The built-in rulesets, then those of the scripts.
+fn all() -> impl Iterator<Item = &'static dyn Ruleset> {
+    RULESETS.iter().chain(scripted()).copied()
+}
+
+#[cfg(feature = "scripting")]
+fn scripted() -> &'static [&'static dyn Ruleset] {
+    crate::scripting::rulesets()
+}
+
+#[cfg(not(feature = "scripting"))]
+fn scripted() -> &'static [&'static dyn Ruleset] {
+    &[]
+}
+
+/// One shot a turn, and hitting a ship earns another.
+pub struct Classic;
+
+impl Ruleset for Classic {
+    fn name(&self) -> &'static str {
+        "classic"
+    }
+}
+
+/// As many shots a turn as the shooter has ships afloat, hit or not.
+struct Salvo;
+
+impl Ruleset for Salvo {
+    fn name(&self) -> &'static str {
+        "salvo"
+    }
+
+    fn shots_per_turn(&self, ships_afloat: usize) -> usize {
+        ships_afloat.max(1)
+    }
+
+    fn has_several_shots(&self) -> bool {
+        true
+    }
+
+    fn resolve_shot(&self, _hit: Option<&Ship>) -> ShotEffect {
+        ShotEffect { extra_shot: false }
+    }
+}
+
+/// Single-deck ships are mines: hitting one ends the turn like a miss, and
+/// they don't have to be found to win.
+struct Mines;
+
+impl Ruleset for Mines {
+    fn name(&self) -> &'static str {
+        "mines"
+    }
+
+    /// Both the room fleet and a handicap fleet need ships that aren't
+    /// mines, or their player is beaten from the start.
+    fn validate_settings(&self, settings: &RoomSettings) -> Result<(), Message> {
+        let handicap_fleet = settings
+            .handicap
+            .as_ref()
+            .and_then(|handicap| handicap.fleet.as_ref());
+        for fleet in std::iter::once(&settings.fleet).chain(handicap_fleet) {
+            let lengths = fleet.lengths();
+            if !lengths.contains(&1) || lengths.iter().all(|length| *length == 1) {
+                return Err(MessageId::MinesNeedMixedFleet.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_shot(&self, hit: Option<&Ship>) -> ShotEffect {
+        ShotEffect {
+            extra_shot: hit.is_some_and(|ship| !is_mine(ship)),
+        }
+    }
+
+    fn is_defeated(&self, afloat: &[&Ship]) -> bool {
+        afloat.iter().all(|ship| is_mine(ship))
+    }
+}
+
+fn is_mine(ship: &Ship) -> bool {
+    ship.hp == 1
+}
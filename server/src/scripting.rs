@@ -0,0 +1,175 @@
+//! House rules written in Rhai, in builds with the `scripting` feature.
+//!
+//! Every `<name>.rhai` file in the directory named by `RULES_SCRIPTS` is a
+//! ruleset called `<name>`, which rooms pick by name like the built-in ones.
+//! A script defines any of these functions, and whatever it leaves out
+//! works as in the classic rules:
+//!
+//! - `validate_placement(ships, fleet)`: runs once the ships match the
+//!   fleet. Returns `()` to accept them or a text telling the player what
+//!   is wrong.
+//! - `resolve_shot(hit)`: whether the shot earns another one. `hit` is the
+//!   ship hit, as it was before the shot, or `()` for a miss.
+//! - `is_defeated(afloat)`: whether a player with these ships afloat lost.
+//!
+//! Ships are maps as `add_ships` sends them (`position`, `direction`,
+//! `type`, `length`) and a fleet maps lengths to counts. Scripts run with
+//! the state locked, so a call is cut off after `MAX_OPERATIONS`. A call
+//! that fails or returns something else is logged and the classic rule
+//! applies.
+//!
+//! ```text
+//! // rules/no_bonus.rhai: hits don't earn another shot.
+//! fn resolve_shot(hit) { false }
+//! ```
+//!
+//! ```text
+//! RULES_SCRIPTS=rules cargo run --features scripting
+//! ```
+
+use std::{fs, path::Path, sync::OnceLock};
+
+use rhai::{Dynamic, Engine, FuncArgs, Scope, AST};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    messages::{Message, MessageId},
+    rules::{self, Classic, Ruleset, ShotEffect},
+    ships::{Fleet, Ship, Ships},
+};
+
+const RULES_SCRIPTS_VAR: &str = "RULES_SCRIPTS";
+/// Most operations one call of a script may take.
+const MAX_OPERATIONS: u64 = 100_000;
+
+static RULESETS: OnceLock<Vec<&'static dyn Ruleset>> = OnceLock::new();
+
+/// The rulesets of the scripts, loaded on first use.
+pub fn rulesets() -> &'static [&'static dyn Ruleset] {
+    RULESETS.get_or_init(|| match std::env::var(RULES_SCRIPTS_VAR) {
+        Ok(dir) if !dir.is_empty() => load(Path::new(&dir)),
+        _ => Vec::new(),
+    })
+}
+
+/// Compiles the scripts of `dir`, leaving out those that don't compile or
+/// are named after a built-in ruleset. They live as long as the server.
+fn load(dir: &Path) -> Vec<&'static dyn Ruleset> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Rules scripts in {} not loaded: {}", dir.display(), err);
+            return Vec::new();
+        }
+    };
+    let mut paths = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "rhai")
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut rulesets = Vec::new();
+    for path in paths {
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if rules::is_built_in(name) {
+            warn!(
+                "Rules script {} ignored: '{}' is a built-in ruleset",
+                path.display(),
+                name
+            );
+            continue;
+        }
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        match engine.compile_file(path.clone()) {
+            Ok(ast) => {
+                info!("Ruleset '{}' loaded from {}", name, path.display());
+                let ruleset = Scripted {
+                    name: name.to_owned().leak(),
+                    engine,
+                    ast,
+                };
+                rulesets.push(Box::leak(Box::new(ruleset)) as &dyn Ruleset);
+            }
+            Err(err) => warn!("Rules script {} not loaded: {}", path.display(), err),
+        }
+    }
+    rulesets
+}
+
+struct Scripted {
+    name: &'static str,
+    engine: Engine,
+    ast: AST,
+}
+
+impl Scripted {
+    /// The result of `function`, `None` if the script doesn't define it or
+    /// the call fails.
+    fn call<T: Clone + Send + Sync + 'static>(
+        &self,
+        function: &str,
+        args: impl FuncArgs,
+    ) -> Option<T> {
+        if !self
+            .ast
+            .iter_functions()
+            .any(|defined| defined.name == function)
+        {
+            return None;
+        }
+        match self
+            .engine
+            .call_fn::<T>(&mut Scope::new(), &self.ast, function, args)
+        {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!("Ruleset '{}': {} failed: {}", self.name, function, err);
+                None
+            }
+        }
+    }
+}
+
+impl Ruleset for Scripted {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn validate_placement(&self, ships: &Ships, fleet: &Fleet) -> Result<(), Message> {
+        fleet.matches(ships)?;
+        let verdict = self.call::<Dynamic>(
+            "validate_placement",
+            (to_dynamic(&ships.ships), to_dynamic(fleet)),
+        );
+        match verdict {
+            Some(verdict) if verdict.is_string() => {
+                Err(Message::new(MessageId::PlacementRejected).arg(verdict))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn resolve_shot(&self, hit: Option<&Ship>) -> ShotEffect {
+        let ship = hit.map_or(Dynamic::UNIT, to_dynamic);
+        match self.call::<bool>("resolve_shot", (ship,)) {
+            Some(extra_shot) => ShotEffect { extra_shot },
+            None => Classic.resolve_shot(hit),
+        }
+    }
+
+    fn is_defeated(&self, afloat: &[&Ship]) -> bool {
+        self.call::<bool>("is_defeated", (to_dynamic(afloat),))
+            .unwrap_or_else(|| Classic.is_defeated(afloat))
+    }
+}
+
+fn to_dynamic(value: &(impl Serialize + ?Sized)) -> Dynamic {
+    rhai::serde::to_dynamic(value).unwrap_or(Dynamic::UNIT)
+}
@@ -0,0 +1,121 @@
+//! Sessions that survive a dropped connection.
+//!
+//! Every registration gets a session token. When a player in a running game
+//! loses the connection, their user and games are held for
+//! `RECONNECT_GRACE` and the events sent to them meanwhile are kept, up to
+//! `MAX_MISSED_EVENTS`. Reconnecting with the token picks the session up
+//! again and delivers what was missed. A player who doesn't come back in
+//! time forfeits their running games.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+use crate::{clock, UserId};
+
+pub const RECONNECT_GRACE: Duration = Duration::from_secs(30);
+const MAX_MISSED_EVENTS: usize = 256;
+
+#[derive(Debug, Default)]
+pub struct Sessions {
+    tokens: HashMap<String, UserId>,
+    absent: HashMap<UserId, Absence>,
+}
+
+/// What happened while a player was away.
+#[derive(Debug)]
+pub struct Absence {
+    since: Instant,
+    /// Events in the order they were sent, oldest dropped first.
+    pub missed: VecDeque<String>,
+    /// More events were missed than kept, so the client has to sync.
+    pub overflowed: bool,
+}
+
+impl Sessions {
+    /// Starts a session and returns its token.
+    pub fn open(&mut self, user_id: &UserId) -> String {
+        let token = Uuid::new_v4().to_string();
+        self.tokens.insert(token.clone(), user_id.clone());
+        token
+    }
+
+    /// Ends every session of the user.
+    pub fn close(&mut self, user_id: &UserId) {
+        self.tokens.retain(|_, owner| owner != user_id);
+        self.absent.remove(user_id);
+    }
+
+    /// Starts holding the user's events. Returns `false` when the user has
+    /// no session to come back to.
+    pub fn disconnect(&mut self, user_id: &UserId) -> bool {
+        if !self.tokens.values().any(|owner| owner == user_id) {
+            return false;
+        }
+        self.absent.insert(
+            user_id.clone(),
+            Absence {
+                since: clock::now(),
+                missed: VecDeque::new(),
+                overflowed: false,
+            },
+        );
+        true
+    }
+
+    /// Time the user has left to reconnect, if they are away.
+    pub fn remaining_grace(&self, user_id: &UserId) -> Option<Duration> {
+        self.absent
+            .get(user_id)
+            .map(|absence| RECONNECT_GRACE.saturating_sub(clock::since(absence.since)))
+    }
+
+    /// Session tokens that can still be resumed.
+    pub fn token_count(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Users held for a reconnect.
+    pub fn absent_count(&self) -> usize {
+        self.absent.len()
+    }
+
+    /// Keeps an event sent to the user if they are away.
+    pub fn record(&mut self, user_id: &UserId, json: &str) {
+        if let Some(absence) = self.absent.get_mut(user_id) {
+            if absence.missed.len() == MAX_MISSED_EVENTS {
+                absence.missed.pop_front();
+                absence.overflowed = true;
+            }
+            absence.missed.push_back(json.to_owned());
+        }
+    }
+
+    /// Picks up the session of `token`. The absence is `None` when the old
+    /// connection wasn't noticed to be gone yet.
+    pub fn resume(&mut self, token: &str) -> Option<(UserId, Option<Absence>)> {
+        let user_id = self.tokens.get(token)?.clone();
+        let absence = self.absent.remove(&user_id);
+        Some((user_id, absence))
+    }
+
+    /// When the user runs out of time to reconnect, if they are away.
+    pub fn grace_deadline(&self, user_id: &UserId) -> Option<Instant> {
+        self.absent
+            .get(user_id)
+            .map(|absence| absence.since + RECONNECT_GRACE)
+    }
+
+    /// Ends the session of a user away for longer than the grace period.
+    /// Returns `false` when they came back or have time left.
+    pub fn expire(&mut self, user_id: &UserId) -> bool {
+        if self.remaining_grace(user_id) != Some(Duration::ZERO) {
+            return false;
+        }
+        self.close(user_id);
+        true
+    }
+}
@@ -0,0 +1,144 @@
+//! Replays players share with anyone, no registration needed.
+//!
+//! The last `MAX_KEPT_GAMES` finished games are kept in memory. A player of
+//! one of them can publish it with `share_replay`, optionally with both
+//! names replaced, and hand out the returned id; `watch_replay` with that id
+//! loads the replay. Players appear by their number, 1 or 2, so no user id
+//! leaves the server. Deleting an account drops the games it played.
+
+use std::collections::VecDeque;
+
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    game::{Game, GameId, Shot},
+    messages::MessageId,
+    ships::Ships,
+    UserId,
+};
+
+const MAX_KEPT_GAMES: usize = 100;
+
+#[derive(Debug)]
+struct FinishedGame {
+    game_id: GameId,
+    player_ids: [UserId; 2],
+    names: [String; 2],
+    json: serde_json::Value,
+    /// Set once shared.
+    replay_id: Option<String>,
+    anonymized: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct SharedReplays {
+    games: VecDeque<FinishedGame>,
+}
+
+impl SharedReplays {
+    /// Keeps a finished game so its players can share it. `names` are in the
+    /// order of `game.players()`.
+    pub fn keep(&mut self, game: &Game, names: [String; 2], winner: Option<&UserId>) {
+        let [Some(player1), Some(player2)] = game.players() else {
+            return;
+        };
+        let player_ids = [player1, player2];
+        let number = |user_id: &UserId| {
+            player_ids
+                .iter()
+                .position(|id| id == user_id)
+                .map(|i| i + 1)
+        };
+        let timeline = game
+            .shots()
+            .iter()
+            .map(|shot: &Shot| {
+                json!({
+                    "round": shot.round,
+                    "player": number(&shot.player),
+                    "position": shot.position,
+                    "status": shot.status,
+                    "hit": shot.hit,
+                    "atMs": shot.at_ms,
+                })
+            })
+            .collect::<Vec<_>>();
+        let ships = player_ids
+            .iter()
+            .map(|user_id| game.ships_of(user_id).clone())
+            .collect::<Vec<Ships>>();
+        let json = json!({
+            "settings": game.settings,
+            "seed": game.seed.to_string(),
+            "winner": winner.and_then(number),
+            "ships": ships,
+            "timeline": timeline,
+        });
+        if self.games.len() == MAX_KEPT_GAMES {
+            self.games.pop_front();
+        }
+        self.games.push_back(FinishedGame {
+            game_id: game.id.clone(),
+            player_ids,
+            names,
+            json,
+            replay_id: None,
+            anonymized: false,
+        });
+    }
+
+    /// Publishes a game of the user and returns the replay id and whether
+    /// names are hidden. Sharing again keeps the id; once either player asked
+    /// for anonymization, the names stay hidden.
+    pub fn share(
+        &mut self,
+        game_id: &GameId,
+        user_id: &UserId,
+        anonymize: bool,
+    ) -> Result<(String, bool), MessageId> {
+        let game = self
+            .games
+            .iter_mut()
+            .find(|game| &game.game_id == game_id)
+            .ok_or(MessageId::ReplayNotFound)?;
+        if !game.player_ids.contains(user_id) {
+            return Err(MessageId::NotAPlayer);
+        }
+        game.anonymized |= anonymize;
+        let replay_id = game
+            .replay_id
+            .get_or_insert_with(|| Uuid::new_v4().to_string());
+        Ok((replay_id.clone(), game.anonymized))
+    }
+
+    pub fn watch(&self, replay_id: &str) -> Option<serde_json::Value> {
+        let game = self
+            .games
+            .iter()
+            .find(|game| game.replay_id.as_deref() == Some(replay_id))?;
+        let mut json = game.json.clone();
+        json["replayId"] = json!(replay_id);
+        json["players"] = if game.anonymized {
+            json!(["Player 1", "Player 2"])
+        } else {
+            json!(game.names)
+        };
+        Some(json)
+    }
+
+    /// Every shared replay as `watch` has it, newest first.
+    #[cfg(feature = "graphql")]
+    pub fn shared(&self) -> Vec<serde_json::Value> {
+        self.games
+            .iter()
+            .rev()
+            .filter_map(|game| self.watch(game.replay_id.as_deref()?))
+            .collect()
+    }
+
+    /// Drops every game the user played, shared or not.
+    pub fn forget(&mut self, user_id: &UserId) {
+        self.games.retain(|game| !game.player_ids.contains(user_id));
+    }
+}
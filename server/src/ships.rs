@@ -1,54 +1,266 @@
-use serde::{Deserialize, Serialize};
-
-const SHIPS_LIMIT: usize = 10;
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Ships {
-    pub ships: Vec<Ship>,
-}
-
-impl Default for Ships {
-    fn default() -> Self {
-        Self {
-            ships: Vec::with_capacity(SHIPS_LIMIT),
-        }
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
-pub enum ShipType {
-    Small,
-    Medium,
-    Large,
-    Huge,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-// #[serde(default)]
-pub struct Ship {
-    pub position: Position,
-    #[serde(rename = "direction")]
-    pub is_vertical: bool,
-    #[serde(rename = "type")]
-    pub ship_type: ShipType,
-    #[serde(rename = "length")]
-    pub hp: u8,
-}
-
-// impl Default for Ship {
-//     fn default() -> Self {
-//         Self {
-//             position: Position { x: 0, y: 0 },
-//             is_vertical: false,
-//             ship_type: ShipType::Small,
-//             hp: 1,
-//         }
-//     }
-// }
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Position {
-    pub x: u8,
-    pub y: u8,
-}
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+
+use crate::messages::{Message, MessageId};
+
+const SHIPS_LIMIT: usize = 10;
+pub const MAX_SHIP_LENGTH: u8 = 4;
+pub const MAX_FLEET_SIZE: usize = 20;
+/// Most cells around a ship, those of a ship of `MAX_SHIP_LENGTH`.
+pub const MAX_AROUND: usize = 2 * MAX_SHIP_LENGTH as usize + 6;
+
+/// The cells of one ship. Like the other short lists the engine builds on
+/// every shot, they are kept inline, so most shots don't allocate.
+pub type ShipCells<T> = SmallVec<[T; MAX_SHIP_LENGTH as usize]>;
+
+/// Number of ships of each length a player has to place.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(from = "BTreeMap<u8, u8>")]
+pub struct Fleet(BTreeMap<u8, u8>);
+
+impl From<BTreeMap<u8, u8>> for Fleet {
+    fn from(counts: BTreeMap<u8, u8>) -> Self {
+        Self::new(counts)
+    }
+}
+
+impl Default for Fleet {
+    /// The classic set: one 4-deck, two 3-deck, three 2-deck and four 1-deck ships.
+    fn default() -> Self {
+        Self(BTreeMap::from([(1, 4), (2, 3), (3, 2), (4, 1)]))
+    }
+}
+
+impl Fleet {
+    pub fn new(counts: BTreeMap<u8, u8>) -> Self {
+        Self(counts.into_iter().filter(|(_, count)| *count > 0).collect())
+    }
+
+    pub fn ship_count(&self) -> usize {
+        self.0.values().map(|count| *count as usize).sum()
+    }
+
+    /// Length of every ship, one entry per ship.
+    pub fn lengths(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .flat_map(|(length, count)| std::iter::repeat_n(*length, *count as usize))
+            .collect()
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.0
+            .iter()
+            .map(|(length, count)| *length as usize * *count as usize)
+            .sum()
+    }
+
+    /// Checks that the fleet can reasonably be placed on a board of `board_size`.
+    pub fn validate(&self, board_size: usize) -> Result<(), Message> {
+        if self.0.is_empty() {
+            return Err(MessageId::EmptyFleet.into());
+        }
+        if let Some(length) = self
+            .0
+            .keys()
+            .find(|length| **length == 0 || **length > MAX_SHIP_LENGTH)
+        {
+            return Err(Message::new(MessageId::ShipLengthOutOfRange)
+                .arg(length)
+                .arg(MAX_SHIP_LENGTH));
+        }
+        if self.ship_count() > MAX_FLEET_SIZE {
+            return Err(Message::new(MessageId::TooManyShips).arg(MAX_FLEET_SIZE));
+        }
+        // Keep enough free water around the ships for a fair game.
+        if self.cell_count() * 4 > board_size * board_size {
+            return Err(MessageId::FleetTooLarge.into());
+        }
+        Ok(())
+    }
+
+    /// Checks that placed ships are exactly this fleet.
+    pub fn matches(&self, ships: &Ships) -> Result<(), Message> {
+        let mut placed = BTreeMap::new();
+        for ship in ships.ships.iter() {
+            *placed.entry(ship.hp).or_insert(0u8) += 1;
+        }
+        if placed != self.0 {
+            return Err(MessageId::FleetMismatch.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ships {
+    pub ships: Vec<Ship>,
+}
+
+impl Default for Ships {
+    fn default() -> Self {
+        Self {
+            ships: Vec::with_capacity(SHIPS_LIMIT),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum ShipType {
+    Small,
+    Medium,
+    Large,
+    Huge,
+}
+
+impl ShipType {
+    /// The type the client shows for a ship of `length` cells.
+    pub fn of_length(length: u8) -> Self {
+        match length {
+            1 => Self::Small,
+            2 => Self::Medium,
+            3 => Self::Large,
+            _ => Self::Huge,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+// #[serde(default)]
+pub struct Ship {
+    pub position: Position,
+    #[serde(rename = "direction")]
+    pub is_vertical: bool,
+    #[serde(rename = "type")]
+    pub ship_type: ShipType,
+    #[serde(rename = "length")]
+    pub hp: u8,
+}
+
+impl Ship {
+    /// Cells occupied by the ship. Coordinates saturate instead of wrapping,
+    /// so ships reaching past the edge always end up out of bounds.
+    pub fn cells(&self) -> ShipCells<Position> {
+        (0..self.hp)
+            .map(|i| {
+                if self.is_vertical {
+                    Position {
+                        x: self.position.x,
+                        y: self.position.y.saturating_add(i),
+                    }
+                } else {
+                    Position {
+                        x: self.position.x.saturating_add(i),
+                        y: self.position.y,
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Cells of the ship on a board of `board_size`, `None` unless the whole
+    /// ship is on the board.
+    pub fn coords(&self, board_size: usize) -> Option<ShipCells<Coord>> {
+        let start = Coord::of(&self.position, board_size)?;
+        (0..self.hp as i16)
+            .map(|i| match self.is_vertical {
+                true => start.offset(0, i),
+                false => start.offset(i, 0),
+            })
+            .collect()
+    }
+}
+
+// impl Default for Ship {
+//     fn default() -> Self {
+//         Self {
+//             position: Position { x: 0, y: 0 },
+//             is_vertical: false,
+//             ship_type: ShipType::Small,
+//             hp: 1,
+//         }
+//     }
+// }
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub x: u8,
+    pub y: u8,
+}
+
+/// A cell of a board of known size. Only cells on the board can be made, so
+/// indexing a board with one can't go out of bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Coord {
+    x: u8,
+    y: u8,
+    board_size: u8,
+}
+
+impl Coord {
+    pub fn new(x: u8, y: u8, board_size: usize) -> Option<Self> {
+        let board_size = u8::try_from(board_size).ok()?;
+        (x < board_size && y < board_size).then_some(Self { x, y, board_size })
+    }
+
+    pub fn of(position: &Position, board_size: usize) -> Option<Self> {
+        Self::new(position.x, position.y, board_size)
+    }
+
+    /// The cell at `index` of a board stored row by row.
+    pub fn from_index(index: usize, board_size: usize) -> Option<Self> {
+        let x = u8::try_from(index % board_size).ok()?;
+        let y = u8::try_from(index / board_size).ok()?;
+        Self::new(x, y, board_size)
+    }
+
+    pub fn x(self) -> u8 {
+        self.x
+    }
+
+    pub fn y(self) -> u8 {
+        self.y
+    }
+
+    pub fn index(self) -> usize {
+        self.x as usize + self.y as usize * self.board_size as usize
+    }
+
+    pub fn position(self) -> Position {
+        Position {
+            x: self.x,
+            y: self.y,
+        }
+    }
+
+    /// The cell `dx` columns and `dy` rows away, if it is on the board.
+    pub fn offset(self, dx: i16, dy: i16) -> Option<Self> {
+        let x = u8::try_from(self.x as i16 + dx).ok()?;
+        let y = u8::try_from(self.y as i16 + dy).ok()?;
+        Self::new(x, y, self.board_size as usize)
+    }
+
+    /// The cells touching this one, diagonals included.
+    pub fn neighbors(self) -> impl Iterator<Item = Coord> {
+        (-1..=1)
+            .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+            .filter(|&offset| offset != (0, 0))
+            .filter_map(move |(dx, dy)| self.offset(dx, dy))
+    }
+
+    /// The cells touching any of `cells` without being one of them.
+    pub fn around(cells: &[Coord]) -> SmallVec<[Coord; MAX_AROUND]> {
+        let mut around = SmallVec::new();
+        for cell in cells.iter() {
+            for neighbor in cell.neighbors() {
+                if !cells.contains(&neighbor) && !around.contains(&neighbor) {
+                    around.push(neighbor);
+                }
+            }
+        }
+        around
+    }
+}
@@ -0,0 +1,276 @@
+//! AI-vs-AI games played straight on the game engine, for comparing
+//! strategies and rule sets offline.
+//!
+//! Every game follows from its seed: fleet placement, the first turn and
+//! every shot, so an interesting game can be replayed from its result row.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+    time::Instant,
+};
+
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+
+use crate::{
+    game::{AttackStatus, Game, RoomSettings, Transition, MAX_BOARD_SIZE, MIN_BOARD_SIZE},
+    ships::{Coord, Fleet, Position, Ship, ShipCells, ShipType, Ships},
+    UserId,
+};
+
+/// Fleet placements that got stuck are restarted this many times.
+const MAX_PLACEMENT_ATTEMPTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Fires at a random cell not fired at yet.
+    Random,
+    /// Fires randomly until a hit, then at the neighbours of the hit until
+    /// the ship sinks.
+    Hunt,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "random" => Ok(Self::Random),
+            "hunt" => Ok(Self::Hunt),
+            _ => Err(format!("Unknown strategy {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Random => write!(f, "random"),
+            Self::Hunt => write!(f, "hunt"),
+        }
+    }
+}
+
+/// Room rules a simulated game is played under, with the classic fleet.
+#[derive(Debug, Clone, Copy)]
+pub struct Rules {
+    pub board_size: usize,
+    pub no_touching: bool,
+}
+
+impl Rules {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(&self.board_size) {
+            return Err(format!(
+                "Board size must be between {} and {}",
+                MIN_BOARD_SIZE, MAX_BOARD_SIZE
+            ));
+        }
+        Fleet::default()
+            .validate(self.board_size)
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// One result row.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameRecord {
+    pub game: usize,
+    pub strategy1: Strategy,
+    pub strategy2: Strategy,
+    pub board_size: usize,
+    pub no_touching: bool,
+    /// As a string: JavaScript numbers can't hold every u64.
+    pub seed: String,
+    /// 1 or 2.
+    pub winner: u8,
+    pub shots1: u32,
+    pub shots2: u32,
+    pub duration_us: u64,
+}
+
+impl GameRecord {
+    pub const CSV_HEADER: &'static str =
+        "game,strategy1,strategy2,boardSize,noTouching,seed,winner,shots1,shots2,durationUs";
+
+    pub fn write_csv(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{}",
+            self.game,
+            self.strategy1,
+            self.strategy2,
+            self.board_size,
+            self.no_touching,
+            self.seed,
+            self.winner,
+            self.shots1,
+            self.shots2,
+            self.duration_us
+        )
+    }
+
+    pub fn write_jsonl(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", serde_json::to_string(self)?)
+    }
+}
+
+/// What a player learned about the opponent's board.
+struct Player {
+    strategy: Strategy,
+    fired: HashSet<(u8, u8)>,
+    /// Cells next to hits of a ship that isn't sunk yet.
+    targets: Vec<(u8, u8)>,
+    shots: u32,
+}
+
+impl Player {
+    fn new(strategy: Strategy) -> Self {
+        Self {
+            strategy,
+            fired: HashSet::new(),
+            targets: Vec::new(),
+            shots: 0,
+        }
+    }
+
+    fn pick_target(&mut self, board_size: usize, rng: &mut ChaCha8Rng) -> (u8, u8) {
+        if self.strategy == Strategy::Hunt {
+            while let Some(target) = self.targets.pop() {
+                if !self.fired.contains(&target) {
+                    return target;
+                }
+            }
+        }
+        let open = (0..board_size as u8)
+            .flat_map(|y| (0..board_size as u8).map(move |x| (x, y)))
+            .filter(|cell| !self.fired.contains(cell))
+            .collect::<Vec<(u8, u8)>>();
+        *open.choose(rng).expect("no cell left to fire at")
+    }
+
+    fn learn(&mut self, board_size: usize, cells: &[(Position, AttackStatus)]) {
+        for (pos, status) in cells {
+            self.fired.insert((pos.x, pos.y));
+            match status {
+                AttackStatus::Shot => {
+                    let (x, y) = (pos.x as i16, pos.y as i16);
+                    for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                        let (nx, ny) = (x + dx, y + dy);
+                        if (0..board_size as i16).contains(&nx)
+                            && (0..board_size as i16).contains(&ny)
+                        {
+                            self.targets.push((nx as u8, ny as u8));
+                        }
+                    }
+                }
+                AttackStatus::Killed => self.targets.clear(),
+                AttackStatus::Miss => {}
+            }
+        }
+    }
+}
+
+/// Plays one game to the end.
+pub fn play(game_index: usize, rules: Rules, strategies: [Strategy; 2], seed: u64) -> GameRecord {
+    let started = Instant::now();
+    let settings = RoomSettings {
+        board_size: rules.board_size,
+        no_touching: rules.no_touching,
+        ..RoomSettings::default()
+    };
+    let ids: [UserId; 2] = ["player1".to_owned(), "player2".to_owned()];
+    let mut game = Game::with_seed(&format!("sim-{}", game_index), &ids[0], &settings, seed);
+    game.apply(Transition::Join(ids[1].clone()))
+        .expect("new game is waiting");
+
+    // Placement and shots draw from their own stream, so the engine's use
+    // of the seed stays the same as in a real game.
+    let mut rng = ChaCha8Rng::seed_from_u64(seed.rotate_left(32));
+    for user_id in ids.iter() {
+        let ships = random_fleet(&settings.fleet, rules, &mut rng);
+        game.add_ships(&ships, user_id)
+            .expect("random fleet was rejected");
+    }
+    game.apply(Transition::Start)
+        .expect("both fleets are placed");
+
+    let mut players = strategies.map(Player::new);
+    let winner = loop {
+        let current = game.current_player().unwrap();
+        let i = ids.iter().position(|id| *id == current).unwrap();
+        let (x, y) = players[i].pick_target(rules.board_size, &mut rng);
+        let result = game
+            .attack(&current, x, y)
+            .expect("simulated shot was rejected");
+        players[i].shots += 1;
+        players[i].learn(rules.board_size, &result.cells);
+        if result.is_won {
+            break i as u8 + 1;
+        }
+    };
+
+    GameRecord {
+        game: game_index,
+        strategy1: strategies[0],
+        strategy2: strategies[1],
+        board_size: rules.board_size,
+        no_touching: rules.no_touching,
+        seed: seed.to_string(),
+        winner,
+        shots1: players[0].shots,
+        shots2: players[1].shots,
+        duration_us: started.elapsed().as_micros() as u64,
+    }
+}
+
+/// Places the fleet at random, longest ships first, restarting whenever a
+/// ship has no room left.
+pub fn random_fleet(fleet: &Fleet, rules: Rules, rng: &mut impl Rng) -> Ships {
+    let mut lengths = fleet.lengths();
+    lengths.sort_unstable_by(|a, b| b.cmp(a));
+    let size = rules.board_size as u8;
+    'attempt: for _ in 0..MAX_PLACEMENT_ATTEMPTS {
+        let mut ships = Ships::default();
+        let mut taken = HashSet::new();
+        for &length in lengths.iter() {
+            let candidates = (0..size)
+                .flat_map(|y| (0..size).map(move |x| (x, y)))
+                .flat_map(|(x, y)| [(x, y, false), (x, y, true)])
+                .map(|(x, y, is_vertical)| Ship {
+                    position: Position { x, y },
+                    is_vertical,
+                    ship_type: ShipType::of_length(length),
+                    hp: length,
+                })
+                .filter_map(|ship| {
+                    let coords = ship.coords(rules.board_size)?;
+                    let free = coords.iter().all(|coord| !taken.contains(coord));
+                    free.then_some((ship, coords))
+                })
+                .collect::<Vec<(Ship, ShipCells<Coord>)>>();
+            let Some((ship, coords)) = candidates.choose(rng).cloned() else {
+                continue 'attempt;
+            };
+            if rules.no_touching {
+                taken.extend(Coord::around(&coords).iter().copied());
+            }
+            taken.extend(coords.iter().copied());
+            ships.ships.push(ship);
+        }
+        return ships;
+    }
+    panic!("Fleet doesn't fit on a {0}x{0} board", size);
+}
+
+/// Seeds for a batch, drawn from `base_seed`.
+pub fn seeds(base_seed: u64) -> impl Iterator<Item = u64> {
+    let mut rng = ChaCha8Rng::seed_from_u64(base_seed);
+    std::iter::repeat_with(move || rng.gen())
+}
@@ -0,0 +1,150 @@
+//! Spectators following running games.
+//!
+//! The public events of a game's current round (shots, turns and the
+//! finish) are kept, so a spectator joining mid-game first gets the board
+//! so far and the current turn, then follows along live. The history is
+//! bounded by `MAX_HISTORY`, which a full round on the largest board stays
+//! below.
+//!
+//! Web pages can follow a game without a WebSocket through a read-only
+//! stream, see `http`. Streams get the same events as spectators and count
+//! as spectators.
+//!
+//! Every spectator costs a copy of each event, so a game takes at most
+//! `MAX_SPECTATORS` of them (unlimited when unset or 0), and its creator
+//! can set a lower cap.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+use crate::{game::GameId, messages::MessageId, UserId};
+
+const MAX_HISTORY: usize = 1024;
+const MAX_SPECTATORS_VAR: &str = "MAX_SPECTATORS";
+
+#[derive(Debug, Default)]
+pub struct Spectators {
+    games: HashMap<GameId, Audience>,
+    /// Server-wide cap per game.
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct Audience {
+    spectators: HashSet<UserId>,
+    /// Open event streams; dropped ones are let go on the next event.
+    streams: Vec<UnboundedSender<String>>,
+    /// Encoded events, oldest first.
+    history: VecDeque<String>,
+}
+
+impl Spectators {
+    pub fn from_env() -> Self {
+        let limit = std::env::var(MAX_SPECTATORS_VAR)
+            .ok()
+            .and_then(|limit| limit.parse::<usize>().ok())
+            .filter(|limit| *limit > 0);
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Server-wide cap of spectators per game.
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Keeps a public event of the game and returns who to send it to.
+    pub fn record(&mut self, game_id: &GameId, json: &str) -> Vec<UserId> {
+        let audience = self.games.entry(game_id.clone()).or_default();
+        if audience.history.len() == MAX_HISTORY {
+            audience.history.pop_front();
+        }
+        audience.history.push_back(json.to_owned());
+        audience
+            .streams
+            .retain(|stream| stream.unbounded_send(json.to_owned()).is_ok());
+        audience.spectators.iter().cloned().collect()
+    }
+
+    /// Starts the history over for the next round of a series.
+    pub fn new_round(&mut self, game_id: &GameId) {
+        if let Some(audience) = self.games.get_mut(game_id) {
+            audience.history.clear();
+        }
+    }
+
+    pub fn count(&self, game_id: &GameId) -> usize {
+        self.games.get(game_id).map_or(0, |audience| {
+            audience.spectators.len() + audience.streams.len()
+        })
+    }
+
+    /// Games an audience is kept for.
+    pub fn game_count(&self) -> usize {
+        self.games.len()
+    }
+
+    /// Whether another spectator may join, `room_limit` being the cap the
+    /// game's creator set.
+    pub fn has_room(&self, game_id: &GameId, room_limit: Option<usize>) -> bool {
+        let limit = [self.limit, room_limit].into_iter().flatten().min();
+        limit.is_none_or(|limit| self.count(game_id) < limit)
+    }
+
+    /// Adds a spectator unless the game is full and returns the history to
+    /// catch up with.
+    pub fn join(
+        &mut self,
+        game_id: &GameId,
+        user_id: &UserId,
+        room_limit: Option<usize>,
+    ) -> Result<Vec<String>, MessageId> {
+        let watching = self
+            .games
+            .get(game_id)
+            .is_some_and(|audience| audience.spectators.contains(user_id));
+        if !watching && !self.has_room(game_id, room_limit) {
+            return Err(MessageId::RoomFull);
+        }
+        let audience = self.games.entry(game_id.clone()).or_default();
+        audience.spectators.insert(user_id.clone());
+        Ok(audience.history.iter().cloned().collect())
+    }
+
+    /// Opens an event stream unless the game is full and returns the
+    /// history to catch up with. The stream ends when the game is closed.
+    pub fn stream(
+        &mut self,
+        game_id: &GameId,
+        room_limit: Option<usize>,
+    ) -> Result<(Vec<String>, UnboundedReceiver<String>), MessageId> {
+        if !self.has_room(game_id, room_limit) {
+            return Err(MessageId::RoomFull);
+        }
+        let audience = self.games.entry(game_id.clone()).or_default();
+        let (sender, receiver) = unbounded();
+        audience.streams.push(sender);
+        Ok((audience.history.iter().cloned().collect(), receiver))
+    }
+
+    /// Returns `false` if the user wasn't watching the game.
+    pub fn leave(&mut self, game_id: &GameId, user_id: &UserId) -> bool {
+        self.games
+            .get_mut(game_id)
+            .is_some_and(|audience| audience.spectators.remove(user_id))
+    }
+
+    pub fn leave_all(&mut self, user_id: &UserId) {
+        for audience in self.games.values_mut() {
+            audience.spectators.remove(user_id);
+        }
+    }
+
+    /// Forgets a closed game.
+    pub fn close(&mut self, game_id: &GameId) {
+        self.games.remove(game_id);
+    }
+}
@@ -0,0 +1,135 @@
+//! Per-player statistics behind the rankings.
+//!
+//! Wins decide the order. Players with as many wins are told apart by their
+//! accuracy, then by how few shots their wins took on average. Server-wide
+//! totals of the finished games sit next to them for the exports.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::game::{FinishReason, GameId};
+
+/// Finished games kept in a player's history.
+const RECENT_GAMES: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlayerStats {
+    shots: u32,
+    hits: u32,
+    games_won: u32,
+    /// Shots fired in the games that were won.
+    shots_in_wins: u32,
+}
+
+impl PlayerStats {
+    pub fn record_game(&mut self, shots: u32, hits: u32, won: bool) {
+        self.shots += shots;
+        self.hits += hits;
+        if won {
+            self.games_won += 1;
+            self.shots_in_wins += shots;
+        }
+    }
+
+    /// Share of shots that hit, 0 before the first shot.
+    pub fn accuracy(&self) -> f64 {
+        match self.shots {
+            0 => 0.0,
+            shots => self.hits as f64 / shots as f64,
+        }
+    }
+
+    pub fn average_shots_to_win(&self) -> Option<f64> {
+        (self.games_won > 0).then(|| self.shots_in_wins as f64 / self.games_won as f64)
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        json!({
+            "accuracy": self.accuracy(),
+            "averageShotsToWin": self.average_shots_to_win(),
+        })
+    }
+}
+
+/// Orders players with the same wins, better first: higher accuracy, then
+/// fewer shots per win, with players who never won last.
+pub fn tie_break(a: &PlayerStats, b: &PlayerStats) -> Ordering {
+    b.accuracy().total_cmp(&a.accuracy()).then_with(|| {
+        match (a.average_shots_to_win(), b.average_shots_to_win()) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    })
+}
+
+/// A finished game in a player's history.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+#[serde(rename_all = "camelCase")]
+pub struct RecentGame {
+    pub game_id: GameId,
+    pub opponent: Option<String>,
+    pub won: bool,
+    pub reason: FinishReason,
+    /// Whether the game counted towards the rankings.
+    pub rated: bool,
+    pub shots: u32,
+    pub hits: u32,
+    /// In milliseconds since the Unix epoch.
+    pub finished_at: u64,
+}
+
+/// The last `RECENT_GAMES` games of a player, newest first.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct RecentGames(VecDeque<RecentGame>);
+
+impl RecentGames {
+    pub fn push(&mut self, game: RecentGame) {
+        self.0.push_front(game);
+        self.0.truncate(RECENT_GAMES);
+    }
+
+    #[cfg(feature = "graphql")]
+    pub fn iter(&self) -> impl Iterator<Item = &RecentGame> {
+        self.0.iter()
+    }
+}
+
+/// Totals over every game finished since the server started.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameTotals {
+    pub games: u32,
+    pub shots: u64,
+    pub hits: u64,
+    pub duration_ms: u64,
+    pub by_ruleset: BTreeMap<String, u32>,
+    pub by_reason: BTreeMap<FinishReason, u32>,
+}
+
+impl GameTotals {
+    pub fn record_game(
+        &mut self,
+        ruleset: &str,
+        reason: FinishReason,
+        shots: usize,
+        hits: usize,
+        duration: Duration,
+    ) {
+        self.games += 1;
+        self.shots += shots as u64;
+        self.hits += hits as u64;
+        self.duration_ms += duration.as_millis() as u64;
+        *self.by_ruleset.entry(ruleset.to_owned()).or_default() += 1;
+        *self.by_reason.entry(reason).or_default() += 1;
+    }
+}
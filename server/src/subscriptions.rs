@@ -0,0 +1,101 @@
+//! Lobby feeds a client can opt out of.
+//!
+//! Every client follows every feed until it sends `unsubscribe` with the
+//! `feed`, say while its player is in a game and the lobby isn't shown.
+//! `subscribe` follows it again and sends its current state. Both reply
+//! with `subscriptions`, the feeds the client follows.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::UserId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Feed {
+    Rooms,
+    Winners,
+    Population,
+    Presence,
+}
+
+impl Feed {
+    pub const ALL: [Feed; 4] = [Feed::Rooms, Feed::Winners, Feed::Population, Feed::Presence];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feed::Rooms => "rooms",
+            Feed::Winners => "winners",
+            Feed::Population => "population",
+            Feed::Presence => "presence",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|feed| feed.name() == name)
+    }
+
+    /// The feed a lobby snapshot belongs to.
+    pub fn of_snapshot(kind: &str) -> Option<Self> {
+        match kind {
+            "update_room" => Some(Feed::Rooms),
+            "update_winners" => Some(Feed::Winners),
+            "update_population" => Some(Feed::Population),
+            "update_presence" => Some(Feed::Presence),
+            _ => None,
+        }
+    }
+}
+
+/// Clients that opted out of each feed.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    unsubscribed: HashMap<Feed, HashSet<UserId>>,
+}
+
+impl Subscriptions {
+    /// `false` when the client already followed the feed.
+    pub fn subscribe(&mut self, user_id: &UserId, feed: Feed) -> bool {
+        self.unsubscribed
+            .get_mut(&feed)
+            .is_some_and(|user_ids| user_ids.remove(user_id))
+    }
+
+    pub fn unsubscribe(&mut self, user_id: &UserId, feed: Feed) {
+        self.unsubscribed
+            .entry(feed)
+            .or_default()
+            .insert(user_id.clone());
+    }
+
+    pub fn leave(&mut self, user_id: &UserId) {
+        for user_ids in self.unsubscribed.values_mut() {
+            user_ids.remove(user_id);
+        }
+    }
+
+    pub fn follows(&self, user_id: &UserId, feed: Feed) -> bool {
+        !self
+            .unsubscribed
+            .get(&feed)
+            .is_some_and(|user_ids| user_ids.contains(user_id))
+    }
+
+    pub fn unsubscribed(&self, feed: Feed) -> impl Iterator<Item = &UserId> {
+        self.unsubscribed.get(&feed).into_iter().flatten()
+    }
+
+    /// Feeds opted out of, over all clients.
+    pub fn opt_out_count(&self) -> usize {
+        self.unsubscribed.values().map(HashSet::len).sum()
+    }
+
+    /// The feeds the client follows.
+    pub fn feeds(&self, user_id: &UserId) -> Vec<Feed> {
+        Feed::ALL
+            .into_iter()
+            .filter(|feed| self.follows(user_id, *feed))
+            .collect()
+    }
+}
@@ -0,0 +1,43 @@
+//! What a node knows of the others: the lookup of their rooms and games,
+//! and the lobbies of nodes that left. The Redis side isn't covered.
+
+use std::collections::HashMap;
+
+use serde_json::json;
+use server::cluster::Cluster;
+
+/// A cluster where node `b` offers one open room.
+fn with_room() -> Cluster {
+    let mut cluster = Cluster::default();
+    let rooms = vec![json!({ "roomId": "room-1", "code": "ABCDEF", "roomUsers": [] })];
+    assert!(cluster.set_lobby("b".into(), "ws://node-b:3000".into(), rooms.clone()));
+    assert!(!cluster.set_lobby("b".into(), "ws://node-b:3000".into(), rooms));
+    cluster
+}
+
+#[test]
+fn rooms_of_other_nodes_are_found_by_id_and_code() {
+    let mut cluster = with_room();
+    let found = Some(("room-1".to_owned(), "ws://node-b:3000"));
+    assert_eq!(cluster.locate("room-1"), found);
+    assert_eq!(cluster.locate("ABCDEF"), found);
+    assert_eq!(cluster.locate("room-2"), None);
+
+    let games = HashMap::from([("game-1".to_owned(), "ws://node-c:3000".to_owned())]);
+    assert!(!cluster.set_registry(games, &["b".into(), "c".into()]));
+    let found = Some(("game-1".to_owned(), "ws://node-c:3000"));
+    assert_eq!(cluster.locate("game-1"), found);
+    assert_eq!(cluster.rooms().count(), 1);
+}
+
+#[test]
+fn lobbies_of_nodes_that_left_are_dropped() {
+    let mut cluster = with_room();
+    assert!(!cluster.set_registry(HashMap::new(), &["b".into()]));
+    assert_eq!(cluster.rooms().count(), 1);
+
+    assert!(cluster.set_registry(HashMap::new(), &["c".into()]));
+    assert_eq!(cluster.rooms().count(), 0);
+    assert_eq!(cluster.locate("room-1"), None);
+    assert_eq!(cluster.locate("ABCDEF"), None);
+}
@@ -0,0 +1,558 @@
+//! In-process harness that drives the server's event handling without
+//! sockets and records every emitted event as a readable transcript.
+
+pub mod network;
+pub mod scenario;
+
+use std::{
+    fmt::Write as _,
+    fs,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use server::{
+    admin::handle_admin_command,
+    clock::{self, ManualClock},
+    features::Features,
+    handle_disconnect, handle_event, handle_join_link,
+    limits::Limits,
+    lock_state, tick, ServerEvent, ServerState, State,
+};
+use uuid::Uuid;
+
+use network::{Conditions, Direction, Network};
+
+const UPDATE_SNAPSHOTS_VAR: &str = "UPDATE_SNAPSHOTS";
+/// A scenario needing a particular player to move first is rerun until the
+/// coin toss goes its way; 64 misses in a row don't happen.
+const MAX_ATTEMPTS: usize = 64;
+
+pub struct Harness {
+    state: ServerState,
+    clients: Vec<(&'static str, SocketAddr)>,
+    connections: u16,
+    /// Raw data of every event a client received, in order.
+    received: Vec<(&'static str, String, Value)>,
+    transcript: String,
+    /// UUIDs in order of appearance, replaced by their position.
+    ids: Vec<String>,
+    /// Room codes, likewise.
+    codes: Vec<String>,
+    /// Messages go through it when set, and arrive on `advance`.
+    network: Option<Network>,
+    /// Clients whose connection the network dropped.
+    offline: Vec<&'static str>,
+    /// The server's time, which only moves on `pass`.
+    clock: ManualClock,
+}
+
+impl Harness {
+    /// A server with every feature enabled, whatever `FEATURES` says.
+    pub fn new() -> Self {
+        let clock = ManualClock::default();
+        clock::set(clock.clone());
+        let state = Arc::new(RwLock::new(State::default()));
+        lock_state(&state).features = Features::all();
+        Self {
+            state,
+            clients: Vec::new(),
+            connections: 0,
+            received: Vec::new(),
+            transcript: String::new(),
+            ids: Vec::new(),
+            codes: Vec::new(),
+            network: None,
+            offline: Vec::new(),
+            clock,
+        }
+    }
+
+    /// Moves the server's clock forward.
+    pub fn pass(&mut self, time: Duration) {
+        let _ = writeln!(self.transcript, "-- {:?} pass", time);
+        self.clock.advance(time);
+    }
+
+    /// Sends every message from now on through a simulated network, which
+    /// the dice of `seed` make flaky as the conditions say.
+    pub fn set_network(&mut self, conditions: Conditions, seed: u64) {
+        let _ = writeln!(self.transcript, "-- network {:?}", conditions);
+        self.network = Some(Network::new(conditions, seed));
+    }
+
+    /// Lets `by` pass on the network, delivering what arrives meanwhile.
+    pub fn advance(&mut self, by: Duration) {
+        let Some(network) = self.network.as_ref() else {
+            return;
+        };
+        let until = network.now + by;
+        while let Some((packet, drops)) = self.network.as_mut().and_then(|n| n.next(until)) {
+            let stamp = self.stamp();
+            // Sent on a connection the client has since replaced.
+            if packet.addr != self.addr(packet.client) {
+                continue;
+            }
+            if drops {
+                let _ = writeln!(self.transcript, "{}{} drops offline", stamp, packet.client);
+                self.offline.push(packet.client);
+                handle_disconnect(&packet.addr, &mut self.state);
+                self.drain();
+                continue;
+            }
+            match packet.direction {
+                Direction::ToServer => self.handle(packet.client, packet.addr, &packet.text),
+                Direction::ToClient => {
+                    let label = format!("{}{}", stamp, packet.client);
+                    self.receive(&label, vec![packet.client], &packet.text);
+                }
+            }
+        }
+        if let Some(network) = self.network.as_mut() {
+            network.now = until;
+        }
+    }
+
+    /// Delivers everything on the way, and whatever that sets off.
+    pub fn settle(&mut self) {
+        while let Some(network) = self.network.as_ref() {
+            let quiet_at = network.quiet_at();
+            if quiet_at <= network.now {
+                return;
+            }
+            self.advance(quiet_at - network.now);
+        }
+    }
+
+    pub fn is_online(&self, client: &str) -> bool {
+        !self.offline.contains(&client)
+    }
+
+    /// The server's state, for the APIs served next to the player protocol.
+    // Only the tests of the optional APIs use it.
+    #[allow(dead_code)]
+    pub fn state(&self) -> ServerState {
+        self.state.clone()
+    }
+
+    pub fn set_features(&mut self, features: Features) {
+        lock_state(&self.state).features = features;
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        lock_state(&self.state).limits = limits;
+    }
+
+    pub fn set_motd(&mut self, motd: &str) {
+        lock_state(&self.state).motd = Some(motd.to_owned());
+    }
+
+    pub fn set_bot_api_key(&mut self, key: &str) {
+        lock_state(&self.state).bot_api_keys = [key.to_owned()].into();
+    }
+
+    pub fn set_practice_seed(&mut self, seed: u64) {
+        lock_state(&self.state).practice_seed = Some(seed);
+    }
+
+    pub fn set_room_idle_timeout(&mut self, timeout: Option<Duration>) {
+        lock_state(&self.state).set_room_idle_timeout(timeout);
+    }
+
+    /// Runs the timer work once.
+    pub fn tick(&mut self) {
+        let _ = writeln!(self.transcript, "-- tick");
+        tick(&mut self.state);
+        self.drain();
+    }
+
+    /// Registers `name` as a client with a fresh address.
+    pub fn connect(&mut self, name: &'static str) {
+        let addr = self.next_addr();
+        self.clients.push((name, addr));
+    }
+
+    /// Registers `name` as a client that opened the connection at `uri`,
+    /// such as an invite link.
+    pub fn connect_to(&mut self, name: &'static str, uri: &str) {
+        let shown = self.replace_codes(uri);
+        let _ = writeln!(self.transcript, "{} connects to {}", name, shown);
+        self.connect(name);
+        handle_join_link(&self.addr(name), uri, &mut self.state);
+    }
+
+    /// Gives a client that disconnected a new connection.
+    pub fn reconnect(&mut self, name: &'static str) {
+        let _ = writeln!(self.transcript, "{} connects again", name);
+        let addr = self.next_addr();
+        let client = self
+            .clients
+            .iter_mut()
+            .find(|(client, _)| *client == name)
+            .unwrap_or_else(|| panic!("Unknown client {}", name));
+        client.1 = addr;
+        self.offline.retain(|client| *client != name);
+    }
+
+    pub fn send(&mut self, client: &'static str, event_type: &str, data: Value) {
+        let frame = json!({ "type": event_type, "data": data.to_string(), "id": 0 });
+        let data = self.normalize(data);
+        let stamp = self.stamp();
+        let _ = writeln!(
+            self.transcript,
+            "{}{} -> {} {}",
+            stamp, client, event_type, data
+        );
+        self.deliver(client, &frame.to_string());
+    }
+
+    /// Runs an operator command as if sent on the admin channel.
+    pub fn admin(&mut self, command: &str, data: Value) {
+        let frame = json!({ "type": command, "data": data.to_string() });
+        let data = self.normalize(data);
+        let _ = writeln!(self.transcript, "admin -> {} {}", command, data);
+        let reply = handle_admin_command(&frame.to_string(), &mut self.state);
+        self.receive("admin", vec!["admin"], &reply);
+        self.drain();
+    }
+
+    /// Sends text as is, for messages that aren't valid frames.
+    pub fn send_raw(&mut self, client: &'static str, text: &str) {
+        let _ = writeln!(self.transcript, "{} -> raw {:?}", client, text);
+        self.deliver(client, text);
+    }
+
+    pub fn disconnect(&mut self, client: &'static str) {
+        let _ = writeln!(self.transcript, "{} disconnects", client);
+        handle_disconnect(&self.addr(client), &mut self.state);
+        self.drain();
+    }
+
+    /// Data of the last `event_type` event the client received.
+    pub fn last(&self, client: &str, event_type: &str) -> Value {
+        self.received
+            .iter()
+            .rev()
+            .find(|(to, received_type, _)| *to == client && received_type == event_type)
+            .map(|(_, _, data)| data.clone())
+            .unwrap_or_else(|| panic!("{} got no {} event", client, event_type))
+    }
+
+    /// Data of every `event_type` event the client received, in order.
+    pub fn received(&self, client: &str, event_type: &str) -> Vec<Value> {
+        self.received
+            .iter()
+            .filter(|(to, received_type, _)| *to == client && received_type == event_type)
+            .map(|(_, _, data)| data.clone())
+            .collect()
+    }
+
+    pub fn user_id(&self, client: &str) -> String {
+        self.last(client, "reg")["index"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    pub fn game_id(&self, client: &str) -> String {
+        self.last(client, "create_game")["idGame"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// `None` unless the last turn event names `client` as the player to
+    /// move, so scenarios can bail out of an unlucky coin toss with `?`.
+    pub fn has_turn(&self, client: &str) -> Option<()> {
+        let current = self.last(client, "turn")["currentPlayer"].clone();
+        (current == json!(self.user_id(client))).then_some(())
+    }
+
+    pub fn transcript(&self) -> &str {
+        &self.transcript
+    }
+
+    /// Network time, as a prefix of transcript lines.
+    fn stamp(&self) -> String {
+        self.network
+            .as_ref()
+            .map(|network| format!("{}ms ", network.now.as_millis()))
+            .unwrap_or_default()
+    }
+
+    fn next_addr(&mut self) -> SocketAddr {
+        self.connections += 1;
+        SocketAddr::from(([127, 0, 0, 1], 10_000 + self.connections))
+    }
+
+    fn addr(&self, client: &str) -> SocketAddr {
+        self.clients
+            .iter()
+            .find(|(name, _)| *name == client)
+            .map(|(_, addr)| *addr)
+            .unwrap_or_else(|| panic!("Unknown client {}", client))
+    }
+
+    fn client_at(&self, addr: &SocketAddr) -> &'static str {
+        self.clients
+            .iter()
+            .find(|(_, client_addr)| client_addr == addr)
+            .map(|(name, _)| *name)
+            .unwrap_or("?")
+    }
+
+    fn deliver(&mut self, client: &'static str, text: &str) {
+        let addr = self.addr(client);
+        let online = self.is_online(client);
+        match self.network.as_mut() {
+            Some(_) if !online => {
+                let _ = writeln!(
+                    self.transcript,
+                    "{} is offline, the message is lost",
+                    client
+                );
+            }
+            Some(network) => network.send(client, addr, Direction::ToServer, text),
+            None => self.handle(client, addr, text),
+        }
+    }
+
+    fn handle(&mut self, client: &'static str, addr: SocketAddr, text: &str) {
+        match handle_event(&addr, text, &mut self.state) {
+            Ok(Some(reply)) => self.record(client, &reply),
+            Ok(None) => {}
+            Err(reason) => {
+                let _ = writeln!(
+                    self.transcript,
+                    "{} closed {} {:?}",
+                    client,
+                    reason.code(),
+                    reason.text()
+                );
+            }
+        }
+        self.drain();
+    }
+
+    /// Takes everything queued for the tick loop, as one flush.
+    fn drain(&mut self) {
+        let events = lock_state(&self.state).take_events();
+        for event in events {
+            match event {
+                ServerEvent::User(addr, json) => self.record(self.client_at(&addr), &json),
+                ServerEvent::All(json) | ServerEvent::Snapshot(_, json) => self.record("*", &json),
+                ServerEvent::AllExcept(addrs, json) => {
+                    let except = addrs
+                        .iter()
+                        .map(|addr| self.client_at(addr))
+                        .collect::<Vec<&str>>();
+                    let recipients = self
+                        .clients
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .filter(|name| !except.contains(name))
+                        .collect();
+                    let label = format!("* except {}", except.join(", "));
+                    self.record_to(&label, recipients, &json);
+                }
+                ServerEvent::Close(addr, reason) => {
+                    let _ = writeln!(
+                        self.transcript,
+                        "{} closed {} {:?}",
+                        self.client_at(&addr),
+                        reason.code(),
+                        reason.text()
+                    );
+                }
+                ServerEvent::Player(..) => unreachable!("take_events resolves player events"),
+            }
+        }
+    }
+
+    fn record(&mut self, to: &'static str, json: &str) {
+        let recipients = match to {
+            "*" => self.clients.iter().map(|(name, _)| *name).collect(),
+            to => vec![to],
+        };
+        self.record_to(to, recipients, json);
+    }
+
+    /// Hands the event to the network, or receives it at once without one.
+    fn record_to(&mut self, label: &str, recipients: Vec<&'static str>, json: &str) {
+        let Some(network) = self.network.as_mut() else {
+            self.receive(label, recipients, json);
+            return;
+        };
+        for recipient in recipients {
+            if self.offline.contains(&recipient) {
+                continue;
+            }
+            let addr = self
+                .clients
+                .iter()
+                .find(|(name, _)| *name == recipient)
+                .map(|(_, addr)| *addr)
+                .unwrap();
+            network.send(recipient, addr, Direction::ToClient, json);
+        }
+    }
+
+    /// Checks the envelope and writes the event with its data decoded.
+    fn receive(&mut self, label: &str, recipients: Vec<&'static str>, json: &str) {
+        let frame: Value = serde_json::from_str(json).expect("event is not JSON");
+        let keys = frame
+            .as_object()
+            .expect("event is not an object")
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        assert_eq!(keys, ["data", "id", "ts", "type"], "envelope of {}", json);
+        assert_eq!(frame["id"], 0, "id of {}", json);
+        assert!(frame["ts"].is_u64(), "ts of {}", json);
+        let event_type = frame["type"].as_str().expect("type is not a string");
+        let data: Value =
+            serde_json::from_str(frame["data"].as_str().expect("data is not a string"))
+                .expect("data is not JSON");
+
+        for recipient in recipients {
+            self.received
+                .push((recipient, event_type.to_owned(), data.clone()));
+        }
+        let normalized = self.normalize(data);
+        let _ = writeln!(
+            self.transcript,
+            "{} <- {} {}",
+            label, event_type, normalized
+        );
+    }
+
+    /// Replaces UUIDs, room codes, clock readings, durations, seeds and
+    /// confirmation codes, which differ between runs, and the server
+    /// version, which would change every snapshot on a release.
+    fn normalize(&mut self, value: Value) -> Value {
+        match value {
+            Value::String(text) => {
+                let text = self.replace_ids(&text);
+                Value::String(self.replace_codes(&text))
+            }
+            Value::Array(items) => items.into_iter().map(|item| self.normalize(item)).collect(),
+            Value::Object(fields) => fields
+                .into_iter()
+                .map(|(key, value)| match key.as_str() {
+                    "serverTime" | "durationMs" | "atMs" | "graceMs" => (key, json!("<time>")),
+                    "seed" => (key, json!("<seed>")),
+                    "version" => (key, json!("<version>")),
+                    "confirmationCode" => (key, json!("<code>")),
+                    "code" => {
+                        if let Value::String(code) = &value {
+                            if !self.codes.contains(code) {
+                                self.codes.push(code.clone());
+                            }
+                        }
+                        (key, self.normalize(value))
+                    }
+                    _ => (key, self.normalize(value)),
+                })
+                .collect(),
+            value => value,
+        }
+    }
+
+    /// Codes are matched whatever their case when they make up the whole
+    /// text, as typed by a player, and as they are inside links.
+    fn replace_codes(&self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for (index, code) in self.codes.iter().enumerate() {
+            let placeholder = format!("<code:{}>", index + 1);
+            if code.eq_ignore_ascii_case(&text) {
+                return placeholder;
+            }
+            text = text.replace(code, &placeholder);
+        }
+        text
+    }
+
+    fn replace_ids(&mut self, text: &str) -> String {
+        let mut result = String::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            let id = rest
+                .get(..36)
+                .filter(|candidate| Uuid::try_parse(candidate).is_ok());
+            match id {
+                Some(id) => {
+                    let index = match self.ids.iter().position(|known| known == id) {
+                        Some(index) => index,
+                        None => {
+                            self.ids.push(id.to_owned());
+                            self.ids.len() - 1
+                        }
+                    };
+                    let _ = write!(result, "<id:{}>", index + 1);
+                    rest = &rest[36..];
+                }
+                None => {
+                    let next = rest.chars().next().unwrap();
+                    result.push(next);
+                    rest = &rest[next.len_utf8()..];
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Runs a scenario, retrying while it returns `None`, and compares the
+/// transcript with `tests/snapshots/<name>.snap`.
+pub fn check_scenario(name: &str, scenario: fn(&mut Harness) -> Option<()>) {
+    for _ in 0..MAX_ATTEMPTS {
+        let mut harness = Harness::new();
+        if scenario(&mut harness).is_some() {
+            assert_snapshot(name, harness.transcript());
+            return;
+        }
+    }
+    panic!("Scenario {} never got the turn order it needs", name);
+}
+
+/// Compares `actual` with the checked-in snapshot. Run with
+/// `UPDATE_SNAPSHOTS=1` to accept the new output.
+pub fn assert_snapshot(name: &str, actual: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(format!("{}.snap", name));
+    if std::env::var(UPDATE_SNAPSHOTS_VAR).is_ok_and(|update| update == "1") {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!(
+            "Missing snapshot {}, run with {}=1 to create it",
+            path.display(),
+            UPDATE_SNAPSHOTS_VAR
+        )
+    });
+    if expected == actual {
+        return;
+    }
+    let expected_lines = expected.lines().collect::<Vec<&str>>();
+    let actual_lines = actual.lines().collect::<Vec<&str>>();
+    let line = expected_lines
+        .iter()
+        .zip(&actual_lines)
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or(expected_lines.len().min(actual_lines.len()));
+    panic!(
+        "Snapshot {} differs at line {}:\n  expected: {}\n  actual:   {}\n\nfull output:\n{}\nRun with {}=1 to accept it",
+        name,
+        line + 1,
+        expected_lines.get(line).unwrap_or(&"<end>"),
+        actual_lines.get(line).unwrap_or(&"<end>"),
+        actual,
+        UPDATE_SNAPSHOTS_VAR
+    );
+}
@@ -0,0 +1,115 @@
+//! A simulated network between the harness's clients and the server, for
+//! testing reconnection and resuming without a real flaky connection.
+//!
+//! Messages in both directions take `latency` give or take up to `jitter`
+//! to arrive and keep their order per connection, except for the share
+//! `reorder` that may overtake the ones sent before them. Every arriving
+//! message drops its connection with probability `disconnect`, losing it
+//! and everything else on the way. Time is virtual and the dice are seeded,
+//! so a run is the same every time.
+
+use std::{net::SocketAddr, time::Duration};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Conditions {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub reorder: f64,
+    pub disconnect: f64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+#[derive(Debug)]
+pub struct Packet {
+    pub client: &'static str,
+    /// Connection it was sent on.
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    pub text: String,
+    pub arrives_at: Duration,
+}
+
+pub struct Network {
+    conditions: Conditions,
+    rng: ChaCha8Rng,
+    pub now: Duration,
+    in_flight: Vec<Packet>,
+}
+
+impl Network {
+    pub fn new(conditions: Conditions, seed: u64) -> Self {
+        Self {
+            conditions,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            now: Duration::ZERO,
+            in_flight: Vec::new(),
+        }
+    }
+
+    pub fn send(
+        &mut self,
+        client: &'static str,
+        addr: SocketAddr,
+        direction: Direction,
+        text: &str,
+    ) {
+        let jitter = self.conditions.jitter.as_micros() as i64;
+        let offset = self.rng.gen_range(-jitter..=jitter);
+        let expected = (self.now + self.conditions.latency).as_micros() as i64;
+        let mut arrives_at =
+            Duration::from_micros((expected + offset).max(self.now.as_micros() as i64) as u64);
+        if !self.rng.gen_bool(self.conditions.reorder) {
+            // Waits for what was sent before it on the same connection.
+            let last = self
+                .in_flight
+                .iter()
+                .filter(|packet| packet.addr == addr && packet.direction == direction)
+                .map(|packet| packet.arrives_at)
+                .max();
+            arrives_at = arrives_at.max(last.unwrap_or_default());
+        }
+        self.in_flight.push(Packet {
+            client,
+            addr,
+            direction,
+            text: text.to_owned(),
+            arrives_at,
+        });
+    }
+
+    /// The next packet arriving by `until`, first sent first on a tie, and
+    /// whether it drops its connection.
+    pub fn next(&mut self, until: Duration) -> Option<(Packet, bool)> {
+        let index = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .filter(|(_, packet)| packet.arrives_at <= until)
+            .min_by_key(|(index, packet)| (packet.arrives_at, *index))
+            .map(|(index, _)| index)?;
+        let packet = self.in_flight.remove(index);
+        self.now = self.now.max(packet.arrives_at);
+        let drops = self.rng.gen_bool(self.conditions.disconnect);
+        if drops {
+            self.in_flight.retain(|other| other.addr != packet.addr);
+        }
+        Some((packet, drops))
+    }
+
+    /// When the last packet on the way arrives.
+    pub fn quiet_at(&self) -> Duration {
+        self.in_flight
+            .iter()
+            .map(|packet| packet.arrives_at)
+            .max()
+            .unwrap_or(self.now)
+    }
+}
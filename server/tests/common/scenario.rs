@@ -0,0 +1,236 @@
+//! Scripted multi-client scenarios, one step per line, for end-to-end tests
+//! of game rules that read like the rule:
+//!
+//! ```text
+//! alice registers
+//! bob registers
+//! alice creates room {"boardSize": 8, "fleet": {"1": 1}}
+//! bob joins
+//! alice places 0,0
+//! bob places 7,7
+//! alice moves first
+//! bob fires 0,0
+//! bob gets error(not_your_turn)
+//! alice fires 7,7
+//! alice gets attack, finish
+//! ```
+//!
+//! Steps:
+//!
+//! - `<client> registers`
+//! - `<client> creates room [settings]`
+//! - `<client> joins`: the room created last
+//! - `<client> places x,y ...`: single-deck ships
+//! - `<client> fires x,y`
+//! - `<client> sends <type> [data]`
+//! - `<client> disconnects`
+//! - `tick`
+//! - `<n>s pass`: moves the server's clock forward
+//! - `<client> moves first`: reruns the script until the coin toss agrees
+//! - `<client> gets <type>, ...`: the client received these events since
+//!   the last step that wasn't a check, in this order, with anything in
+//!   between; `error(code)` stands for an error with that code
+//! - `<client> gets nothing`
+//!
+//! Blank lines and lines starting with `#` are skipped.
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use super::{Harness, MAX_ATTEMPTS};
+
+enum Outcome {
+    /// The coin toss went the other way.
+    Retry,
+    Failed(String),
+}
+
+struct Run {
+    h: Harness,
+    /// Room joined by `joins`.
+    room: Option<Value>,
+    /// Length of `received` before the last step that wasn't a check.
+    checked_from: usize,
+}
+
+/// Runs `script` until its `moves first` steps hold, and panics at the first
+/// step that fails.
+pub fn check_script(script: &'static str) {
+    for _ in 0..MAX_ATTEMPTS {
+        let mut run = Run {
+            h: Harness::new(),
+            room: None,
+            checked_from: 0,
+        };
+        match run.all(script) {
+            Ok(()) => return,
+            Err((_, Outcome::Retry)) => continue,
+            Err((line, Outcome::Failed(reason))) => panic!(
+                "Step `{}` failed: {}\n\ntranscript:\n{}",
+                line,
+                reason,
+                run.h.transcript()
+            ),
+        }
+    }
+    panic!("Script never got the turn order it needs:\n{}", script);
+}
+
+impl Run {
+    fn all(&mut self, script: &'static str) -> Result<(), (&'static str, Outcome)> {
+        script
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .try_for_each(|line| self.step(line).map_err(|outcome| (line, outcome)))
+    }
+
+    fn step(&mut self, line: &'static str) -> Result<(), Outcome> {
+        if line == "tick" {
+            self.act();
+            self.h.tick();
+            return Ok(());
+        }
+        if let Some(seconds) = line.strip_suffix("s pass") {
+            let seconds = seconds
+                .parse()
+                .map_err(|_| failed("not a number of seconds"))?;
+            self.h.pass(Duration::from_secs(seconds));
+            return Ok(());
+        }
+        let (client, rest) = line
+            .split_once(' ')
+            .ok_or_else(|| failed("no step after the client"))?;
+        let (verb, args) = rest.split_once(' ').unwrap_or((rest, ""));
+        match (verb, args) {
+            ("gets", "nothing") => self.gets(client, &[]),
+            ("gets", events) => self.gets(client, &events.split(", ").collect::<Vec<&str>>()),
+            ("moves", "first") => self.h.has_turn(client).ok_or(Outcome::Retry),
+            _ => {
+                self.act();
+                self.act_on(client, verb, args)
+            }
+        }
+    }
+
+    fn act_on(&mut self, client: &'static str, verb: &str, args: &str) -> Result<(), Outcome> {
+        match verb {
+            "registers" => {
+                self.h.connect(client);
+                self.h.send(
+                    client,
+                    "reg",
+                    json!({ "name": client, "password": "secret" }),
+                );
+            }
+            "creates" => {
+                let settings = args
+                    .strip_prefix("room")
+                    .ok_or_else(|| failed("not a room"))?;
+                self.h.send(client, "create_room", data(settings)?);
+                let user_id = self.h.user_id(client);
+                self.room = self
+                    .h
+                    .last(client, "update_room")
+                    .as_array()
+                    .and_then(|rooms| {
+                        rooms
+                            .iter()
+                            .find(|room| room["roomUsers"][0]["index"] == user_id.as_str())
+                    })
+                    .map(|room| room["roomId"].clone());
+            }
+            "joins" => {
+                let room = self.room.clone().ok_or_else(|| failed("no room to join"))?;
+                self.h
+                    .send(client, "add_user_to_room", json!({ "indexRoom": room }));
+            }
+            "places" => {
+                let ships = args
+                    .split_whitespace()
+                    .map(|cell| {
+                        let (x, y) = coordinates(cell)?;
+                        Ok(json!({
+                            "position": { "x": x, "y": y },
+                            "direction": false,
+                            "type": "small",
+                            "length": 1,
+                        }))
+                    })
+                    .collect::<Result<Vec<Value>, Outcome>>()?;
+                let game_id = self.h.game_id(client);
+                self.h.send(
+                    client,
+                    "add_ships",
+                    json!({ "gameId": game_id, "ships": ships }),
+                );
+            }
+            "fires" => {
+                let (x, y) = coordinates(args)?;
+                let game_id = self.h.game_id(client);
+                self.h.send(
+                    client,
+                    "attack",
+                    json!({ "gameId": game_id, "x": x, "y": y }),
+                );
+            }
+            "sends" => {
+                let (event_type, data_text) = args.split_once(' ').unwrap_or((args, ""));
+                self.h.send(client, event_type, data(data_text)?);
+            }
+            "disconnects" => self.h.disconnect(client),
+            _ => return Err(failed(&format!("unknown step {}", verb))),
+        }
+        Ok(())
+    }
+
+    /// Starts a new stretch of events for `gets`.
+    fn act(&mut self) {
+        self.checked_from = self.h.received.len();
+    }
+
+    fn gets(&self, client: &str, expected: &[&str]) -> Result<(), Outcome> {
+        let received = self.h.received[self.checked_from..]
+            .iter()
+            .filter(|(to, _, _)| *to == client)
+            .map(|(_, event_type, data)| match event_type.as_str() {
+                "error" => format!("error({})", data["errorCode"].as_str().unwrap_or("?")),
+                _ => event_type.clone(),
+            })
+            .collect::<Vec<String>>();
+        if expected.is_empty() && !received.is_empty() {
+            return Err(failed(&format!("got {}", received.join(", "))));
+        }
+        let mut rest = received.iter();
+        for event in expected {
+            if !rest.any(|received| received == event) {
+                return Err(failed(&format!(
+                    "no {} in order, got {}",
+                    event,
+                    received.join(", ")
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn failed(reason: &str) -> Outcome {
+    Outcome::Failed(reason.to_owned())
+}
+
+/// Event data, `{}` when left out.
+fn data(text: &str) -> Result<Value, Outcome> {
+    match text.trim() {
+        "" => Ok(json!({})),
+        text => serde_json::from_str(text).map_err(|err| failed(&err.to_string())),
+    }
+}
+
+fn coordinates(cell: &str) -> Result<(u8, u8), Outcome> {
+    cell.trim()
+        .split_once(',')
+        .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+        .ok_or_else(|| failed(&format!("{} is not x,y", cell)))
+}
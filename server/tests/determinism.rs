@@ -0,0 +1,53 @@
+//! The engine must reach the same positions from the same inputs on every
+//! platform and in every release, or recorded games stop replaying and the
+//! audit log stops being a record of what happened.
+//!
+//! `recordings/audit.log` was written by a server with `AUDIT_LOG` set. It
+//! holds a classic game, a best-of-3 series, a salvo game, a mines game and
+//! a game with a scan, all played with random attacks. Re-record it only
+//! when a change of the rules is meant to change outcomes.
+
+use server::{
+    replay::Replay,
+    simulation::{self, Rules, Strategy},
+};
+
+const RECORDING: &str = include_str!("recordings/audit.log");
+
+#[test]
+fn recorded_games_replay_to_their_outcome() {
+    let finished = RECORDING
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|record| record["action"] == "finish_game")
+        .filter_map(|record| Some(record["details"]["game"].as_str()?.to_owned()))
+        .collect::<Vec<String>>();
+    assert_eq!(finished.len(), 5);
+    for game_id in finished {
+        Replay::from_audit_log(RECORDING, Some(&game_id))
+            .and_then(Replay::verify)
+            .unwrap_or_else(|err| panic!("Game {}: {}", game_id, err));
+    }
+}
+
+/// Seeded simulations pin the engine's and the placement's use of the
+/// random stream, which the recording only covers for who moves first.
+#[test]
+fn seeded_simulations_are_stable() {
+    let rules = Rules {
+        board_size: 10,
+        no_touching: true,
+    };
+    let results = simulation::seeds(42)
+        .take(4)
+        .enumerate()
+        .map(|(i, seed)| {
+            let record = simulation::play(i, rules, [Strategy::Hunt, Strategy::Random], seed);
+            (record.winner, record.shots1, record.shots2)
+        })
+        .collect::<Vec<(u8, u32, u32)>>();
+    assert_eq!(
+        results,
+        vec![(1, 65, 55), (1, 62, 53), (1, 66, 63), (1, 66, 58)]
+    );
+}
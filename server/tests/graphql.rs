@@ -0,0 +1,118 @@
+//! The GraphQL query API, in builds with the `graphql` feature, run against
+//! a server the harness set up.
+
+#![cfg(feature = "graphql")]
+
+// Most of the harness is for the protocol snapshots.
+#[allow(dead_code)]
+mod common;
+
+use async_graphql::Request;
+use common::Harness;
+use serde_json::{json, Value};
+use server::graphql;
+
+/// Alice and bob in a room being set up, carol waiting in one of her own
+/// and dave in none.
+fn lobby() -> Harness {
+    let mut h = Harness::new();
+    for name in ["alice", "bob", "carol", "dave"] {
+        h.connect(name);
+        h.send(name, "reg", json!({ "name": name, "password": "secret" }));
+    }
+    h.send("alice", "create_room", json!({}));
+    let room_id = h.last("alice", "update_room")[0]["roomId"].clone();
+    h.send("bob", "add_user_to_room", json!({ "indexRoom": room_id }));
+    h.send("carol", "create_room", json!({ "ruleset": "salvo" }));
+    h
+}
+
+async fn query(h: &Harness, query: &str) -> Value {
+    let response = graphql::schema()
+        .execute(Request::new(query).data(h.state()))
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    response.data.into_json().unwrap()
+}
+
+#[tokio::test]
+async fn players_are_paged_in_leaderboard_order() {
+    let h = lobby();
+    let data = query(
+        &h,
+        r#"{
+            players(first: 2, after: "0") {
+                edges { cursor node { name rank presence } }
+                pageInfo { hasPreviousPage hasNextPage }
+            }
+        }"#,
+    )
+    .await;
+    assert_eq!(
+        data["players"],
+        json!({
+            "edges": [
+                { "cursor": "1", "node": { "name": "bob", "rank": 2, "presence": "IN_GAME" } },
+                { "cursor": "2", "node": { "name": "carol", "rank": 3, "presence": "SEARCHING" } },
+            ],
+            "pageInfo": { "hasPreviousPage": true, "hasNextPage": true },
+        })
+    );
+
+    let data = query(
+        &h,
+        r#"{
+            players(nameContains: "a", first: 1) {
+                edges { node { name } }
+                pageInfo { hasNextPage }
+            }
+            past: players(after: "18446744073709551615") {
+                edges { node { name } }
+                pageInfo { hasNextPage }
+            }
+        }"#,
+    )
+    .await;
+    assert_eq!(
+        data,
+        json!({
+            "players": {
+                "edges": [{ "node": { "name": "alice" } }],
+                "pageInfo": { "hasNextPage": true },
+            },
+            "past": { "edges": [], "pageInfo": { "hasNextPage": false } },
+        })
+    );
+}
+
+#[tokio::test]
+async fn games_are_filtered() {
+    let h = lobby();
+    let data = query(
+        &h,
+        r#"{
+            waiting: games(status: WAITING) { edges { node { players ruleset } } }
+            alice: games(player: "alice") { edges { node { players status } } }
+            classic: games(ruleset: "classic", first: 1) {
+                edges { node { players } }
+                pageInfo { hasNextPage }
+            }
+        }"#,
+    )
+    .await;
+    assert_eq!(
+        data,
+        json!({
+            "waiting": {
+                "edges": [{ "node": { "players": ["carol"], "ruleset": "salvo" } }],
+            },
+            "alice": {
+                "edges": [{ "node": { "players": ["alice", "bob"], "status": "PLACING_SHIPS" } }],
+            },
+            "classic": {
+                "edges": [{ "node": { "players": ["alice", "bob"] } }],
+                "pageInfo": { "hasNextPage": false },
+            },
+        })
+    );
+}
@@ -0,0 +1,137 @@
+//! The gRPC integration service, in builds with the `grpc` feature. Calls
+//! go through the service the way the transport hands them over, framed
+//! and encoded by hand, so the messages written out in `src/grpc.rs` are
+//! checked against the wire format `proto/integration.proto` describes.
+
+#![cfg(feature = "grpc")]
+
+// Most of the harness is for the protocol snapshots.
+#[allow(dead_code)]
+mod common;
+
+use axum::body::{to_bytes, Body};
+use common::Harness;
+use prost::Message;
+use serde_json::json;
+use server::grpc::{
+    AnnounceRequest, AnnounceResponse, GetLeaderboardRequest, GetLeaderboardResponse,
+    GetReplayRequest, Integration, ListGamesRequest, ListGamesResponse,
+};
+use tonic::{
+    codegen::{http, Service},
+    Code,
+};
+
+const TOKEN: &str = "grpc-test-token";
+
+/// What came back: the status, and the message when there is one.
+struct Reply {
+    code: Code,
+    message: Vec<u8>,
+}
+
+impl Reply {
+    fn decode<M: Message + Default>(&self) -> M {
+        assert_eq!(self.code, Code::Ok);
+        M::decode(self.message.as_slice()).unwrap()
+    }
+}
+
+async fn call(h: &Harness, method: &str, token: Option<&str>, request: impl Message) -> Reply {
+    let message = request.encode_to_vec();
+    // Uncompressed, then the length of the message.
+    let mut frame = vec![0];
+    frame.extend((message.len() as u32).to_be_bytes());
+    frame.extend(message);
+    let mut builder = http::Request::post(format!(
+        "http://localhost/battleships.Integration/{}",
+        method
+    ))
+    .header("content-type", "application/grpc")
+    .header("te", "trailers");
+    if let Some(token) = token {
+        builder = builder.header("authorization", format!("Bearer {}", token));
+    }
+    let request = builder.body(Body::from(frame)).unwrap();
+    let response = Integration::new(h.state()).call(request).await.unwrap();
+    // Errors come in the headers, the status of an answer in the trailers.
+    let code = response
+        .headers()
+        .get("grpc-status")
+        .and_then(|status| status.to_str().ok()?.parse::<i32>().ok())
+        .map_or(Code::Ok, Code::from);
+    let body = to_bytes(Body::new(response.into_body()), usize::MAX)
+        .await
+        .unwrap();
+    Reply {
+        code,
+        message: body.get(5..).unwrap_or_default().to_vec(),
+    }
+}
+
+#[tokio::test]
+async fn integration_calls() {
+    std::env::set_var("ADMIN_TOKEN", TOKEN);
+    let mut h = Harness::new();
+    for name in ["alice", "bob"] {
+        h.connect(name);
+        h.send(name, "reg", json!({ "name": name, "password": "secret" }));
+    }
+    h.send(
+        "alice",
+        "create_room",
+        json!({ "boardSize": 8, "fleet": { "1": 1 } }),
+    );
+
+    for token in [None, Some("wrong")] {
+        let reply = call(&h, "ListGames", token, ListGamesRequest {}).await;
+        assert_eq!(reply.code, Code::Unauthenticated);
+    }
+
+    let games = call(&h, "ListGames", Some(TOKEN), ListGamesRequest {})
+        .await
+        .decode::<ListGamesResponse>()
+        .games;
+    assert_eq!(games.len(), 1);
+    assert_eq!(games[0].status, "waiting");
+    assert_eq!(games[0].ruleset, "classic");
+    assert_eq!(games[0].board_size, 8);
+    assert_eq!(games[0].best_of, 1);
+    let players = games[0]
+        .players
+        .iter()
+        .map(|player| (player.id.as_str(), player.name.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(players, [(h.user_id("alice").as_str(), "alice")]);
+
+    let request = GetLeaderboardRequest { limit: 1 };
+    let standings = call(&h, "GetLeaderboard", Some(TOKEN), request)
+        .await
+        .decode::<GetLeaderboardResponse>()
+        .standings;
+    assert_eq!(standings.len(), 1);
+    assert_eq!(
+        (standings[0].rank, standings[0].name.as_str()),
+        (1, "alice")
+    );
+    assert_eq!(standings[0].average_shots_to_win, None);
+
+    let request = GetReplayRequest {
+        replay_id: "missing".into(),
+    };
+    let reply = call(&h, "GetReplay", Some(TOKEN), request).await;
+    assert_eq!(reply.code, Code::NotFound);
+
+    let request = AnnounceRequest {
+        text: "Maintenance at noon".into(),
+        severity: "warning".into(),
+        expires_in_seconds: 60,
+    };
+    let reply = call(&h, "Announce", Some(TOKEN), request).await;
+    assert_eq!(reply.decode::<AnnounceResponse>().id, 1);
+    // `uint32 id = 1`: field 1 as a varint, then the value.
+    assert_eq!(reply.message, [0x08, 1]);
+
+    let reply = call(&h, "Shutdown", Some(TOKEN), ListGamesRequest {}).await;
+    assert_eq!(reply.code, Code::Unimplemented);
+}
@@ -0,0 +1,1390 @@
+//! Snapshot tests of the wire format. Every scenario's full event
+//! transcript is compared with a checked-in file under `tests/snapshots`,
+//! so any change of what the frontend receives shows up in review.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{check_scenario, network::Conditions, scenario::check_script, Harness};
+use serde_json::json;
+use server::{features::Features, limits::Limits};
+
+fn register_both(h: &mut Harness) {
+    h.connect("alice");
+    h.connect("bob");
+    h.send(
+        "alice",
+        "reg",
+        json!({ "name": "alice", "password": "secret" }),
+    );
+    h.send("bob", "reg", json!({ "name": "bob", "password": "secret" }));
+}
+
+/// Single-deck ships of `(x, y)` positions, as sent by `add_ships`.
+fn small_ships(positions: &[(u8, u8)]) -> serde_json::Value {
+    positions
+        .iter()
+        .map(|(x, y)| {
+            json!({
+                "position": { "x": x, "y": y },
+                "direction": false,
+                "type": "small",
+                "length": 1,
+            })
+        })
+        .collect()
+}
+
+/// Both players in a started game on an 8x8 board, alice to move. Returns
+/// the game id, or `None` when bob got the first move.
+fn start_game(
+    h: &mut Harness,
+    settings: serde_json::Value,
+    alice_ships: serde_json::Value,
+    bob_ships: serde_json::Value,
+) -> Option<String> {
+    register_both(h);
+    let mut room = json!({ "boardSize": 8 });
+    room.as_object_mut()
+        .unwrap()
+        .extend(settings.as_object().unwrap().clone());
+    h.send("alice", "create_room", room);
+    let room_id = h.last("alice", "update_room")[0]["roomId"].clone();
+    h.send("bob", "add_user_to_room", json!({ "indexRoom": room_id }));
+    let game_id = h.game_id("alice");
+    for (client, ships) in [("alice", alice_ships), ("bob", bob_ships)] {
+        h.send(
+            client,
+            "add_ships",
+            json!({ "gameId": game_id, "ships": ships }),
+        );
+    }
+    h.has_turn("alice")?;
+    Some(game_id)
+}
+
+/// One single-deck ship each, alice's at (0, 0) and bob's at (7, 7).
+fn start_small_game(h: &mut Harness, settings: serde_json::Value) -> Option<String> {
+    let mut room = json!({ "fleet": { "1": 1 } });
+    room.as_object_mut()
+        .unwrap()
+        .extend(settings.as_object().unwrap().clone());
+    start_game(h, room, small_ships(&[(0, 0)]), small_ships(&[(7, 7)]))
+}
+
+#[test]
+fn registration() {
+    check_scenario("registration", |h| {
+        register_both(h);
+        h.send("alice", "ping", json!({ "clientTime": 42 }));
+        h.send("alice", "sync", json!({}));
+        h.send("alice", "population", json!({}));
+        Some(())
+    });
+}
+
+#[test]
+fn full_game() {
+    check_scenario("full_game", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 4, "y": 4 }),
+        );
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 7, "y": 7 }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn rejected_moves() {
+    check_scenario("rejected_moves", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send(
+            "alice",
+            "add_ships",
+            json!({ "gameId": game_id, "ships": [] }),
+        );
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 0, "y": 0 }),
+        );
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 4, "y": 4 }),
+        );
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn rejected_scans() {
+    check_scenario("rejected_scans", |h| {
+        let game_id = start_small_game(h, json!({ "scan": true }))?;
+        h.send(
+            "alice",
+            "scan",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 4, "y": 4 }),
+        );
+        h.send(
+            "alice",
+            "scan",
+            json!({ "gameId": game_id, "x": 5, "y": 5 }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn abort_requests() {
+    check_scenario("abort_requests", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send("bob", "abort_accept", json!({ "gameId": game_id }));
+        h.send("alice", "abort_request", json!({ "gameId": game_id }));
+        h.send("alice", "abort_accept", json!({ "gameId": game_id }));
+        h.send("bob", "abort_accept", json!({ "gameId": game_id }));
+        Some(())
+    });
+}
+
+#[test]
+fn wasted_repeat_shot() {
+    check_scenario("wasted_repeat_shot", |h| {
+        let game_id = start_small_game(h, json!({ "repeatShot": "waste" }))?;
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 4, "y": 4 }),
+        );
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn invalid_room_settings() {
+    check_scenario("invalid_room_settings", |h| {
+        register_both(h);
+        h.send("alice", "create_room", json!({ "boardSize": 4 }));
+        h.send("alice", "create_room", json!({ "bestOf": 2 }));
+        h.send("alice", "create_room", json!({ "repeatShot": "maybe" }));
+        h.send("alice", "create_room", json!({ "ruleset": "chess" }));
+        h.send(
+            "alice",
+            "create_room",
+            json!({ "ruleset": "mines", "fleet": { "1": 2 } }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn disabled_features() {
+    check_scenario("disabled_features", |h| {
+        h.set_features(Features::parse("matchmaking"));
+        register_both(h);
+        h.send("alice", "create_room", json!({ "ruleset": "salvo" }));
+        h.send("alice", "create_room", json!({ "scan": true }));
+        Some(())
+    });
+}
+
+#[test]
+fn server_info() {
+    check_scenario("server_info", |h| {
+        h.set_features(Features::parse("powerups"));
+        h.set_motd("Tournament tonight at 20:00");
+        h.connect("alice");
+        h.send(
+            "alice",
+            "reg",
+            json!({ "name": "alice", "password": "secret" }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn announcements() {
+    check_scenario("announcements", |h| {
+        register_both(h);
+        h.admin(
+            "announce",
+            json!({
+                "text": "Maintenance at 22:00",
+                "severity": "warning",
+                "expiresInSeconds": 600,
+            }),
+        );
+        h.admin("announce", json!({ "text": "Welcome to the spring cup" }));
+        h.admin("announce", json!({ "text": "Hi", "severity": "loud" }));
+        h.pass(Duration::from_secs(60));
+        h.connect("carol");
+        h.send(
+            "carol",
+            "reg",
+            json!({ "name": "carol", "password": "secret" }),
+        );
+        h.pass(Duration::from_secs(541));
+        h.connect("dave");
+        h.send(
+            "dave",
+            "reg",
+            json!({ "name": "dave", "password": "secret" }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn bot_channel() {
+    check_scenario("bot_channel", |h| {
+        h.set_bot_api_key("bot-key");
+        h.set_limits(Limits {
+            moves_per_second: Some(1),
+            ..Limits::default()
+        });
+        h.connect("alice");
+        h.connect("bob");
+        h.send(
+            "bob",
+            "bot_reg",
+            json!({ "name": "bob", "apiKey": "wrong" }),
+        );
+        h.send(
+            "bob",
+            "bot_reg",
+            json!({ "name": "bob", "apiKey": "bot-key" }),
+        );
+        h.send(
+            "alice",
+            "reg",
+            json!({ "name": "alice", "password": "secret" }),
+        );
+        h.send(
+            "alice",
+            "create_room",
+            json!({ "boardSize": 8, "fleet": { "1": 1 } }),
+        );
+        let room_id = h.last("alice", "update_room")[0]["roomId"].clone();
+        h.send("bob", "add_user_to_room", json!({ "indexRoom": room_id }));
+        let game_id = h.game_id("alice");
+        for (client, ships) in [
+            ("alice", small_ships(&[(0, 0)])),
+            ("bob", small_ships(&[(7, 7)])),
+        ] {
+            h.send(
+                client,
+                "add_ships",
+                json!({ "gameId": game_id, "ships": ships }),
+            );
+        }
+        h.has_turn("alice")?;
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.send("bob", "board_view", json!({ "gameId": game_id }));
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 4, "y": 4 }),
+        );
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 4, "y": 5 }),
+        );
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 4 }),
+        );
+        h.pass(Duration::from_secs(1));
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 7, "y": 7 }),
+        );
+        h.send("alice", "winners", json!({}));
+        Some(())
+    });
+}
+
+#[test]
+fn resource_limits() {
+    check_scenario("resource_limits", |h| {
+        h.set_limits(Limits {
+            max_games: Some(1),
+            max_users: Some(3),
+            max_rooms_per_user: Some(1),
+            ..Limits::default()
+        });
+        register_both(h);
+        for name in ["carol", "dave"] {
+            h.connect(name);
+            h.send(name, "reg", json!({ "name": name, "password": "secret" }));
+        }
+        h.send("alice", "create_room", json!({}));
+        let room_id = h.last("alice", "update_room")[0]["roomId"].clone();
+        h.send("alice", "create_room", json!({}));
+        h.send("bob", "add_user_to_room", json!({ "indexRoom": room_id }));
+        h.send("bob", "create_room", json!({}));
+        h.send("carol", "create_room", json!({}));
+        Some(())
+    });
+}
+
+#[test]
+fn idle_room_waits_for_owner() {
+    check_scenario("idle_room_waits_for_owner", |h| {
+        h.set_room_idle_timeout(Some(Duration::from_secs(60)));
+        register_both(h);
+        h.send("alice", "create_room", json!({}));
+        h.pass(Duration::from_secs(25));
+        h.send("alice", "list_live_games", json!({}));
+        h.pass(Duration::from_secs(25));
+        h.send("alice", "ping", json!({ "clientTime": 50 }));
+        // Alice was active 35 seconds ago, so the room stays open.
+        h.pass(Duration::from_secs(10));
+        h.tick();
+        h.pass(Duration::from_secs(15));
+        h.send("alice", "ping", json!({ "clientTime": 75 }));
+        h.pass(Duration::from_secs(5));
+        h.tick();
+        // Heartbeats alone don't keep it open.
+        h.pass(Duration::from_secs(10));
+        h.tick();
+        Some(())
+    });
+}
+
+#[test]
+fn room_throttling() {
+    check_scenario("room_throttling", |h| {
+        h.set_limits(Limits {
+            max_open_rooms_per_user: Some(1),
+            room_creations_per_minute: Some(3),
+            ..Limits::default()
+        });
+        register_both(h);
+        for _ in 0..4 {
+            h.send("alice", "create_room", json!({}));
+        }
+        Some(())
+    });
+}
+
+#[test]
+fn idle_room_expires() {
+    check_scenario("idle_room_expires", |h| {
+        register_both(h);
+        h.send("alice", "create_room", json!({}));
+        h.tick();
+        h.set_room_idle_timeout(Some(Duration::ZERO));
+        h.tick();
+        Some(())
+    });
+}
+
+#[test]
+fn pause_requests() {
+    check_scenario("pause_requests", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send("alice", "resume", json!({ "gameId": game_id }));
+        for client in ["alice", "bob", "alice"] {
+            h.send(client, "pause_request", json!({ "gameId": game_id }));
+        }
+        h.send("bob", "resume", json!({ "gameId": game_id }));
+        Some(())
+    });
+}
+
+#[test]
+fn pause_runs_out() {
+    check_scenario("pause_runs_out", |h| {
+        let game_id = start_small_game(h, json!({ "clock": { "initial": 60 } }))?;
+        for client in ["alice", "bob"] {
+            h.send(client, "pause_request", json!({ "gameId": game_id }));
+        }
+        h.pass(Duration::from_secs(599));
+        h.tick();
+        h.pass(Duration::from_secs(2));
+        h.tick();
+        Some(())
+    });
+}
+
+#[test]
+fn held_player_leaves_open_room() {
+    check_scenario("held_player_leaves_open_room", |h| {
+        start_small_game(h, json!({}))?;
+        h.send("bob", "create_room", json!({}));
+        h.disconnect("bob");
+        Some(())
+    });
+}
+
+#[test]
+fn room_deltas() {
+    check_scenario("room_deltas", |h| {
+        register_both(h);
+        h.connect("carol");
+        h.send(
+            "carol",
+            "reg",
+            json!({ "name": "carol", "password": "secret", "capabilities": ["roomDeltas", "unknown"] }),
+        );
+        h.send("alice", "create_room", json!({}));
+        let room_id = h.last("alice", "update_room")[0]["roomId"].clone();
+        h.send("bob", "add_user_to_room", json!({ "indexRoom": room_id }));
+        Some(())
+    });
+}
+
+#[test]
+fn winner_deltas() {
+    check_scenario("winner_deltas", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.connect("carol");
+        h.send(
+            "carol",
+            "reg",
+            json!({ "name": "carol", "password": "secret", "capabilities": ["winnerDeltas"] }),
+        );
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 7, "y": 7 }),
+        );
+        h.send("carol", "winners", json!({ "offset": 1, "limit": 5 }));
+        Some(())
+    });
+}
+
+#[test]
+fn subscriptions() {
+    check_scenario("subscriptions", |h| {
+        register_both(h);
+        h.send("bob", "unsubscribe", json!({ "feed": "rooms" }));
+        h.send("bob", "unsubscribe", json!({ "feed": "presence" }));
+        h.send("bob", "unsubscribe", json!({ "feed": "chat" }));
+        h.send("alice", "create_room", json!({}));
+        h.send("bob", "subscribe", json!({ "feed": "rooms" }));
+        Some(())
+    });
+}
+
+#[test]
+fn join_by_code() {
+    check_scenario("join_by_code", |h| {
+        register_both(h);
+        h.send("alice", "create_room", json!({}));
+        let code = h.last("alice", "update_room")[0]["code"].clone();
+        h.send("bob", "add_user_to_room", json!({ "indexRoom": "NOCODE" }));
+        let code = code.as_str()?.to_lowercase();
+        h.send("bob", "add_user_to_room", json!({ "indexRoom": code }));
+        Some(())
+    });
+}
+
+#[test]
+fn join_links() {
+    check_scenario("join_links", |h| {
+        register_both(h);
+        h.send("alice", "create_room", json!({}));
+        let code = h.last("alice", "update_room")[0]["code"].clone();
+        let code = code.as_str()?.to_owned();
+        h.connect_to("carol", &format!("/ws?join={}", code));
+        h.send(
+            "carol",
+            "reg",
+            json!({ "name": "carol", "password": "secret" }),
+        );
+        h.send("bob", "create_room", json!({}));
+        let code = h.last("bob", "update_room")[0]["code"].clone();
+        h.connect("dave");
+        h.send(
+            "dave",
+            "reg",
+            json!({ "name": "dave", "password": "secret", "joinLink": format!("https://example.com/join/{}", code.as_str()?) }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn owner_leaves_before_start() {
+    check_scenario("owner_leaves_before_start", |h| {
+        register_both(h);
+        h.send("alice", "create_room", json!({}));
+        let room_id = h.last("alice", "update_room")[0]["roomId"].clone();
+        h.send("bob", "add_user_to_room", json!({ "indexRoom": room_id }));
+        h.send("alice", "delete_account", json!({}));
+        let code = h.last("alice", "delete_account")["confirmationCode"].clone();
+        h.send(
+            "alice",
+            "delete_account",
+            json!({ "confirmationCode": code }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn reopened_room_forgets_abort() {
+    check_scenario("reopened_room_forgets_abort", |h| {
+        register_both(h);
+        h.send("alice", "create_room", json!({}));
+        let room_id = h.last("alice", "update_room")[0]["roomId"].clone();
+        h.send("bob", "add_user_to_room", json!({ "indexRoom": room_id }));
+        let game_id = h.game_id("bob");
+        h.send("bob", "abort_request", json!({ "gameId": game_id }));
+        h.send("bob", "delete_account", json!({}));
+        let code = h.last("bob", "delete_account")["confirmationCode"].clone();
+        h.send("bob", "delete_account", json!({ "confirmationCode": code }));
+        h.connect("carol");
+        h.send(
+            "carol",
+            "reg",
+            json!({ "name": "carol", "password": "secret" }),
+        );
+        h.send("carol", "add_user_to_room", json!({ "indexRoom": room_id }));
+        h.send("alice", "abort_accept", json!({ "gameId": game_id }));
+        Some(())
+    });
+}
+
+#[test]
+fn disconnect_during_game() {
+    check_scenario("disconnect_during_game", |h| {
+        start_small_game(h, json!({}))?;
+        h.disconnect("bob");
+        Some(())
+    });
+}
+
+#[test]
+fn kicked_player_forfeits() {
+    check_scenario("kicked_player_forfeits", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.admin("kick", json!({ "name": "bob" }));
+        h.disconnect("bob");
+        Some(())
+    });
+}
+
+#[test]
+fn deleted_account_forfeits() {
+    check_scenario("deleted_account_forfeits", |h| {
+        start_small_game(h, json!({}))?;
+        h.send("bob", "delete_account", json!({}));
+        let code = h.last("bob", "delete_account")["confirmationCode"].clone();
+        h.send("bob", "delete_account", json!({ "confirmationCode": code }));
+        Some(())
+    });
+}
+
+#[test]
+fn reconnect_during_game() {
+    check_scenario("reconnect_during_game", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        let token = h.last("bob", "reg")["sessionToken"].clone();
+        h.disconnect("bob");
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 5, "y": 5 }),
+        );
+        h.reconnect("bob");
+        h.send("bob", "reconnect", json!({ "sessionToken": "unknown" }));
+        h.send("bob", "reconnect", json!({ "sessionToken": token }));
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 5, "y": 5 }),
+        );
+        Some(())
+    });
+}
+
+/// Resumes the client's session until it is back online.
+fn resume(h: &mut Harness, client: &'static str) {
+    while !h.is_online(client) {
+        let token = h.last(client, "reg")["sessionToken"].clone();
+        h.reconnect(client);
+        h.send(client, "reconnect", json!({ "sessionToken": token }));
+        h.settle();
+    }
+}
+
+/// Fires until the shooter sees the shot land, resuming whenever its
+/// connection drops.
+fn fire_through(h: &mut Harness, shooter: &'static str, game_id: &str, x: u8, y: u8) {
+    let shooter_id = h.user_id(shooter);
+    let landed = |h: &Harness| {
+        h.received(shooter, "attack").iter().any(|attack| {
+            attack["currentPlayer"] == shooter_id.as_str()
+                && attack["position"] == json!({ "x": x, "y": y })
+        })
+    };
+    while !landed(h) {
+        resume(h, shooter);
+        h.send(
+            shooter,
+            "attack",
+            json!({ "gameId": game_id, "x": x, "y": y }),
+        );
+        h.settle();
+    }
+}
+
+#[test]
+fn flaky_network() {
+    check_scenario("flaky_network", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.has_turn("alice")?;
+        h.set_network(
+            Conditions {
+                latency: Duration::from_millis(40),
+                jitter: Duration::from_millis(30),
+                reorder: 0.2,
+                disconnect: 0.15,
+            },
+            7,
+        );
+        for (shooter, x, y) in [("alice", 3, 3), ("bob", 5, 5), ("alice", 7, 7)] {
+            fire_through(h, shooter, &game_id, x, y);
+        }
+        for client in ["alice", "bob"] {
+            resume(h, client);
+        }
+        // Whatever was lost on the way, nothing arrives twice and the
+        // result is the same wherever it arrives.
+        let alice_id = h.user_id("alice");
+        for client in ["alice", "bob"] {
+            let finishes = h.received(client, "finish");
+            assert!(
+                finishes.len() <= 1,
+                "{} got {} finishes",
+                client,
+                finishes.len()
+            );
+            assert!(finishes
+                .iter()
+                .all(|finish| finish["winPlayer"] == alice_id.as_str()));
+            let shots = h.received(client, "attack");
+            for (i, shot) in shots.iter().enumerate() {
+                assert!(!shots[..i].contains(shot), "{} got {} twice", client, shot);
+            }
+        }
+        assert_eq!(h.received("alice", "finish").len(), 1);
+        Some(())
+    });
+}
+
+#[test]
+fn malformed_message() {
+    check_scenario("malformed_message", |h| {
+        h.connect("alice");
+        h.send_raw("alice", "not json");
+        Some(())
+    });
+}
+
+#[test]
+fn invalid_events() {
+    check_scenario("invalid_events", |h| {
+        h.connect("alice");
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": "game", "x": 1, "y": 1 }),
+        );
+        h.send("alice", "reg", json!({}));
+        h.send(
+            "alice",
+            "reg",
+            json!({ "name": "alice", "password": "secret" }),
+        );
+        h.send("alice", "attack", json!({ "gameId": "game" }));
+        h.send(
+            "alice",
+            "add_ships",
+            json!({ "gameId": "game", "ships": 3 }),
+        );
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": "game", "x": 1, "y": 1 }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn salvo() {
+    check_scenario("salvo", |h| {
+        let game_id = start_game(
+            h,
+            json!({ "ruleset": "salvo", "fleet": { "1": 2 } }),
+            small_ships(&[(0, 0), (2, 0)]),
+            small_ships(&[(7, 7), (5, 7)]),
+        )?;
+        for (client, x, y) in [
+            ("alice", 3, 3),
+            ("alice", 7, 7),
+            // Bob is down to one ship, so one shot.
+            ("bob", 0, 0),
+            ("alice", 5, 7),
+        ] {
+            h.send(
+                client,
+                "attack",
+                json!({ "gameId": game_id, "x": x, "y": y }),
+            );
+        }
+        Some(())
+    });
+}
+
+#[test]
+fn salvo_handicap() {
+    check_scenario("salvo_handicap", |h| {
+        let game_id = start_game(
+            h,
+            json!({
+                "ruleset": "salvo",
+                "fleet": { "1": 2 },
+                "handicap": { "player": "creator", "fewerShots": 1 },
+            }),
+            small_ships(&[(0, 0), (2, 0)]),
+            small_ships(&[(7, 7), (5, 7)]),
+        )?;
+        // Alice has two ships afloat but one shot a turn.
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn mines() {
+    check_scenario("mines", |h| {
+        let ship = |x: u8, y: u8| {
+            json!({
+                "position": { "x": x, "y": y },
+                "direction": false,
+                "type": "medium",
+                "length": 2,
+            })
+        };
+        let mut alice_ships = small_ships(&[(0, 0)]);
+        alice_ships.as_array_mut().unwrap().push(ship(0, 3));
+        let mut bob_ships = small_ships(&[(7, 7)]);
+        bob_ships.as_array_mut().unwrap().push(ship(0, 7));
+        let game_id = start_game(
+            h,
+            json!({ "ruleset": "mines", "fleet": { "1": 1, "2": 1 } }),
+            alice_ships,
+            bob_ships,
+        )?;
+        for (client, x, y) in [
+            ("alice", 7, 7),
+            ("bob", 4, 4),
+            ("alice", 0, 7),
+            ("alice", 1, 7),
+        ] {
+            h.send(
+                client,
+                "attack",
+                json!({ "gameId": game_id, "x": x, "y": y }),
+            );
+        }
+        Some(())
+    });
+}
+
+#[test]
+fn quickplay() {
+    check_scenario("quickplay", |h| {
+        register_both(h);
+        h.send("alice", "quickplay", json!({}));
+        h.send("alice", "quickplay", json!({}));
+        h.send("alice", "cancel_search", json!({}));
+        h.send("alice", "cancel_search", json!({}));
+        h.send("alice", "quickplay", json!({}));
+        h.send("bob", "quickplay", json!({}));
+        h.send("bob", "quickplay", json!({}));
+        Some(())
+    });
+}
+
+#[test]
+fn quickplay_limits() {
+    check_scenario("quickplay_limits", |h| {
+        h.set_limits(Limits {
+            max_games: Some(2),
+            max_rooms_per_user: Some(1),
+            ..Limits::default()
+        });
+        register_both(h);
+        for name in ["carol", "dave", "erin"] {
+            h.connect(name);
+            h.send(name, "reg", json!({ "name": name, "password": "secret" }));
+        }
+        h.send("alice", "create_room", json!({}));
+        // Alice can't take another room, whichever side of the match she is.
+        h.send("alice", "quickplay", json!({}));
+        h.send("carol", "quickplay", json!({}));
+        h.send("alice", "quickplay", json!({}));
+        h.send("bob", "quickplay", json!({}));
+        // The second game fills the server.
+        h.send("dave", "quickplay", json!({}));
+        h.send("erin", "quickplay", json!({}));
+        // Dave is no longer searching, which shows at the next tick.
+        h.tick();
+        Some(())
+    });
+}
+
+#[test]
+fn deleted_account_leaves_queue() {
+    check_scenario("deleted_account_leaves_queue", |h| {
+        register_both(h);
+        h.connect("carol");
+        h.send(
+            "carol",
+            "reg",
+            json!({ "name": "carol", "password": "secret" }),
+        );
+        h.send("alice", "quickplay", json!({}));
+        h.send("alice", "delete_account", json!({}));
+        let code = h.last("alice", "delete_account")["confirmationCode"].clone();
+        h.send(
+            "alice",
+            "delete_account",
+            json!({ "confirmationCode": code }),
+        );
+        h.send("bob", "quickplay", json!({}));
+        h.send("carol", "quickplay", json!({}));
+        Some(())
+    });
+}
+
+#[test]
+fn resident_bots() {
+    check_scenario("resident_bots", |h| {
+        h.admin("add_bot", json!({ "name": "Ace", "strategy": "hunt" }));
+        h.admin("add_bot", json!({ "name": "Ace", "strategy": "random" }));
+        h.admin(
+            "add_bot",
+            json!({ "name": "Oracle", "strategy": "psychic" }),
+        );
+        h.connect("alice");
+        h.send(
+            "alice",
+            "reg",
+            json!({ "name": "alice", "password": "secret" }),
+        );
+        h.send("alice", "quickplay", json!({}));
+        h.pass(Duration::from_secs(30));
+        h.tick();
+        h.admin("remove_bot", json!({ "name": "Ace" }));
+        h.admin("remove_bot", json!({ "name": "alice" }));
+        h.tick();
+        Some(())
+    });
+}
+
+#[test]
+fn one_off_bots() {
+    check_scenario("one_off_bots", |h| {
+        h.admin("add_bot", json!({ "name": "Bot-9", "strategy": "hunt" }));
+        register_both(h);
+        h.connect("carol");
+        h.send(
+            "carol",
+            "reg",
+            json!({ "name": "bot-1", "password": "secret" }),
+        );
+        h.send("alice", "quickplay", json!({}));
+        h.pass(Duration::from_secs(30));
+        h.tick();
+        h.send("alice", "list_live_games", json!({}));
+        h.send("bob", "quickplay", json!({}));
+        h.pass(Duration::from_secs(30));
+        h.tick();
+        // Listed alone, as live games with as many wins come in random order.
+        let game_id = h.game_id("alice");
+        h.admin("delete_game", json!({ "gameId": game_id }));
+        h.send("alice", "list_live_games", json!({}));
+        Some(())
+    });
+}
+
+#[test]
+fn practice_range() {
+    check_scenario("practice_range", |h| {
+        h.set_practice_seed(7);
+        h.connect("alice");
+        h.send(
+            "alice",
+            "reg",
+            json!({ "name": "alice", "password": "secret" }),
+        );
+        h.send("alice", "practice_attack", json!({ "x": 0, "y": 0 }));
+        h.send("alice", "practice_start", json!({ "boardSize": 20 }));
+        h.send("alice", "practice_start", json!({ "boardSize": 8 }));
+        for x in 0..8 {
+            h.send("alice", "practice_attack", json!({ "x": x, "y": 2 }));
+        }
+        h.send("alice", "practice_attack", json!({ "x": 0, "y": 2 }));
+        h.send("alice", "practice_attack", json!({ "x": 8, "y": 0 }));
+        h.send("alice", "practice_start", json!({}));
+        h.send("alice", "practice_stop", json!({}));
+        h.send("alice", "practice_stop", json!({}));
+        Some(())
+    });
+}
+
+#[test]
+fn late_spectator() {
+    check_scenario("late_spectator", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.connect("carol");
+        h.send(
+            "carol",
+            "reg",
+            json!({ "name": "carol", "password": "secret" }),
+        );
+        h.send("carol", "spectate", json!({ "gameId": game_id }));
+        h.send("alice", "spectate", json!({ "gameId": game_id }));
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 4, "y": 4 }),
+        );
+        h.send("carol", "stop_spectating", json!({ "gameId": game_id }));
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 7, "y": 7 }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn validate_ships() {
+    check_scenario("validate_ships", |h| {
+        register_both(h);
+        h.send(
+            "alice",
+            "create_room",
+            json!({ "boardSize": 8, "fleet": { "1": 3 }, "noTouching": true }),
+        );
+        let room_id = h.last("alice", "update_room")[0]["roomId"].clone();
+        h.send("bob", "add_user_to_room", json!({ "indexRoom": room_id }));
+        let game_id = h.game_id("alice");
+        for ships in [
+            small_ships(&[(0, 0), (4, 4), (6, 6)]),
+            small_ships(&[(0, 0), (0, 0), (1, 1), (9, 9)]),
+        ] {
+            h.send(
+                "alice",
+                "validate_ships",
+                json!({ "gameId": game_id, "ships": ships }),
+            );
+        }
+        Some(())
+    });
+}
+
+#[test]
+fn shared_replay() {
+    check_scenario("shared_replay", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 7, "y": 7 }),
+        );
+        h.send(
+            "bob",
+            "share_replay",
+            json!({ "gameId": game_id, "anonymize": true }),
+        );
+        let replay_id = h.last("bob", "share_replay")["replayId"].clone();
+        h.send("alice", "share_replay", json!({ "gameId": "unknown" }));
+        h.connect("carol");
+        h.send("carol", "watch_replay", json!({ "replayId": replay_id }));
+        h.send("alice", "share_replay", json!({ "gameId": game_id }));
+        h.send("carol", "watch_replay", json!({ "replayId": "unknown" }));
+        Some(())
+    });
+}
+
+#[test]
+fn deleted_account_forgets_replays() {
+    check_scenario("deleted_account_forgets_replays", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 7, "y": 7 }),
+        );
+        h.send("bob", "share_replay", json!({ "gameId": game_id }));
+        let replay_id = h.last("bob", "share_replay")["replayId"].clone();
+        h.send("alice", "delete_account", json!({}));
+        let code = h.last("alice", "delete_account")["confirmationCode"].clone();
+        h.send(
+            "alice",
+            "delete_account",
+            json!({ "confirmationCode": code }),
+        );
+        h.send("bob", "watch_replay", json!({ "replayId": replay_id }));
+        Some(())
+    });
+}
+
+#[test]
+fn game_snapshots() {
+    check_scenario("game_snapshots", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.admin("snapshot_game", json!({ "gameId": game_id }));
+        let snapshot = h.last("admin", "snapshot_game");
+        h.admin("restore_game", json!({ "snapshot": snapshot }));
+        let tampered = [
+            // A ship index past bob's fleet.
+            ("/boards/1/cells/63", json!({ "alive": 5 })),
+            ("/boards/1/hits", json!([])),
+            ("/boards/1/hits/0", json!(2)),
+            ("/boards/1/ships/ships/0/position/x", json!(20)),
+            ("/boards/0/cells/63", json!("killed")),
+            ("/settings/boardSize", json!(100)),
+        ];
+        for (pointer, value) in tampered {
+            let mut snapshot = snapshot.clone();
+            *snapshot.pointer_mut(pointer)? = value;
+            h.admin("restore_game", json!({ "snapshot": snapshot }));
+        }
+        h.admin("delete_game", json!({ "gameId": game_id }));
+        h.admin("restore_game", json!({ "snapshot": snapshot }));
+        h.admin("snapshot_game", json!({ "gameId": game_id }));
+        assert_eq!(h.last("admin", "snapshot_game"), snapshot);
+        h.send(
+            "bob",
+            "attack",
+            json!({ "gameId": game_id, "x": 0, "y": 0 }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn localized_errors() {
+    check_scenario("localized_errors", |h| {
+        h.connect("alice");
+        h.send(
+            "alice",
+            "reg",
+            json!({ "name": "alice", "locale": "ru-RU" }),
+        );
+        h.send("alice", "attack", json!({ "gameId": "game" }));
+        h.send("alice", "create_room", json!({ "boardSize": 3 }));
+        h.send("alice", "cancel_search", json!({}));
+        h.connect("bob");
+        h.send("bob", "reg", json!({ "name": "bob", "locale": "tlh" }));
+        h.send("bob", "attack", json!({ "gameId": "game" }));
+        Some(())
+    });
+}
+
+#[test]
+fn presence() {
+    check_scenario("presence", |h| {
+        register_both(h);
+        h.send("alice", "add_friend", json!({ "name": "bob" }));
+        h.send("bob", "accept_friend", json!({ "name": "alice" }));
+        h.send("alice", "quickplay", json!({}));
+        h.send("alice", "cancel_search", json!({}));
+        h.disconnect("alice");
+        Some(())
+    });
+}
+
+#[test]
+fn friend_accounts() {
+    check_scenario("friend_accounts", |h| {
+        register_both(h);
+        h.send("alice", "add_friend", json!({ "name": "nobody" }));
+        h.send("alice", "add_friend", json!({ "name": "bob" }));
+        h.send("alice", "add_friend", json!({ "name": "bob" }));
+        let account = h.last("alice", "reg")["account"].clone();
+        h.send("bob", "accept_friend", json!({ "account": account }));
+        let key = h.last("alice", "reg")["accountKey"].clone();
+        h.disconnect("alice");
+        // Another player taking the name doesn't take the friendship.
+        h.connect("mallory");
+        h.send("mallory", "reg", json!({ "name": "alice" }));
+        h.send("mallory", "challenge_friend", json!({ "name": "bob" }));
+        h.connect("alicia");
+        h.send(
+            "alicia",
+            "reg",
+            json!({ "name": "alicia", "accountKey": key }),
+        );
+        h.send("alicia", "challenge_friend", json!({ "name": "bob" }));
+        Some(())
+    });
+}
+
+#[test]
+fn spectator_cap() {
+    check_scenario("spectator_cap", |h| {
+        let game_id = start_small_game(h, json!({ "maxSpectators": 1 }))?;
+        for name in ["carol", "dave"] {
+            h.connect(name);
+            h.send(name, "reg", json!({ "name": name, "password": "secret" }));
+            h.send(name, "spectate", json!({ "gameId": game_id }));
+        }
+        h.send("carol", "spectate", json!({ "gameId": game_id }));
+        h.send("carol", "stop_spectating", json!({ "gameId": game_id }));
+        h.send("dave", "spectate", json!({ "gameId": game_id }));
+        Some(())
+    });
+}
+
+#[test]
+fn handicap() {
+    check_scenario("handicap", |h| {
+        let game_id = start_game(
+            h,
+            json!({
+                "fleet": { "1": 2 },
+                "handicap": { "player": "creator", "fleet": { "1": 1 } },
+            }),
+            small_ships(&[(0, 0)]),
+            small_ships(&[(5, 5), (7, 7)]),
+        )?;
+        h.send(
+            "alice",
+            "create_room",
+            json!({ "fleet": { "1": 2 }, "handicap": { "player": "opponent", "fleet": { "1": 2 } } }),
+        );
+        h.send(
+            "alice",
+            "create_room",
+            json!({ "handicap": { "player": "opponent", "fewerShots": 1 } }),
+        );
+        h.send(
+            "alice",
+            "create_room",
+            json!({
+                "ruleset": "mines",
+                "fleet": { "1": 2, "2": 1 },
+                "handicap": { "player": "opponent", "fleet": { "1": 2 } },
+            }),
+        );
+        for (x, y) in [(5, 5), (7, 7)] {
+            h.send(
+                "alice",
+                "attack",
+                json!({ "gameId": game_id, "x": x, "y": y }),
+            );
+        }
+        Some(())
+    });
+}
+
+#[test]
+fn sync_board() {
+    check_scenario("sync_board", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.send("bob", "sync", json!({}));
+        Some(())
+    });
+}
+
+#[test]
+fn desync_report() {
+    check_scenario("desync_report", |h| {
+        let game_id = start_small_game(h, json!({}))?;
+        let checksum = h.last("bob", "turn")["checksum"].clone();
+        h.send(
+            "alice",
+            "attack",
+            json!({ "gameId": game_id, "x": 3, "y": 3 }),
+        );
+        h.send(
+            "bob",
+            "sync",
+            json!({ "gameId": game_id, "checksum": checksum }),
+        );
+        Some(())
+    });
+}
+
+#[test]
+fn live_games() {
+    check_scenario("live_games", |h| {
+        start_small_game(h, json!({}))?;
+        h.connect("carol");
+        h.send(
+            "carol",
+            "reg",
+            json!({ "name": "carol", "password": "secret" }),
+        );
+        h.send("carol", "list_live_games", json!({}));
+        Some(())
+    });
+}
+
+#[test]
+fn scripted_game() {
+    check_script(
+        r#"
+        alice registers
+        bob registers
+        alice creates room {"boardSize": 8, "fleet": {"1": 2}}
+        bob joins
+        alice gets create_game
+        alice places 0,0 2,2
+        bob places 7,7 5,5
+        alice gets start_game, turn
+        alice moves first
+        bob fires 0,0
+        bob gets error(not_your_turn)
+        alice gets nothing
+        alice fires 7,7
+        alice gets attack, turn
+        bob gets attack, turn
+        alice fires 5,5
+        alice gets attack, finish
+        bob gets finish
+        "#,
+    );
+}
+
+#[test]
+fn scripted_reconnect_grace_runs_out() {
+    check_script(
+        r#"
+        alice registers
+        bob registers
+        alice creates room {"boardSize": 8, "fleet": {"1": 1}, "clock": {"initial": 60}}
+        bob joins
+        alice places 0,0
+        bob places 7,7
+        alice moves first
+        alice fires 3,3
+        bob disconnects
+        29s pass
+        tick
+        alice gets nothing
+        2s pass
+        tick
+        alice gets finish, game_summary, reveal, update_winners
+        "#,
+    );
+}
+
+#[test]
+fn scripted_turn_clock_runs_out() {
+    check_script(
+        r#"
+        alice registers
+        bob registers
+        alice creates room {"boardSize": 8, "fleet": {"1": 1}, "clock": {"initial": 60}}
+        bob joins
+        alice places 0,0
+        bob places 7,7
+        alice moves first
+        59s pass
+        tick
+        bob gets nothing
+        2s pass
+        tick
+        bob gets finish
+        "#,
+    );
+}
+
+#[test]
+fn scripted_owner_disconnects() {
+    check_script(
+        r#"
+        alice registers
+        bob registers
+        alice creates room
+        bob gets update_room
+        alice disconnects
+        bob gets update_room
+        # The room is gone with its owner, joining it does nothing.
+        bob joins
+        bob gets nothing
+        "#,
+    );
+}
@@ -0,0 +1,72 @@
+//! Rulesets written in Rhai, in builds with the `scripting` feature. The
+//! scripts under `tests/rules` are loaded once per process, so they get a
+//! test binary of their own.
+
+#![cfg(feature = "scripting")]
+
+// Most of the harness is for the protocol snapshots.
+#[allow(dead_code)]
+mod common;
+
+use common::check_scenario;
+use serde_json::json;
+
+fn ships(positions: &[(u8, u8)]) -> serde_json::Value {
+    positions
+        .iter()
+        .map(|(x, y)| {
+            json!({
+                "position": { "x": x, "y": y },
+                "direction": false,
+                "type": "small",
+                "length": 1,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn scripted_ruleset() {
+    std::env::set_var(
+        "RULES_SCRIPTS",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/rules"),
+    );
+    check_scenario("scripted_ruleset", |h| {
+        h.connect("alice");
+        h.connect("bob");
+        h.send(
+            "alice",
+            "reg",
+            json!({ "name": "alice", "password": "secret" }),
+        );
+        h.send("bob", "reg", json!({ "name": "bob", "password": "secret" }));
+        h.send(
+            "alice",
+            "create_room",
+            json!({ "boardSize": 8, "ruleset": "house", "fleet": { "1": 2 } }),
+        );
+        let room_id = h.last("alice", "update_room")[0]["roomId"].clone();
+        h.send("bob", "add_user_to_room", json!({ "indexRoom": room_id }));
+        let game_id = h.game_id("alice");
+        for (client, positions) in [
+            ("alice", [(0, 0), (2, 2)]),
+            ("alice", [(1, 1), (3, 3)]),
+            ("bob", [(5, 5), (3, 6)]),
+        ] {
+            h.send(
+                client,
+                "add_ships",
+                json!({ "gameId": game_id, "ships": ships(&positions) }),
+            );
+        }
+        h.has_turn("alice")?;
+        for (client, x, y) in [("alice", 5, 5), ("bob", 1, 1), ("alice", 3, 6)] {
+            h.send(
+                client,
+                "attack",
+                json!({ "gameId": game_id, "x": x, "y": y }),
+            );
+        }
+        Some(())
+    });
+}